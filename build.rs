@@ -1,9 +1,14 @@
+// The pure parsing of the network settings, for checking them at build time
+#[allow(dead_code)]
+#[path = "src/network_settings.rs"]
+mod network_settings;
+
 fn main() {
     linker_be_nice();
     load_config();
-    println!("cargo:rustc-link-arg=-Tdefmt.x");
+    println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
     // make sure linkall.x is the last linker script (otherwise might cause problems with flip-link)
-    println!("cargo:rustc-link-arg=-Tlinkall.x");
+    println!("cargo:rustc-link-arg-bins=-Tlinkall.x");
 }
 
 fn load_config() {
@@ -12,6 +17,10 @@ fn load_config() {
 
     let config_path = Path::new("config.toml");
 
+    // Empty unless an enterprise CA certificate is configured
+    let ca_cert_path = Path::new(&std::env::var("OUT_DIR").unwrap()).join("wifi_ca_cert");
+    std::fs::write(&ca_cert_path, []).expect("Failed to write CA certificate");
+
     if !config_path.exists() {
         eprintln!("config.toml not found");
         return;
@@ -24,30 +33,362 @@ fn load_config() {
 
     // WiFi settings
     if let Ok(ssid) = settings.get_string("wifi.ssid") {
-        println!("cargo:rustc-env=SSID={}", ssid);
+        println!("cargo:rustc-env=SSID={ssid}");
     }
     if let Ok(psk) = settings.get_string("wifi.psk") {
-        println!("cargo:rustc-env=PSK={}", psk);
+        println!("cargo:rustc-env=PSK={psk}");
+    }
+    if let Ok(networks) = settings.get_array("wifi.networks") {
+        let networks: Vec<String> = networks
+            .into_iter()
+            .filter_map(|network| network.into_table().ok())
+            .map(|network| {
+                let get = |key: &str| {
+                    network
+                        .get(key)
+                        .and_then(|value| value.clone().into_string().ok())
+                        .unwrap_or_default()
+                };
+                // The EAP password of enterprise networks takes the place
+                // of the passphrase
+                let psk = match network.contains_key("password") {
+                    true => get("password"),
+                    false => get("psk"),
+                };
+                [
+                    get("priority"),
+                    get("ssid"),
+                    psk,
+                    get("eap"),
+                    get("identity"),
+                    get("username"),
+                    get("phase2"),
+                ]
+                .join("\x1f")
+            })
+            .collect();
+        println!("cargo:rustc-env=WIFI_NETWORKS={}", networks.join("\x1e"));
+    }
+    // The CA certificate for enterprise networks is embedded from a file. PEM
+    // is passed on NUL terminated as esp-wifi expects.
+    if let Ok(path) = settings.get_string("wifi.ca_cert") {
+        println!("cargo:rerun-if-changed={path}");
+        let mut ca_cert = std::fs::read(&path).expect("Failed to read wifi.ca_cert");
+        if ca_cert.starts_with(b"-----BEGIN") {
+            ca_cert.push(0);
+        }
+        std::fs::write(&ca_cert_path, ca_cert).expect("Failed to write CA certificate");
+    }
+    if let Ok(insecure) = settings.get_bool("wifi.insecure") {
+        println!("cargo:rustc-env=WIFI_INSECURE={insecure}");
+    }
+
+    // Network settings
+    if let Ok(ipv6) = settings.get_bool("network.ipv6") {
+        println!("cargo:rustc-env=NETWORK_IPV6={ipv6}");
+    }
+    for (key, env) in [
+        ("network.address", "NETWORK_ADDRESS"),
+        ("network.gateway", "NETWORK_GATEWAY"),
+        ("network.hostname", "NETWORK_HOSTNAME"),
+    ] {
+        if let Ok(value) = settings.get_string(key) {
+            println!("cargo:rustc-env={env}={value}");
+        }
+    }
+    let dns = match settings.get_array("network.dns") {
+        Ok(dns) => {
+            let dns: Vec<String> = dns
+                .into_iter()
+                .filter_map(|server| server.into_string().ok())
+                .collect();
+            println!("cargo:rustc-env=NETWORK_DNS={}", dns.join(","));
+            dns.join(",")
+        }
+        Err(_) => String::new(),
+    };
+    // The firmware would fall back to DHCP, leaving the board unreachable at
+    // the address it was meant to have
+    let setting = |key| settings.get_string(key).unwrap_or_default();
+    if let Err(e) = network_settings::parse_static(
+        &setting("network.address"),
+        &setting("network.gateway"),
+        &dns,
+    ) {
+        panic!("Invalid static network settings: {e:?}");
+    }
+
+    // mDNS settings
+    for (key, env) in [
+        ("mdns.enabled", "MDNS_ENABLED"),
+        ("mdns.prometheus", "MDNS_PROMETHEUS"),
+    ] {
+        if let Ok(value) = settings.get_bool(key) {
+            println!("cargo:rustc-env={env}={value}");
+        }
+    }
+    if let Ok(hostname) = settings.get_string("mdns.hostname") {
+        println!("cargo:rustc-env=MDNS_HOSTNAME={hostname}");
     }
 
     // MQTT settings
     if let Ok(host) = settings.get_string("mqtt.host") {
-        println!("cargo:rustc-env=MQTT_HOST={}", host);
+        println!("cargo:rustc-env=MQTT_HOST={host}");
     }
     if let Ok(port) = settings.get_int("mqtt.port") {
-        println!("cargo:rustc-env=MQTT_PORT={}", port);
+        println!("cargo:rustc-env=MQTT_PORT={port}");
     }
     if let Ok(username) = settings.get_string("mqtt.username") {
-        println!("cargo:rustc-env=MQTT_USERNAME={}", username);
+        println!("cargo:rustc-env=MQTT_USERNAME={username}");
     }
     if let Ok(password) = settings.get_string("mqtt.password") {
-        println!("cargo:rustc-env=MQTT_PASSWORD={}", password);
+        println!("cargo:rustc-env=MQTT_PASSWORD={password}");
+    }
+    if let Ok(format) = settings.get_string("mqtt.format") {
+        println!("cargo:rustc-env=MQTT_FORMAT={format}");
+    }
+    if let Ok(enabled) = settings.get_bool("mqtt.fields.enabled") {
+        println!("cargo:rustc-env=MQTT_FIELDS_ENABLED={enabled}");
+    }
+    if let Ok(retain) = settings.get_bool("mqtt.fields.retain") {
+        println!("cargo:rustc-env=MQTT_FIELDS_RETAIN={retain}");
+    }
+    for (key, env) in [
+        ("mqtt.fields.template", "MQTT_FIELDS_TEMPLATE"),
+        ("mqtt.fields.base", "MQTT_FIELDS_BASE"),
+    ] {
+        if let Ok(value) = settings.get_string(key) {
+            println!("cargo:rustc-env={env}={value}");
+        }
+    }
+    // Per-field tables are passed as `field=value,...` lists
+    for (key, env) in [
+        ("mqtt.fields.precision", "MQTT_FIELDS_PRECISION"),
+        ("mqtt.deadband", "MQTT_DEADBAND"),
+        ("mqtt.relative_deadband", "MQTT_RELATIVE_DEADBAND"),
+    ] {
+        if let Ok(table) = settings.get_table(key) {
+            let mut entries: Vec<String> = table
+                .into_iter()
+                .filter_map(|(field, value)| Some(format!("{field}={}", value.into_float().ok()?)))
+                .collect();
+            entries.sort();
+            println!("cargo:rustc-env={env}={}", entries.join(","));
+        }
+    }
+    if let Ok(enabled) = settings.get_bool("mqtt.exception.enabled") {
+        println!("cargo:rustc-env=MQTT_EXCEPTION_ENABLED={enabled}");
+    }
+    for (key, env) in [
+        ("mqtt.exception.min_interval", "MQTT_EXCEPTION_MIN_INTERVAL"),
+        ("mqtt.exception.heartbeat", "MQTT_EXCEPTION_HEARTBEAT"),
+    ] {
+        if let Ok(value) = settings.get_int(key) {
+            println!("cargo:rustc-env={env}={value}");
+        }
     }
     if let Ok(topic_scd41) = settings.get_string("mqtt.topic_scd41") {
-        println!("cargo:rustc-env=MQTT_TOPIC_SCD41={}", topic_scd41);
+        println!("cargo:rustc-env=MQTT_TOPIC_SCD41={topic_scd41}");
     }
     if let Ok(topic_bme680) = settings.get_string("mqtt.topic_bme680") {
-        println!("cargo:rustc-env=MQTT_TOPIC_BME680={}", topic_bme680);
+        println!("cargo:rustc-env=MQTT_TOPIC_BME680={topic_bme680}");
+    }
+    if let Ok(base) = settings.get_string("mqtt.base") {
+        println!("cargo:rustc-env=MQTT_BASE={base}");
+    }
+    if let Ok(topic_ventilation) = settings.get_string("mqtt.topic_ventilation") {
+        println!("cargo:rustc-env=MQTT_TOPIC_VENTILATION={topic_ventilation}");
+    }
+    if let Ok(topic_fusion) = settings.get_string("mqtt.topic_fusion") {
+        println!("cargo:rustc-env=MQTT_TOPIC_FUSION={topic_fusion}");
+    }
+    if let Ok(topic_health) = settings.get_string("mqtt.topic_health") {
+        println!("cargo:rustc-env=MQTT_TOPIC_HEALTH={topic_health}");
+    }
+    for (key, env) in [
+        ("mqtt.keep_alive", "MQTT_KEEP_ALIVE"),
+        ("mqtt.sensor_timeout", "MQTT_SENSOR_TIMEOUT"),
+        ("mqtt.failover_after", "MQTT_FAILOVER_AFTER"),
+        ("mqtt.probe_interval", "MQTT_PROBE_INTERVAL"),
+    ] {
+        if let Ok(value) = settings.get_int(key) {
+            println!("cargo:rustc-env={env}={value}");
+        }
+    }
+    if let Ok(topic_inventory) = settings.get_string("mqtt.topic_inventory") {
+        println!("cargo:rustc-env=MQTT_TOPIC_INVENTORY={topic_inventory}");
+    }
+    if let Ok(brokers) = settings.get_array("mqtt.brokers") {
+        let brokers: Vec<String> = brokers
+            .into_iter()
+            .filter_map(|broker| broker.into_string().ok())
+            .collect();
+        println!("cargo:rustc-env=MQTT_BROKERS={}", brokers.join(","));
+    }
+
+    // Site settings
+    match settings.get_float("site.altitude") {
+        Ok(altitude) if altitude.is_finite() => {
+            println!("cargo:rustc-env=SITE_ALTITUDE={altitude}");
+        }
+        Ok(altitude) => panic!("Invalid site.altitude: {altitude}"),
+        Err(config::ConfigError::NotFound(_)) => {}
+        Err(e) => panic!("Invalid site.altitude: {e}"),
+    }
+
+    // Statistics settings
+    if let Ok(windows) = settings.get_array("stats.windows") {
+        let windows: Vec<String> = windows
+            .into_iter()
+            .filter_map(|window| window.into_int().ok())
+            .map(|window| window.to_string())
+            .collect();
+        println!("cargo:rustc-env=STATS_WINDOWS={}", windows.join(","));
+    }
+
+    // Alert rules
+    if let Ok(rules) = settings.get_array("alerts") {
+        let rules: Vec<String> = rules
+            .into_iter()
+            .filter_map(|rule| rule.into_table().ok())
+            .map(|rule| {
+                let get = |key: &str| {
+                    rule.get(key)
+                        .and_then(|value| value.clone().into_string().ok())
+                        .unwrap_or_default()
+                };
+                let (op, trigger) = match rule.contains_key("below") {
+                    true => ("<", get("below")),
+                    false => (">", get("above")),
+                };
+                [
+                    get("name"),
+                    get("field"),
+                    op.to_string(),
+                    trigger,
+                    get("clear"),
+                    get("for"),
+                    get("clear_for"),
+                ]
+                .join(",")
+            })
+            .collect();
+        println!("cargo:rustc-env=ALERT_RULES={}", rules.join(";"));
+    }
+
+    // Ventilation advisor settings
+    if let Ok(volume) = settings.get_float("ventilation.room_volume") {
+        println!("cargo:rustc-env=VENTILATION_ROOM_VOLUME={volume}");
+    }
+    if let Ok(co2) = settings.get_float("ventilation.outdoor_co2") {
+        println!("cargo:rustc-env=VENTILATION_OUTDOOR_CO2={co2}");
+    }
+    if let Ok(air_changes) = settings.get_float("ventilation.air_changes") {
+        println!("cargo:rustc-env=VENTILATION_AIR_CHANGES={air_changes}");
+    }
+
+    // Sensor fusion settings
+    for (key, env) in [
+        (
+            "fusion.scd41_temperature_offset",
+            "FUSION_SCD41_TEMPERATURE_OFFSET",
+        ),
+        (
+            "fusion.scd41_humidity_offset",
+            "FUSION_SCD41_HUMIDITY_OFFSET",
+        ),
+        (
+            "fusion.bme680_temperature_offset",
+            "FUSION_BME680_TEMPERATURE_OFFSET",
+        ),
+        (
+            "fusion.bme680_humidity_offset",
+            "FUSION_BME680_HUMIDITY_OFFSET",
+        ),
+        (
+            "fusion.temperature_threshold",
+            "FUSION_TEMPERATURE_THRESHOLD",
+        ),
+        ("fusion.humidity_threshold", "FUSION_HUMIDITY_THRESHOLD"),
+    ] {
+        if let Ok(value) = settings.get_float(key) {
+            println!("cargo:rustc-env={env}={value}");
+        }
+    }
+    if let Ok(calibrate) = settings.get_bool("fusion.calibrate_scd41") {
+        println!("cargo:rustc-env=FUSION_CALIBRATE_SCD41={calibrate}");
+    }
+
+    // Self-heating compensation settings
+    for (key, env) in [
+        ("self_heating.warmup", "SELF_HEATING_WARMUP"),
+        ("self_heating.warmup_time", "SELF_HEATING_WARMUP_TIME"),
+        ("self_heating.tx", "SELF_HEATING_TX"),
+        ("self_heating.tx_time", "SELF_HEATING_TX_TIME"),
+    ] {
+        if let Ok(value) = settings.get_float(key) {
+            println!("cargo:rustc-env={env}={value}");
+        }
+    }
+
+    // History log settings
+    if let Ok(enabled) = settings.get_bool("history.enabled") {
+        println!("cargo:rustc-env=HISTORY_ENABLED={enabled}");
+    }
+    if let Ok(interval) = settings.get_int("history.interval") {
+        println!("cargo:rustc-env=HISTORY_INTERVAL={interval}");
+    }
+
+    // InfluxDB settings
+    if let Ok(enabled) = settings.get_bool("influx.enabled") {
+        println!("cargo:rustc-env=INFLUX_ENABLED={enabled}");
+    }
+    for (key, env) in [
+        ("influx.transport", "INFLUX_TRANSPORT"),
+        ("influx.host", "INFLUX_HOST"),
+        ("influx.port", "INFLUX_PORT"),
+        ("influx.org", "INFLUX_ORG"),
+        ("influx.bucket", "INFLUX_BUCKET"),
+        ("influx.token", "INFLUX_TOKEN"),
+        ("influx.device", "INFLUX_DEVICE"),
+    ] {
+        if let Ok(value) = settings.get_string(key) {
+            println!("cargo:rustc-env={env}={value}");
+        }
+    }
+
+    // HTTP push settings
+    if let Ok(enabled) = settings.get_bool("push.enabled") {
+        println!("cargo:rustc-env=PUSH_ENABLED={enabled}");
+    }
+    for (key, env) in [
+        ("push.host", "PUSH_HOST"),
+        ("push.port", "PUSH_PORT"),
+        ("push.path", "PUSH_PATH"),
+        ("push.format", "PUSH_FORMAT"),
+        ("push.token", "PUSH_TOKEN"),
+    ] {
+        if let Ok(value) = settings.get_string(key) {
+            println!("cargo:rustc-env={env}={value}");
+        }
+    }
+
+    // Serial logging settings
+    if let Ok(enabled) = settings.get_bool("serial.enabled") {
+        println!("cargo:rustc-env=SERIAL_ENABLED={enabled}");
+    }
+    if let Ok(format) = settings.get_string("serial.format") {
+        println!("cargo:rustc-env=SERIAL_FORMAT={format}");
+    }
+
+    // Time settings
+    if let Ok(server) = settings.get_string("time.ntp_server") {
+        println!("cargo:rustc-env=NTP_SERVER={server}");
+    }
+
+    // Display settings
+    if let Ok(epaper) = settings.get_bool("display.epaper") {
+        println!("cargo:rustc-env=DISPLAY_EPAPER={epaper}");
     }
 
     println!("cargo:rerun-if-changed=config.toml");
@@ -83,7 +424,7 @@ fn linker_be_nice() {
     }
 
     println!(
-        "cargo:rustc-link-arg=--error-handling-script={}",
+        "cargo:rustc-link-arg-bins=--error-handling-script={}",
         std::env::current_exe().unwrap().display()
     );
 }
@@ -0,0 +1,423 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::{debug, info, warn, Format};
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+use serde::Serialize;
+
+use crate::bme680::Bme680Measurement;
+use crate::mqtt::{self, Outgoing};
+use crate::scd41::Scd41Measurement;
+use crate::sink::{self, Reading};
+
+/// Whether any rule is currently raised, used to drive the LED
+pub static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+pub const MAX_RULES: usize = 8;
+
+/// Rules as `name,field,op,trigger,clear,for,clear_for` separated by `;`,
+/// see `config.example.toml`
+const ALERT_RULES: &str = match option_env!("ALERT_RULES") {
+    Some(rules) => rules,
+    None => "co2_high,co2,>,1200,1000,120,0",
+};
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Field {
+    Co2,
+    Scd41Temperature,
+    Scd41Humidity,
+    Temperature,
+    Humidity,
+    Pressure,
+    GasResistance,
+}
+
+impl Field {
+    fn parse(field: &str) -> Option<Field> {
+        Some(match field {
+            "co2" | "scd41.co2" => Field::Co2,
+            "scd41.temperature" => Field::Scd41Temperature,
+            "scd41.humidity" => Field::Scd41Humidity,
+            "temperature" | "bme680.temperature" => Field::Temperature,
+            "humidity" | "bme680.humidity" => Field::Humidity,
+            "pressure" | "bme680.pressure" => Field::Pressure,
+            "gas_resistance" | "bme680.gas_resistance" => Field::GasResistance,
+            _ => return None,
+        })
+    }
+
+    fn read_scd41(self, measurement: &Scd41Measurement) -> Option<f32> {
+        match self {
+            Field::Co2 => Some(measurement.co2 as f32),
+            Field::Scd41Temperature => Some(measurement.temperature),
+            Field::Scd41Humidity => Some(measurement.humidity),
+            _ => None,
+        }
+    }
+
+    fn read_bme680(self, measurement: &Bme680Measurement) -> Option<f32> {
+        match self {
+            Field::Temperature => Some(measurement.temperature),
+            Field::Humidity => Some(measurement.humidity),
+            Field::Pressure => Some(measurement.pressure),
+            Field::GasResistance => measurement.gas_resistance,
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Raised when the value rises above the trigger threshold
+    Above,
+    /// Raised when the value falls below the trigger threshold
+    Below,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Rule {
+    pub name: &'static str,
+    pub field: Field,
+    pub direction: Direction,
+    /// Threshold the value must cross to raise the alert
+    pub trigger: f32,
+    /// Threshold the value must cross back over to clear it, giving hysteresis
+    pub clear: f32,
+    /// How long the trigger condition must hold before raising
+    pub trigger_for: Duration,
+    /// How long the clear condition must hold before clearing
+    pub clear_for: Duration,
+}
+
+impl Rule {
+    /// Parse one `name,field,op,trigger,clear,for,clear_for` rule. `clear`,
+    /// `for` and `clear_for` may be empty, defaulting to the trigger threshold
+    /// and no delay.
+    pub fn parse(rule: &'static str) -> Option<Rule> {
+        let mut parts = rule.split(',').map(str::trim);
+        let name = parts.next().filter(|name| !name.is_empty())?;
+        let field = Field::parse(parts.next()?)?;
+        let direction = match parts.next()? {
+            ">" => Direction::Above,
+            "<" => Direction::Below,
+            _ => return None,
+        };
+        let trigger = parts.next()?.parse().ok()?;
+        let clear = match parts.next() {
+            None | Some("") => trigger,
+            Some(clear) => clear.parse().ok()?,
+        };
+        let mut seconds = || match parts.next() {
+            None | Some("") => Some(0),
+            Some(seconds) => seconds.parse::<u64>().ok(),
+        };
+        let trigger_for = Duration::from_secs(seconds()?);
+        let clear_for = Duration::from_secs(seconds()?);
+
+        // A clear threshold on the wrong side of the trigger would never clear
+        let hysteresis_ok = match direction {
+            Direction::Above => clear <= trigger,
+            Direction::Below => clear >= trigger,
+        };
+        hysteresis_ok.then_some(Rule {
+            name,
+            field,
+            direction,
+            trigger,
+            clear,
+            trigger_for,
+            clear_for,
+        })
+    }
+
+    fn triggered(&self, value: f32) -> bool {
+        match self.direction {
+            Direction::Above => value > self.trigger,
+            Direction::Below => value < self.trigger,
+        }
+    }
+
+    fn cleared(&self, value: f32) -> bool {
+        match self.direction {
+            Direction::Above => value < self.clear,
+            Direction::Below => value > self.clear,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Clear,
+    /// Trigger condition has held since the given instant
+    Pending(Instant),
+    Raised,
+    /// Clear condition has held since the given instant
+    Clearing(Instant),
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transition {
+    Raised,
+    Cleared,
+}
+
+#[derive(Debug, Format, Clone, Serialize)]
+pub struct Event {
+    pub rule: &'static str,
+    pub field: Field,
+    pub state: Transition,
+    pub value: f32,
+    pub threshold: f32,
+}
+
+/// Tracks one rule across a series of values
+#[derive(Debug, Clone, Copy)]
+pub struct Evaluator {
+    pub rule: Rule,
+    state: State,
+}
+
+impl Evaluator {
+    pub fn new(rule: Rule) -> Self {
+        Evaluator {
+            rule,
+            state: State::Clear,
+        }
+    }
+
+    pub fn is_raised(&self) -> bool {
+        matches!(self.state, State::Raised | State::Clearing(_))
+    }
+
+    /// Feed the next value, taken at `now`, returning an event when the
+    /// alert is raised or cleared
+    pub fn update(&mut self, value: f32, now: Instant) -> Option<Event> {
+        let rule = &self.rule;
+        let state = match self.state {
+            State::Clear | State::Pending(_) if !rule.triggered(value) => State::Clear,
+            State::Clear => State::Pending(now),
+            State::Raised | State::Clearing(_) if !rule.cleared(value) => State::Raised,
+            State::Raised => State::Clearing(now),
+            state => state,
+        };
+
+        // Promote pending states once they've held for long enough, including
+        // immediately for rules without a minimum duration
+        let (state, transition) = match state {
+            State::Pending(since) if now.saturating_duration_since(since) >= rule.trigger_for => {
+                (State::Raised, Some(Transition::Raised))
+            }
+            State::Clearing(since) if now.saturating_duration_since(since) >= rule.clear_for => {
+                (State::Clear, Some(Transition::Cleared))
+            }
+            state => (state, None),
+        };
+        self.state = state;
+
+        transition.map(|state| Event {
+            rule: rule.name,
+            field: rule.field,
+            state,
+            value,
+            threshold: match state {
+                Transition::Raised => rule.trigger,
+                Transition::Cleared => rule.clear,
+            },
+        })
+    }
+}
+
+/// Parse all configured rules, skipping and logging invalid ones
+pub fn parse_rules(rules: &'static str) -> Vec<Rule, MAX_RULES> {
+    let mut parsed = Vec::new();
+    for rule in rules.split(';').filter(|rule| !rule.trim().is_empty()) {
+        match Rule::parse(rule) {
+            Some(rule) => {
+                if parsed.push(rule).is_err() {
+                    warn!(
+                        "Alerts: more than {} rules configured, ignoring the rest",
+                        MAX_RULES
+                    );
+                    break;
+                }
+            }
+            None => warn!("Alerts: ignoring invalid rule: {}", rule),
+        }
+    }
+    parsed
+}
+
+#[embassy_executor::task]
+pub async fn engine() -> ! {
+    let mut evaluators: Vec<Evaluator, MAX_RULES> = parse_rules(ALERT_RULES)
+        .into_iter()
+        .map(Evaluator::new)
+        .collect();
+    info!("Alerts: evaluating {} rules", evaluators.len());
+
+    loop {
+        let reading = sink::ALERTS.receive().await;
+        let now = Instant::now();
+
+        for evaluator in evaluators.iter_mut() {
+            let value = match &reading {
+                Reading::Scd41(measurement) => evaluator.rule.field.read_scd41(measurement),
+                Reading::Bme680(measurement) => evaluator.rule.field.read_bme680(measurement),
+            };
+            let Some(value) = value.filter(|value| value.is_finite()) else {
+                continue;
+            };
+
+            if let Some(event) = evaluator.update(value, now) {
+                info!(
+                    "Alerts: {}: {:?} at {}",
+                    event.rule, event.state, event.value
+                );
+                if mqtt::OUTBOX.try_send(Outgoing::Alert(event)).is_err() {
+                    warn!("Alerts: MQTT outbox full, dropping event");
+                }
+            }
+        }
+
+        let active = evaluators.iter().any(Evaluator::is_raised);
+        if ACTIVE.swap(active, Ordering::Relaxed) != active {
+            debug!("Alerts: active changed to {}", active);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CO2_HIGH: &str = "co2_high,co2,>,1200,1000,120,60";
+
+    /// Feed `(seconds, value)` samples, returning the transitions with the
+    /// time they happened at
+    fn run(rule: Rule, series: &[(u64, f32)]) -> std::vec::Vec<(u64, Transition)> {
+        let mut evaluator = Evaluator::new(rule);
+        series
+            .iter()
+            .filter_map(|&(seconds, value)| {
+                let event = evaluator.update(value, Instant::from_secs(seconds))?;
+                Some((seconds, event.state))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rule = Rule::parse(CO2_HIGH).unwrap();
+        assert_eq!(rule.name, "co2_high");
+        assert_eq!(rule.field, Field::Co2);
+        assert_eq!(rule.direction, Direction::Above);
+        assert_eq!((rule.trigger, rule.clear), (1200.0, 1000.0));
+        assert_eq!(rule.trigger_for, Duration::from_secs(120));
+        assert_eq!(rule.clear_for, Duration::from_secs(60));
+
+        let rule = Rule::parse("cold, bme680.temperature, <, 16").unwrap();
+        assert_eq!(rule.field, Field::Temperature);
+        assert_eq!(rule.direction, Direction::Below);
+        assert_eq!(rule.clear, 16.0);
+        assert_eq!(rule.trigger_for, Duration::from_secs(0));
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        for rule in [
+            ",co2,>,1200",
+            "x,radon,>,100",
+            "x,co2,>=,1200",
+            "x,co2,>,high",
+            "x,co2,>,1200,1000,soon",
+            // Clear threshold on the wrong side of the trigger
+            "x,co2,>,1200,1300",
+            "x,temperature,<,16,15",
+        ] {
+            assert!(Rule::parse(rule).is_none(), "{rule}");
+        }
+        let rules = parse_rules("a,co2,>,1;;b,nope,>,1; c,humidity,<,30");
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[1].name, "c");
+    }
+
+    #[test]
+    fn raises_after_hold_time() {
+        let rule = Rule::parse(CO2_HIGH).unwrap();
+        // Above the trigger from 60 s on, held for 120 s at 180 s
+        let series = [
+            (0, 900.0),
+            (60, 1250.0),
+            (120, 1300.0),
+            (179, 1300.0),
+            (180, 1300.0),
+        ];
+        assert_eq!(run(rule, &series), [(180, Transition::Raised)]);
+    }
+
+    #[test]
+    fn short_peaks_do_not_raise() {
+        let rule = Rule::parse(CO2_HIGH).unwrap();
+        let series = [
+            (0, 1250.0),
+            (100, 1250.0),
+            (110, 1150.0),
+            (120, 1250.0),
+            (230, 1250.0),
+        ];
+        assert!(run(rule, &series).is_empty());
+        // Landing exactly on the trigger isn't above it
+        assert!(run(rule, &[(0, 1200.0), (500, 1200.0)]).is_empty());
+    }
+
+    #[test]
+    fn hysteresis_keeps_alert_raised() {
+        let rule = Rule::parse(CO2_HIGH).unwrap();
+        let series = [
+            (0, 1300.0),
+            (120, 1300.0),
+            // Between the thresholds, still raised however long it stays
+            (130, 1100.0),
+            (1000, 1001.0),
+            // Below clear, but not for long enough
+            (1010, 950.0),
+            (1050, 1050.0),
+            // Below clear for the full 60 s
+            (1060, 990.0),
+            (1120, 980.0),
+        ];
+        assert_eq!(
+            run(rule, &series),
+            [(120, Transition::Raised), (1120, Transition::Cleared)]
+        );
+    }
+
+    #[test]
+    fn immediate_rules_follow_every_crossing() {
+        let rule = Rule::parse("dry,humidity,<,30,35").unwrap();
+        let series = [(0, 40.0), (1, 29.0), (2, 33.0), (3, 36.0), (4, 20.0)];
+        assert_eq!(
+            run(rule, &series),
+            [
+                (1, Transition::Raised),
+                (3, Transition::Cleared),
+                (4, Transition::Raised)
+            ]
+        );
+    }
+
+    #[test]
+    fn events_carry_the_crossed_threshold() {
+        let rule = Rule::parse("dry,humidity,<,30,35").unwrap();
+        let mut evaluator = Evaluator::new(rule);
+        let raised = evaluator.update(25.0, Instant::from_secs(0)).unwrap();
+        assert_eq!((raised.value, raised.threshold), (25.0, 30.0));
+        assert!(evaluator.is_raised());
+        let cleared = evaluator.update(40.0, Instant::from_secs(1)).unwrap();
+        assert_eq!((cleared.value, cleared.threshold), (40.0, 35.0));
+        assert!(!evaluator.is_raised());
+    }
+}
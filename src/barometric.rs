@@ -0,0 +1,91 @@
+//! Pressure reduction to sea level and standard atmosphere at altitude
+
+use libm::powf;
+
+/// Site altitude in metres above sea level, if configured
+const SITE_ALTITUDE: Option<&str> = option_env!("SITE_ALTITUDE");
+
+/// Temperature lapse rate of the standard atmosphere in K/m
+const LAPSE_RATE: f32 = 0.0065;
+/// Sea level pressure of the standard atmosphere in hPa
+const STANDARD_PRESSURE: f32 = 1013.25;
+const STANDARD_TEMPERATURE: f32 = 288.15;
+/// g M / (R L), the exponent of the barometric formula
+const EXPONENT: f32 = 5.255_88;
+
+pub fn site_altitude() -> Option<f32> {
+    SITE_ALTITUDE.and_then(|altitude| altitude.parse().ok())
+}
+
+/// Reduce station pressure in hPa to sea level, using the measured temperature
+/// in °C and assuming the standard lapse rate between the site and sea level
+pub fn sea_level_pressure(pressure: f32, temperature: f32, altitude: f32) -> f32 {
+    let kelvin = temperature + 273.15;
+    pressure
+        * powf(
+            1.0 - LAPSE_RATE * altitude / (kelvin + LAPSE_RATE * altitude),
+            -EXPONENT,
+        )
+}
+
+/// Pressure in hPa of the standard atmosphere at an altitude in metres
+pub fn standard_pressure(altitude: f32) -> f32 {
+    STANDARD_PRESSURE * powf(1.0 - LAPSE_RATE * altitude / STANDARD_TEMPERATURE, EXPONENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{actual} isn't within {tolerance} of {expected}"
+        );
+    }
+
+    #[test]
+    fn standard_atmosphere_table() {
+        // ICAO standard atmosphere, pressure in hPa by altitude in m
+        for (altitude, pressure) in [
+            (0.0, 1013.25),
+            (500.0, 954.61),
+            (1000.0, 898.76),
+            (1609.0, 834.3),
+            (2000.0, 794.98),
+            (3000.0, 701.12),
+        ] {
+            assert_close(standard_pressure(altitude), pressure, 0.1);
+        }
+    }
+
+    #[test]
+    fn reduces_standard_atmosphere_to_sea_level() {
+        // 1609 m has 835 hPa and 4.5 °C in the standard atmosphere, 15 °C
+        // at sea level less the lapse rate
+        let temperature = 15.0 - LAPSE_RATE * 1609.0;
+        assert_close(sea_level_pressure(835.0, temperature, 1609.0), 1014.1, 0.2);
+        let pressure = standard_pressure(1609.0);
+        assert_close(
+            sea_level_pressure(pressure, temperature, 1609.0),
+            STANDARD_PRESSURE,
+            0.05,
+        );
+    }
+
+    #[test]
+    fn warmer_air_reduces_less() {
+        // At 15 °C on site the column down to sea level is taken to be
+        // warmer than standard and so lighter
+        assert_close(sea_level_pressure(835.0, 15.0, 1609.0), 1007.1, 0.2);
+        assert!(sea_level_pressure(835.0, 30.0, 1609.0) < sea_level_pressure(835.0, 0.0, 1609.0));
+    }
+
+    #[test]
+    fn sea_level_is_unchanged() {
+        assert_eq!(sea_level_pressure(1000.0, 20.0, 0.0), 1000.0);
+        assert_eq!(standard_pressure(0.0), STANDARD_PRESSURE);
+        // Below sea level the pressure is reduced upwards
+        assert!(sea_level_pressure(1020.0, 25.0, -400.0) < 1020.0);
+    }
+}
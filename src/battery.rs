@@ -0,0 +1,92 @@
+use defmt::Format;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, watch::Watch};
+use serde::Serialize;
+
+pub static WATCH: Watch<CriticalSectionRawMutex, BatteryMeasurement, 2> = Watch::new();
+
+/// The battery is measured through a 1:1 resistor divider so a full LiPo
+/// cell stays within the ADC range at 11 dB attenuation
+pub const DIVIDER_RATIO: f32 = 2.0;
+
+/// Resting LiPo cell voltage in mV against state of charge in %
+const DISCHARGE_CURVE: [(u16, u8); 11] = [
+    (3270, 0),
+    (3610, 5),
+    (3690, 10),
+    (3710, 15),
+    (3730, 20),
+    (3770, 30),
+    (3790, 40),
+    (3830, 50),
+    (3870, 60),
+    (3950, 75),
+    (4200, 100),
+];
+
+#[derive(Debug, Format, Clone, Copy, Serialize)]
+pub struct BatteryMeasurement {
+    /// Cell voltage in mV
+    pub voltage: u16,
+    /// Estimated state of charge in %
+    pub level: u8,
+}
+
+/// Estimate the state of charge by interpolating along the discharge curve
+pub fn level_from_voltage(voltage: u16) -> u8 {
+    let (first_voltage, first_level) = DISCHARGE_CURVE[0];
+    if voltage <= first_voltage {
+        return first_level;
+    }
+
+    for window in DISCHARGE_CURVE.windows(2) {
+        let [(low_voltage, low_level), (high_voltage, high_level)] = [window[0], window[1]];
+        if voltage <= high_voltage {
+            let fraction = (voltage - low_voltage) as u32 * (high_level - low_level) as u32
+                / (high_voltage - low_voltage) as u32;
+            return low_level + fraction as u8;
+        }
+    }
+
+    100
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_at_the_curve_points() {
+        assert_eq!(level_from_voltage(3270), 0);
+        assert_eq!(level_from_voltage(3830), 50);
+        assert_eq!(level_from_voltage(4200), 100);
+        for (voltage, level) in DISCHARGE_CURVE {
+            assert_eq!(level_from_voltage(voltage), level);
+        }
+    }
+
+    #[test]
+    fn interpolates_between_points() {
+        // Half way from 3950 mV at 75 % to 4200 mV at 100 %
+        assert_eq!(level_from_voltage(4075), 87);
+        // A quarter of the way from 3270 mV at 0 % to 3610 mV at 5 %,
+        // rounded down
+        assert_eq!(level_from_voltage(3355), 1);
+        assert_eq!(level_from_voltage(3271), 0);
+        assert_eq!(level_from_voltage(4199), 99);
+    }
+
+    #[test]
+    fn clamps_out_of_range_voltages() {
+        assert_eq!(level_from_voltage(0), 0);
+        assert_eq!(level_from_voltage(3000), 0);
+        assert_eq!(level_from_voltage(4350), 100);
+        assert_eq!(level_from_voltage(u16::MAX), 100);
+    }
+
+    #[test]
+    fn levels_never_fall_as_voltage_rises() {
+        let levels = (3200..4300).map(level_from_voltage);
+        let levels: std::vec::Vec<u8> = levels.collect();
+        assert!(levels.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+}
@@ -1,36 +1,46 @@
 use core::fmt::Debug;
+use core::sync::atomic::Ordering;
 
-use bosch_bme680::{AsyncBme680, Configuration, MeasurmentData};
+use bosch_bme680::{AsyncBme680, Configuration, DeviceAddress, MeasurmentData};
 use defmt::{debug, error, expect, info, warn, Format};
-use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
-use embassy_sync::{
-    blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex},
-    watch::Watch,
-};
-use embassy_time::{Delay, Timer};
-use esp_hal::{i2c::master::I2c, Async};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, watch::Watch};
+use embassy_time::{Delay, Instant, Timer};
+use embedded_hal_async::i2c::I2c;
 use serde::Serialize;
 
+use crate::barometric;
+use crate::psychrometrics::{self, Psychrometrics};
+use crate::self_heating::{self, Compensator};
+use crate::traffic;
+
+/// Newest measurement, read by the sink dispatcher and by the SCD41 for
+/// pressure compensation. Everything else goes through a sink.
 pub static WATCH: Watch<CriticalSectionRawMutex, Bme680Measurement, 2> = Watch::new();
 
-#[derive(Debug, Format, Clone, Serialize)]
+#[derive(Debug, Format, Clone, Default, Serialize)]
 pub struct Bme680Measurement {
-    /// Temperature in °C
+    /// Temperature in °C, compensated for self-heating
     pub temperature: f32,
-    /// Relative humidity in %
+    /// Self-heating in °C subtracted from the measured temperature
+    pub self_heating: f32,
+    /// Relative humidity in %, re-derived at the compensated temperature
     pub humidity: f32,
     /// Pressure in hPa
     pub pressure: f32,
+    /// Pressure reduced to sea level in hPa, None if the site altitude isn't configured
+    pub sea_level_pressure: Option<f32>,
     /// Gas resistance in Ohms
     /// None if gas measurment is disabled or gas measurment hasn't finished in time according to the gas_measuring bit.
     pub gas_resistance: Option<f32>,
+    /// Values derived from temperature, humidity and pressure
+    pub derived: Psychrometrics,
 }
 
-#[embassy_executor::task]
-pub async fn bme680_sensor_task(
-    i2c_device: I2cDevice<'static, NoopRawMutex, I2c<'static, Async>>,
-) -> ! {
-    let device_address = bosch_bme680::DeviceAddress::Secondary;
+pub async fn bme680_sensor_task<I: I2c>(i2c_device: I, address: u8) -> ! {
+    let device_address = match address {
+        0x76 => DeviceAddress::Primary,
+        _ => DeviceAddress::Secondary,
+    };
     let mut sensor = AsyncBme680::new(i2c_device, device_address, Delay, 23);
     let sensor_config = Configuration::builder().build();
 
@@ -41,6 +51,8 @@ pub async fn bme680_sensor_task(
     );
     info!("BME680: initialized successfully");
 
+    let site_altitude = barometric::site_altitude();
+    let mut compensator = Compensator::new(self_heating::Model::configured());
     let sender = WATCH.sender();
     debug!("BME680: obtained Sender for Watch");
 
@@ -54,14 +66,28 @@ pub async fn bme680_sensor_task(
                 humidity,
                 pressure,
                 gas_resistance,
-            }) => Bme680Measurement {
-                temperature,
-                humidity,
+            }) => {
+                let uptime = Instant::now().as_millis() as f32 / 1000.0;
+                let self_heating =
+                    compensator.update(uptime, traffic::TX_BYTES.load(Ordering::Relaxed));
+                let humidity =
+                    psychrometrics::humidity_at(temperature, humidity, temperature - self_heating);
+                let temperature = temperature - self_heating;
                 // bosch-bme680 crate docs are wrong, pressure is returned
                 // in Pa, not hPa. TODO: contribute upstream
-                pressure: pressure / 100.0, // Convert Pa to hPa
-                gas_resistance,
-            },
+                let pressure = pressure / 100.0; // Convert Pa to hPa
+                Bme680Measurement {
+                    temperature,
+                    self_heating,
+                    humidity,
+                    pressure,
+                    sea_level_pressure: site_altitude.map(|altitude| {
+                        barometric::sea_level_pressure(pressure, temperature, altitude)
+                    }),
+                    gas_resistance,
+                    derived: Psychrometrics::new(temperature, humidity, pressure),
+                }
+            }
             Err(_error) => {
                 // TODO: print error
                 error!("BME680: failed to get measurement");
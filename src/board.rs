@@ -0,0 +1,86 @@
+//! Tasks bound to the peripherals of the board, running the portable code of
+//! the library with the ESP32-C6 HAL types
+
+use air::{battery, bme680, display, epaper, history, http, scd41};
+use defmt::{debug, info};
+use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
+use embassy_net::Stack;
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+use embassy_time::{Delay, Timer};
+use embedded_hal_bus::spi::ExclusiveDevice;
+use esp_hal::{
+    analog::adc::{Adc, AdcCalCurve, AdcConfig, Attenuation},
+    gpio::{Input, Output},
+    i2c::master::I2c,
+    peripherals::{ADC1, GPIO0},
+    spi::master::Spi,
+    Async,
+};
+use esp_storage::FlashStorage;
+
+/// A device on the shared I2C bus
+pub type SharedI2c = I2cDevice<'static, NoopRawMutex, I2c<'static, Async>>;
+pub type EpaperSpi = ExclusiveDevice<Spi<'static, Async>, Output<'static>, Delay>;
+
+/// Measurement history in the SPI flash, shared by the recorder and the HTTP
+/// server
+static HISTORY: history::Shared<FlashStorage> = Mutex::new(None);
+
+#[embassy_executor::task]
+pub async fn scd41_supervisor(i2c_device: SharedI2c, bme680_present: bool) -> ! {
+    scd41::supervisor(i2c_device, bme680_present).await
+}
+
+#[embassy_executor::task]
+pub async fn bme680_sensor_task(i2c_device: SharedI2c, address: u8) -> ! {
+    bme680::bme680_sensor_task(i2c_device, address).await
+}
+
+#[embassy_executor::task]
+pub async fn display_task(i2c_device: SharedI2c, address: u8, stack: Stack<'static>) -> ! {
+    display::display_task(i2c_device, address, stack).await
+}
+
+#[embassy_executor::task]
+pub async fn epaper_task(
+    spi: EpaperSpi,
+    busy: Input<'static>,
+    dc: Output<'static>,
+    rst: Output<'static>,
+) -> ! {
+    epaper::epaper_task(spi, busy, dc, rst).await
+}
+
+#[embassy_executor::task]
+pub async fn history_recorder() {
+    history::recorder(&HISTORY, FlashStorage::new()).await
+}
+
+#[embassy_executor::task]
+pub async fn http_server(stack: Stack<'static>) -> ! {
+    http::server(stack, &HISTORY).await
+}
+
+#[embassy_executor::task]
+pub async fn battery_task(adc: ADC1<'static>, pin: GPIO0<'static>) -> ! {
+    let mut adc_config = AdcConfig::new();
+    let mut pin = adc_config.enable_pin_with_cal::<_, AdcCalCurve<ADC1>>(pin, Attenuation::_11dB);
+    let mut adc = Adc::new(adc, adc_config).into_async();
+    info!("Battery: ADC initialized");
+
+    let sender = battery::WATCH.sender();
+
+    loop {
+        // Calibrated readings are in mV at the pin
+        let pin_voltage = adc.read_oneshot(&mut pin).await;
+        let voltage = (pin_voltage as f32 * battery::DIVIDER_RATIO) as u16;
+        let measurement = battery::BatteryMeasurement {
+            voltage,
+            level: battery::level_from_voltage(voltage),
+        };
+        debug!("Battery: got measurement: {:?}", measurement);
+
+        sender.send(measurement);
+        Timer::after_secs(60).await;
+    }
+}
@@ -0,0 +1,300 @@
+//! Choice of MQTT broker from an ordered list, failing over to the next after
+//! repeated connection failures and probing back to the primary
+
+use defmt::Format;
+use heapless::Vec;
+
+/// Brokers as `host[:port],...`, most preferred first, IPv6 addresses with a
+/// port in brackets. The first entry is the primary.
+pub const BROKERS: &str = match option_env!("MQTT_BROKERS") {
+    Some(brokers) => brokers,
+    None => "",
+};
+/// Consecutive connection failures before failing over to the next broker
+const FAILOVER_AFTER: &str = match option_env!("MQTT_FAILOVER_AFTER") {
+    Some(failures) => failures,
+    None => "3",
+};
+/// Seconds on a backup broker before trying the primary again
+const PROBE_INTERVAL: &str = match option_env!("MQTT_PROBE_INTERVAL") {
+    Some(interval) => interval,
+    None => "600",
+};
+
+/// Most brokers in the list, later ones are ignored
+pub const MAX_BROKERS: usize = 4;
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub struct Endpoint<'a> {
+    pub host: &'a str,
+    pub port: u16,
+}
+
+impl<'a> Endpoint<'a> {
+    /// Parse `host`, `host:port` or `[address]:port`. An IPv6 address
+    /// without brackets has too many colons to tell a port apart, so it's
+    /// taken as a host on the default port.
+    pub fn parse(endpoint: &'a str, default_port: u16) -> Option<Self> {
+        let endpoint = endpoint.trim();
+        let (host, port) = match endpoint.strip_prefix('[') {
+            Some(bracketed) => match bracketed.split_once(']')? {
+                (address, "") => (address, default_port),
+                (address, port) => (address, port.strip_prefix(':')?.parse().ok()?),
+            },
+            None => match endpoint.split_once(':') {
+                Some((host, port)) if !port.contains(':') => (host, port.parse().ok()?),
+                _ => (endpoint, default_port),
+            },
+        };
+        (!host.is_empty()).then_some(Endpoint { host, port })
+    }
+}
+
+/// Endpoints in a comma separated list, skipping invalid entries. An empty
+/// list falls back to `default`.
+pub fn endpoints<'a>(
+    list: &'a str,
+    default_port: u16,
+    default: Endpoint<'a>,
+) -> Vec<Endpoint<'a>, MAX_BROKERS> {
+    let mut endpoints: Vec<_, MAX_BROKERS> = list
+        .split(',')
+        .filter_map(|endpoint| Endpoint::parse(endpoint, default_port))
+        .take(MAX_BROKERS)
+        .collect();
+    if endpoints.is_empty() {
+        let _ = endpoints.push(default);
+    }
+    endpoints
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    pub failover_after: u32,
+    /// In s
+    pub probe_interval: u64,
+}
+
+impl Settings {
+    pub fn configured() -> Self {
+        Settings {
+            failover_after: FAILOVER_AFTER.parse().unwrap_or(3).max(1),
+            probe_interval: PROBE_INTERVAL.parse().unwrap_or(600),
+        }
+    }
+}
+
+/// Tracks which of `count` brokers to use, by index into the list
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub struct Failover {
+    settings: Settings,
+    count: usize,
+    active: usize,
+    failures: u32,
+    /// Time in s the active backup broker was chosen
+    since: u64,
+    /// Backup broker to return to if probing the primary fails
+    probing: Option<usize>,
+}
+
+impl Failover {
+    pub fn new(count: usize, settings: Settings) -> Self {
+        Failover {
+            settings,
+            count: count.max(1),
+            active: 0,
+            failures: 0,
+            since: 0,
+            probing: None,
+        }
+    }
+
+    /// Index of the broker to connect to
+    pub fn active(&self) -> usize {
+        self.active
+    }
+
+    pub fn connected(&mut self) {
+        self.failures = 0;
+        self.probing = None;
+    }
+
+    /// Record a failed connection attempt at time `now` in s
+    pub fn failed(&mut self, now: u64) {
+        if let Some(backup) = self.probing.take() {
+            // The primary is still down, go straight back to the backup
+            self.active = backup;
+            self.failures = 0;
+            self.since = now;
+            return;
+        }
+        self.failures += 1;
+        if self.failures >= self.settings.failover_after {
+            self.active = (self.active + 1) % self.count;
+            self.failures = 0;
+            self.since = now;
+        }
+    }
+
+    /// Time in s at which to probe the primary again, None when on it
+    pub fn probe_at(&self) -> Option<u64> {
+        (self.active != 0).then_some(self.since + self.settings.probe_interval)
+    }
+
+    /// Switch to the primary for one attempt if a probe is due at `now`,
+    /// returning whether it is
+    pub fn probe(&mut self, now: u64) -> bool {
+        match self.probe_at() {
+            Some(probe_at) if now >= probe_at => {
+                self.probing = Some(self.active);
+                self.active = 0;
+                self.failures = 0;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULT: Endpoint = Endpoint {
+        host: "mqtt.example.com",
+        port: 1883,
+    };
+
+    const SETTINGS: Settings = Settings {
+        failover_after: 2,
+        probe_interval: 100,
+    };
+
+    fn endpoint(host: &str, port: u16) -> Option<Endpoint<'_>> {
+        Some(Endpoint { host, port })
+    }
+
+    #[test]
+    fn parses_hosts_and_ports() {
+        assert_eq!(Endpoint::parse("broker", 1883), endpoint("broker", 1883));
+        assert_eq!(
+            Endpoint::parse(" broker:8883 ", 1883),
+            endpoint("broker", 8883)
+        );
+        assert_eq!(
+            Endpoint::parse("10.0.0.2:1884", 1883),
+            endpoint("10.0.0.2", 1884)
+        );
+        assert_eq!(Endpoint::parse("broker:", 1883), None);
+        assert_eq!(Endpoint::parse("broker:x", 1883), None);
+        assert_eq!(Endpoint::parse(":1883", 1883), None);
+        assert_eq!(Endpoint::parse("", 1883), None);
+    }
+
+    #[test]
+    fn parses_ipv6_addresses() {
+        assert_eq!(
+            Endpoint::parse("[2001:db8::1]:8883", 1883),
+            endpoint("2001:db8::1", 8883)
+        );
+        assert_eq!(
+            Endpoint::parse("[fd00::2]", 1883),
+            endpoint("fd00::2", 1883)
+        );
+        // Without brackets the last group isn't a port
+        assert_eq!(
+            Endpoint::parse("2001:db8::1883", 1883),
+            endpoint("2001:db8::1883", 1883)
+        );
+        assert_eq!(Endpoint::parse("[2001:db8::1", 1883), None);
+        assert_eq!(Endpoint::parse("[2001:db8::1]8883", 1883), None);
+        assert_eq!(Endpoint::parse("[]:1883", 1883), None);
+    }
+
+    #[test]
+    fn lists_skip_invalid_entries() {
+        let brokers = endpoints("a:1, b ,:5,c:x,[::1]:2", 1883, DEFAULT);
+        assert_eq!(
+            brokers.as_slice(),
+            &[
+                Endpoint { host: "a", port: 1 },
+                Endpoint {
+                    host: "b",
+                    port: 1883
+                },
+                Endpoint {
+                    host: "::1",
+                    port: 2
+                },
+            ]
+        );
+        assert_eq!(endpoints("", 1883, DEFAULT).as_slice(), &[DEFAULT]);
+        assert_eq!(endpoints(",x:", 1883, DEFAULT).as_slice(), &[DEFAULT]);
+        assert_eq!(endpoints("a,b,c,d,e", 1883, DEFAULT).len(), MAX_BROKERS);
+    }
+
+    #[test]
+    fn fails_over_in_order() {
+        let mut failover = Failover::new(3, SETTINGS);
+        failover.failed(1);
+        assert_eq!(failover.active(), 0);
+        assert_eq!(failover.probe_at(), None);
+        failover.failed(2);
+        assert_eq!(failover.active(), 1);
+        failover.failed(3);
+        failover.failed(4);
+        assert_eq!(failover.active(), 2);
+        // Wraps round to the primary after the last backup
+        failover.failed(5);
+        failover.failed(6);
+        assert_eq!(failover.active(), 0);
+    }
+
+    #[test]
+    fn connecting_resets_the_failures() {
+        let mut failover = Failover::new(2, SETTINGS);
+        failover.failed(1);
+        failover.connected();
+        failover.failed(2);
+        assert_eq!(failover.active(), 0);
+    }
+
+    #[test]
+    fn probes_the_primary() {
+        let mut failover = Failover::new(2, SETTINGS);
+        failover.failed(1);
+        failover.failed(2);
+        failover.connected();
+        assert_eq!(failover.probe_at(), Some(102));
+        assert!(!failover.probe(101));
+        assert!(failover.probe(102));
+        assert_eq!(failover.active(), 0);
+
+        // The primary is still down, straight back to the backup
+        failover.failed(103);
+        assert_eq!(failover.active(), 1);
+        assert_eq!(failover.probe_at(), Some(203));
+
+        // The primary is back
+        assert!(failover.probe(203));
+        failover.connected();
+        assert_eq!(failover.active(), 0);
+        assert_eq!(failover.probe_at(), None);
+        failover.failed(204);
+        assert_eq!(failover.active(), 0);
+    }
+
+    #[test]
+    fn single_broker_stays() {
+        let mut failover = Failover::new(
+            1,
+            Settings {
+                failover_after: 1,
+                probe_interval: 1,
+            },
+        );
+        failover.failed(0);
+        assert_eq!(failover.active(), 0);
+        assert_eq!(failover.probe_at(), None);
+    }
+}
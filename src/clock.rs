@@ -0,0 +1,277 @@
+//! Wall clock time, synchronised over SNTP
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use defmt::{debug, error, info, warn};
+use embassy_net::{
+    udp::{PacketMetadata, UdpSocket},
+    Stack,
+};
+use embassy_time::{Duration, Instant, Timer, WithTimeout};
+
+use crate::resolve;
+
+const NTP_SERVER: &str = match option_env!("NTP_SERVER") {
+    Some(server) => server,
+    None => "pool.ntp.org",
+};
+const NTP_PORT: u16 = 123;
+const LOCAL_PORT: u16 = 50123;
+const RESYNC_INTERVAL: Duration = Duration::from_secs(3600);
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Seconds from the NTP epoch (1900) to the Unix epoch (1970)
+const NTP_UNIX_OFFSET: u32 = 2_208_988_800;
+
+/// Unix time in seconds at boot, 0 until the first synchronisation
+static BOOT_TIME: AtomicU32 = AtomicU32::new(0);
+
+/// Seconds since boot
+pub fn uptime() -> u32 {
+    Instant::now().as_secs() as u32
+}
+
+/// Unix time in seconds at boot, None until synchronised
+pub fn boot_time() -> Option<u32> {
+    match BOOT_TIME.load(Ordering::Relaxed) {
+        0 => None,
+        boot_time => Some(boot_time),
+    }
+}
+
+/// Current Unix time in seconds, None until synchronised
+pub fn now() -> Option<u32> {
+    Some(boot_time()? + uptime())
+}
+
+/// SNTP request carrying `transmit` as its transmit timestamp, which the
+/// server echoes as the originate timestamp of its response
+pub fn request(transmit: [u8; 8]) -> [u8; 48] {
+    let mut request = [0; 48];
+    // LI 0, version 4, mode 3 (client)
+    request[0] = 0x23;
+    request[40..48].copy_from_slice(&transmit);
+    request
+}
+
+/// Unix time in seconds from the transmit timestamp of an SNTP response, None
+/// if it isn't a usable server response to the request sent with
+/// `transmit`. A response to an earlier request, or a forged one, lacks the
+/// originate timestamp.
+pub fn parse_response(response: &[u8], transmit: [u8; 8]) -> Option<u32> {
+    let header = *response.first()?;
+    let leap_indicator = header >> 6;
+    let mode = header & 0x07;
+    let stratum = *response.get(1)?;
+    // Leap indicator 3 means the server isn't synchronised, stratum 0 is a
+    // kiss-o'-death and 16 unsynchronised
+    if leap_indicator == 3 || mode != 4 || !(1..16).contains(&stratum) {
+        return None;
+    }
+    if response.get(24..32)? != transmit {
+        return None;
+    }
+    let seconds = u32::from_be_bytes(response.get(40..44)?.try_into().ok()?);
+    seconds.checked_sub(NTP_UNIX_OFFSET)
+}
+
+/// Ask each address of the server in turn until one answers
+async fn query(stack: Stack<'static>) -> Option<u32> {
+    let addresses = match resolve::addresses(stack, NTP_SERVER).await {
+        Ok(addresses) => addresses,
+        Err(e) => {
+            warn!("Clock: DNS lookup of {} failed: {:?}", NTP_SERVER, e);
+            return None;
+        }
+    };
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; 64];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; 64];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if socket.bind(LOCAL_PORT).is_err() {
+        error!("Clock: failed to bind UDP socket");
+        return None;
+    }
+
+    for address in addresses {
+        // The clock isn't set yet, but the time since boot is just as unique
+        // to this request
+        let transmit = Instant::now().as_ticks().to_be_bytes();
+        if let Err(e) = socket
+            .send_to(&request(transmit), (address, NTP_PORT))
+            .await
+        {
+            warn!("Clock: failed to send SNTP request to {}: {:?}", address, e);
+            continue;
+        }
+
+        // Keep waiting for the response past stale or invalid datagrams
+        let deadline = Instant::now() + RESPONSE_TIMEOUT;
+        let mut response = [0u8; 48];
+        loop {
+            match socket
+                .recv_from(&mut response)
+                .with_deadline(deadline)
+                .await
+            {
+                Ok(Ok((len, meta))) => match parse_response(&response[..len], transmit) {
+                    Some(unix_time) => return Some(unix_time),
+                    None => warn!(
+                        "Clock: ignoring invalid SNTP response from {}",
+                        meta.endpoint
+                    ),
+                },
+                Ok(Err(e)) => {
+                    warn!("Clock: failed to receive SNTP response: {:?}", e);
+                    break;
+                }
+                Err(_) => {
+                    warn!(
+                        "Clock: timed out waiting for SNTP response from {}",
+                        address
+                    );
+                    break;
+                }
+            }
+        }
+    }
+    None
+}
+
+#[embassy_executor::task]
+pub async fn sntp(stack: Stack<'static>) -> ! {
+    loop {
+        match query(stack).await {
+            Some(unix_time) => {
+                let boot_time = unix_time.saturating_sub(uptime());
+                let previous = BOOT_TIME.swap(boot_time, Ordering::Relaxed);
+                match previous {
+                    0 => info!("Clock: synchronised, Unix time {}", unix_time),
+                    previous => debug!(
+                        "Clock: resynchronised, drift {} s",
+                        boot_time as i64 - previous as i64
+                    ),
+                }
+                Timer::after(RESYNC_INTERVAL).await;
+            }
+            None => Timer::after(RETRY_INTERVAL).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRANSMIT: [u8; 8] = [0, 0, 0, 0, 0x12, 0x34, 0x56, 0x78];
+    /// 2024-01-01T00:00:00Z
+    const UNIX_TIME: u32 = 1_704_067_200;
+
+    /// Server response to `request`, stratum 2 and transmitted at `seconds`
+    /// since the NTP epoch
+    fn response(request: &[u8; 48], seconds: u32) -> [u8; 48] {
+        let mut response = [0; 48];
+        // LI 0, version 4, mode 4 (server)
+        response[0] = 0x24;
+        response[1] = 2;
+        response[24..32].copy_from_slice(&request[40..48]);
+        response[40..44].copy_from_slice(&seconds.to_be_bytes());
+        response
+    }
+
+    fn valid() -> [u8; 48] {
+        response(&request(TRANSMIT), UNIX_TIME + NTP_UNIX_OFFSET)
+    }
+
+    #[test]
+    fn requests_carry_the_transmit_timestamp() {
+        let request = request(TRANSMIT);
+        assert_eq!(request[0], 0x23);
+        assert_eq!(request[40..48], TRANSMIT);
+        assert!(request[1..40].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn reads_the_transmit_timestamp() {
+        assert_eq!(parse_response(&valid(), TRANSMIT), Some(UNIX_TIME));
+        // Longer responses with extension fields are fine
+        let mut long = [0; 68];
+        long[..48].copy_from_slice(&valid());
+        assert_eq!(parse_response(&long, TRANSMIT), Some(UNIX_TIME));
+        // So is a leap second being announced
+        let mut leap = valid();
+        leap[0] |= 0x40;
+        assert_eq!(parse_response(&leap, TRANSMIT), Some(UNIX_TIME));
+    }
+
+    #[test]
+    fn rejects_responses_to_other_requests() {
+        let other = [0, 0, 0, 0, 0x12, 0x34, 0x56, 0x79];
+        assert_eq!(parse_response(&valid(), other), None);
+        let mut spoofed = valid();
+        spoofed[24..32].fill(0);
+        assert_eq!(parse_response(&spoofed, TRANSMIT), None);
+    }
+
+    #[test]
+    fn rejects_unsynchronised_servers() {
+        let mut unsynchronised = valid();
+        unsynchronised[0] |= 0xc0;
+        assert_eq!(parse_response(&unsynchronised, TRANSMIT), None);
+    }
+
+    #[test]
+    fn rejects_other_modes() {
+        for mode in [0, 1, 2, 3, 5, 6, 7] {
+            let mut packet = valid();
+            packet[0] = 0x20 | mode;
+            assert_eq!(parse_response(&packet, TRANSMIT), None, "mode {mode}");
+        }
+    }
+
+    #[test]
+    fn rejects_kiss_of_death_and_unsynchronised_strata() {
+        for (stratum, expected) in [
+            (0, None),
+            (1, Some(UNIX_TIME)),
+            (15, Some(UNIX_TIME)),
+            (16, None),
+        ] {
+            let mut packet = valid();
+            packet[1] = stratum;
+            assert_eq!(
+                parse_response(&packet, TRANSMIT),
+                expected,
+                "stratum {stratum}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_short_packets() {
+        let packet = valid();
+        for length in [0, 1, 2, 31, 43] {
+            assert_eq!(parse_response(&packet[..length], TRANSMIT), None);
+        }
+        assert_eq!(parse_response(&packet[..44], TRANSMIT), Some(UNIX_TIME));
+    }
+
+    #[test]
+    fn rejects_times_before_1970() {
+        let request = request(TRANSMIT);
+        assert_eq!(parse_response(&response(&request, 0), TRANSMIT), None);
+        let before = response(&request, NTP_UNIX_OFFSET - 1);
+        assert_eq!(parse_response(&before, TRANSMIT), None);
+        let epoch = response(&request, NTP_UNIX_OFFSET);
+        assert_eq!(parse_response(&epoch, TRANSMIT), Some(0));
+    }
+}
@@ -0,0 +1,56 @@
+//! Const helpers for settings that `build.rs` passes through from
+//! `config.toml` as environment variables
+
+use heapless::String;
+
+/// Parse an optional boolean setting, falling back to `default` if unset
+pub const fn flag(value: Option<&str>, default: bool) -> bool {
+    match value {
+        Some(value) => matches!(value.as_bytes(), b"true"),
+        None => default,
+    }
+}
+
+/// Value for `field` in a `field=value,...` list, falling back to the
+/// `default` entry
+pub fn lookup(list: &str, field: &str) -> Option<f32> {
+    let mut default = None;
+    for (key, value) in list.split(',').filter_map(|entry| entry.split_once('=')) {
+        let Ok(value) = value.trim().parse() else {
+            continue;
+        };
+        match key.trim() {
+            key if key == field => return Some(value),
+            "default" => default = Some(value),
+            _ => {}
+        }
+    }
+    default
+}
+
+/// Expand a template, replacing `{name}` placeholders with `value(name)`.
+/// Unknown placeholders are kept as they are. None if the result is too
+/// long.
+pub fn expand<'a, const N: usize>(
+    template: &str,
+    value: impl Fn(&str) -> Option<&'a str>,
+) -> Option<String<N>> {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]).ok()?;
+        rest = &rest[start..];
+        match rest.find('}') {
+            Some(end) => {
+                match value(&rest[1..end]) {
+                    Some(value) => out.push_str(value).ok()?,
+                    None => out.push_str(&rest[..=end]).ok()?,
+                }
+                rest = &rest[end + 1..];
+            }
+            None => break,
+        }
+    }
+    out.push_str(rest).ok()?;
+    Some(out)
+}
@@ -0,0 +1,185 @@
+//! Deadbands deciding whether a value moved far enough from the one last
+//! published to be published again, shared by report-by-exception and the
+//! per-field topics
+
+use heapless::LinearMap;
+
+use crate::config;
+use crate::payload::{Record, MAX_RECORDS};
+
+/// Absolute deadbands per field as `field=value,...`, `default` applying to
+/// fields not listed
+const DEADBAND: &str = match option_env!("MQTT_DEADBAND") {
+    Some(deadband) => deadband,
+    None => "default=0",
+};
+/// Relative deadbands in % of the last published value, in the same form
+const RELATIVE_DEADBAND: &str = match option_env!("MQTT_RELATIVE_DEADBAND") {
+    Some(deadband) => deadband,
+    None => "default=0",
+};
+
+/// Most fields tracked, those of both sensors
+const MAX_FIELDS: usize = 2 * MAX_RECORDS;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Deadband<'a> {
+    pub absolute: &'a str,
+    pub relative: &'a str,
+}
+
+impl Deadband<'static> {
+    pub const fn configured() -> Self {
+        Deadband {
+            absolute: DEADBAND,
+            relative: RELATIVE_DEADBAND,
+        }
+    }
+}
+
+impl Deadband<'_> {
+    /// Whether `value` moved beyond the deadband of `field` from `last`, the
+    /// larger of the absolute and relative deadbands applying
+    pub fn exceeds(&self, field: &str, last: f32, value: f32) -> bool {
+        let absolute = config::lookup(self.absolute, field).unwrap_or(0.0);
+        let relative = config::lookup(self.relative, field).unwrap_or(0.0) / 100.0;
+        (value - last).abs() > absolute.max(relative * last.abs())
+    }
+}
+
+/// Last published value of each field of each sensor
+pub struct Deadbands<'a> {
+    deadband: Deadband<'a>,
+    last: LinearMap<(&'static str, &'static str), f32, MAX_FIELDS>,
+}
+
+impl<'a> Deadbands<'a> {
+    pub fn new(deadband: Deadband<'a>) -> Self {
+        Deadbands {
+            deadband,
+            last: LinearMap::new(),
+        }
+    }
+
+    /// Whether a value moved beyond its deadband from the one last
+    /// published. The first value of a field always counts as changed.
+    pub fn changed(&self, sensor: &'static str, field: &'static str, value: f32) -> bool {
+        match self.last.get(&(sensor, field)) {
+            Some(&last) => self.deadband.exceeds(field, last, value),
+            None => true,
+        }
+    }
+
+    /// Record a value as published
+    pub fn publish(&mut self, sensor: &'static str, field: &'static str, value: f32) {
+        // A full map means every value counts as changed, which is still
+        // correct
+        let _ = self.last.insert((sensor, field), value);
+    }
+
+    /// Whether any field of a reading moved beyond its deadband, appeared or
+    /// disappeared
+    pub fn reading_changed(&self, sensor: &'static str, records: &[Record]) -> bool {
+        let published = self.last.keys().filter(|(name, _)| *name == sensor).count();
+        published != records.len()
+            || records
+                .iter()
+                .any(|record| self.changed(sensor, record.name, record.value))
+    }
+
+    /// Record every field of a reading as published, forgetting fields the
+    /// reading no longer has
+    pub fn publish_reading(&mut self, sensor: &'static str, records: &[Record]) {
+        let gone: heapless::Vec<_, MAX_RECORDS> = self
+            .last
+            .keys()
+            .filter(|(name, field)| {
+                *name == sensor && !records.iter().any(|record| record.name == *field)
+            })
+            .copied()
+            .collect();
+        for key in gone {
+            self.last.remove(&key);
+        }
+        for record in records {
+            self.publish(sensor, record.name, record.value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEADBAND: Deadband = Deadband {
+        absolute: "default=0.1,co2=10",
+        relative: "default=0,co2=2",
+    };
+
+    fn record(name: &'static str, value: f32) -> Record {
+        Record {
+            name,
+            unit: None,
+            value,
+        }
+    }
+
+    #[test]
+    fn larger_deadband_applies() {
+        // 2 % of 400 ppm is less than 10 ppm
+        assert!(!DEADBAND.exceeds("co2", 400.0, 410.0));
+        assert!(DEADBAND.exceeds("co2", 400.0, 411.0));
+        assert!(DEADBAND.exceeds("co2", 400.0, 389.0));
+        // 2 % of 2000 ppm is more
+        assert!(!DEADBAND.exceeds("co2", 2000.0, 2040.0));
+        assert!(DEADBAND.exceeds("co2", 2000.0, 2041.0));
+        // Fields not listed fall back to the defaults
+        assert!(!DEADBAND.exceeds("temperature", 21.0, 21.05));
+        assert!(DEADBAND.exceeds("temperature", 21.0, 21.2));
+    }
+
+    #[test]
+    fn no_deadband_publishes_every_change() {
+        let none = Deadband {
+            absolute: "",
+            relative: "",
+        };
+        assert!(!none.exceeds("co2", 400.0, 400.0));
+        assert!(none.exceeds("co2", 400.0, 401.0));
+    }
+
+    #[test]
+    fn fields_are_tracked_per_sensor() {
+        let mut deadbands = Deadbands::new(DEADBAND);
+        assert!(deadbands.changed("scd41", "temperature", 21.0));
+
+        deadbands.publish("scd41", "temperature", 21.0);
+        assert!(!deadbands.changed("scd41", "temperature", 21.05));
+        assert!(deadbands.changed("bme680", "temperature", 21.0));
+
+        // Compared against the last published value, so slow drift counts
+        assert!(!deadbands.changed("scd41", "temperature", 21.08));
+        assert!(deadbands.changed("scd41", "temperature", 21.11));
+    }
+
+    #[test]
+    fn readings_change_when_fields_come_and_go() {
+        let mut deadbands = Deadbands::new(DEADBAND);
+        let with_gas = [record("temperature", 21.0), record("gas_resistance", 5e4)];
+        let without_gas = [record("temperature", 21.0)];
+
+        assert!(deadbands.reading_changed("bme680", &with_gas));
+        deadbands.publish_reading("bme680", &with_gas);
+        assert!(!deadbands.reading_changed("bme680", &with_gas));
+
+        assert!(deadbands.reading_changed("bme680", &without_gas));
+        deadbands.publish_reading("bme680", &without_gas);
+        assert!(!deadbands.reading_changed("bme680", &without_gas));
+        assert!(deadbands.reading_changed("bme680", &with_gas));
+
+        let warmer = [record("temperature", 21.5)];
+        assert!(deadbands.reading_changed("bme680", &warmer));
+        // Other sensors' fields don't count
+        assert!(deadbands.reading_changed("scd41", &without_gas));
+    }
+}
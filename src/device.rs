@@ -0,0 +1,114 @@
+//! Identity of this board, so identical images can be told apart
+
+use core::fmt::Write;
+
+use defmt::expect;
+use embassy_sync::once_lock::OnceLock;
+use heapless::String;
+
+use crate::config;
+
+/// Length of a device ID, the MAC address in hex
+pub const ID_LENGTH: usize = 12;
+
+/// Factory MAC address and the ID derived from it, set once at boot
+static IDENTITY: OnceLock<([u8; 6], String<ID_LENGTH>)> = OnceLock::new();
+
+/// Record the factory MAC address from eFuse. Called once at boot, before
+/// any task asks for the ID.
+pub fn init(mac: [u8; 6]) {
+    let _ = IDENTITY.init((mac, id_from_mac(mac)));
+}
+
+fn identity() -> &'static ([u8; 6], String<ID_LENGTH>) {
+    expect!(IDENTITY.try_get(), "device identity should be set at boot")
+}
+
+/// Factory MAC address of the board
+pub fn mac() -> [u8; 6] {
+    identity().0
+}
+
+/// Stable ID of this board, the factory MAC address from eFuse as lowercase
+/// hex, e.g. `40a3cc01f2e4`
+pub fn id() -> &'static str {
+    &identity().1
+}
+
+pub fn id_from_mac(mac: [u8; 6]) -> String<ID_LENGTH> {
+    let mut id = String::new();
+    for byte in mac {
+        let _ = write!(id, "{byte:02x}");
+    }
+    id
+}
+
+/// Expand `{device_id}` placeholders in a configured setting such as a topic.
+/// None if the result is too long.
+pub fn expand<const N: usize>(template: &str) -> Option<String<N>> {
+    expand_id(template, id())
+}
+
+/// Expand `{device_id}` placeholders with the given ID
+pub fn expand_id<const N: usize>(template: &str, device_id: &str) -> Option<String<N>> {
+    config::expand(template, |name| (name == "device_id").then_some(device_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0x40, 0xa3, 0xcc, 0x01, 0xf2, 0xe4];
+
+    #[test]
+    fn ids_are_lowercase_hex() {
+        assert_eq!(id_from_mac(MAC), "40a3cc01f2e4");
+        assert_eq!(
+            id_from_mac([0xab, 0xcd, 0xef, 0x9a, 0xbc, 0xde]),
+            "abcdef9abcde"
+        );
+    }
+
+    #[test]
+    fn ids_keep_leading_zeros() {
+        assert_eq!(id_from_mac([0; 6]), "000000000000");
+        assert_eq!(
+            id_from_mac([0x00, 0x01, 0x0a, 0x10, 0x00, 0x0f]),
+            "00010a10000f"
+        );
+        assert_eq!(id_from_mac([0xff; 6]).len(), ID_LENGTH);
+    }
+
+    #[test]
+    fn expands_device_ids() {
+        let expanded: String<64> = expand_id("air/{device_id}/scd41", "40a3cc01f2e4").unwrap();
+        assert_eq!(expanded, "air/40a3cc01f2e4/scd41");
+        let expanded: String<64> = expand_id("{device_id}-{device_id}", "00").unwrap();
+        assert_eq!(expanded, "00-00");
+        // Other placeholders are left alone
+        let expanded: String<64> = expand_id("{base}/{device_id}", "00").unwrap();
+        assert_eq!(expanded, "{base}/00");
+        let expanded: String<64> = expand_id("air-quality/scd41", "00").unwrap();
+        assert_eq!(expanded, "air-quality/scd41");
+    }
+
+    #[test]
+    fn long_expansions_are_rejected() {
+        // 4 + 12 bytes fit exactly, one more doesn't
+        assert_eq!(
+            expand_id::<16>("air/{device_id}", "40a3cc01f2e4").unwrap(),
+            "air/40a3cc01f2e4"
+        );
+        assert_eq!(expand_id::<15>("air/{device_id}", "40a3cc01f2e4"), None);
+        assert_eq!(expand_id::<4>("{device_id}", "40a3cc01f2e4"), None);
+    }
+
+    #[test]
+    fn expands_the_board_id() {
+        init(MAC);
+        assert_eq!(mac(), MAC);
+        assert_eq!(id(), "40a3cc01f2e4");
+        let expanded: String<32> = expand("air-{device_id}").unwrap();
+        assert_eq!(expanded, "air-40a3cc01f2e4");
+    }
+}
@@ -0,0 +1,507 @@
+use core::fmt::Write;
+use core::sync::atomic::Ordering;
+
+use defmt::{debug, error, info, Format};
+use embassy_futures::select::{select, Either};
+use embassy_net::{Ipv4Address, Stack};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_graphics::{
+    mono_font::{
+        ascii::{FONT_10X20, FONT_6X10},
+        MonoTextStyle,
+    },
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{Line, PrimitiveStyle},
+    text::{Alignment, Baseline, Text, TextStyleBuilder},
+};
+use embedded_hal_async::i2c::I2c;
+use heapless::{HistoryBuffer, String};
+use ssd1306::{prelude::*, I2CDisplayInterface, Ssd1306Async};
+
+use crate::bme680::Bme680Measurement;
+use crate::mqtt;
+use crate::scd41::Scd41Measurement;
+use crate::sink::{self, Reading};
+
+/// Number of CO2 samples kept for the sparkline, one per SCD41 measurement
+pub const HISTORY_LEN: usize = 120;
+
+const PAGE_INTERVAL: Duration = Duration::from_secs(4);
+/// Wait after a failed initialization or flush before trying again, so a
+/// missing display doesn't keep the I2C bus it shares with the sensors busy
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+const WIDTH: i32 = 128;
+const HEIGHT: i32 = 64;
+const SPARKLINE_TOP: i32 = 40;
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Page {
+    Co2,
+    Temperature,
+    Humidity,
+    Pressure,
+    Connection,
+}
+
+impl Page {
+    pub const ALL: [Page; 5] = [
+        Page::Co2,
+        Page::Temperature,
+        Page::Humidity,
+        Page::Pressure,
+        Page::Connection,
+    ];
+
+    pub fn next(self) -> Page {
+        let index = Page::ALL.iter().position(|&page| page == self).unwrap_or(0);
+        Page::ALL[(index + 1) % Page::ALL.len()]
+    }
+}
+
+/// Network state shown on the connection page
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Connection {
+    pub wifi_up: bool,
+    pub ip: Option<Ipv4Address>,
+    pub mqtt_connected: bool,
+}
+
+/// Everything needed to draw a page
+pub struct Snapshot<'a> {
+    pub scd41: Option<&'a Scd41Measurement>,
+    pub bme680: Option<&'a Bme680Measurement>,
+    pub connection: Connection,
+    pub co2_history: &'a HistoryBuffer<u16, HISTORY_LEN>,
+}
+
+/// Draw a single page onto a 128x64 monochrome target.
+///
+/// Kept independent of the SSD1306 driver so frames can be rendered to any
+/// `DrawTarget`, e.g. a simulator framebuffer.
+pub fn render<D>(target: &mut D, page: Page, snapshot: &Snapshot) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    target.clear(BinaryColor::Off)?;
+
+    let mut value: String<32> = String::new();
+    let (label, unit) = match page {
+        Page::Co2 => {
+            match snapshot.scd41 {
+                Some(m) => write!(value, "{}", m.co2),
+                None => write!(value, "--"),
+            }
+            .ok();
+            ("CO2", "ppm")
+        }
+        Page::Temperature => {
+            match snapshot.bme680.map(|m| m.temperature) {
+                Some(temperature) => write!(value, "{temperature:.1}"),
+                None => write!(value, "--"),
+            }
+            .ok();
+            ("Temperature", "C")
+        }
+        Page::Humidity => {
+            match snapshot.bme680.map(|m| m.humidity) {
+                Some(humidity) => write!(value, "{humidity:.1}"),
+                None => write!(value, "--"),
+            }
+            .ok();
+            ("Humidity", "%")
+        }
+        Page::Pressure => {
+            match snapshot.bme680.map(|m| m.pressure) {
+                Some(pressure) => write!(value, "{pressure:.1}"),
+                None => write!(value, "--"),
+            }
+            .ok();
+            ("Pressure", "hPa")
+        }
+        Page::Connection => return render_connection(target, &snapshot.connection),
+    };
+
+    let small = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+    let large = MonoTextStyle::new(&FONT_10X20, BinaryColor::On);
+    let right_aligned = TextStyleBuilder::new()
+        .alignment(Alignment::Right)
+        .baseline(Baseline::Top)
+        .build();
+
+    Text::with_baseline(label, Point::zero(), small, Baseline::Top).draw(target)?;
+    Text::with_text_style(unit, Point::new(WIDTH - 1, 0), small, right_aligned).draw(target)?;
+    Text::with_baseline(&value, Point::new(0, 14), large, Baseline::Top).draw(target)?;
+
+    if page == Page::Co2 {
+        render_sparkline(target, snapshot.co2_history)?;
+    }
+
+    Ok(())
+}
+
+fn render_connection<D>(target: &mut D, connection: &Connection) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let small = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+    let mut line: String<32> = String::new();
+
+    Text::with_baseline("Connection", Point::zero(), small, Baseline::Top).draw(target)?;
+
+    let wifi = if connection.wifi_up { "up" } else { "down" };
+    write!(line, "WiFi: {wifi}").ok();
+    Text::with_baseline(&line, Point::new(0, 16), small, Baseline::Top).draw(target)?;
+
+    line.clear();
+    match connection.ip {
+        Some(ip) => write!(line, "IP: {ip}"),
+        None => write!(line, "IP: --"),
+    }
+    .ok();
+    Text::with_baseline(&line, Point::new(0, 28), small, Baseline::Top).draw(target)?;
+
+    line.clear();
+    let mqtt = if connection.mqtt_connected {
+        "connected"
+    } else {
+        "disconnected"
+    };
+    write!(line, "MQTT: {mqtt}").ok();
+    Text::with_baseline(&line, Point::new(0, 40), small, Baseline::Top).draw(target)?;
+
+    Ok(())
+}
+
+/// Draw the CO2 history across the bottom of the screen, scaled to the range
+/// of the buffered samples and right-aligned so the newest sample is at the edge
+fn render_sparkline<D>(
+    target: &mut D,
+    history: &HistoryBuffer<u16, HISTORY_LEN>,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    if history.len() < 2 {
+        return Ok(());
+    }
+
+    let min = history.oldest_ordered().copied().min().unwrap_or(0) as i32;
+    let max = history.oldest_ordered().copied().max().unwrap_or(0) as i32;
+    let span = (max - min).max(1);
+    let height = HEIGHT - 1 - SPARKLINE_TOP;
+    let x_offset = WIDTH - history.len() as i32;
+
+    let points = history.oldest_ordered().enumerate().map(|(i, &co2)| {
+        let y = HEIGHT - 1 - (co2 as i32 - min) * height / span;
+        Point::new(x_offset + i as i32, y)
+    });
+
+    let style = PrimitiveStyle::with_stroke(BinaryColor::On, 1);
+    let mut previous = None;
+    for point in points {
+        if let Some(previous) = previous {
+            Line::new(previous, point).into_styled(style).draw(target)?;
+        }
+        previous = Some(point);
+    }
+
+    Ok(())
+}
+
+pub async fn display_task<I: I2c>(i2c_device: I, address: u8, stack: Stack<'static>) -> ! {
+    let interface = I2CDisplayInterface::new_custom_address(i2c_device, address);
+    let mut display = Ssd1306Async::new(interface, DisplaySize128x64, DisplayRotation::Rotate0)
+        .into_buffered_graphics_mode();
+
+    let mut co2_history = HistoryBuffer::new();
+    let mut page = Page::Co2;
+    let mut next_page_at = Instant::now() + PAGE_INTERVAL;
+    let mut initialized = false;
+
+    loop {
+        if !initialized {
+            debug!("Display: initializing...");
+            match display.init().await {
+                Ok(()) => {
+                    info!("Display: initialized successfully");
+                    initialized = true;
+                }
+                Err(_) => {
+                    error!("Display: failed to initialize, retrying...");
+                    Timer::after(RETRY_INTERVAL).await;
+                    continue;
+                }
+            }
+        }
+
+        let scd41_measurement = sink::LATEST.scd41();
+        let bme680_measurement = sink::LATEST.bme680();
+        let snapshot = Snapshot {
+            scd41: scd41_measurement.as_ref(),
+            bme680: bme680_measurement.as_ref(),
+            connection: Connection {
+                wifi_up: stack.is_link_up(),
+                ip: stack.config_v4().map(|config| config.address.address()),
+                mqtt_connected: mqtt::CONNECTED.load(Ordering::Relaxed),
+            },
+            co2_history: &co2_history,
+        };
+
+        // Drawing into the buffer can't fail, only flushing it over I2C
+        let _ = render(&mut display, page, &snapshot);
+        if display.flush().await.is_err() {
+            error!("Display: failed to flush frame, reinitializing...");
+            initialized = false;
+            Timer::after(RETRY_INTERVAL).await;
+            continue;
+        }
+
+        match select(sink::DISPLAY.receive(), Timer::at(next_page_at)).await {
+            Either::First(Reading::Scd41(measurement)) => co2_history.write(measurement.co2),
+            // Redrawn with the new values
+            Either::First(Reading::Bme680(_)) => {}
+            Either::Second(()) => {
+                page = page.next();
+                next_page_at += PAGE_INTERVAL;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_graphics::Pixel;
+
+    use super::*;
+
+    /// 128x64 framebuffer that fails on pixels drawn off screen
+    struct Frame([[bool; WIDTH as usize]; HEIGHT as usize]);
+
+    impl Frame {
+        fn new() -> Self {
+            Frame([[false; WIDTH as usize]; HEIGHT as usize])
+        }
+
+        fn on(&self, x: i32, y: i32) -> bool {
+            self.0[y as usize][x as usize]
+        }
+
+        /// Whether any pixel in the rows from `top` is on
+        fn any_below(&self, top: i32) -> bool {
+            self.0[top as usize..].iter().flatten().any(|&on| on)
+        }
+    }
+
+    impl OriginDimensions for Frame {
+        fn size(&self) -> Size {
+            Size::new(WIDTH as u32, HEIGHT as u32)
+        }
+    }
+
+    impl DrawTarget for Frame {
+        type Color = BinaryColor;
+        type Error = Point;
+
+        fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Point>
+        where
+            I: IntoIterator<Item = Pixel<BinaryColor>>,
+        {
+            for Pixel(point, color) in pixels {
+                if !(0..WIDTH).contains(&point.x) || !(0..HEIGHT).contains(&point.y) {
+                    return Err(point);
+                }
+                self.0[point.y as usize][point.x as usize] = color.is_on();
+            }
+            Ok(())
+        }
+    }
+
+    /// A page laid out the way `render` does, from its parts
+    fn expected(label: &str, unit: &str, value: &str) -> Frame {
+        let mut frame = Frame::new();
+        let small = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+        let large = MonoTextStyle::new(&FONT_10X20, BinaryColor::On);
+        let right_aligned = TextStyleBuilder::new()
+            .alignment(Alignment::Right)
+            .baseline(Baseline::Top)
+            .build();
+        Text::with_baseline(label, Point::zero(), small, Baseline::Top)
+            .draw(&mut frame)
+            .unwrap();
+        Text::with_text_style(unit, Point::new(WIDTH - 1, 0), small, right_aligned)
+            .draw(&mut frame)
+            .unwrap();
+        Text::with_baseline(value, Point::new(0, 14), large, Baseline::Top)
+            .draw(&mut frame)
+            .unwrap();
+        frame
+    }
+
+    fn rendered(page: Page, snapshot: &Snapshot) -> Frame {
+        let mut frame = Frame::new();
+        render(&mut frame, page, snapshot).unwrap();
+        frame
+    }
+
+    fn history(samples: &[u16]) -> HistoryBuffer<u16, HISTORY_LEN> {
+        let mut history = HistoryBuffer::new();
+        history.extend_from_slice(samples);
+        history
+    }
+
+    #[test]
+    fn pages_cycle() {
+        let mut page = Page::Co2;
+        for expected in Page::ALL.iter().cycle().skip(1).take(Page::ALL.len()) {
+            page = page.next();
+            assert_eq!(page, *expected);
+        }
+        assert_eq!(page, Page::Co2);
+    }
+
+    #[test]
+    fn every_page_stays_on_screen() {
+        let mut scd41 = Scd41Measurement::default();
+        scd41.co2 = 40000;
+        let bme680 = Bme680Measurement {
+            temperature: -40.0,
+            humidity: 100.0,
+            pressure: 1100.0,
+            ..Default::default()
+        };
+        let full = history(&[400; HISTORY_LEN]);
+        let varied: Vec<u16> = (0..HISTORY_LEN as u16)
+            .map(|i| 400 + i * 37 % 500)
+            .collect();
+        let varied = history(&varied);
+        for co2_history in [&HistoryBuffer::new(), &full, &varied] {
+            for (scd41, bme680) in [(None, None), (Some(&scd41), Some(&bme680))] {
+                let snapshot = Snapshot {
+                    scd41,
+                    bme680,
+                    connection: Connection {
+                        wifi_up: true,
+                        ip: Some(Ipv4Address::new(192, 168, 100, 200)),
+                        mqtt_connected: false,
+                    },
+                    co2_history,
+                };
+                for page in Page::ALL {
+                    let mut frame = Frame::new();
+                    assert_eq!(render(&mut frame, page, &snapshot), Ok(()), "{page:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn value_pages_match_layout() {
+        let bme680 = Bme680Measurement {
+            temperature: 21.46,
+            humidity: 48.04,
+            pressure: 1013.25,
+            ..Default::default()
+        };
+        let snapshot = Snapshot {
+            scd41: None,
+            bme680: Some(&bme680),
+            connection: Connection::default(),
+            co2_history: &HistoryBuffer::new(),
+        };
+        for (page, label, unit, value) in [
+            (Page::Temperature, "Temperature", "C", "21.5"),
+            (Page::Humidity, "Humidity", "%", "48.0"),
+            (Page::Pressure, "Pressure", "hPa", "1013.2"),
+            (Page::Co2, "CO2", "ppm", "--"),
+        ] {
+            assert!(
+                rendered(page, &snapshot).0 == expected(label, unit, value).0,
+                "{page:?} should show {value}"
+            );
+        }
+    }
+
+    #[test]
+    fn missing_readings_show_dashes() {
+        let snapshot = Snapshot {
+            scd41: None,
+            bme680: None,
+            connection: Connection::default(),
+            co2_history: &HistoryBuffer::new(),
+        };
+        assert!(rendered(Page::Humidity, &snapshot).0 == expected("Humidity", "%", "--").0);
+    }
+
+    #[test]
+    fn sparkline_is_scaled_and_right_aligned() {
+        let mut scd41 = Scd41Measurement::default();
+        scd41.co2 = 800;
+        let co2_history = history(&[400, 600, 800]);
+        let snapshot = Snapshot {
+            scd41: Some(&scd41),
+            bme680: None,
+            connection: Connection::default(),
+            co2_history: &co2_history,
+        };
+        let frame = rendered(Page::Co2, &snapshot);
+
+        // Oldest and lowest at the bottom, newest and highest at the top of
+        // the sparkline and the right edge
+        assert!(frame.on(WIDTH - 3, HEIGHT - 1));
+        assert!(frame.on(WIDTH - 2, (SPARKLINE_TOP + HEIGHT - 1) / 2));
+        assert!(frame.on(WIDTH - 1, SPARKLINE_TOP));
+        let left_of_line = (SPARKLINE_TOP..HEIGHT).any(|y| (0..WIDTH - 3).any(|x| frame.on(x, y)));
+        assert!(!left_of_line);
+    }
+
+    #[test]
+    fn no_sparkline_from_a_single_sample() {
+        let co2_history = history(&[612]);
+        let snapshot = Snapshot {
+            scd41: None,
+            bme680: None,
+            connection: Connection::default(),
+            co2_history: &co2_history,
+        };
+        assert!(!rendered(Page::Co2, &snapshot).any_below(SPARKLINE_TOP));
+    }
+
+    #[test]
+    fn connection_page_shows_state() {
+        let up = Snapshot {
+            scd41: None,
+            bme680: None,
+            connection: Connection {
+                wifi_up: true,
+                ip: Some(Ipv4Address::new(10, 0, 0, 7)),
+                mqtt_connected: true,
+            },
+            co2_history: &HistoryBuffer::new(),
+        };
+        let down = Snapshot {
+            connection: Connection::default(),
+            ..up
+        };
+        let (up, down) = (
+            rendered(Page::Connection, &up),
+            rendered(Page::Connection, &down),
+        );
+
+        let mut expected = Frame::new();
+        let small = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+        for (line, y) in [
+            ("Connection", 0),
+            ("WiFi: up", 16),
+            ("IP: 10.0.0.7", 28),
+            ("MQTT: connected", 40),
+        ] {
+            Text::with_baseline(line, Point::new(0, y), small, Baseline::Top)
+                .draw(&mut expected)
+                .unwrap();
+        }
+        assert!(up.0 == expected.0);
+        assert!(up.0 != down.0);
+    }
+}
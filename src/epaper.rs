@@ -0,0 +1,819 @@
+use core::{convert::Infallible, fmt::Write};
+
+use defmt::{debug, error, info, Format};
+use embassy_futures::select::{select, Either};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use embedded_graphics::{
+    mono_font::{
+        ascii::{FONT_10X20, FONT_6X10},
+        MonoTextStyle,
+    },
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{Line, PrimitiveStyle, Rectangle},
+    text::{Alignment, Baseline, Text, TextStyleBuilder},
+};
+use embedded_hal::digital::OutputPin;
+use embedded_hal_async::{digital::Wait, spi::SpiDevice};
+use heapless::{HistoryBuffer, String};
+
+use crate::battery;
+use crate::sink::{self, Reading};
+
+/// Buckets in the CO2 chart, covering 24 h
+pub const CHART_LEN: usize = 288;
+const CHART_BUCKET: Duration = Duration::from_secs(24 * 60 * 60 / CHART_LEN as u64);
+
+const WIDTH: i32 = 296;
+const HEIGHT: i32 = 128;
+const CHART_TOP: i32 = 48;
+const CHART_LEFT: i32 = WIDTH - CHART_LEN as i32;
+
+/// How often readings are compared against the frame on screen
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Refresh whenever a reading moves this far from what is on screen
+pub const REFRESH_POLICY: RefreshPolicy = RefreshPolicy {
+    co2: 50,
+    temperature: 0.5,
+    humidity: 3.0,
+    battery: 5,
+    min_interval: Duration::from_secs(3 * 60),
+    max_interval: Duration::from_secs(60 * 60),
+};
+
+/// The values shown on screen, None where the source isn't available
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Readings {
+    pub co2: Option<u16>,
+    pub temperature: Option<f32>,
+    pub humidity: Option<f32>,
+    pub battery: Option<u8>,
+}
+
+impl Readings {
+    fn latest() -> Readings {
+        let bme680 = sink::LATEST.bme680();
+        Readings {
+            co2: sink::LATEST.scd41().map(|m| m.co2),
+            temperature: bme680.as_ref().map(|m| m.temperature),
+            humidity: bme680.as_ref().map(|m| m.humidity),
+            battery: battery::WATCH.try_get().map(|m| m.level),
+        }
+    }
+}
+
+/// Limits how often the panel is redrawn. Every refresh costs several seconds
+/// of flashing and a noticeable amount of charge.
+#[derive(Debug, Clone, Copy)]
+pub struct RefreshPolicy {
+    /// Change in CO2 in ppm
+    pub co2: u16,
+    /// Change in temperature in °C
+    pub temperature: f32,
+    /// Change in relative humidity in %
+    pub humidity: f32,
+    /// Change in battery level in %
+    pub battery: u8,
+    /// Never refresh more often than this, however much values change
+    pub min_interval: Duration,
+    /// Always refresh after this long so the chart keeps moving
+    pub max_interval: Duration,
+}
+
+impl RefreshPolicy {
+    /// Decide whether `current` differs enough from the `shown` frame to be
+    /// worth a refresh, `since_attempt` after the last refresh was attempted.
+    /// A failed refresh leaves nothing shown but still counts towards
+    /// `min_interval`, so a broken panel isn't retried on every sample.
+    pub fn should_refresh(
+        &self,
+        shown: Option<&Readings>,
+        current: &Readings,
+        since_attempt: Option<Duration>,
+    ) -> bool {
+        let Some(elapsed) = since_attempt else {
+            return true;
+        };
+        if elapsed < self.min_interval {
+            return false;
+        }
+        let Some(shown) = shown else {
+            return true;
+        };
+        if elapsed >= self.max_interval {
+            return true;
+        }
+
+        fn exceeds<T: PartialOrd + core::ops::Sub<Output = T> + Copy>(
+            shown: Option<T>,
+            current: Option<T>,
+            threshold: T,
+        ) -> bool {
+            match (shown, current) {
+                (Some(shown), Some(current)) => {
+                    let change = if shown > current {
+                        shown - current
+                    } else {
+                        current - shown
+                    };
+                    change >= threshold
+                }
+                (None, None) => false,
+                // A source appearing or going away is always worth showing
+                _ => true,
+            }
+        }
+
+        exceeds(shown.co2, current.co2, self.co2)
+            || exceeds(shown.temperature, current.temperature, self.temperature)
+            || exceeds(shown.humidity, current.humidity, self.humidity)
+            || exceeds(shown.battery, current.battery, self.battery)
+    }
+}
+
+/// CO2 averaged into fixed-length buckets for the 24 h chart
+pub struct Co2Chart {
+    buckets: HistoryBuffer<u16, CHART_LEN>,
+    bucket_start: Option<Instant>,
+    sum: u32,
+    count: u32,
+}
+
+impl Default for Co2Chart {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Co2Chart {
+    pub const fn new() -> Self {
+        Co2Chart {
+            buckets: HistoryBuffer::new(),
+            bucket_start: None,
+            sum: 0,
+            count: 0,
+        }
+    }
+
+    /// Add a sample taken at `now`, closing the current bucket if it has
+    /// run for a full bucket length
+    pub fn push(&mut self, co2: u16, now: Instant) {
+        let bucket_start = *self.bucket_start.get_or_insert(now);
+        if now.saturating_duration_since(bucket_start) >= CHART_BUCKET {
+            if self.count > 0 {
+                self.buckets.write((self.sum / self.count) as u16);
+            }
+            self.bucket_start = Some(now);
+            self.sum = 0;
+            self.count = 0;
+        }
+        self.sum += co2 as u32;
+        self.count += 1;
+    }
+
+    /// Completed buckets, oldest first
+    pub fn buckets(&self) -> impl Iterator<Item = u16> + '_ {
+        self.buckets.oldest_ordered().copied()
+    }
+}
+
+/// Draw a full frame onto a 296x128 landscape target.
+///
+/// Kept independent of the panel driver so frames can be rendered to any
+/// `DrawTarget`, e.g. a simulator framebuffer.
+pub fn render<D>(target: &mut D, readings: &Readings, chart: &Co2Chart) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    target.clear(BinaryColor::Off)?;
+
+    let small = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+    let large = MonoTextStyle::new(&FONT_10X20, BinaryColor::On);
+    let right_aligned = TextStyleBuilder::new()
+        .alignment(Alignment::Right)
+        .baseline(Baseline::Top)
+        .build();
+    let mut text: String<32> = String::new();
+
+    match readings.co2 {
+        Some(co2) => write!(text, "{co2} ppm"),
+        None => write!(text, "-- ppm"),
+    }
+    .ok();
+    Text::with_baseline(&text, Point::zero(), large, Baseline::Top).draw(target)?;
+
+    text.clear();
+    match readings.temperature {
+        Some(temperature) => write!(text, "{temperature:.1} C"),
+        None => write!(text, "-- C"),
+    }
+    .ok();
+    Text::with_baseline(&text, Point::new(0, 24), small, Baseline::Top).draw(target)?;
+
+    text.clear();
+    match readings.humidity {
+        Some(humidity) => write!(text, "{humidity:.0} %RH"),
+        None => write!(text, "-- %RH"),
+    }
+    .ok();
+    Text::with_baseline(&text, Point::new(60, 24), small, Baseline::Top).draw(target)?;
+
+    render_battery(target, readings.battery)?;
+
+    text.clear();
+    write!(text, "CO2 24h").ok();
+    Text::with_text_style(&text, Point::new(WIDTH - 1, 36), small, right_aligned).draw(target)?;
+
+    render_chart(target, chart)
+}
+
+/// Battery outline in the top right corner, filled to the current level
+fn render_battery<D>(target: &mut D, level: Option<u8>) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let outline = Rectangle::new(Point::new(WIDTH - 26, 0), Size::new(24, 12));
+    let terminal = Rectangle::new(Point::new(WIDTH - 2, 3), Size::new(2, 6));
+    let stroke = PrimitiveStyle::with_stroke(BinaryColor::On, 1);
+    let fill = PrimitiveStyle::with_fill(BinaryColor::On);
+
+    outline.into_styled(stroke).draw(target)?;
+    terminal.into_styled(fill).draw(target)?;
+
+    let small = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+    let right_aligned = TextStyleBuilder::new()
+        .alignment(Alignment::Right)
+        .baseline(Baseline::Top)
+        .build();
+    let mut text: String<8> = String::new();
+    match level {
+        Some(level) => {
+            let width = 20 * level.min(100) as u32 / 100;
+            Rectangle::new(Point::new(WIDTH - 24, 2), Size::new(width, 8))
+                .into_styled(fill)
+                .draw(target)?;
+            write!(text, "{level}%").ok();
+        }
+        None => {
+            write!(text, "--%").ok();
+        }
+    }
+    Text::with_text_style(&text, Point::new(WIDTH - 30, 1), small, right_aligned).draw(target)?;
+
+    Ok(())
+}
+
+/// Line chart of the bucketed CO2 history, scaled to its own range and
+/// right-aligned so the newest bucket is at the edge
+fn render_chart<D>(target: &mut D, chart: &Co2Chart) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let stroke = PrimitiveStyle::with_stroke(BinaryColor::On, 1);
+    Line::new(
+        Point::new(CHART_LEFT, HEIGHT - 1),
+        Point::new(WIDTH - 1, HEIGHT - 1),
+    )
+    .into_styled(stroke)
+    .draw(target)?;
+
+    let len = chart.buckets().count() as i32;
+    if len < 2 {
+        return Ok(());
+    }
+
+    let min = chart.buckets().min().unwrap_or(0) as i32;
+    let max = chart.buckets().max().unwrap_or(0) as i32;
+    let span = (max - min).max(1);
+    let height = HEIGHT - 2 - CHART_TOP;
+    let x_offset = WIDTH - len;
+
+    // The range goes on the heading line, the chart spans almost the full
+    // width and would run through labels at its side
+    let small = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+    let mut text: String<16> = String::new();
+    write!(text, "{min}-{max} ppm").ok();
+    Text::with_baseline(&text, Point::new(0, 36), small, Baseline::Top).draw(target)?;
+
+    let mut previous = None;
+    for (i, co2) in chart.buckets().enumerate() {
+        let point = Point::new(
+            x_offset + i as i32,
+            HEIGHT - 2 - (co2 as i32 - min) * height / span,
+        );
+        if let Some(previous) = previous {
+            Line::new(previous, point)
+                .into_styled(stroke)
+                .draw(target)?;
+        }
+        previous = Some(point);
+    }
+
+    Ok(())
+}
+
+/// Frame buffer in the panel's RAM layout: 128 pixels wide rows of 16 bytes,
+/// most significant bit first, a set bit for white. Drawn to in landscape,
+/// rotated by 90° onto the 128x296 panel.
+pub struct Frame([u8; FRAME_LEN]);
+
+const FRAME_LEN: usize = (HEIGHT * WIDTH / 8) as usize;
+
+impl Default for Frame {
+    fn default() -> Self {
+        Frame([0xff; FRAME_LEN])
+    }
+}
+
+impl Frame {
+    pub fn buffer(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl OriginDimensions for Frame {
+    fn size(&self) -> Size {
+        Size::new(WIDTH as u32, HEIGHT as u32)
+    }
+}
+
+impl DrawTarget for Frame {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Infallible>
+    where
+        I: IntoIterator<Item = Pixel<BinaryColor>>,
+    {
+        for Pixel(point, color) in pixels {
+            if !(0..WIDTH).contains(&point.x) || !(0..HEIGHT).contains(&point.y) {
+                continue;
+            }
+            let x = (HEIGHT - 1 - point.y) as usize;
+            let index = point.x as usize * HEIGHT as usize / 8 + x / 8;
+            let bit = 0x80 >> (x % 8);
+            match color {
+                BinaryColor::On => self.0[index] &= !bit,
+                BinaryColor::Off => self.0[index] |= bit,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Waveform of the panel, loaded in place of the controller's built-in one
+/// (Waveshare's `WS_20_30`): 153 bytes of LUT, then the end option, gate
+/// and source voltages and VCOM
+const LUT: [u8; 159] = [
+    0x80, 0x66, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x00, 0x00, 0x10, 0x66, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x80, 0x66, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x40, 0x00, 0x00, 0x00, 0x10, 0x66, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x14, 0x08, 0x00, 0x00,
+    0x00, 0x00, 0x01, 0x0a, 0x0a, 0x00, 0x0a, 0x0a, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x14, 0x08, 0x00, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x00, 0x00, 0x00, 0x22, 0x17, 0x41, 0x00, 0x32, 0x36,
+];
+
+/// A full refresh takes about 3 s. Give up on a panel that stays busy for
+/// much longer, e.g. one that isn't connected.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Format)]
+pub enum PanelError {
+    Spi,
+    Pin,
+    /// The busy line didn't go low in time
+    Busy,
+}
+
+/// SSD1680 controller of the Waveshare 2.9" V2 panel, driven with the same
+/// command sequence as the `epd-waveshare` crate. That driver is blocking
+/// and polls the busy line through a whole refresh, which would stall every
+/// other task on the executor, so the busy line is awaited here instead.
+struct Panel<SPI, BUSY, DC, RST> {
+    spi: SPI,
+    busy: BUSY,
+    dc: DC,
+    rst: RST,
+}
+
+impl<SPI, BUSY, DC, RST> Panel<SPI, BUSY, DC, RST>
+where
+    SPI: SpiDevice,
+    BUSY: Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+{
+    /// Send a command byte with DC low, followed by its data with DC high
+    async fn command(&mut self, command: u8, data: &[u8]) -> Result<(), PanelError> {
+        self.dc.set_low().map_err(|_| PanelError::Pin)?;
+        self.spi
+            .write(&[command])
+            .await
+            .map_err(|_| PanelError::Spi)?;
+        if !data.is_empty() {
+            self.dc.set_high().map_err(|_| PanelError::Pin)?;
+            self.spi.write(data).await.map_err(|_| PanelError::Spi)?;
+        }
+        Ok(())
+    }
+
+    async fn wait_until_idle(&mut self) -> Result<(), PanelError> {
+        match with_timeout(BUSY_TIMEOUT, self.busy.wait_for_low()).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_)) => Err(PanelError::Pin),
+            Err(_) => Err(PanelError::Busy),
+        }
+    }
+
+    /// Reset the controller out of deep sleep and load the waveform
+    async fn init(&mut self) -> Result<(), PanelError> {
+        self.rst.set_high().map_err(|_| PanelError::Pin)?;
+        Timer::after_millis(10).await;
+        self.rst.set_low().map_err(|_| PanelError::Pin)?;
+        Timer::after_millis(2).await;
+        self.rst.set_high().map_err(|_| PanelError::Pin)?;
+        Timer::after_millis(200).await;
+
+        self.wait_until_idle().await?;
+        self.command(0x12, &[]).await?; // software reset
+        self.wait_until_idle().await?;
+
+        self.command(0x01, &[0x27, 0x01, 0x00]).await?; // 296 gate lines
+        self.command(0x11, &[0x03]).await?; // x and y increment
+        self.command(0x44, &[0x00, 0x0f]).await?; // RAM x range, 128 / 8 bytes
+        self.command(0x45, &[0x00, 0x00, 0x27, 0x01]).await?; // RAM y range
+        self.command(0x21, &[0x00, 0x80]).await?;
+        self.command(0x4e, &[0x00]).await?; // RAM x counter
+        self.command(0x4f, &[0x00, 0x00]).await?; // RAM y counter
+        self.wait_until_idle().await?;
+
+        self.command(0x32, &LUT[..153]).await?;
+        self.wait_until_idle().await?;
+        self.command(0x3f, &LUT[153..154]).await?;
+        self.command(0x03, &LUT[154..155]).await?;
+        self.command(0x04, &LUT[155..158]).await?;
+        self.command(0x2c, &LUT[158..159]).await
+    }
+
+    /// Wake the panel, show `frame` with a full refresh and put it back into
+    /// deep sleep
+    async fn refresh(&mut self, frame: &[u8]) -> Result<(), PanelError> {
+        self.init().await?;
+        self.command(0x24, frame).await?; // write black/white RAM
+        self.command(0x22, &[0xc7]).await?; // full update with the loaded LUT
+        self.command(0x20, &[]).await?; // master activation
+        self.wait_until_idle().await?;
+        self.command(0x10, &[0x01]).await // deep sleep
+    }
+}
+
+/// Drives a 2.9" SSD1680 e-paper panel, redrawing only when the readings
+/// have changed enough to matter.
+pub async fn epaper_task<SPI, BUSY, DC, RST>(spi: SPI, busy: BUSY, dc: DC, rst: RST) -> !
+where
+    SPI: SpiDevice,
+    BUSY: Wait,
+    DC: OutputPin,
+    RST: OutputPin,
+{
+    let mut panel = Panel { spi, busy, dc, rst };
+
+    let mut frame = Frame::default();
+
+    let mut chart = Co2Chart::new();
+    let mut shown: Option<Readings> = None;
+    let mut last_attempt: Option<Instant> = None;
+
+    loop {
+        let readings = Readings::latest();
+        let since_attempt = last_attempt.map(|attempt| attempt.elapsed());
+        if REFRESH_POLICY.should_refresh(shown.as_ref(), &readings, since_attempt) {
+            debug!("E-paper: refreshing");
+            let Ok(()) = render(&mut frame, &readings, &chart);
+            last_attempt = Some(Instant::now());
+            match panel.refresh(frame.buffer()).await {
+                Ok(()) => {
+                    info!("E-paper: refreshed");
+                    shown = Some(readings);
+                }
+                Err(e) => error!("E-paper: failed to refresh panel: {:?}", e),
+            }
+        }
+
+        match select(sink::EPAPER.receive(), Timer::after(CHECK_INTERVAL)).await {
+            Either::First(Reading::Scd41(measurement)) => {
+                chart.push(measurement.co2, Instant::now())
+            }
+            Either::First(Reading::Bme680(_)) | Either::Second(()) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    use embassy_futures::block_on;
+    use embedded_hal::digital::ErrorType as PinErrorType;
+    use embedded_hal_async::spi::{ErrorType, Operation};
+
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn readings(co2: u16) -> Readings {
+        Readings {
+            co2: Some(co2),
+            temperature: Some(21.0),
+            humidity: Some(45.0),
+            battery: Some(80),
+        }
+    }
+
+    fn pixel(frame: &Frame, x: i32, y: i32) -> bool {
+        let native_x = (HEIGHT - 1 - y) as usize;
+        let byte = frame.0[x as usize * HEIGHT as usize / 8 + native_x / 8];
+        byte & (0x80 >> (native_x % 8)) == 0
+    }
+
+    /// Region of the frame as text, `#` for black
+    fn snapshot(frame: &Frame, area: Rectangle) -> String<1024> {
+        let mut text = String::new();
+        for y in area.rows() {
+            for x in area.columns() {
+                text.push(if pixel(frame, x, y) { '#' } else { '.' })
+                    .unwrap();
+            }
+            text.push('\n').unwrap();
+        }
+        text
+    }
+
+    fn chart(samples: &[u16]) -> Co2Chart {
+        let mut chart = Co2Chart::new();
+        let start = Instant::from_secs(1_000);
+        for (i, &co2) in samples.iter().chain([&0]).enumerate() {
+            chart.push(co2, start + CHART_BUCKET * i as u32);
+        }
+        chart
+    }
+
+    #[test]
+    fn first_refresh_is_immediate() {
+        assert!(REFRESH_POLICY.should_refresh(None, &Readings::default(), None));
+    }
+
+    #[test]
+    fn failed_refresh_waits_for_min_interval() {
+        let current = readings(600);
+        assert!(!REFRESH_POLICY.should_refresh(None, &current, Some(MINUTE)));
+        assert!(REFRESH_POLICY.should_refresh(None, &current, Some(3 * MINUTE)));
+    }
+
+    #[test]
+    fn refreshes_on_threshold_within_interval_bounds() {
+        let shown = readings(600);
+        let small = readings(640);
+        let large = readings(650);
+        assert!(!REFRESH_POLICY.should_refresh(Some(&shown), &large, Some(2 * MINUTE)));
+        assert!(!REFRESH_POLICY.should_refresh(Some(&shown), &small, Some(10 * MINUTE)));
+        assert!(REFRESH_POLICY.should_refresh(Some(&shown), &large, Some(10 * MINUTE)));
+        assert!(REFRESH_POLICY.should_refresh(Some(&shown), &small, Some(60 * MINUTE)));
+
+        let lost = Readings { co2: None, ..shown };
+        assert!(REFRESH_POLICY.should_refresh(Some(&shown), &lost, Some(3 * MINUTE)));
+    }
+
+    #[test]
+    fn chart_averages_buckets() {
+        let mut chart = Co2Chart::new();
+        let start = Instant::from_secs(1_000);
+        chart.push(400, start);
+        chart.push(600, start + CHART_BUCKET / 2);
+        assert_eq!(chart.buckets().count(), 0);
+        chart.push(900, start + CHART_BUCKET);
+        chart.push(900, start + CHART_BUCKET * 2);
+        assert!(chart.buckets().eq([500, 900]));
+    }
+
+    #[test]
+    fn chart_keeps_last_day() {
+        let samples: Vec<u16> = (0..CHART_LEN as u16 + 10).collect();
+        let chart = chart(&samples);
+        assert_eq!(chart.buckets().count(), CHART_LEN);
+        assert_eq!(chart.buckets().next(), Some(10));
+    }
+
+    #[test]
+    fn range_label_stays_out_of_the_chart() {
+        let samples: Vec<u16> = (0..CHART_LEN as u16).map(|i| 400 + i * 53 % 1200).collect();
+        let chart = chart(&samples);
+
+        let mut full = Frame::default();
+        let Ok(()) = render(&mut full, &readings(812), &chart);
+        let mut chart_only = Frame::default();
+        let Ok(()) = render_chart(&mut chart_only, &chart);
+
+        let area = Rectangle::new(
+            Point::new(0, CHART_TOP),
+            Size::new(WIDTH as u32, (HEIGHT - CHART_TOP) as u32),
+        );
+        for point in area.points() {
+            assert_eq!(
+                pixel(&full, point.x, point.y),
+                pixel(&chart_only, point.x, point.y),
+                "{point:?}"
+            );
+        }
+        assert!((36..CHART_TOP).any(|y| (0..60).any(|x| pixel(&full, x, y))));
+    }
+
+    #[test]
+    fn chart_spans_its_range() {
+        let chart = chart(&[400, 1000, 700]);
+        let mut frame = Frame::default();
+        let Ok(()) = render_chart(&mut frame, &chart);
+        assert!(pixel(&frame, WIDTH - 3, HEIGHT - 2));
+        assert!(pixel(&frame, WIDTH - 2, CHART_TOP));
+        assert!(pixel(&frame, WIDTH - 1, (CHART_TOP + HEIGHT - 2) / 2));
+        // Axis
+        assert!((CHART_LEFT..WIDTH).all(|x| pixel(&frame, x, HEIGHT - 1)));
+    }
+
+    #[test]
+    fn battery_snapshot() {
+        let mut frame = Frame::default();
+        let Ok(()) = render_battery(&mut frame, Some(50));
+        let icon = Rectangle::new(Point::new(WIDTH - 26, 0), Size::new(26, 12));
+        let expected = "\
+########################..
+#......................#..
+#.##########...........#..
+#.##########...........###
+#.##########...........###
+#.##########...........###
+#.##########...........###
+#.##########...........###
+#.##########...........###
+#.##########...........#..
+#......................#..
+########################..
+";
+        assert_eq!(snapshot(&frame, icon), expected);
+    }
+
+    #[test]
+    fn frame_layout_matches_panel_ram() {
+        let mut frame = Frame::default();
+        assert!(frame.buffer().iter().all(|&byte| byte == 0xff));
+        // Landscape top left is the last pixel of the panel's first row
+        Pixel(Point::zero(), BinaryColor::On)
+            .draw(&mut frame)
+            .unwrap();
+        // Landscape bottom right is the first pixel of its last row
+        Pixel(Point::new(WIDTH - 1, HEIGHT - 1), BinaryColor::On)
+            .draw(&mut frame)
+            .unwrap();
+        // Off screen is ignored
+        Pixel(Point::new(WIDTH, 0), BinaryColor::On)
+            .draw(&mut frame)
+            .unwrap();
+
+        assert_eq!(frame.buffer()[15], 0xfe);
+        assert_eq!(frame.buffer()[FRAME_LEN - 16], 0x7f);
+        assert_eq!(
+            frame.buffer().iter().filter(|&&byte| byte != 0xff).count(),
+            2
+        );
+    }
+
+    /// Commands sent to the panel with their data, and the frame written
+    type Log = Rc<RefCell<Vec<(u8, Vec<u8>)>>>;
+
+    struct FakeSpi {
+        dc: Rc<Cell<bool>>,
+        log: Log,
+    }
+
+    impl ErrorType for FakeSpi {
+        type Error = Infallible;
+    }
+
+    impl SpiDevice for FakeSpi {
+        async fn transaction(
+            &mut self,
+            operations: &mut [Operation<'_, u8>],
+        ) -> Result<(), Infallible> {
+            for operation in operations {
+                let Operation::Write(bytes) = operation else {
+                    panic!("panel is write only");
+                };
+                let mut log = self.log.borrow_mut();
+                if self.dc.get() {
+                    log.last_mut().unwrap().1.extend_from_slice(bytes);
+                } else {
+                    assert_eq!(bytes.len(), 1);
+                    log.push((bytes[0], Vec::new()));
+                }
+            }
+            Ok(())
+        }
+    }
+
+    struct FakePin(Rc<Cell<bool>>);
+
+    impl PinErrorType for FakePin {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for FakePin {
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.0.set(false);
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.0.set(true);
+            Ok(())
+        }
+    }
+
+    /// Busy line that is ready at once, logging the waits by the level or
+    /// edge waited for
+    struct FakeBusy(Rc<RefCell<Vec<&'static str>>>);
+
+    impl FakeBusy {
+        fn wait(&mut self, kind: &'static str) -> Result<(), Infallible> {
+            self.0.borrow_mut().push(kind);
+            Ok(())
+        }
+    }
+
+    impl PinErrorType for FakeBusy {
+        type Error = Infallible;
+    }
+
+    impl Wait for FakeBusy {
+        async fn wait_for_high(&mut self) -> Result<(), Infallible> {
+            self.wait("high")
+        }
+
+        async fn wait_for_low(&mut self) -> Result<(), Infallible> {
+            self.wait("low")
+        }
+
+        async fn wait_for_rising_edge(&mut self) -> Result<(), Infallible> {
+            self.wait("rising edge")
+        }
+
+        async fn wait_for_falling_edge(&mut self) -> Result<(), Infallible> {
+            self.wait("falling edge")
+        }
+
+        async fn wait_for_any_edge(&mut self) -> Result<(), Infallible> {
+            self.wait("any edge")
+        }
+    }
+
+    #[test]
+    fn refresh_sends_frame_and_sleeps() {
+        let dc = Rc::new(Cell::new(false));
+        let log = Log::default();
+        let waits = Rc::new(RefCell::new(vec![]));
+        let mut panel = Panel {
+            spi: FakeSpi {
+                dc: dc.clone(),
+                log: log.clone(),
+            },
+            busy: FakeBusy(waits.clone()),
+            dc: FakePin(dc),
+            rst: FakePin(Rc::new(Cell::new(false))),
+        };
+        let mut frame = Frame::default();
+        let Ok(()) = render(&mut frame, &readings(700), &Co2Chart::new());
+
+        block_on(panel.refresh(frame.buffer())).unwrap();
+
+        let log = log.borrow();
+        let commands: Vec<u8> = log.iter().map(|(command, _)| *command).collect();
+        assert_eq!(
+            commands,
+            [
+                0x12, 0x01, 0x11, 0x44, 0x45, 0x21, 0x4e, 0x4f, 0x32, 0x3f, 0x03, 0x04, 0x2c, 0x24,
+                0x22, 0x20, 0x10
+            ]
+        );
+        assert_eq!(log[8].1, LUT[..153]);
+        assert_eq!(log[13].1, frame.buffer());
+        assert_eq!(log[16].1, [0x01]);
+        // The refresh itself is waited for before going to sleep
+        // Every wait is for the busy line to go low
+        assert_eq!(*waits.borrow(), ["low"; 5]);
+    }
+}
@@ -0,0 +1,239 @@
+//! Report-by-exception filtering of sensor readings: a reading is only
+//! published when a field moved beyond its deadband, no sooner than a
+//! minimum interval after the last one and at least every heartbeat interval
+
+use heapless::LinearMap;
+
+use crate::config;
+use crate::deadband::{Deadband, Deadbands};
+use crate::payload::{self, Record};
+use crate::sink::Reading;
+
+pub const ENABLED: bool = config::flag(option_env!("MQTT_EXCEPTION_ENABLED"), false);
+const MIN_INTERVAL: &str = match option_env!("MQTT_EXCEPTION_MIN_INTERVAL") {
+    Some(interval) => interval,
+    None => "0",
+};
+const HEARTBEAT: &str = match option_env!("MQTT_EXCEPTION_HEARTBEAT") {
+    Some(heartbeat) => heartbeat,
+    None => "300",
+};
+/// Readings from this many sensors are tracked
+const MAX_SENSORS: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Settings<'a> {
+    /// Shortest time between published readings of a sensor in s
+    pub min_interval: u64,
+    /// Longest time between published readings of a sensor in s, however
+    /// little changed
+    pub heartbeat: u64,
+    pub deadband: Deadband<'a>,
+}
+
+impl Settings<'static> {
+    pub fn configured() -> Self {
+        Settings {
+            min_interval: MIN_INTERVAL.parse().unwrap_or(0),
+            heartbeat: HEARTBEAT.parse().unwrap_or(300),
+            deadband: Deadband::configured(),
+        }
+    }
+}
+
+/// Decides which readings are published, remembering the last published
+/// reading of each sensor. A change within the minimum interval is held
+/// back and published once the interval is over.
+pub struct Filter<'a> {
+    settings: Settings<'a>,
+    published: Deadbands<'a>,
+    /// Uptime in s at which each sensor's reading was last published
+    times: LinearMap<&'static str, u64, MAX_SENSORS>,
+    /// Newest reading of each sensor held back by the minimum interval
+    pending: LinearMap<&'static str, Reading, MAX_SENSORS>,
+}
+
+impl<'a> Filter<'a> {
+    pub fn new(settings: Settings<'a>) -> Self {
+        Filter {
+            settings,
+            published: Deadbands::new(settings.deadband),
+            times: LinearMap::new(),
+            pending: LinearMap::new(),
+        }
+    }
+
+    /// Whether to publish a reading taken at uptime `now` in s, recording it
+    /// as published if so. A change that comes too soon after the last
+    /// published reading is held back until it's [`due`](Filter::due)
+    /// instead.
+    pub fn publish(&mut self, reading: &Reading, now: u64) -> bool {
+        let (sensor, records) = payload::records(reading);
+        let Some(&last) = self.times.get(&sensor) else {
+            self.record(sensor, &records, now);
+            return true;
+        };
+        let elapsed = now.saturating_sub(last);
+        let changed = self.published.reading_changed(sensor, &records);
+        if elapsed < self.settings.min_interval {
+            // Once a change is pending, newer readings replace it even if
+            // they're back within the deadbands
+            if changed || self.pending.contains_key(&sensor) {
+                // Room for every sensor
+                let _ = self.pending.insert(sensor, reading.clone());
+            }
+            return false;
+        }
+        self.pending.remove(&sensor);
+        if changed || elapsed >= self.settings.heartbeat {
+            self.record(sensor, &records, now);
+            return true;
+        }
+        false
+    }
+
+    /// Uptime in s at which the first held back reading is due
+    pub fn due(&self) -> Option<u64> {
+        self.pending
+            .keys()
+            .filter_map(|sensor| self.times.get(sensor))
+            .map(|last| last + self.settings.min_interval)
+            .min()
+    }
+
+    /// Take a held back reading that is due at `now`, recording it as
+    /// published
+    pub fn take_due(&mut self, now: u64) -> Option<Reading> {
+        let sensor = *self.pending.keys().find(|sensor| {
+            self.times
+                .get(*sensor)
+                .is_some_and(|last| now >= last + self.settings.min_interval)
+        })?;
+        let reading = self.pending.remove(&sensor)?;
+        let (_, records) = payload::records(&reading);
+        self.record(sensor, &records, now);
+        Some(reading)
+    }
+
+    fn record(&mut self, sensor: &'static str, records: &[Record], now: u64) {
+        self.published.publish_reading(sensor, records);
+        // Room for every sensor
+        let _ = self.times.insert(sensor, now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scd41::Scd41Measurement;
+
+    use super::*;
+
+    const SETTINGS: Settings = Settings {
+        min_interval: 10,
+        heartbeat: 300,
+        deadband: Deadband {
+            absolute: "default=100,co2=10",
+            relative: "",
+        },
+    };
+
+    fn co2(co2: u16) -> Reading {
+        let mut measurement = Scd41Measurement::default();
+        measurement.co2 = co2;
+        Reading::Scd41(measurement)
+    }
+
+    fn co2_of(reading: Option<Reading>) -> Option<u16> {
+        match reading? {
+            Reading::Scd41(measurement) => Some(measurement.co2),
+            Reading::Bme680(_) => None,
+        }
+    }
+
+    #[test]
+    fn publishes_the_first_reading_and_changes() {
+        let mut filter = Filter::new(SETTINGS);
+        assert!(filter.publish(&co2(400), 0));
+        assert!(!filter.publish(&co2(405), 15));
+        assert!(!filter.publish(&co2(391), 30));
+        assert!(filter.publish(&co2(411), 45));
+        // Compared with the last published reading
+        assert!(!filter.publish(&co2(420), 60));
+        assert_eq!(filter.due(), None);
+    }
+
+    #[test]
+    fn heartbeat_publishes_unchanged_readings() {
+        let mut filter = Filter::new(SETTINGS);
+        assert!(filter.publish(&co2(400), 0));
+        assert!(!filter.publish(&co2(400), 299));
+        assert!(filter.publish(&co2(400), 300));
+        assert!(!filter.publish(&co2(400), 305));
+    }
+
+    #[test]
+    fn changes_within_the_min_interval_are_deferred() {
+        let mut filter = Filter::new(SETTINGS);
+        assert!(filter.publish(&co2(400), 0));
+
+        // Within the deadband, nothing to publish later
+        assert!(!filter.publish(&co2(405), 2));
+        assert_eq!(filter.due(), None);
+
+        assert!(!filter.publish(&co2(500), 4));
+        assert_eq!(filter.due(), Some(10));
+        assert_eq!(co2_of(filter.take_due(9)), None);
+        // The newest reading replaces the held back one, even if it's back
+        // within the deadband
+        assert!(!filter.publish(&co2(402), 6));
+        assert_eq!(filter.due(), Some(10));
+
+        assert_eq!(co2_of(filter.take_due(10)), Some(402));
+        assert_eq!(filter.due(), None);
+        assert_eq!(co2_of(filter.take_due(10)), None);
+
+        // The held back reading counts as published when it's taken
+        assert!(!filter.publish(&co2(440), 15));
+        assert_eq!(filter.due(), Some(20));
+    }
+
+    #[test]
+    fn later_reading_replaces_one_not_yet_taken() {
+        let mut filter = Filter::new(SETTINGS);
+        assert!(filter.publish(&co2(400), 0));
+        assert!(!filter.publish(&co2(500), 5));
+
+        // Back within the deadband after the interval, so the change is gone
+        assert!(!filter.publish(&co2(401), 12));
+        assert_eq!(filter.due(), None);
+
+        // Past the interval, a change is published at once
+        assert!(filter.publish(&co2(500), 14));
+        assert_eq!(filter.due(), None);
+    }
+
+    #[test]
+    fn sensors_are_filtered_separately() {
+        let mut filter = Filter::new(SETTINGS);
+        let bme680 = Reading::Bme680(crate::bme680::Bme680Measurement::default());
+        assert!(filter.publish(&co2(400), 0));
+        assert!(filter.publish(&bme680, 1));
+        assert!(!filter.publish(&bme680, 5));
+
+        assert!(!filter.publish(&co2(500), 3));
+        assert_eq!(filter.due(), Some(10));
+        assert!(matches!(filter.take_due(10), Some(Reading::Scd41(_))));
+    }
+
+    #[test]
+    fn no_min_interval_publishes_changes_at_once() {
+        let mut filter = Filter::new(Settings {
+            min_interval: 0,
+            ..SETTINGS
+        });
+        assert!(filter.publish(&co2(400), 0));
+        assert!(filter.publish(&co2(500), 0));
+        assert!(!filter.publish(&co2(505), 1));
+        assert_eq!(filter.due(), None);
+    }
+}
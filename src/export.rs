@@ -0,0 +1,394 @@
+//! Query parsing and CSV formatting for history exports
+
+use core::fmt::Write;
+
+use defmt::Format;
+use heapless::{String, Vec};
+
+use crate::history::Sample;
+
+/// Longest CSV row, a timestamp and all four fields
+pub const MAX_ROW: usize = 96;
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Co2,
+    Temperature,
+    Humidity,
+    Pressure,
+}
+
+impl Field {
+    pub const ALL: [Field; 4] = [
+        Field::Co2,
+        Field::Temperature,
+        Field::Humidity,
+        Field::Pressure,
+    ];
+
+    fn parse(field: &str) -> Option<Field> {
+        Some(match field {
+            "co2" => Field::Co2,
+            "temperature" => Field::Temperature,
+            "humidity" => Field::Humidity,
+            "pressure" => Field::Pressure,
+            _ => return None,
+        })
+    }
+
+    /// Column heading with units
+    fn heading(self) -> &'static str {
+        match self {
+            Field::Co2 => "co2 (ppm)",
+            Field::Temperature => "temperature (°C)",
+            Field::Humidity => "humidity (%)",
+            Field::Pressure => "pressure (hPa)",
+        }
+    }
+
+    fn write(self, row: &mut String<MAX_ROW>, sample: &Sample) -> core::fmt::Result {
+        match self {
+            Field::Co2 => match sample.co2 {
+                Some(co2) => write!(row, "{co2}"),
+                None => Ok(()),
+            },
+            Field::Temperature => write_decimal(row, sample.temperature, 2),
+            Field::Humidity => write_decimal(row, sample.humidity, 2),
+            Field::Pressure => write_decimal(row, sample.pressure, 1),
+        }
+    }
+}
+
+fn write_decimal(
+    row: &mut String<MAX_ROW>,
+    value: Option<f32>,
+    places: usize,
+) -> core::fmt::Result {
+    match value.filter(|value| value.is_finite()) {
+        Some(value) => write!(row, "{value:.places$}"),
+        None => Ok(()),
+    }
+}
+
+#[derive(Debug, Format, Clone, PartialEq, Eq)]
+pub struct Query {
+    /// Inclusive range of Unix times
+    pub from: u32,
+    pub to: u32,
+    pub fields: Vec<Field, 4>,
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum QueryError {
+    InvalidTime,
+    UnknownField,
+}
+
+impl Query {
+    /// Parse `from`, `to` and `fields` from a query string. Times are Unix
+    /// seconds or ISO 8601 UTC dates and times, and fields are comma
+    /// separated, defaulting to all of them.
+    pub fn parse(query: &str) -> Result<Query, QueryError> {
+        let mut parsed = Query {
+            from: 0,
+            to: u32::MAX,
+            fields: Vec::new(),
+        };
+        for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+            match key {
+                "from" => parsed.from = parse_time(value).ok_or(QueryError::InvalidTime)?,
+                "to" => parsed.to = parse_time(value).ok_or(QueryError::InvalidTime)?,
+                "fields" => {
+                    for field in split_list(value).filter(|field| !field.is_empty()) {
+                        let field = Field::parse(field).ok_or(QueryError::UnknownField)?;
+                        if !parsed.fields.contains(&field) {
+                            let _ = parsed.fields.push(field);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        if parsed.fields.is_empty() {
+            parsed.fields = Vec::from_slice(&Field::ALL).unwrap_or_default();
+        }
+        Ok(parsed)
+    }
+}
+
+/// Split a comma separated list, which may have had its commas
+/// percent-encoded
+fn split_list(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',')
+        .flat_map(|part| part.split("%2C"))
+        .flat_map(|part| part.split("%2c"))
+}
+
+/// Unix seconds, or an ISO 8601 UTC `YYYY-MM-DD` date optionally followed by
+/// `THH:MM:SS` and `Z`, with `:` possibly percent-encoded
+pub fn parse_time(time: &str) -> Option<u32> {
+    if let Ok(seconds) = time.parse() {
+        return Some(seconds);
+    }
+
+    let mut buffer: String<32> = String::new();
+    for part in time.split("%3A").flat_map(|part| part.split("%3a")) {
+        if !buffer.is_empty() {
+            buffer.push(':').ok()?;
+        }
+        buffer.push_str(part).ok()?;
+    }
+    let time = buffer.strip_suffix('Z').unwrap_or(&buffer);
+
+    let (date, clock) = time.split_once('T').unwrap_or((time, "00:00:00"));
+    let mut date = date.split('-').map(str::parse::<u32>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let mut clock = clock.split(':').map(str::parse::<u32>);
+    let (hour, minute, second) = (
+        clock.next()?.ok()?,
+        clock.next()?.ok()?,
+        clock.next()?.ok()?,
+    );
+    if date.next().is_some()
+        || clock.next().is_some()
+        || !(1970..=2105).contains(&year)
+        || !(1..=12).contains(&month)
+        || !(1..=days_in_month(year, month)).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    Some(days * 86_400 + hour * 3600 + minute * 60 + second)
+}
+
+fn days_in_month(year: u32, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar, for
+/// years from 1970 (Howard Hinnant's algorithm)
+fn days_from_civil(year: u32, month: u32, day: u32) -> u32 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Date of a number of days since 1970-01-01, as (year, month, day)
+fn civil_from_days(days: u32) -> (u32, u32, u32) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u32::from(month <= 2);
+    (year, month, day)
+}
+
+/// Write a Unix time as an ISO 8601 UTC timestamp
+pub fn write_timestamp<W: Write>(out: &mut W, time: u32) -> core::fmt::Result {
+    let (year, month, day) = civil_from_days(time / 86_400);
+    let seconds = time % 86_400;
+    write!(
+        out,
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Formats the header and rows of a CSV export one line at a time, so it can
+/// be streamed without buffering the whole response
+pub struct CsvWriter<'a> {
+    fields: &'a [Field],
+}
+
+impl<'a> CsvWriter<'a> {
+    pub fn new(fields: &'a [Field]) -> Self {
+        CsvWriter { fields }
+    }
+
+    pub fn header(&self) -> String<MAX_ROW> {
+        let mut row = String::new();
+        let _ = row.push_str("time (UTC)");
+        for field in self.fields {
+            let _ = row.push(',');
+            let _ = row.push_str(field.heading());
+        }
+        let _ = row.push_str("\r\n");
+        row
+    }
+
+    /// A row, empty cells standing for missing values
+    pub fn row(&self, time: u32, sample: &Sample) -> String<MAX_ROW> {
+        let mut row = String::new();
+        let _ = write_timestamp(&mut row, time);
+        for field in self.fields {
+            let _ = row.push(',');
+            let _ = field.write(&mut row, sample);
+        }
+        let _ = row.push_str("\r\n");
+        row
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timestamp(time: u32) -> String<32> {
+        let mut out = String::new();
+        write_timestamp(&mut out, time).unwrap();
+        out
+    }
+
+    #[test]
+    fn parses_unix_and_iso_times() {
+        assert_eq!(parse_time("1700000000"), Some(1_700_000_000));
+        assert_eq!(parse_time("1970-01-01"), Some(0));
+        assert_eq!(parse_time("2023-11-14T22:13:20Z"), Some(1_700_000_000));
+        assert_eq!(parse_time("2023-11-14T22:13:20"), Some(1_700_000_000));
+        assert_eq!(parse_time("2023-11-14T22%3A13%3a20Z"), Some(1_700_000_000));
+        assert_eq!(parse_time("2024-01-31T22:00:00Z"), Some(1_706_738_400));
+        assert_eq!(parse_time("2105-12-31T23:59:59Z"), Some(4_291_747_199));
+    }
+
+    #[test]
+    fn validates_days_against_month() {
+        assert_eq!(parse_time("2025-02-31"), None);
+        assert_eq!(parse_time("2025-02-29"), None);
+        assert_eq!(parse_time("2025-04-31"), None);
+        assert_eq!(parse_time("2025-02-00"), None);
+        assert_eq!(parse_time("2024-02-29"), Some(1_709_164_800));
+        assert_eq!(parse_time("2000-02-29"), Some(951_782_400));
+        assert_eq!(parse_time("2100-02-29"), None);
+        assert!(parse_time("2025-12-31").is_some());
+    }
+
+    #[test]
+    fn rejects_malformed_times() {
+        for time in [
+            "",
+            "yesterday",
+            "-5",
+            "2025-13-01",
+            "1969-12-31",
+            "2106-01-01",
+            "2025-01",
+            "2025-01-01-01",
+            "2025-01-01T24:00:00Z",
+            "2025-01-01T12:60:00Z",
+            "2025-01-01T12:00:60Z",
+            "2025-01-01T12:00Z",
+            "2025-01-01T12:00:00:00Z",
+        ] {
+            assert_eq!(parse_time(time), None, "{time}");
+        }
+    }
+
+    #[test]
+    fn dates_round_trip() {
+        for days in (0..49_000).step_by(17) {
+            let (year, month, day) = civil_from_days(days);
+            assert!(day <= days_in_month(year, month));
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+        assert_eq!(timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(timestamp(951_868_799), "2000-02-29T23:59:59Z");
+        assert_eq!(timestamp(u32::MAX), "2106-02-07T06:28:15Z");
+    }
+
+    #[test]
+    fn parses_queries() {
+        let query =
+            Query::parse("from=2024-01-01&to=1704153600&fields=humidity%2Cco2,humidity").unwrap();
+        assert_eq!(query.from, 1_704_067_200);
+        assert_eq!(query.to, 1_704_153_600);
+        assert_eq!(query.fields, [Field::Humidity, Field::Co2]);
+
+        let query = Query::parse("").unwrap();
+        assert_eq!((query.from, query.to), (0, u32::MAX));
+        assert_eq!(query.fields, Field::ALL);
+
+        assert_eq!(
+            Query::parse("from=2025-02-31"),
+            Err(QueryError::InvalidTime)
+        );
+        assert_eq!(
+            Query::parse("fields=co2,radon"),
+            Err(QueryError::UnknownField)
+        );
+    }
+
+    #[test]
+    fn writes_csv() {
+        let csv = CsvWriter::new(&Field::ALL);
+        assert_eq!(
+            csv.header(),
+            "time (UTC),co2 (ppm),temperature (°C),humidity (%),pressure (hPa)\r\n"
+        );
+        let sample = Sample {
+            co2: Some(612),
+            temperature: Some(21.456),
+            humidity: None,
+            pressure: Some(1013.25),
+        };
+        assert_eq!(
+            csv.row(1_700_000_000, &sample),
+            "2023-11-14T22:13:20Z,612,21.46,,1013.2\r\n"
+        );
+        let nan = Sample {
+            temperature: Some(f32::NAN),
+            ..Sample::default()
+        };
+        assert_eq!(csv.row(0, &nan), "1970-01-01T00:00:00Z,,,,\r\n");
+    }
+
+    #[test]
+    fn writes_selected_columns() {
+        let fields = [Field::Pressure, Field::Co2];
+        let csv = CsvWriter::new(&fields);
+        assert_eq!(csv.header(), "time (UTC),pressure (hPa),co2 (ppm)\r\n");
+        let sample = Sample {
+            co2: Some(400),
+            pressure: Some(990.04),
+            ..Sample::default()
+        };
+        assert_eq!(csv.row(60, &sample), "1970-01-01T00:01:00Z,990.0,400\r\n");
+    }
+
+    #[test]
+    fn longest_row_fits() {
+        let csv = CsvWriter::new(&Field::ALL);
+        let sample = Sample {
+            co2: Some(u16::MAX),
+            temperature: Some(-40.0),
+            humidity: Some(100.0),
+            pressure: Some(1100.0),
+        };
+        assert_eq!(
+            csv.row(u32::MAX, &sample),
+            "2106-02-07T06:28:15Z,65535,-40.00,100.00,1100.0\r\n"
+        );
+    }
+}
@@ -0,0 +1,100 @@
+//! Per-field MQTT topics with plain numeric payloads, e.g.
+//! `air/40a3cc01f2e4/scd41/co2` carrying `612`, for consumers that don't want to
+//! parse JSON
+
+use core::fmt::Write;
+
+use heapless::String;
+
+use crate::config;
+
+pub const ENABLED: bool = config::flag(option_env!("MQTT_FIELDS_ENABLED"), false);
+/// Topic template with `{base}`, `{device_id}`, `{sensor}` and `{field}`
+/// placeholders
+pub const TEMPLATE: &str = match option_env!("MQTT_FIELDS_TEMPLATE") {
+    Some(template) => template,
+    None => "{base}/{device_id}/{sensor}/{field}",
+};
+pub const BASE: &str = match option_env!("MQTT_FIELDS_BASE") {
+    Some(base) => base,
+    None => "air",
+};
+pub const RETAIN: bool = config::flag(option_env!("MQTT_FIELDS_RETAIN"), true);
+/// Decimal places per field as `field=places,...`, `default` applying to
+/// fields not listed
+pub const PRECISION: &str = match option_env!("MQTT_FIELDS_PRECISION") {
+    Some(precision) => precision,
+    None => "default=1,co2=0",
+};
+/// Longest topic and payload
+pub const MAX_TOPIC: usize = 96;
+pub const MAX_PAYLOAD: usize = 24;
+
+/// Topic of a field, expanded from the template
+pub fn topic<const N: usize>(
+    template: &str,
+    base: &str,
+    device_id: &str,
+    sensor: &str,
+    field: &str,
+) -> Option<String<N>> {
+    config::expand(template, |name| match name {
+        "base" => Some(base),
+        "device_id" => Some(device_id),
+        "sensor" => Some(sensor),
+        "field" => Some(field),
+        _ => None,
+    })
+}
+
+/// A value with a fixed number of decimal places
+pub fn format_value<const N: usize>(value: f32, decimals: usize) -> Option<String<N>> {
+    let mut out = String::new();
+    write!(out, "{value:.decimals$}").ok()?;
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_topic_templates() {
+        let expanded: String<MAX_TOPIC> =
+            topic(TEMPLATE, "air", "40a3cc01f2e4", "scd41", "co2").unwrap();
+        assert_eq!(expanded, "air/40a3cc01f2e4/scd41/co2");
+
+        let expanded: String<MAX_TOPIC> = topic(
+            "homie/{device_id}/{sensor}-{field}/$value",
+            "air",
+            "40a3cc01f2e4",
+            "bme680",
+            "gas_resistance",
+        )
+        .unwrap();
+        assert_eq!(expanded, "homie/40a3cc01f2e4/bme680-gas_resistance/$value");
+    }
+
+    #[test]
+    fn unknown_placeholders_are_kept() {
+        let expanded: String<MAX_TOPIC> =
+            topic("{base}/{room}/{field}", "air", "id", "scd41", "co2").unwrap();
+        assert_eq!(expanded, "air/{room}/co2");
+    }
+
+    #[test]
+    fn long_topics_are_rejected() {
+        let base = "b".repeat(MAX_TOPIC);
+        assert!(topic::<MAX_TOPIC>(TEMPLATE, &base, "id", "scd41", "co2").is_none());
+    }
+
+    #[test]
+    fn formats_values_to_precision() {
+        let value = |value, decimals| format_value::<MAX_PAYLOAD>(value, decimals).unwrap();
+        assert_eq!(value(612.4, 0), "612");
+        assert_eq!(value(21.456, 1), "21.5");
+        assert_eq!(value(-3.0, 2), "-3.00");
+        assert_eq!(value(101_325.0, 0), "101325");
+        assert!(format_value::<4>(101_325.0, 0).is_none());
+    }
+}
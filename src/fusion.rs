@@ -0,0 +1,475 @@
+//! Combines temperature and humidity from the SCD41 and BME680 into a single
+//! best estimate, and calibrates the SCD41 temperature offset against the
+//! BME680
+
+use defmt::{debug, info, warn, Format};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant};
+use serde::Serialize;
+
+use crate::config;
+use crate::mqtt::{self, Outgoing};
+use crate::psychrometrics;
+use crate::sink;
+
+/// Correction in °C to add to the SCD41's own temperature offset, taken by
+/// the SCD41 task between measurements
+pub static SCD41_OFFSET_CORRECTION: Signal<CriticalSectionRawMutex, f32> = Signal::new();
+
+/// Offsets in °C and % subtracted from each sensor's readings
+const SCD41_TEMPERATURE_OFFSET: &str = match option_env!("FUSION_SCD41_TEMPERATURE_OFFSET") {
+    Some(offset) => offset,
+    None => "0",
+};
+const SCD41_HUMIDITY_OFFSET: &str = match option_env!("FUSION_SCD41_HUMIDITY_OFFSET") {
+    Some(offset) => offset,
+    None => "0",
+};
+const BME680_TEMPERATURE_OFFSET: &str = match option_env!("FUSION_BME680_TEMPERATURE_OFFSET") {
+    Some(offset) => offset,
+    None => "0",
+};
+const BME680_HUMIDITY_OFFSET: &str = match option_env!("FUSION_BME680_HUMIDITY_OFFSET") {
+    Some(offset) => offset,
+    None => "0",
+};
+/// Largest differences in °C and % at which the sensors still count as agreeing
+const TEMPERATURE_THRESHOLD: &str = match option_env!("FUSION_TEMPERATURE_THRESHOLD") {
+    Some(threshold) => threshold,
+    None => "1.0",
+};
+const HUMIDITY_THRESHOLD: &str = match option_env!("FUSION_HUMIDITY_THRESHOLD") {
+    Some(threshold) => threshold,
+    None => "5.0",
+};
+/// Whether to correct the SCD41's temperature offset against the BME680
+const CALIBRATE_SCD41: bool = config::flag(option_env!("FUSION_CALIBRATE_SCD41"), true);
+
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(30);
+/// Readings older than this are no longer fused
+const MAX_AGE: Duration = Duration::from_secs(30);
+
+/// Typical accuracy of each sensor, used to weight the fused estimate
+const SCD41_TEMPERATURE_ACCURACY: f32 = 0.8;
+const SCD41_HUMIDITY_ACCURACY: f32 = 6.0;
+const BME680_TEMPERATURE_ACCURACY: f32 = 1.0;
+const BME680_HUMIDITY_ACCURACY: f32 = 3.0;
+
+/// Smoothing of the SCD41 temperature error, see [`OffsetCalibration`]
+const CALIBRATION_SMOOTHING: f32 = 0.05;
+/// Paired samples needed before the smoothed error is trusted
+const CALIBRATION_SAMPLES: u32 = 60;
+/// Smallest error in °C worth correcting, below the offset resolution this
+/// would only churn the sensor
+const CALIBRATION_MIN_ERROR: f32 = 0.3;
+
+#[derive(Debug, Format, Clone, Copy, PartialEq)]
+pub struct Reading {
+    /// °C
+    pub temperature: f32,
+    /// %
+    pub humidity: f32,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Offsets {
+    /// °C
+    pub temperature: f32,
+    /// %
+    pub humidity: f32,
+}
+
+impl Offsets {
+    /// Correct a reading. Relative humidity is re-derived at the corrected
+    /// temperature first, as the sensor measured it relative to its own
+    /// (offset) temperature while the vapour pressure is the same.
+    pub fn apply(&self, reading: Reading) -> Reading {
+        let temperature = reading.temperature - self.temperature;
+        let humidity =
+            psychrometrics::humidity_at(reading.temperature, reading.humidity, temperature);
+        Reading {
+            temperature,
+            humidity: (humidity - self.humidity).clamp(0.0, 100.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Thresholds {
+    /// °C
+    pub temperature: f32,
+    /// %
+    pub humidity: f32,
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    /// Weighted mean of both sensors
+    Fused,
+    Scd41,
+    Bme680,
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Confidence {
+    /// Both sensors agree
+    High,
+    /// Only one sensor available
+    Medium,
+    /// The sensors disagree
+    Low,
+}
+
+#[derive(Debug, Format, Clone, Copy, Serialize)]
+pub struct Fused {
+    /// °C
+    pub temperature: f32,
+    /// %
+    pub humidity: f32,
+    pub source: Source,
+    pub confidence: Confidence,
+    /// SCD41 minus BME680 temperature in °C, if both are available
+    pub temperature_difference: Option<f32>,
+    /// SCD41 minus BME680 humidity in %, if both are available
+    pub humidity_difference: Option<f32>,
+}
+
+fn weighted_mean(a: f32, a_accuracy: f32, b: f32, b_accuracy: f32) -> f32 {
+    // Inverse variance weighting
+    let a_weight = 1.0 / (a_accuracy * a_accuracy);
+    let b_weight = 1.0 / (b_accuracy * b_accuracy);
+    (a * a_weight + b * b_weight) / (a_weight + b_weight)
+}
+
+/// Best estimate from corrected readings. Agreeing sensors are averaged, and
+/// when they disagree the BME680 is preferred as it heats itself much less
+/// in forced mode than the continuously running SCD41.
+pub fn fuse(
+    scd41: Option<Reading>,
+    bme680: Option<Reading>,
+    thresholds: &Thresholds,
+) -> Option<Fused> {
+    let single = |reading: Reading, source| Fused {
+        temperature: reading.temperature,
+        humidity: reading.humidity,
+        source,
+        confidence: Confidence::Medium,
+        temperature_difference: None,
+        humidity_difference: None,
+    };
+
+    match (scd41, bme680) {
+        (None, None) => None,
+        (Some(scd41), None) => Some(single(scd41, Source::Scd41)),
+        (None, Some(bme680)) => Some(single(bme680, Source::Bme680)),
+        (Some(scd41), Some(bme680)) => {
+            let temperature_difference = scd41.temperature - bme680.temperature;
+            let humidity_difference = scd41.humidity - bme680.humidity;
+            let agree = temperature_difference.abs() <= thresholds.temperature
+                && humidity_difference.abs() <= thresholds.humidity;
+
+            let (temperature, humidity, source, confidence) = if agree {
+                (
+                    weighted_mean(
+                        scd41.temperature,
+                        SCD41_TEMPERATURE_ACCURACY,
+                        bme680.temperature,
+                        BME680_TEMPERATURE_ACCURACY,
+                    ),
+                    weighted_mean(
+                        scd41.humidity,
+                        SCD41_HUMIDITY_ACCURACY,
+                        bme680.humidity,
+                        BME680_HUMIDITY_ACCURACY,
+                    ),
+                    Source::Fused,
+                    Confidence::High,
+                )
+            } else {
+                (
+                    bme680.temperature,
+                    bme680.humidity,
+                    Source::Bme680,
+                    Confidence::Low,
+                )
+            };
+
+            Some(Fused {
+                temperature,
+                humidity,
+                source,
+                confidence,
+                temperature_difference: Some(temperature_difference),
+                humidity_difference: Some(humidity_difference),
+            })
+        }
+    }
+}
+
+/// Learns how far the SCD41 temperature is off from the BME680, so the
+/// SCD41's temperature offset (and with it its humidity compensation) can be
+/// corrected on the sensor itself
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OffsetCalibration {
+    /// Smoothed SCD41 minus BME680 temperature in °C
+    error: f32,
+    samples: u32,
+}
+
+impl OffsetCalibration {
+    pub const fn new() -> Self {
+        OffsetCalibration {
+            error: 0.0,
+            samples: 0,
+        }
+    }
+
+    /// Add the temperature difference of a pair of corrected readings,
+    /// returning the correction in °C to add to the SCD41 offset once enough
+    /// samples show a significant error. Starts over after a correction, as
+    /// earlier samples were taken with the old offset.
+    pub fn update(&mut self, difference: f32) -> Option<f32> {
+        if !difference.is_finite() {
+            return None;
+        }
+        self.error = match self.samples {
+            0 => difference,
+            _ => CALIBRATION_SMOOTHING * difference + (1.0 - CALIBRATION_SMOOTHING) * self.error,
+        };
+        self.samples += 1;
+
+        if self.samples < CALIBRATION_SAMPLES || self.error.abs() < CALIBRATION_MIN_ERROR {
+            return None;
+        }
+        let correction = self.error;
+        *self = OffsetCalibration::new();
+        Some(correction)
+    }
+}
+
+/// Feed the SCD41 minus BME680 temperature difference to the calibration and
+/// hand any correction to the SCD41 task
+fn calibrate(calibration: &mut OffsetCalibration, difference: f32) {
+    if let Some(correction) = calibration.update(difference) {
+        info!(
+            "Fusion: correcting SCD41 temperature offset by {} C",
+            correction
+        );
+        SCD41_OFFSET_CORRECTION.signal(correction);
+    }
+}
+
+fn fresh<T: Copy>(reading: Option<(T, Instant)>, now: Instant) -> Option<T> {
+    reading
+        .filter(|(_, at)| now.saturating_duration_since(*at) <= MAX_AGE)
+        .map(|(reading, _)| reading)
+}
+
+#[embassy_executor::task]
+pub async fn engine() -> ! {
+    let offset = |value: &str| value.parse().unwrap_or(0.0);
+    let scd41_offsets = Offsets {
+        temperature: offset(SCD41_TEMPERATURE_OFFSET),
+        humidity: offset(SCD41_HUMIDITY_OFFSET),
+    };
+    let bme680_offsets = Offsets {
+        temperature: offset(BME680_TEMPERATURE_OFFSET),
+        humidity: offset(BME680_HUMIDITY_OFFSET),
+    };
+    let thresholds = Thresholds {
+        temperature: TEMPERATURE_THRESHOLD.parse().unwrap_or(1.0),
+        humidity: HUMIDITY_THRESHOLD.parse().unwrap_or(5.0),
+    };
+    info!(
+        "Fusion: agreement within {} C and {} %, SCD41 calibration {}",
+        thresholds.temperature,
+        thresholds.humidity,
+        if CALIBRATE_SCD41 { "on" } else { "off" }
+    );
+
+    let mut scd41_reading: Option<(Reading, Instant)> = None;
+    let mut bme680_reading: Option<(Reading, Instant)> = None;
+    let mut calibration = OffsetCalibration::new();
+    let mut last_published: Option<Instant> = None;
+
+    loop {
+        let now = match sink::FUSION.receive().await {
+            sink::Reading::Scd41(measurement) => {
+                let now = Instant::now();
+                let reading = Reading {
+                    temperature: measurement.temperature,
+                    humidity: measurement.humidity,
+                };
+                scd41_reading = Some((scd41_offsets.apply(reading), now));
+                now
+            }
+            sink::Reading::Bme680(measurement) => {
+                let now = Instant::now();
+                let reading = Reading {
+                    temperature: measurement.temperature,
+                    humidity: measurement.humidity,
+                };
+                bme680_reading = Some((bme680_offsets.apply(reading), now));
+                now
+            }
+        };
+
+        let scd41 = fresh(scd41_reading, now);
+        let bme680 = fresh(bme680_reading, now);
+        let Some(fused) = fuse(scd41, bme680, &thresholds) else {
+            continue;
+        };
+        debug!("Fusion: {:?}", fused);
+
+        // Calibrate once per SCD41 measurement, the slower of the two sensors
+        if let (true, Some((_, at)), Some(difference)) =
+            (CALIBRATE_SCD41, scd41_reading, fused.temperature_difference)
+        {
+            if at == now {
+                calibrate(&mut calibration, difference);
+            }
+        }
+
+        if last_published.is_some_and(|at| now.saturating_duration_since(at) < PUBLISH_INTERVAL) {
+            continue;
+        }
+        last_published = Some(now);
+        if mqtt::OUTBOX.try_send(Outgoing::Fusion(fused)).is_err() {
+            warn!("Fusion: MQTT outbox full, dropping estimate");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLDS: Thresholds = Thresholds {
+        temperature: 1.0,
+        humidity: 5.0,
+    };
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{actual} should be within {tolerance} of {expected}"
+        );
+    }
+
+    fn reading(temperature: f32, humidity: f32) -> Reading {
+        Reading {
+            temperature,
+            humidity,
+        }
+    }
+
+    #[test]
+    fn agreeing_sensors_are_weighted_by_accuracy() {
+        let fused = fuse(
+            Some(reading(22.0, 40.0)),
+            Some(reading(21.0, 43.0)),
+            &THRESHOLDS,
+        )
+        .unwrap();
+        assert_eq!(fused.source, Source::Fused);
+        assert_eq!(fused.confidence, Confidence::High);
+        // Weights 1/0.8² and 1/1² for temperature, 1/6² and 1/3² for humidity
+        assert_close(fused.temperature, 21.0 + 1.5625 / 2.5625, 1e-4);
+        assert_close(fused.humidity, 43.0 - 3.0 * 0.25 / 1.25, 1e-4);
+        assert_eq!(fused.temperature_difference, Some(1.0));
+        assert_eq!(fused.humidity_difference, Some(-3.0));
+    }
+
+    #[test]
+    fn equal_accuracy_is_plain_mean() {
+        assert_close(weighted_mean(20.0, 2.0, 30.0, 2.0), 25.0, 1e-4);
+        // Four times the variance, a fifth of the weight
+        assert_close(weighted_mean(20.0, 1.0, 30.0, 2.0), 22.0, 1e-4);
+    }
+
+    #[test]
+    fn disagreeing_sensors_fall_back_to_bme680() {
+        for scd41 in [reading(23.5, 40.0), reading(22.0, 50.0)] {
+            let fused = fuse(Some(scd41), Some(reading(22.0, 44.0)), &THRESHOLDS).unwrap();
+            assert_eq!(fused.source, Source::Bme680);
+            assert_eq!(fused.confidence, Confidence::Low);
+            assert_eq!((fused.temperature, fused.humidity), (22.0, 44.0));
+        }
+    }
+
+    #[test]
+    fn single_sensor_passes_through() {
+        let fused = fuse(Some(reading(22.0, 40.0)), None, &THRESHOLDS).unwrap();
+        assert_eq!(fused.source, Source::Scd41);
+        assert_eq!(fused.confidence, Confidence::Medium);
+        assert_eq!(fused.temperature_difference, None);
+        let fused = fuse(None, Some(reading(21.0, 45.0)), &THRESHOLDS).unwrap();
+        assert_eq!(fused.source, Source::Bme680);
+        assert!(fuse(None, None, &THRESHOLDS).is_none());
+    }
+
+    #[test]
+    fn offsets_rederive_humidity() {
+        let offsets = Offsets {
+            temperature: 2.0,
+            humidity: 1.0,
+        };
+        let corrected = offsets.apply(reading(24.0, 40.0));
+        assert_eq!(corrected.temperature, 22.0);
+        // Same vapour pressure at the lower temperature, less the offset
+        let expected = psychrometrics::humidity_at(24.0, 40.0, 22.0) - 1.0;
+        assert_close(corrected.humidity, expected, 1e-4);
+        assert!(corrected.humidity > 43.0);
+        assert_eq!(
+            Offsets::default().apply(reading(24.0, 40.0)),
+            reading(24.0, 40.0)
+        );
+    }
+
+    #[test]
+    fn calibration_waits_for_enough_samples() {
+        let mut calibration = OffsetCalibration::new();
+        for _ in 1..CALIBRATION_SAMPLES {
+            assert_eq!(calibration.update(1.5), None);
+        }
+        assert_close(calibration.update(1.5).unwrap(), 1.5, 1e-4);
+        // Starts over after a correction
+        assert_eq!(calibration.update(1.5), None);
+    }
+
+    #[test]
+    fn calibration_ignores_small_errors_and_noise() {
+        let mut calibration = OffsetCalibration::new();
+        for i in 0..10 * CALIBRATION_SAMPLES {
+            let noise = if i % 2 == 0 { 0.8 } else { -0.6 };
+            assert_eq!(calibration.update(0.1 + noise), None);
+            assert_eq!(calibration.update(f32::NAN), None);
+        }
+    }
+
+    #[test]
+    fn offset_feedback_converges() {
+        // The SCD41 reads its die temperature less its offset, heated 2.5 °C
+        // above the room by its own measurements
+        let room = 21.0;
+        let self_heating = 2.5;
+        let mut sensor_offset: f32 = 0.5;
+        let mut calibration = OffsetCalibration::new();
+        let mut corrections = 0;
+
+        for _ in 0..10 * CALIBRATION_SAMPLES {
+            let scd41 = room + self_heating - sensor_offset;
+            calibrate(&mut calibration, scd41 - room);
+            // As the SCD41 task does between measurements
+            if let Some(correction) = SCD41_OFFSET_CORRECTION.try_take() {
+                sensor_offset = (sensor_offset + correction).clamp(0.0, 20.0);
+                corrections += 1;
+            }
+        }
+
+        assert_eq!(corrections, 1);
+        assert_close(sensor_offset, self_heating, CALIBRATION_MIN_ERROR);
+    }
+}
@@ -0,0 +1,982 @@
+//! Measurement history kept in a ring of flash sectors
+//!
+//! Each sector starts with a header and holds a sequence of records. Records
+//! are padded to the flash word size and end in a CRC, so one torn by a power
+//! loss ends the sector when it's read back. Sectors are used round robin,
+//! always erasing the oldest, which spreads wear evenly. Every boot starts a
+//! fresh sector, so nothing is ever appended after a torn write.
+//!
+//! Sample records hold the uptime and values as deltas from the previous
+//! record in the same sector, so each sector can be decoded on its own once
+//! older ones have been overwritten. The Unix time at boot is kept in sector
+//! headers, or in an anchor record once the clock is synchronised, and samples
+//! from a boot that never got one can't be dated.
+
+use defmt::{debug, error, info, warn, Format};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Ticker};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use heapless::{FnvIndexMap, Vec};
+use serde::Serialize;
+
+use crate::clock;
+use crate::config;
+use crate::sink;
+
+pub const ENABLED: bool = config::flag(option_env!("HISTORY_ENABLED"), true);
+/// Seconds between samples
+const INTERVAL: &str = match option_env!("HISTORY_INTERVAL") {
+    Some(interval) => interval,
+    None => "60",
+};
+
+/// Where the bootloader expects the partition table, and its largest size
+const PARTITION_TABLE: u32 = 0x8000;
+const PARTITION_TABLE_SIZE: u32 = 0xc00;
+const PARTITION_ENTRY_SIZE: usize = 32;
+const PARTITION_MAGIC: [u8; 2] = [0xaa, 0x50];
+const PARTITION_TYPE_DATA: u8 = 0x01;
+/// The log takes the whole data partition with this label
+const PARTITION_LABEL: &[u8] = b"history";
+
+/// The log, shared between the recorder and readers once opened
+pub type Shared<F> = Mutex<CriticalSectionRawMutex, Option<Log<F>>>;
+
+pub const SECTOR_SIZE: usize = 4096;
+const WORD_SIZE: usize = 4;
+const MAGIC: u32 = 0x3147_4f4c;
+const HEADER_SIZE: usize = 16;
+const UNKNOWN_TIME: u32 = u32::MAX;
+
+const KIND_SAMPLE: u8 = 0x01;
+const KIND_ANCHOR: u8 = 0x02;
+/// Kind, length and CRC around the payload
+const RECORD_OVERHEAD: usize = 3;
+/// Presence mask, uptime and four values, each a varint of up to 5 bytes
+const MAX_PAYLOAD: usize = 1 + 5 * 5;
+const MAX_RECORD: usize = (RECORD_OVERHEAD + MAX_PAYLOAD).next_multiple_of(WORD_SIZE);
+
+/// Fixed point scale of each field as stored
+const SCALES: [f32; 4] = [1.0, 100.0, 100.0, 10.0];
+
+/// Most boots with distinct boot times that can be dated in one read
+pub const MAX_BOOTS: usize = 32;
+
+#[derive(Debug, Format, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Sample {
+    /// ppm
+    pub co2: Option<u16>,
+    /// °C
+    pub temperature: Option<f32>,
+    /// %
+    pub humidity: Option<f32>,
+    /// hPa
+    pub pressure: Option<f32>,
+}
+
+impl Sample {
+    fn quantize(&self) -> [Option<i32>; 4] {
+        let values = [
+            self.co2.map(f32::from),
+            self.temperature,
+            self.humidity,
+            self.pressure,
+        ];
+        let mut quantized = [None; 4];
+        for ((quantized, value), scale) in quantized.iter_mut().zip(values).zip(SCALES) {
+            *quantized = value
+                .filter(|value| value.is_finite())
+                .map(|value| libm::roundf(value * scale) as i32);
+        }
+        quantized
+    }
+
+    fn from_quantized(values: [Option<i32>; 4]) -> Self {
+        let [co2, temperature, humidity, pressure] = values;
+        Sample {
+            co2: co2.map(|co2| co2.clamp(0, u16::MAX as i32) as u16),
+            temperature: temperature.map(|value| value as f32 / SCALES[1]),
+            humidity: humidity.map(|value| value as f32 / SCALES[2]),
+            pressure: pressure.map(|value| value as f32 / SCALES[3]),
+        }
+    }
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /// Increases by one for every sector started, the highest is the newest
+    pub sequence: u32,
+    /// Increases by one every boot
+    pub boot: u16,
+    /// Unix time at boot, if known when the sector was started
+    pub boot_time: Option<u32>,
+}
+
+impl Header {
+    /// Whether this sector was started after the one with `sequence`.
+    /// Sequence numbers only move forward, so they're compared by distance to
+    /// cope with them wrapping around.
+    pub fn is_newer_than(&self, sequence: u32) -> bool {
+        self.sequence.wrapping_sub(sequence) as i32 > 0
+    }
+
+    fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut header = [0xff; HEADER_SIZE];
+        header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&self.sequence.to_le_bytes());
+        header[8..12].copy_from_slice(&self.boot_time.unwrap_or(UNKNOWN_TIME).to_le_bytes());
+        header[12..14].copy_from_slice(&self.boot.to_le_bytes());
+        header[HEADER_SIZE - 1] = crc8(&header[..HEADER_SIZE - 1]);
+        header
+    }
+
+    /// Parse the header at the start of a sector, None if it's erased or
+    /// wasn't written completely
+    pub fn parse(sector: &[u8]) -> Option<Header> {
+        let header = sector.get(..HEADER_SIZE)?;
+        let word = |at: usize| {
+            u32::from_le_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]])
+        };
+        if word(0) != MAGIC || crc8(&header[..HEADER_SIZE - 1]) != header[HEADER_SIZE - 1] {
+            return None;
+        }
+        Some(Header {
+            sequence: word(4),
+            boot_time: Some(word(8)).filter(|&time| time != UNKNOWN_TIME),
+            boot: u16::from_le_bytes([header[12], header[13]]),
+        })
+    }
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq)]
+pub enum Record {
+    /// Values taken `uptime` seconds after boot
+    Sample { uptime: u32, sample: Sample },
+    /// Unix time at boot, learnt after the sector was started
+    Anchor { boot_time: u32 },
+}
+
+/// Decodes the records of one sector, stopping at the first erased or
+/// corrupt one
+pub struct SectorReader<'a> {
+    sector: &'a [u8],
+    position: usize,
+    uptime: u32,
+    previous: [i32; 4],
+}
+
+impl<'a> SectorReader<'a> {
+    /// Reader over the records of a sector, None if its header isn't valid
+    pub fn new(sector: &'a [u8]) -> Option<(Header, Self)> {
+        let header = Header::parse(sector)?;
+        Some((
+            header,
+            SectorReader {
+                sector,
+                position: HEADER_SIZE,
+                uptime: 0,
+                previous: [0; 4],
+            },
+        ))
+    }
+
+    fn decode_sample(&mut self, payload: &[u8]) -> Option<Record> {
+        let mut position = 0;
+        let present = *payload.first()?;
+        position += 1;
+        let uptime = self
+            .uptime
+            .wrapping_add(read_varint(payload, &mut position)?);
+
+        let mut values = [None; 4];
+        let mut previous = self.previous;
+        for (field, (value, previous)) in values.iter_mut().zip(previous.iter_mut()).enumerate() {
+            if present & (1 << field) != 0 {
+                *previous = previous.wrapping_add(unzigzag(read_varint(payload, &mut position)?));
+                *value = Some(*previous);
+            }
+        }
+        if position != payload.len() {
+            return None;
+        }
+
+        self.uptime = uptime;
+        self.previous = previous;
+        Some(Record::Sample {
+            uptime,
+            sample: Sample::from_quantized(values),
+        })
+    }
+}
+
+impl Iterator for SectorReader<'_> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        let remaining = self.sector.get(self.position..)?;
+        let (&kind, rest) = remaining.split_first()?;
+        let length = *rest.first()? as usize;
+        let record = remaining.get(..RECORD_OVERHEAD + length)?;
+        let (body, crc) = record.split_at(record.len() - 1);
+        if crc8(body) != crc[0] {
+            // Erased space or a torn write, either way the end of the sector
+            return None;
+        }
+        let payload = &body[2..];
+
+        let record = match kind {
+            KIND_SAMPLE => self.decode_sample(payload)?,
+            KIND_ANCHOR => Record::Anchor {
+                boot_time: u32::from_le_bytes(payload.try_into().ok()?),
+            },
+            _ => return None,
+        };
+        self.position += record_size(length);
+        Some(record)
+    }
+}
+
+fn record_size(payload_length: usize) -> usize {
+    (RECORD_OVERHEAD + payload_length).next_multiple_of(WORD_SIZE)
+}
+
+/// Unix time at boot of each boot found in sector headers and anchors
+#[derive(Debug, Default)]
+pub struct BootTimes(FnvIndexMap<u16, u32, MAX_BOOTS>);
+
+impl BootTimes {
+    /// Learn boot times from a whole sector
+    pub fn scan(&mut self, sector: &[u8]) {
+        let Some((header, records)) = SectorReader::new(sector) else {
+            return;
+        };
+        if let Some(boot_time) = header.boot_time {
+            self.insert(header.boot, boot_time);
+        }
+        for record in records {
+            if let Record::Anchor { boot_time } = record {
+                self.insert(header.boot, boot_time);
+            }
+        }
+    }
+
+    fn insert(&mut self, boot: u16, boot_time: u32) {
+        if self.0.insert(boot, boot_time).is_err() {
+            debug!("History: too many boots to date boot {}", boot);
+        }
+    }
+
+    pub fn get(&self, boot: u16) -> Option<u32> {
+        self.0.get(&boot).copied()
+    }
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// There's no history partition, or it isn't made of whole sectors
+    Partition,
+    Flash,
+}
+
+/// Append-only log in a region of NOR flash made of whole erase sectors
+pub struct Log<F> {
+    flash: F,
+    offset: u32,
+    sectors: u32,
+    /// Sector currently being written
+    sector: u32,
+    /// Write position within it
+    position: usize,
+    sequence: u32,
+    boot: u16,
+    /// Unix time at boot, once known
+    boot_time: Option<u32>,
+    /// Whether the current sector's boot time is recorded, in its header or
+    /// an anchor
+    dated: bool,
+    uptime: u32,
+    previous: [i32; 4],
+}
+
+impl<F: NorFlash> Log<F> {
+    /// Open the log in `size` bytes at `offset` and start a new sector for
+    /// this boot after the newest one found
+    pub fn open(
+        mut flash: F,
+        offset: u32,
+        size: u32,
+        boot_time: Option<u32>,
+    ) -> Result<Self, Error> {
+        let sector_size = SECTOR_SIZE as u32;
+        if offset % sector_size != 0
+            || size % sector_size != 0
+            || size / sector_size < 2
+            || SECTOR_SIZE % F::ERASE_SIZE != 0
+            || WORD_SIZE % F::WRITE_SIZE != 0
+        {
+            return Err(Error::Partition);
+        }
+        let sectors = size / sector_size;
+
+        let mut newest: Option<(u32, Header)> = None;
+        let mut boot = None;
+        for sector in 0..sectors {
+            let mut header = [0; HEADER_SIZE];
+            flash
+                .read(offset + sector * sector_size, &mut header)
+                .map_err(|_| Error::Flash)?;
+            let Some(header) = Header::parse(&header) else {
+                continue;
+            };
+            if newest.is_none_or(|(_, newest)| header.is_newer_than(newest.sequence)) {
+                newest = Some((sector, header));
+            }
+            if boot.is_none_or(|boot: u16| header.boot.wrapping_sub(boot) as i16 > 0) {
+                boot = Some(header.boot);
+            }
+        }
+
+        let (sector, sequence) = match newest {
+            Some((sector, header)) => ((sector + 1) % sectors, header.sequence.wrapping_add(1)),
+            None => (0, 0),
+        };
+        let mut log = Log {
+            flash,
+            offset,
+            sectors,
+            sector,
+            position: 0,
+            sequence,
+            boot: boot.map_or(0, |boot| boot.wrapping_add(1)),
+            boot_time,
+            dated: false,
+            uptime: 0,
+            previous: [0; 4],
+        };
+        log.start_sector(sector)?;
+        Ok(log)
+    }
+
+    fn sector_address(&self, sector: u32) -> u32 {
+        self.offset + sector * SECTOR_SIZE as u32
+    }
+
+    fn start_sector(&mut self, sector: u32) -> Result<(), Error> {
+        let address = self.sector_address(sector);
+        self.flash
+            .erase(address, address + SECTOR_SIZE as u32)
+            .map_err(|_| Error::Flash)?;
+        let header = Header {
+            sequence: self.sequence,
+            boot: self.boot,
+            boot_time: self.boot_time,
+        };
+        self.flash
+            .write(address, &header.encode())
+            .map_err(|_| Error::Flash)?;
+
+        self.sector = sector;
+        self.position = HEADER_SIZE;
+        self.sequence = self.sequence.wrapping_add(1);
+        self.dated = self.boot_time.is_some();
+        self.uptime = 0;
+        self.previous = [0; 4];
+        Ok(())
+    }
+
+    fn write_record(&mut self, kind: u8, payload: &[u8]) -> Result<(), Error> {
+        let size = record_size(payload.len());
+        if self.position + size > SECTOR_SIZE {
+            self.start_sector((self.sector + 1) % self.sectors)?;
+        }
+
+        let mut record: Vec<u8, MAX_RECORD> = Vec::new();
+        let _ = record.extend_from_slice(&[kind, payload.len() as u8]);
+        let _ = record.extend_from_slice(payload);
+        let _ = record.push(crc8(&record));
+        // Padding is left erased
+        let _ = record.resize(size, 0xff);
+
+        let address = self.sector_address(self.sector) + self.position as u32;
+        self.flash
+            .write(address, &record)
+            .map_err(|_| Error::Flash)?;
+        self.position += size;
+        Ok(())
+    }
+
+    /// Append a sample taken `uptime` seconds after boot
+    pub fn append(&mut self, uptime: u32, sample: &Sample) -> Result<(), Error> {
+        // Deltas are relative to the sector the record ends up in
+        if self.position + MAX_RECORD > SECTOR_SIZE {
+            self.start_sector((self.sector + 1) % self.sectors)?;
+        }
+
+        let values = sample.quantize();
+        let mut payload: Vec<u8, MAX_PAYLOAD> = Vec::new();
+        let present = values
+            .iter()
+            .enumerate()
+            .filter(|(_, value)| value.is_some())
+            .fold(0u8, |present, (field, _)| present | 1 << field);
+        let _ = payload.push(present);
+        write_varint(&mut payload, uptime.wrapping_sub(self.uptime));
+        let mut previous = self.previous;
+        for (value, previous) in values.iter().zip(previous.iter_mut()) {
+            if let Some(value) = *value {
+                write_varint(&mut payload, zigzag(value.wrapping_sub(*previous)));
+                *previous = value;
+            }
+        }
+
+        self.write_record(KIND_SAMPLE, &payload)?;
+        self.uptime = uptime;
+        self.previous = previous;
+        Ok(())
+    }
+
+    /// Set the Unix time at boot once the clock has been synchronised,
+    /// recording it in the current sector unless its header already has it
+    pub fn anchor(&mut self, boot_time: u32) -> Result<(), Error> {
+        self.boot_time = Some(boot_time);
+        if !self.dated {
+            self.write_record(KIND_ANCHOR, &boot_time.to_le_bytes())?;
+            self.dated = true;
+        }
+        Ok(())
+    }
+
+    /// Sequence number of the sector being written. Readers going through
+    /// [`Self::sectors`] skip sectors newer than this, which the recorder
+    /// has reused since.
+    pub fn newest_sequence(&self) -> u32 {
+        self.sequence.wrapping_sub(1)
+    }
+
+    /// Sector indices from the oldest to the one being written
+    pub fn sectors(&self) -> impl Iterator<Item = u32> + Clone + use<F> {
+        let (sectors, newest) = (self.sectors, self.sector);
+        (1..=sectors).map(move |i| (newest + i) % sectors)
+    }
+
+    pub fn read_sector(
+        &mut self,
+        sector: u32,
+        buffer: &mut [u8; SECTOR_SIZE],
+    ) -> Result<(), Error> {
+        let address = self.sector_address(sector);
+        self.flash.read(address, buffer).map_err(|_| Error::Flash)
+    }
+}
+
+/// Offset and size of the data partition labelled `label`, from the ESP-IDF
+/// partition table in `flash`
+pub fn find_partition<F: ReadNorFlash>(flash: &mut F, label: &[u8]) -> Result<(u32, u32), Error> {
+    let mut entry = [0; PARTITION_ENTRY_SIZE];
+    for offset in
+        (PARTITION_TABLE..PARTITION_TABLE + PARTITION_TABLE_SIZE).step_by(PARTITION_ENTRY_SIZE)
+    {
+        flash.read(offset, &mut entry).map_err(|_| Error::Flash)?;
+        // The table ends with an MD5 entry or erased flash
+        if entry[0..2] != PARTITION_MAGIC {
+            break;
+        }
+        let word = |at: usize| {
+            u32::from_le_bytes([entry[at], entry[at + 1], entry[at + 2], entry[at + 3]])
+        };
+        let name = &entry[12..28];
+        let name = &name[..name.iter().position(|&byte| byte == 0).unwrap_or(16)];
+        if entry[2] == PARTITION_TYPE_DATA && name == label {
+            return Ok((word(4), word(8)));
+        }
+    }
+    Err(Error::Partition)
+}
+
+fn write_varint<const N: usize>(buffer: &mut Vec<u8, N>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            let _ = buffer.push(byte);
+            return;
+        }
+        let _ = buffer.push(byte | 0x80);
+    }
+}
+
+fn read_varint(data: &[u8], position: &mut usize) -> Option<u32> {
+    let mut value = 0u32;
+    for shift in (0..35).step_by(7) {
+        let byte = *data.get(*position)?;
+        *position += 1;
+        value |= ((byte & 0x7f) as u32).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn zigzag(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
+
+fn unzigzag(value: u32) -> i32 {
+    (value >> 1) as i32 ^ -((value & 1) as i32)
+}
+
+/// CRC-8 with polynomial 0x07
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                crc << 1 ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Latest values from whichever sensors have reported, preferring the BME680
+/// for temperature and humidity as it heats itself less
+fn latest() -> Sample {
+    let scd41 = sink::LATEST.scd41();
+    let bme680 = sink::LATEST.bme680();
+    Sample {
+        co2: scd41.as_ref().map(|m| m.co2),
+        temperature: bme680
+            .as_ref()
+            .map(|m| m.temperature)
+            .or(scd41.as_ref().map(|m| m.temperature)),
+        humidity: bme680
+            .as_ref()
+            .map(|m| m.humidity)
+            .or(scd41.as_ref().map(|m| m.humidity)),
+        pressure: bme680.as_ref().map(|m| m.pressure),
+    }
+}
+
+/// Open the log in `flash` and append a sample every interval
+pub async fn recorder<F: NorFlash>(shared: &Shared<F>, mut flash: F) {
+    let (offset, size) = match find_partition(&mut flash, PARTITION_LABEL) {
+        Ok(partition) => partition,
+        Err(e) => {
+            error!("History: failed to find the history partition: {:?}", e);
+            return;
+        }
+    };
+    let log = match Log::open(flash, offset, size, clock::boot_time()) {
+        Ok(log) => log,
+        Err(e) => {
+            error!("History: failed to open log at {:#x}: {:?}", offset, e);
+            return;
+        }
+    };
+    info!(
+        "History: logging to {} sectors at {:#x}, boot {}",
+        log.sectors, offset, log.boot
+    );
+    shared.lock().await.replace(log);
+
+    let mut ticker = Ticker::every(Duration::from_secs(INTERVAL.parse().unwrap_or(60)));
+    loop {
+        ticker.next().await;
+
+        let sample = latest();
+        if sample == Sample::default() {
+            continue;
+        }
+
+        let mut log = shared.lock().await;
+        let Some(log) = log.as_mut() else {
+            continue;
+        };
+        if let Some(boot_time) = clock::boot_time() {
+            if let Err(e) = log.anchor(boot_time) {
+                warn!("History: failed to write boot time: {:?}", e);
+            }
+        }
+        match log.append(clock::uptime(), &sample) {
+            Ok(()) => debug!("History: logged {:?}", sample),
+            Err(e) => warn!("History: failed to log sample: {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_storage::nor_flash::{ErrorType, NorFlashErrorKind};
+
+    use super::*;
+
+    const OFFSET: u32 = 0x30_0000;
+    const SECTORS: u32 = 3;
+    const SIZE: u32 = SECTORS * SECTOR_SIZE as u32;
+
+    /// NOR flash in RAM: erasing sets whole sectors to 0xff and writing can
+    /// only clear bits. Once `write_budget` bytes are used up a write stops
+    /// part way through and fails, like one cut short by a power loss.
+    struct SimFlash {
+        data: std::vec::Vec<u8>,
+        write_budget: Option<usize>,
+    }
+
+    impl SimFlash {
+        fn new() -> Self {
+            SimFlash {
+                data: vec![0xff; (OFFSET + SIZE) as usize],
+                write_budget: None,
+            }
+        }
+
+        fn sector(&self, sector: u32) -> [u8; SECTOR_SIZE] {
+            let start = (OFFSET + sector * SECTOR_SIZE as u32) as usize;
+            self.data[start..start + SECTOR_SIZE].try_into().unwrap()
+        }
+    }
+
+    impl ErrorType for SimFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for SimFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), NorFlashErrorKind> {
+            let data = self
+                .data
+                .get(offset as usize..offset as usize + bytes.len())
+                .ok_or(NorFlashErrorKind::OutOfBounds)?;
+            bytes.copy_from_slice(data);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+    }
+
+    impl NorFlash for SimFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = SECTOR_SIZE;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), NorFlashErrorKind> {
+            if from as usize % SECTOR_SIZE != 0 || to as usize % SECTOR_SIZE != 0 {
+                return Err(NorFlashErrorKind::NotAligned);
+            }
+            self.data[from as usize..to as usize].fill(0xff);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), NorFlashErrorKind> {
+            if offset as usize % Self::WRITE_SIZE != 0 || bytes.len() % Self::WRITE_SIZE != 0 {
+                return Err(NorFlashErrorKind::NotAligned);
+            }
+            let allowed = self
+                .write_budget
+                .map_or(bytes.len(), |budget| budget.min(bytes.len()));
+            for (target, &byte) in self.data[offset as usize..]
+                .iter_mut()
+                .zip(&bytes[..allowed])
+            {
+                *target &= byte;
+            }
+            if let Some(budget) = self.write_budget.as_mut() {
+                *budget -= allowed;
+                if allowed < bytes.len() {
+                    return Err(NorFlashErrorKind::Other);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn sample(i: u32) -> Sample {
+        Sample {
+            co2: Some(400 + (i % 50) as u16 * 7),
+            temperature: Some(20.0 + (i % 10) as f32 * 0.25),
+            humidity: (i % 3 != 0).then_some(45.5),
+            pressure: Some(1013.2),
+        }
+    }
+
+    fn open(flash: SimFlash, boot_time: Option<u32>) -> Log<SimFlash> {
+        Log::open(flash, OFFSET, SIZE, boot_time).unwrap()
+    }
+
+    /// Headers and samples of every readable sector, oldest first
+    fn read_all(log: &mut Log<SimFlash>) -> std::vec::Vec<(Header, std::vec::Vec<Record>)> {
+        let mut buffer = [0; SECTOR_SIZE];
+        let mut sectors = vec![];
+        for sector in log.sectors() {
+            log.read_sector(sector, &mut buffer).unwrap();
+            if let Some((header, records)) = SectorReader::new(&buffer) {
+                sectors.push((header, records.collect()));
+            }
+        }
+        sectors
+    }
+
+    fn samples(records: &[Record]) -> std::vec::Vec<(u32, Sample)> {
+        records
+            .iter()
+            .filter_map(|record| match *record {
+                Record::Sample { uptime, sample } => Some((uptime, sample)),
+                Record::Anchor { .. } => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn samples_read_back() {
+        let mut log = open(SimFlash::new(), None);
+        for i in 0..20 {
+            log.append(i * 60, &sample(i)).unwrap();
+        }
+        log.anchor(1_700_000_000).unwrap();
+        log.append(1200, &Sample::default()).unwrap();
+
+        let sectors = read_all(&mut log);
+        assert_eq!(sectors.len(), 1);
+        let (header, records) = &sectors[0];
+        assert_eq!(
+            (header.sequence, header.boot, header.boot_time),
+            (0, 0, None)
+        );
+        let expected: std::vec::Vec<_> = (0..20).map(|i| (i * 60, sample(i))).collect();
+        assert_eq!(samples(records)[..20], expected);
+        assert_eq!(
+            records[20],
+            Record::Anchor {
+                boot_time: 1_700_000_000
+            }
+        );
+        assert_eq!(
+            records[21],
+            Record::Sample {
+                uptime: 1200,
+                sample: Sample::default()
+            }
+        );
+    }
+
+    #[test]
+    fn torn_record_ends_sector() {
+        let mut log = open(SimFlash::new(), Some(1_700_000_000));
+        for i in 0..5 {
+            log.append(i, &sample(i)).unwrap();
+        }
+        // Power fails two words into the next record
+        log.flash.write_budget = Some(8);
+        assert_eq!(log.append(5, &sample(5)), Err(Error::Flash));
+
+        let mut flash = log.flash;
+        flash.write_budget = None;
+        let sector = flash.sector(0);
+        let (header, records) = SectorReader::new(&sector).unwrap();
+        assert_eq!(header.boot_time, Some(1_700_000_000));
+        let expected: std::vec::Vec<_> = (0..5).map(|i| (i, sample(i))).collect();
+        assert_eq!(samples(&records.collect::<std::vec::Vec<_>>()), expected);
+
+        // The next boot starts after the torn sector rather than appending
+        let mut log = open(flash, None);
+        log.append(0, &sample(9)).unwrap();
+        let sectors = read_all(&mut log);
+        assert_eq!(sectors.len(), 2);
+        assert_eq!(samples(&sectors[0].1).len(), 5);
+        assert_eq!((sectors[1].0.sequence, sectors[1].0.boot), (1, 1));
+        assert_eq!(samples(&sectors[1].1), [(0, sample(9))]);
+    }
+
+    #[test]
+    fn torn_header_is_ignored() {
+        let mut flash = SimFlash::new();
+        let header = Header {
+            sequence: 7,
+            boot: 3,
+            boot_time: None,
+        };
+        // Power failed while the header of sector 1 was being written
+        flash
+            .write(OFFSET + SECTOR_SIZE as u32, &header.encode()[..8])
+            .unwrap();
+
+        let mut log = open(flash, None);
+        assert_eq!((log.sector, log.newest_sequence(), log.boot), (0, 0, 0));
+        assert_eq!(read_all(&mut log).len(), 1);
+    }
+
+    #[test]
+    fn wraps_over_oldest_sector() {
+        let mut log = open(SimFlash::new(), None);
+        // Enough for a little over four sectors
+        let mut uptime = 0;
+        while log.newest_sequence() < SECTORS + 1 {
+            log.append(uptime, &sample(uptime)).unwrap();
+            uptime += 1;
+        }
+        log.append(uptime, &sample(uptime)).unwrap();
+
+        let sectors = read_all(&mut log);
+        let sequences: std::vec::Vec<u32> =
+            sectors.iter().map(|(header, _)| header.sequence).collect();
+        assert_eq!(sequences, [2, 3, 4]);
+        // Every sector decodes on its own, and they follow on from each other
+        let uptimes: std::vec::Vec<u32> = sectors
+            .iter()
+            .flat_map(|(_, records)| samples(records))
+            .map(|(uptime, sample)| {
+                assert_eq!(sample, self::sample(uptime));
+                uptime
+            })
+            .collect();
+        assert!(uptimes.windows(2).all(|pair| pair[1] == pair[0] + 1));
+        assert_eq!(uptimes.last(), Some(&uptime));
+        assert!(uptimes[0] > 0, "the oldest sector should have been reused");
+    }
+
+    #[test]
+    fn recovers_head_on_boot() {
+        let mut log = open(SimFlash::new(), None);
+        let mut uptime = 0;
+        while log.newest_sequence() < 4 {
+            log.append(uptime, &sample(uptime)).unwrap();
+            uptime += 1;
+        }
+        // Sequence 4 went into sector 1
+        assert_eq!(log.sector, 1);
+
+        let mut log = open(log.flash, None);
+        assert_eq!(log.sector, 2);
+        assert_eq!(log.newest_sequence(), 5);
+        assert_eq!(log.boot, 1);
+        let sectors = read_all(&mut log);
+        assert_eq!(sectors.last().unwrap().0.sequence, 5);
+        assert!(sectors.last().unwrap().1.is_empty());
+    }
+
+    #[test]
+    fn recovers_head_across_sequence_wrap() {
+        let mut flash = SimFlash::new();
+        for (sector, sequence, boot) in [
+            (0, u32::MAX - 1, u16::MAX),
+            (1, u32::MAX, u16::MAX),
+            (2, 0, 0),
+        ] {
+            let header = Header {
+                sequence,
+                boot,
+                boot_time: None,
+            };
+            let address = OFFSET + sector * SECTOR_SIZE as u32;
+            flash.write(address, &header.encode()).unwrap();
+        }
+        let log = open(flash, None);
+        // Sector 2 has the newest sequence, so the oldest, sector 0, is reused
+        assert_eq!(log.sector, 0);
+        assert_eq!(log.newest_sequence(), 1);
+        assert_eq!(log.boot, 1);
+    }
+
+    #[test]
+    fn newer_sectors_are_recognised() {
+        let header = |sequence| Header {
+            sequence,
+            boot: 0,
+            boot_time: None,
+        };
+        assert!(header(5).is_newer_than(4));
+        assert!(!header(4).is_newer_than(4));
+        assert!(!header(3).is_newer_than(4));
+        assert!(header(1).is_newer_than(u32::MAX));
+    }
+
+    #[test]
+    fn rejects_partial_sectors() {
+        for (offset, size) in [
+            (OFFSET + 1, SIZE),
+            (OFFSET, SIZE - 1),
+            (OFFSET, SECTOR_SIZE as u32),
+        ] {
+            assert_eq!(
+                Log::open(SimFlash::new(), offset, size, None).err(),
+                Some(Error::Partition)
+            );
+        }
+    }
+
+    fn partition_entry(kind: u8, subtype: u8, offset: u32, size: u32, label: &str) -> [u8; 32] {
+        let mut entry = [0; PARTITION_ENTRY_SIZE];
+        entry[0..2].copy_from_slice(&PARTITION_MAGIC);
+        entry[2] = kind;
+        entry[3] = subtype;
+        entry[4..8].copy_from_slice(&offset.to_le_bytes());
+        entry[8..12].copy_from_slice(&size.to_le_bytes());
+        entry[12..12 + label.len()].copy_from_slice(label.as_bytes());
+        entry
+    }
+
+    /// Flash with a partition table of `entries`
+    fn with_partition_table(entries: &[[u8; 32]]) -> SimFlash {
+        let mut flash = SimFlash::new();
+        let mut table = entries.concat();
+        // MD5 entry
+        table.extend_from_slice(&[0xeb; 32]);
+        flash.write(PARTITION_TABLE, &table).unwrap();
+        flash
+    }
+
+    #[test]
+    fn finds_history_partition() {
+        let mut flash = with_partition_table(&[
+            partition_entry(0x01, 0x02, 0x9000, 0x5000, "nvs"),
+            partition_entry(0x01, 0x41, 0xe000, 0x1000, "wifi"),
+            partition_entry(0x01, 0x01, 0xf000, 0x1000, "phy_init"),
+            partition_entry(0x00, 0x00, 0x10000, 0x2f0000, "factory"),
+            partition_entry(0x01, 0x40, 0x300000, 0x100000, "history"),
+        ]);
+        assert_eq!(
+            find_partition(&mut flash, PARTITION_LABEL),
+            Ok((0x300000, 0x100000))
+        );
+        assert_eq!(find_partition(&mut flash, b"wifi"), Ok((0xe000, 0x1000)));
+    }
+
+    #[test]
+    fn missing_history_partition() {
+        let mut flash = with_partition_table(&[
+            partition_entry(0x01, 0x02, 0x9000, 0x6000, "nvs"),
+            // An app partition of the same name doesn't count
+            partition_entry(0x00, 0x10, 0x10000, 0x100000, "history"),
+            partition_entry(0x01, 0x40, 0x110000, 0x1000, "history2"),
+        ]);
+        assert_eq!(
+            find_partition(&mut flash, PARTITION_LABEL),
+            Err(Error::Partition)
+        );
+        assert_eq!(
+            find_partition(&mut SimFlash::new(), PARTITION_LABEL),
+            Err(Error::Partition)
+        );
+    }
+
+    #[test]
+    fn varints_and_zigzag_round_trip() {
+        for value in [0, 1, 127, 128, 300, u32::MAX] {
+            let mut buffer: Vec<u8, 5> = Vec::new();
+            write_varint(&mut buffer, value);
+            let mut position = 0;
+            assert_eq!(read_varint(&buffer, &mut position), Some(value));
+            assert_eq!(position, buffer.len());
+        }
+        for value in [0, -1, 1, i32::MIN, i32::MAX] {
+            assert_eq!(unzigzag(zigzag(value)), value);
+        }
+        assert_eq!(read_varint(&[0x80, 0x80], &mut 0), None);
+    }
+}
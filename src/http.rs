@@ -0,0 +1,401 @@
+//! Minimal HTTP/1.1 server for reading data off the device, one connection at
+//! a time
+
+use core::fmt::Write as _;
+
+use defmt::{debug, info, warn, Format};
+use embassy_net::{tcp::TcpSocket, Stack};
+use embassy_time::Duration;
+use embedded_io_async::Write;
+use embedded_storage::nor_flash::NorFlash;
+use heapless::{String, Vec};
+use serde::Serialize;
+
+use crate::export::{CsvWriter, Query};
+use crate::history::{self, BootTimes, Record, SectorReader, SECTOR_SIZE};
+use crate::payload::{self, MAX_RECORDS};
+use crate::sink::{self, Reading};
+
+const PORT: u16 = 80;
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest request head accepted, anything larger is rejected
+const MAX_REQUEST: usize = 1024;
+/// Longest `/metrics` response body, enough for every field of both sensors
+const MAX_METRICS: usize = 3072;
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub struct Request<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub query: &'a str,
+}
+
+impl<'a> Request<'a> {
+    /// Parse the request line of a request head
+    pub fn parse(head: &'a [u8]) -> Option<Request<'a>> {
+        let head = core::str::from_utf8(head).ok()?;
+        let line = head.split("\r\n").next()?;
+        let mut parts = line.split(' ');
+        let method = parts.next()?;
+        let target = parts.next()?;
+        if !parts.next()?.starts_with("HTTP/1.") {
+            return None;
+        }
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        Some(Request {
+            method,
+            path,
+            query,
+        })
+    }
+}
+
+/// Read a request head into `buffer`, returning its length
+async fn read_head(socket: &mut TcpSocket<'_>, buffer: &mut [u8]) -> Option<usize> {
+    let mut length = 0;
+    while !buffer[..length]
+        .windows(4)
+        .any(|window| window == b"\r\n\r\n")
+    {
+        if length == buffer.len() {
+            return None;
+        }
+        match socket.read(&mut buffer[length..]).await {
+            Ok(0) | Err(_) => return None,
+            Ok(read) => length += read,
+        }
+    }
+    Some(length)
+}
+
+async fn respond(
+    socket: &mut TcpSocket<'_>,
+    status: &str,
+    content_type: &str,
+) -> Result<(), embassy_net::tcp::Error> {
+    for part in [
+        "HTTP/1.1 ",
+        status,
+        "\r\nContent-Type: ",
+        content_type,
+        "\r\nConnection: close\r\n\r\n",
+    ] {
+        socket.write_all(part.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Readings in the Prometheus text format, a gauge `air_<field>` per field
+/// with a sample for each sensor that measures it. None if they don't fit.
+pub fn metrics<const N: usize>(readings: &[Reading]) -> Option<String<N>> {
+    let mut samples: Vec<_, { 2 * MAX_RECORDS }> = Vec::new();
+    for reading in readings {
+        let (sensor, records) = payload::records(reading);
+        for record in records {
+            samples.push((sensor, record)).ok()?;
+        }
+    }
+    let mut out = String::new();
+    for (index, (_, record)) in samples.iter().enumerate() {
+        // All samples of a metric go together under its TYPE line
+        if samples[..index]
+            .iter()
+            .any(|(_, earlier)| earlier.name == record.name)
+        {
+            continue;
+        }
+        writeln!(out, "# TYPE air_{} gauge", record.name).ok()?;
+        for (sensor, sample) in samples
+            .iter()
+            .filter(|(_, sample)| sample.name == record.name)
+        {
+            writeln!(
+                out,
+                "air_{}{{sensor=\"{}\"}} {}",
+                record.name, sensor, sample.value
+            )
+            .ok()?;
+        }
+    }
+    Some(out)
+}
+
+/// Serve the latest readings for Prometheus to scrape
+async fn latest_metrics(socket: &mut TcpSocket<'_>) -> Result<(), embassy_net::tcp::Error> {
+    let readings: Vec<Reading, 2> = [
+        sink::LATEST.scd41().map(Reading::Scd41),
+        sink::LATEST.bme680().map(Reading::Bme680),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    match metrics::<MAX_METRICS>(&readings) {
+        Some(body) => {
+            respond(socket, "200 OK", "text/plain; version=0.0.4").await?;
+            socket.write_all(body.as_bytes()).await
+        }
+        None => {
+            warn!("HTTP: metrics don't fit {} bytes", MAX_METRICS);
+            respond(socket, "500 Internal Server Error", "text/plain").await
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Row {
+    /// Unix time in seconds
+    time: u32,
+    co2: Option<u16>,
+    temperature: Option<f32>,
+    humidity: Option<f32>,
+    pressure: Option<f32>,
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+enum Export {
+    /// Newline-delimited JSON with all fields
+    Json,
+    /// CSV with the requested fields
+    Csv,
+}
+
+/// Stream dated samples in the queried range, oldest first
+async fn history<F: NorFlash>(
+    socket: &mut TcpSocket<'_>,
+    log: &history::Shared<F>,
+    request: &Request<'_>,
+    export: Export,
+    sector: &mut [u8; SECTOR_SIZE],
+) -> Result<(), embassy_net::tcp::Error> {
+    let query = match Query::parse(request.query) {
+        Ok(query) => query,
+        Err(e) => {
+            debug!("HTTP: invalid history query: {:?}", e);
+            return respond(socket, "400 Bad Request", "text/plain").await;
+        }
+    };
+    let csv = CsvWriter::new(&query.fields);
+
+    // Sectors are copied out one at a time so the recorder isn't held up
+    // while rows are sent. It can move on to reuse the oldest sectors in the
+    // meantime, so sectors started after this snapshot are skipped.
+    let snapshot = log
+        .lock()
+        .await
+        .as_ref()
+        .map(|log| (log.sectors(), log.newest_sequence()));
+    let Some((sectors, newest)) = snapshot else {
+        return respond(socket, "503 Service Unavailable", "text/plain").await;
+    };
+
+    let read = async |index: u32, sector: &mut [u8; SECTOR_SIZE]| {
+        let mut log = log.lock().await;
+        log.as_mut()
+            .is_some_and(|log| log.read_sector(index, sector).is_ok())
+    };
+
+    let mut boot_times = BootTimes::default();
+    for index in sectors.clone() {
+        if read(index, sector).await {
+            boot_times.scan(sector);
+        }
+    }
+
+    match export {
+        Export::Json => respond(socket, "200 OK", "application/x-ndjson").await?,
+        Export::Csv => {
+            respond(socket, "200 OK", "text/csv; charset=utf-8").await?;
+            socket.write_all(csv.header().as_bytes()).await?;
+        }
+    }
+    let mut undated = 0u32;
+    for index in sectors.clone() {
+        if !read(index, sector).await {
+            continue;
+        }
+        let Some((header, records)) = SectorReader::new(sector) else {
+            continue;
+        };
+        if header.is_newer_than(newest) {
+            continue;
+        }
+        let boot_time = boot_times.get(header.boot);
+        for record in records {
+            let Record::Sample { uptime, sample } = record else {
+                continue;
+            };
+            let Some(boot_time) = boot_time else {
+                undated += 1;
+                continue;
+            };
+            let time = boot_time.saturating_add(uptime);
+            if !(query.from..=query.to).contains(&time) {
+                continue;
+            }
+
+            match export {
+                Export::Json => {
+                    let mut line = [0u8; 160];
+                    let row = Row {
+                        time,
+                        co2: sample.co2,
+                        temperature: sample.temperature,
+                        humidity: sample.humidity,
+                        pressure: sample.pressure,
+                    };
+                    if let Ok(size) = serde_json_core::to_slice(&row, &mut line) {
+                        socket.write_all(&line[..size]).await?;
+                        socket.write_all(b"\n").await?;
+                    }
+                }
+                Export::Csv => socket.write_all(csv.row(time, &sample).as_bytes()).await?,
+            }
+        }
+    }
+    if undated > 0 {
+        debug!("HTTP: skipped {} samples without a known time", undated);
+    }
+    Ok(())
+}
+
+/// Serve requests, reading history from `log`
+pub async fn server<F: NorFlash>(stack: Stack<'static>, log: &history::Shared<F>) -> ! {
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 2048];
+    let mut head = [0; MAX_REQUEST];
+    let mut sector = [0; SECTOR_SIZE];
+    info!("HTTP: listening on port {}", PORT);
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(TIMEOUT));
+        if let Err(e) = socket.accept(PORT).await {
+            warn!("HTTP: accept error: {:?}", e);
+            continue;
+        }
+
+        let result = match read_head(&mut socket, &mut head).await {
+            Some(length) => match Request::parse(&head[..length]) {
+                Some(request) => {
+                    debug!("HTTP: {} {}", request.method, request.path);
+                    match (request.method, request.path) {
+                        ("GET", "/history") => {
+                            history(&mut socket, log, &request, Export::Json, &mut sector).await
+                        }
+                        ("GET", "/history.csv") => {
+                            history(&mut socket, log, &request, Export::Csv, &mut sector).await
+                        }
+                        ("GET", "/metrics") => latest_metrics(&mut socket).await,
+                        ("GET", _) => respond(&mut socket, "404 Not Found", "text/plain").await,
+                        _ => respond(&mut socket, "405 Method Not Allowed", "text/plain").await,
+                    }
+                }
+                None => respond(&mut socket, "400 Bad Request", "text/plain").await,
+            },
+            None => respond(&mut socket, "400 Bad Request", "text/plain").await,
+        };
+        if let Err(e) = result {
+            warn!("HTTP: error writing response: {:?}", e);
+        }
+
+        socket.close();
+        let _ = socket.flush().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bme680::Bme680Measurement;
+    use crate::psychrometrics::Psychrometrics;
+    use crate::scd41::Scd41Measurement;
+
+    use super::*;
+
+    #[test]
+    fn parses_request_lines() {
+        let request = Request::parse(b"GET /history.csv?from=5 HTTP/1.1\r\nHost: air\r\n").unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/history.csv");
+        assert_eq!(request.query, "from=5");
+        assert_eq!(
+            Request::parse(b"GET /metrics HTTP/1.1\r\n").unwrap().query,
+            ""
+        );
+        assert_eq!(Request::parse(b"GET\r\n"), None);
+    }
+
+    #[test]
+    fn metrics_group_samples_by_field() {
+        let mut scd41 = Scd41Measurement::default();
+        scd41.co2 = 800;
+        scd41.temperature = 21.5;
+        scd41.humidity = 40.0;
+        scd41.derived.humidex = f32::NAN;
+        let bme680 = Bme680Measurement {
+            temperature: 22.0,
+            pressure: 1013.25,
+            derived: Psychrometrics {
+                humidex: f32::NAN,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let readings = [Reading::Scd41(scd41), Reading::Bme680(bme680)];
+        let body: String<MAX_METRICS> = metrics(&readings).unwrap();
+        let lines: std::vec::Vec<_> = body.lines().collect();
+        assert_eq!(
+            lines[..9],
+            [
+                "# TYPE air_co2 gauge",
+                "air_co2{sensor=\"scd41\"} 800",
+                "# TYPE air_temperature gauge",
+                "air_temperature{sensor=\"scd41\"} 21.5",
+                "air_temperature{sensor=\"bme680\"} 22",
+                "# TYPE air_humidity gauge",
+                "air_humidity{sensor=\"scd41\"} 40",
+                "air_humidity{sensor=\"bme680\"} 0",
+                "# TYPE air_dew_point gauge",
+            ]
+        );
+        assert!(body.contains("\nair_pressure{sensor=\"bme680\"} 1013.25\n"));
+        assert!(body.contains("# TYPE air_self_heating gauge\n"));
+        // Values that aren't finite are left out
+        assert!(!body.contains("humidex"));
+        assert_eq!(
+            lines
+                .iter()
+                .filter(|line| line.starts_with("# TYPE"))
+                .count(),
+            9
+        );
+        assert_eq!(metrics::<0>(&[]).unwrap(), "");
+        assert_eq!(metrics::<64>(&readings), None);
+    }
+
+    #[test]
+    fn longest_metrics_fit() {
+        let derived = Psychrometrics {
+            dew_point: -123.456_79,
+            absolute_humidity: -123.456_79,
+            humidity_ratio: -0.000_123_456_79,
+            heat_index: -123.456_79,
+            humidex: -123.456_79,
+        };
+        let mut scd41 = Scd41Measurement::default();
+        scd41.co2 = u16::MAX;
+        scd41.temperature = -123.456_79;
+        scd41.humidity = -123.456_79;
+        scd41.derived = derived;
+        let bme680 = Bme680Measurement {
+            temperature: -123.456_79,
+            self_heating: -123.456_79,
+            humidity: -123.456_79,
+            pressure: -123_456.79,
+            sea_level_pressure: Some(-123_456.79),
+            gas_resistance: Some(-123_456_790.0),
+            derived,
+        };
+        let readings = [Reading::Scd41(scd41), Reading::Bme680(bme680)];
+        assert!(metrics::<MAX_METRICS>(&readings).is_some());
+    }
+}
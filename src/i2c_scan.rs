@@ -0,0 +1,271 @@
+use defmt::{debug, info, warn, Format};
+use embassy_time::Timer;
+use embedded_hal_async::i2c::I2c;
+use serde::Serialize;
+
+pub const SCD41_ADDRESS: u8 = 0x62;
+pub const BME680_ADDRESSES: [u8; 2] = [0x76, 0x77];
+pub const SSD1306_ADDRESSES: [u8; 2] = [0x3c, 0x3d];
+
+const SCD41_CMD_WAKE_UP: [u8; 2] = [0x36, 0xf6];
+const SCD41_CMD_STOP_PERIODIC_MEASUREMENT: [u8; 2] = [0x3f, 0x86];
+const SCD41_CMD_GET_SERIAL_NUMBER: [u8; 2] = [0x36, 0x82];
+
+const BME680_REG_CHIP_ID: u8 = 0xd0;
+const BME680_CHIP_ID: u8 = 0x61;
+
+/// Sensors found on the bus at boot
+#[derive(Debug, Format, Clone, Copy, Default, Serialize)]
+pub struct Inventory {
+    /// Serial number of the SCD41, None if not present
+    pub scd41: Option<u64>,
+    /// I2C address of the BME680, None if not present
+    pub bme680: Option<u8>,
+    /// I2C address of the SSD1306 display, None if not present
+    pub ssd1306: Option<u8>,
+}
+
+/// Probe all known sensor addresses and identify the chips found there.
+///
+/// Devices that ACK but don't return the expected ID are not reported, so a
+/// different chip sharing an address won't get a sensor task spawned for it.
+pub async fn scan<I: I2c>(i2c: &mut I) -> Inventory {
+    let mut inventory = Inventory::default();
+
+    match probe_scd41(i2c).await {
+        Some(serial_number) => {
+            info!(
+                "I2C scan: found SCD41 with serial number {:04x}",
+                serial_number
+            );
+            inventory.scd41 = Some(serial_number);
+        }
+        None => warn!("I2C scan: no SCD41 at {:#04x}", SCD41_ADDRESS),
+    }
+
+    for address in BME680_ADDRESSES {
+        if probe_bme680(i2c, address).await {
+            info!("I2C scan: found BME680 at {:#04x}", address);
+            inventory.bme680 = Some(address);
+            break;
+        }
+    }
+    if inventory.bme680.is_none() {
+        warn!("I2C scan: no BME680 found");
+    }
+
+    for address in SSD1306_ADDRESSES {
+        if probe_ssd1306(i2c, address).await {
+            info!("I2C scan: found SSD1306 at {:#04x}", address);
+            inventory.ssd1306 = Some(address);
+            break;
+        }
+    }
+
+    inventory
+}
+
+/// Read the SCD41 serial number, returning None if the device is absent or
+/// the response fails its CRC check.
+async fn probe_scd41<I: I2c>(i2c: &mut I) -> Option<u64> {
+    // Sensor does not acknowledge wake-up, and it may still be measuring if
+    // only the MCU was reset, in which case it ignores the serial number command
+    let _ = i2c.write(SCD41_ADDRESS, &SCD41_CMD_WAKE_UP).await;
+    Timer::after_millis(30).await;
+    i2c.write(SCD41_ADDRESS, &SCD41_CMD_STOP_PERIODIC_MEASUREMENT)
+        .await
+        .ok()?;
+    Timer::after_millis(500).await;
+
+    i2c.write(SCD41_ADDRESS, &SCD41_CMD_GET_SERIAL_NUMBER)
+        .await
+        .ok()?;
+    Timer::after_millis(1).await;
+    let mut response = [0u8; 9];
+    i2c.read(SCD41_ADDRESS, &mut response).await.ok()?;
+
+    let mut serial_number = 0u64;
+    for word in response.chunks_exact(3) {
+        if sensirion_crc8(&word[..2]) != word[2] {
+            debug!("I2C scan: SCD41 serial number CRC mismatch");
+            return None;
+        }
+        serial_number = serial_number << 16 | u16::from_be_bytes([word[0], word[1]]) as u64;
+    }
+    Some(serial_number)
+}
+
+/// Check the chip ID register of a BME680 candidate address
+async fn probe_bme680<I: I2c>(i2c: &mut I, address: u8) -> bool {
+    let mut chip_id = [0u8; 1];
+    match i2c
+        .write_read(address, &[BME680_REG_CHIP_ID], &mut chip_id)
+        .await
+    {
+        Ok(()) if chip_id[0] == BME680_CHIP_ID => true,
+        Ok(()) => {
+            debug!(
+                "I2C scan: unexpected chip ID {:#04x} at {:#04x}",
+                chip_id[0], address
+            );
+            false
+        }
+        Err(_) => false,
+    }
+}
+
+/// The SSD1306 has no ID register, so an ACK on its status byte read is the
+/// best we can do
+async fn probe_ssd1306<I: I2c>(i2c: &mut I, address: u8) -> bool {
+    let mut status = [0u8; 1];
+    i2c.read(address, &mut status).await.is_ok()
+}
+
+/// CRC-8 used by Sensirion sensors (polynomial 0x31, init 0xff)
+fn sensirion_crc8(data: &[u8]) -> u8 {
+    let mut crc = 0xffu8;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                crc << 1 ^ 0x31
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use embedded_hal_async::i2c::{ErrorKind, ErrorType, NoAcknowledgeSource, Operation};
+
+    use super::*;
+
+    /// Bus with a set of devices that answer like the real chips
+    #[derive(Default)]
+    struct MockBus {
+        /// Response to the SCD41 serial number command
+        scd41: Option<[u8; 9]>,
+        /// Chip ID register of devices at the BME680 addresses
+        chip_ids: Vec<(u8, u8)>,
+        /// Addresses acknowledging reads without an ID, like the SSD1306
+        displays: Vec<u8>,
+    }
+
+    impl ErrorType for MockBus {
+        type Error = ErrorKind;
+    }
+
+    impl I2c for MockBus {
+        async fn transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> Result<(), Self::Error> {
+            let nack = ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address);
+            for operation in operations {
+                match (address, operation) {
+                    (SCD41_ADDRESS, Operation::Write(_)) if self.scd41.is_some() => {}
+                    (SCD41_ADDRESS, Operation::Read(buffer)) => {
+                        buffer.copy_from_slice(&self.scd41.ok_or(nack)?)
+                    }
+                    (address, Operation::Write(&[BME680_REG_CHIP_ID])) => {
+                        self.chip_id(address).ok_or(nack)?;
+                    }
+                    (address, Operation::Read(buffer)) => match self.chip_id(address) {
+                        Some(chip_id) => buffer[0] = chip_id,
+                        None if self.displays.contains(&address) => buffer[0] = 0x43,
+                        None => return Err(nack),
+                    },
+                    _ => return Err(nack),
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl MockBus {
+        fn chip_id(&self, address: u8) -> Option<u8> {
+            self.chip_ids
+                .iter()
+                .find(|(at, _)| *at == address)
+                .map(|&(_, chip_id)| chip_id)
+        }
+    }
+
+    /// Serial number words with their CRCs, as the SCD41 sends them
+    fn serial_response(words: [u16; 3]) -> [u8; 9] {
+        let mut response = [0; 9];
+        for (chunk, word) in response.chunks_exact_mut(3).zip(words) {
+            chunk[..2].copy_from_slice(&word.to_be_bytes());
+            chunk[2] = sensirion_crc8(&chunk[..2]);
+        }
+        response
+    }
+
+    #[test]
+    fn crc_matches_datasheet_example() {
+        // Example from the SCD4x datasheet
+        assert_eq!(sensirion_crc8(&[0xbe, 0xef]), 0x92);
+    }
+
+    #[test]
+    fn finds_all_sensors() {
+        let mut bus = MockBus {
+            scd41: Some(serial_response([0x1234, 0x5678, 0x9abc])),
+            chip_ids: vec![(0x77, BME680_CHIP_ID)],
+            displays: vec![0x3c],
+        };
+        let inventory = block_on(scan(&mut bus));
+        assert_eq!(inventory.scd41, Some(0x1234_5678_9abc));
+        assert_eq!(inventory.bme680, Some(0x77));
+        assert_eq!(inventory.ssd1306, Some(0x3c));
+    }
+
+    #[test]
+    fn empty_bus() {
+        let inventory = block_on(scan(&mut MockBus::default()));
+        assert_eq!(inventory.scd41, None);
+        assert_eq!(inventory.bme680, None);
+        assert_eq!(inventory.ssd1306, None);
+    }
+
+    #[test]
+    fn rejects_serial_number_with_bad_crc() {
+        let mut response = serial_response([0x1234, 0x5678, 0x9abc]);
+        response[5] ^= 0x01;
+        let mut bus = MockBus {
+            scd41: Some(response),
+            ..Default::default()
+        };
+        assert_eq!(block_on(scan(&mut bus)).scd41, None);
+    }
+
+    #[test]
+    fn skips_other_chip_at_bme680_address() {
+        // A BMP280 answers at the primary address with its own chip ID
+        let mut bus = MockBus {
+            chip_ids: vec![(0x76, 0x58), (0x77, BME680_CHIP_ID)],
+            ..Default::default()
+        };
+        assert_eq!(block_on(scan(&mut bus)).bme680, Some(0x77));
+
+        let mut bus = MockBus {
+            chip_ids: vec![(0x76, 0x58)],
+            ..Default::default()
+        };
+        assert_eq!(block_on(scan(&mut bus)).bme680, None);
+    }
+
+    #[test]
+    fn display_at_secondary_address() {
+        let mut bus = MockBus {
+            displays: vec![0x3d],
+            ..Default::default()
+        };
+        assert_eq!(block_on(scan(&mut bus)).ssd1306, Some(0x3d));
+    }
+}
@@ -0,0 +1,712 @@
+//! InfluxDB line protocol output, written over HTTP to `/api/v2/write` or
+//! sent as UDP datagrams to a Telegraf/InfluxDB UDP listener
+
+use core::fmt::Write as _;
+
+use defmt::{debug, error, expect, info, warn, Format};
+use embassy_futures::select::{select, Either};
+use embassy_net::{
+    tcp::TcpSocket,
+    udp::{PacketMetadata, UdpSocket},
+    IpAddress, Stack,
+};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_io_async::{Read, Write};
+use heapless::{String, Vec};
+
+use crate::bme680::Bme680Measurement;
+use crate::clock;
+use crate::config;
+use crate::device;
+use crate::psychrometrics::Psychrometrics;
+use crate::resolve::{self, MAX_ADDRESSES};
+use crate::scd41::Scd41Measurement;
+use crate::sink::{self, Reading};
+
+pub const ENABLED: bool = config::flag(option_env!("INFLUX_ENABLED"), false);
+/// `http` or `udp`
+const TRANSPORT: &str = match option_env!("INFLUX_TRANSPORT") {
+    Some(transport) => transport,
+    None => "http",
+};
+const HOST: &str = match option_env!("INFLUX_HOST") {
+    Some(host) => host,
+    None => "influxdb.local",
+};
+const PORT: &str = match option_env!("INFLUX_PORT") {
+    Some(port) => port,
+    None => "8086",
+};
+const ORG: &str = match option_env!("INFLUX_ORG") {
+    Some(org) => org,
+    None => "",
+};
+const BUCKET: &str = match option_env!("INFLUX_BUCKET") {
+    Some(bucket) => bucket,
+    None => "air-quality",
+};
+const TOKEN: &str = match option_env!("INFLUX_TOKEN") {
+    Some(token) => token,
+    None => "",
+};
+/// Value of the `device` tag on every point, `{device_id}` expanding to the
+/// device ID
+const DEVICE: &str = match option_env!("INFLUX_DEVICE") {
+    Some(device) => device,
+    None => "{device_id}",
+};
+
+/// Points are batched and written at most this often
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Batch of lines, written early if the next point might not fit
+pub const BATCH_SIZE: usize = 1024;
+/// Longest single line
+pub const MAX_LINE: usize = 384;
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Http,
+    Udp,
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The line or batch buffer is full
+    BufferFull,
+}
+
+impl From<core::fmt::Error> for Error {
+    fn from(_: core::fmt::Error) -> Self {
+        Error::BufferFull
+    }
+}
+
+/// Characters escaped in tag keys, tag values and field keys. A trailing
+/// backslash would otherwise escape the `=` or `,` that follows it.
+const KEY_SPECIAL: &[char] = &['\\', ',', '=', ' '];
+
+/// Writes one point in line protocol:
+/// `measurement,tag=value field=value,field=value timestamp`
+pub struct Line<const N: usize> {
+    line: String<N>,
+    fields: usize,
+}
+
+impl<const N: usize> Line<N> {
+    pub fn new(measurement: &str) -> Result<Self, Error> {
+        let mut line = Line {
+            line: String::new(),
+            fields: 0,
+        };
+        escape(&mut line.line, measurement, &[',', ' '])?;
+        Ok(line)
+    }
+
+    pub fn tag(mut self, key: &str, value: &str) -> Result<Self, Error> {
+        // Empty tag values aren't allowed, leave the tag out instead
+        if value.is_empty() {
+            return Ok(self);
+        }
+        self.line.push(',').map_err(|_| Error::BufferFull)?;
+        escape(&mut self.line, key, KEY_SPECIAL)?;
+        self.line.push('=').map_err(|_| Error::BufferFull)?;
+        escape(&mut self.line, value, KEY_SPECIAL)?;
+        Ok(self)
+    }
+
+    fn field_key(&mut self, key: &str) -> Result<(), Error> {
+        let separator = if self.fields == 0 { ' ' } else { ',' };
+        self.line.push(separator).map_err(|_| Error::BufferFull)?;
+        escape(&mut self.line, key, KEY_SPECIAL)?;
+        self.line.push('=').map_err(|_| Error::BufferFull)?;
+        self.fields += 1;
+        Ok(())
+    }
+
+    /// Float field, left out if it's not finite as line protocol can't
+    /// represent NaN or infinity
+    pub fn float(mut self, key: &str, value: f32) -> Result<Self, Error> {
+        if value.is_finite() {
+            self.field_key(key)?;
+            write!(self.line, "{value}")?;
+        }
+        Ok(self)
+    }
+
+    pub fn integer(mut self, key: &str, value: i64) -> Result<Self, Error> {
+        self.field_key(key)?;
+        write!(self.line, "{value}i")?;
+        Ok(self)
+    }
+
+    /// Finish the line with a timestamp in ns, or without one to let the
+    /// server use its receive time. None if no fields were written, which
+    /// line protocol doesn't allow.
+    pub fn finish(mut self, timestamp: Option<u64>) -> Result<Option<String<N>>, Error> {
+        if self.fields == 0 {
+            return Ok(None);
+        }
+        if let Some(timestamp) = timestamp {
+            write!(self.line, " {timestamp}")?;
+        }
+        self.line.push('\n').map_err(|_| Error::BufferFull)?;
+        Ok(Some(self.line))
+    }
+}
+
+/// Append `value`, escaping `special` characters with a backslash
+pub fn escape<const N: usize>(
+    out: &mut String<N>,
+    value: &str,
+    special: &[char],
+) -> Result<(), Error> {
+    for c in value.chars() {
+        // Line breaks would end the line, so they can't appear escaped or not
+        let c = if c == '\n' || c == '\r' { ' ' } else { c };
+        if special.contains(&c) {
+            out.push('\\').map_err(|_| Error::BufferFull)?;
+        }
+        out.push(c).map_err(|_| Error::BufferFull)?;
+    }
+    Ok(())
+}
+
+fn derived<const N: usize>(line: Line<N>, derived: &Psychrometrics) -> Result<Line<N>, Error> {
+    line.float("dew_point", derived.dew_point)?
+        .float("absolute_humidity", derived.absolute_humidity)?
+        .float("humidity_ratio", derived.humidity_ratio)?
+        .float("heat_index", derived.heat_index)?
+        .float("humidex", derived.humidex)
+}
+
+pub fn scd41_line<const N: usize>(
+    measurement: &Scd41Measurement,
+    device: &str,
+    timestamp: Option<u64>,
+) -> Result<Option<String<N>>, Error> {
+    let line = Line::new("scd41")?
+        .tag("device", device)?
+        .integer("co2", measurement.co2.into())?
+        .float("temperature", measurement.temperature)?
+        .float("humidity", measurement.humidity)?;
+    derived(line, &measurement.derived)?.finish(timestamp)
+}
+
+pub fn bme680_line<const N: usize>(
+    measurement: &Bme680Measurement,
+    device: &str,
+    timestamp: Option<u64>,
+) -> Result<Option<String<N>>, Error> {
+    let mut line = Line::new("bme680")?
+        .tag("device", device)?
+        .float("temperature", measurement.temperature)?
+        .float("self_heating", measurement.self_heating)?
+        .float("humidity", measurement.humidity)?
+        .float("pressure", measurement.pressure)?;
+    if let Some(pressure) = measurement.sea_level_pressure {
+        line = line.float("sea_level_pressure", pressure)?;
+    }
+    if let Some(resistance) = measurement.gas_resistance {
+        line = line.float("gas_resistance", resistance)?;
+    }
+    derived(line, &measurement.derived)?.finish(timestamp)
+}
+
+/// Whether the batch should be written before `line` is added to it, either
+/// because the flush interval has passed or because the line wouldn't fit
+pub fn flush_due<const N: usize>(batch: &String<N>, line: Option<&str>, elapsed: Duration) -> bool {
+    let full = line.is_some_and(|line| batch.len() + line.len() > batch.capacity());
+    !batch.is_empty() && (full || elapsed >= FLUSH_INTERVAL)
+}
+
+/// Current Unix time in ns, None until the clock is synchronised
+fn timestamp() -> Option<u64> {
+    let boot_time = clock::boot_time()?;
+    Some(boot_time as u64 * 1_000_000_000 + Instant::now().as_micros() * 1000)
+}
+
+/// Percent-encode a query parameter value
+fn encode_query<const N: usize>(out: &mut String<N>, value: &str) -> Result<(), Error> {
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(byte as char).map_err(|_| Error::BufferFull)?
+            }
+            byte => write!(out, "%{byte:02X}")?,
+        }
+    }
+    Ok(())
+}
+
+/// Status code from an HTTP response status line
+pub fn parse_status(response: &[u8]) -> Option<u16> {
+    let line = response.split(|&byte| byte == b'\r').next()?;
+    let line = core::str::from_utf8(line).ok()?;
+    let mut parts = line.split(' ');
+    if !parts.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+    parts.next()?.parse().ok()
+}
+
+/// Write a batch with a `POST /api/v2/write`, returning the response status
+async fn write_http(
+    stack: Stack<'static>,
+    addresses: &[IpAddress],
+    port: u16,
+    batch: &str,
+) -> Result<u16, ()> {
+    let mut rx_buffer = [0; 512];
+    let mut tx_buffer = [0; 1024];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    socket.set_timeout(Some(TIMEOUT));
+    resolve::connect(stack, &mut socket, addresses, port)
+        .await
+        .map_err(|e| warn!("Influx: connect error: {:?}", e))?;
+    let status = request(&mut socket, batch).await;
+    socket.close();
+    let _ = socket.flush().await;
+    status
+}
+
+/// Send the write request over a connected socket and read the status line
+/// of the response
+async fn request<S>(socket: &mut S, batch: &str) -> Result<u16, ()>
+where
+    S: Read + Write,
+    S::Error: Format,
+{
+    let mut head: String<512> = String::new();
+    let _ = head.push_str("POST /api/v2/write?org=");
+    encode_query(&mut head, ORG).map_err(|_| error!("Influx: org too long"))?;
+    let _ = head.push_str("&bucket=");
+    encode_query(&mut head, BUCKET).map_err(|_| error!("Influx: bucket too long"))?;
+    write!(
+        head,
+        "&precision=ns HTTP/1.1\r\nHost: {HOST}\r\nAuthorization: Token {TOKEN}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        batch.len()
+    )
+    .map_err(|_| error!("Influx: request head too long"))?;
+
+    socket
+        .write_all(head.as_bytes())
+        .await
+        .map_err(|e| warn!("Influx: write error: {:?}", e))?;
+    socket
+        .write_all(batch.as_bytes())
+        .await
+        .map_err(|e| warn!("Influx: write error: {:?}", e))?;
+    socket
+        .flush()
+        .await
+        .map_err(|e| warn!("Influx: write error: {:?}", e))?;
+
+    // Only the status line is of interest
+    let mut response = [0; 64];
+    let mut length = 0;
+    while length < response.len() && !response[..length].contains(&b'\r') {
+        match socket.read(&mut response[length..]).await {
+            Ok(0) => break,
+            Ok(read) => length += read,
+            Err(e) => {
+                warn!("Influx: read error: {:?}", e);
+                return Err(());
+            }
+        }
+    }
+    parse_status(&response[..length]).ok_or_else(|| warn!("Influx: invalid response"))
+}
+
+async fn send_udp(socket: &mut UdpSocket<'_>, address: IpAddress, port: u16, batch: &str) {
+    if let Err(e) = socket.send_to(batch.as_bytes(), (address, port)).await {
+        warn!("Influx: UDP send error: {:?}", e);
+    }
+}
+
+#[embassy_executor::task]
+pub async fn writer(stack: Stack<'static>) -> ! {
+    let transport = match TRANSPORT {
+        "udp" => Transport::Udp,
+        _ => Transport::Http,
+    };
+    let port: u16 = PORT.parse().unwrap_or(match transport {
+        Transport::Http => 8086,
+        Transport::Udp => 8089,
+    });
+    info!("Influx: writing to {}:{} over {:?}", HOST, port, transport);
+    let device: String<64> = expect!(
+        device::expand(DEVICE),
+        "Influx device tag {} should fit 64 bytes",
+        DEVICE
+    );
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; 16];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0; BATCH_SIZE * 2];
+    let mut udp = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if transport == Transport::Udp && udp.bind(0).is_err() {
+        error!("Influx: failed to bind UDP socket");
+    }
+
+    let mut batch: String<BATCH_SIZE> = String::new();
+    let mut addresses: Vec<IpAddress, MAX_ADDRESSES> = Vec::new();
+    let mut last_flush = Instant::now();
+
+    loop {
+        let until_flush = FLUSH_INTERVAL
+            .checked_sub(last_flush.elapsed())
+            .unwrap_or_default();
+        let line: Option<String<MAX_LINE>> =
+            match select(sink::INFLUX.receive(), Timer::after(until_flush)).await {
+                Either::First(Reading::Scd41(measurement)) => {
+                    scd41_line(&measurement, &device, timestamp())
+                        .map_err(|e| error!("Influx: failed to format SCD41 point: {:?}", e))
+                        .ok()
+                        .flatten()
+                }
+                Either::First(Reading::Bme680(measurement)) => {
+                    bme680_line(&measurement, &device, timestamp())
+                        .map_err(|e| error!("Influx: failed to format BME680 point: {:?}", e))
+                        .ok()
+                        .flatten()
+                }
+                Either::Second(()) => None,
+            };
+
+        if flush_due(&batch, line.as_deref(), last_flush.elapsed()) {
+            if addresses.is_empty() {
+                addresses = resolve::addresses(stack, HOST).await.unwrap_or_else(|e| {
+                    warn!("Influx: DNS lookup of {} failed: {:?}", HOST, e);
+                    Vec::new()
+                });
+            }
+            match (addresses.first(), transport) {
+                (Some(_), Transport::Http) => {
+                    match write_http(stack, &addresses, port, &batch).await {
+                        Ok(204) => debug!("Influx: wrote {} bytes", batch.len()),
+                        Ok(status) => warn!("Influx: write failed with status {}", status),
+                        // Look the host up again in case it moved
+                        Err(()) => addresses.clear(),
+                    }
+                }
+                // Datagrams go to the preferred address, there's no telling
+                // whether they arrive
+                (Some(&preferred), Transport::Udp) => {
+                    send_udp(&mut udp, preferred, port, &batch).await
+                }
+                (None, _) => {}
+            }
+            // Points that couldn't be written are dropped rather than
+            // holding up newer ones
+            batch.clear();
+            last_flush = Instant::now();
+        } else if batch.is_empty() && line.is_none() {
+            last_flush = Instant::now();
+        }
+
+        if let Some(line) = line {
+            let _ = batch.push_str(&line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+
+    use embassy_futures::block_on;
+
+    use super::*;
+
+    /// Stand-in for the InfluxDB server, recording the request and answering
+    /// with `response` a few bytes at a time
+    struct FakeServer {
+        request: std::vec::Vec<u8>,
+        response: &'static [u8],
+        chunk: usize,
+    }
+
+    impl FakeServer {
+        fn new(response: &'static [u8], chunk: usize) -> Self {
+            FakeServer {
+                request: vec![],
+                response,
+                chunk,
+            }
+        }
+
+        fn request(&self) -> &str {
+            core::str::from_utf8(&self.request).unwrap()
+        }
+    }
+
+    impl embedded_io_async::ErrorType for FakeServer {
+        type Error = Infallible;
+    }
+
+    impl Read for FakeServer {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+            let length = self.chunk.min(buf.len()).min(self.response.len());
+            buf[..length].copy_from_slice(&self.response[..length]);
+            self.response = &self.response[length..];
+            Ok(length)
+        }
+    }
+
+    impl Write for FakeServer {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+            self.request.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    fn line(line: Line<MAX_LINE>) -> String<MAX_LINE> {
+        line.finish(None).unwrap().unwrap()
+    }
+
+    #[test]
+    fn escapes_measurement_tags_and_fields() {
+        let point = Line::new("air quality,v2").unwrap();
+        assert_eq!(
+            line(point.integer("x", 1).unwrap()),
+            "air\\ quality\\,v2 x=1i\n"
+        );
+
+        let point = Line::new("m")
+            .unwrap()
+            .tag("room name", "living room, east=1")
+            .unwrap()
+            .float("a=b,c d", 1.5)
+            .unwrap();
+        assert_eq!(
+            line(point),
+            "m,room\\ name=living\\ room\\,\\ east\\=1 a\\=b\\,c\\ d=1.5\n"
+        );
+    }
+
+    #[test]
+    fn escapes_backslashes_in_keys() {
+        // Unescaped, the trailing backslash would escape the `=` after it
+        let point = Line::new("m")
+            .unwrap()
+            .tag("path\\", "C:\\air\\")
+            .unwrap()
+            .integer("k\\", 2)
+            .unwrap();
+        assert_eq!(line(point), "m,path\\\\=C:\\\\air\\\\ k\\\\=2i\n");
+    }
+
+    #[test]
+    fn line_breaks_become_spaces() {
+        let point = Line::new("m")
+            .unwrap()
+            .tag("room", "a\nb")
+            .unwrap()
+            .integer("x", 1)
+            .unwrap();
+        assert_eq!(line(point), "m,room=a\\ b x=1i\n");
+    }
+
+    #[test]
+    fn empty_tags_and_non_finite_fields_are_left_out() {
+        let point = Line::new("m")
+            .unwrap()
+            .tag("device", "")
+            .unwrap()
+            .float("nan", f32::NAN)
+            .unwrap()
+            .float("inf", f32::INFINITY)
+            .unwrap()
+            .float("t", 21.5)
+            .unwrap();
+        assert_eq!(line(point), "m t=21.5\n");
+
+        let point = Line::<MAX_LINE>::new("m")
+            .unwrap()
+            .float("nan", f32::NAN)
+            .unwrap();
+        assert_eq!(point.finish(Some(1)).unwrap(), None);
+    }
+
+    #[test]
+    fn timestamp_ends_the_line() {
+        let point = Line::<MAX_LINE>::new("m")
+            .unwrap()
+            .integer("x", -3)
+            .unwrap();
+        assert_eq!(
+            point
+                .finish(Some(1_700_000_000_000_000_000))
+                .unwrap()
+                .unwrap(),
+            "m x=-3i 1700000000000000000\n"
+        );
+    }
+
+    #[test]
+    fn overflowing_line_is_an_error() {
+        let point = Line::<6>::new("m").unwrap().integer("x", 1).unwrap();
+        assert_eq!(point.finish(None).err(), Some(Error::BufferFull));
+    }
+
+    #[test]
+    fn sensor_lines() {
+        let mut scd41 = Scd41Measurement::default();
+        scd41.co2 = 812;
+        scd41.temperature = 22.5;
+        scd41.humidity = 45.0;
+        scd41.derived = Psychrometrics::new(22.5, 45.0, 1013.25);
+        let line: String<MAX_LINE> = scd41_line(&scd41, "air-1", Some(5)).unwrap().unwrap();
+        assert!(line.starts_with("scd41,device=air-1 co2=812i,temperature=22.5,humidity=45,"));
+        assert!(line.contains(",dew_point="));
+        assert!(line.ends_with(" 5\n"));
+
+        let bme680 = Bme680Measurement {
+            temperature: 23.0,
+            humidity: 40.0,
+            pressure: 1013.25,
+            gas_resistance: Some(120_000.0),
+            ..Default::default()
+        };
+        let line: String<MAX_LINE> = bme680_line(&bme680, "air-1", None).unwrap().unwrap();
+        assert!(line.starts_with(
+            "bme680,device=air-1 temperature=23,self_heating=0,humidity=40,pressure=1013.25,\
+             gas_resistance=120000,"
+        ));
+        assert!(!line.contains("sea_level_pressure"));
+        assert!(line.ends_with('\n') && !line.ends_with(" 5\n"));
+    }
+
+    #[test]
+    fn longest_lines_fit() {
+        // Worst case values, every field present and as long as it gets
+        let mut scd41 = Scd41Measurement::default();
+        scd41.co2 = u16::MAX;
+        scd41.temperature = -123.456_79;
+        scd41.humidity = -123.456_79;
+        scd41.derived = Psychrometrics {
+            dew_point: -123.456_79,
+            absolute_humidity: -123.456_79,
+            humidity_ratio: -0.000_123_456_79,
+            heat_index: -123.456_79,
+            humidex: -123.456_79,
+        };
+        let device = "a".repeat(64);
+        let line: String<MAX_LINE> = scd41_line(&scd41, &device, Some(u64::MAX))
+            .unwrap()
+            .unwrap();
+        assert!(line.len() <= MAX_LINE);
+
+        let bme680 = Bme680Measurement {
+            temperature: -123.456_79,
+            self_heating: -123.456_79,
+            humidity: -123.456_79,
+            pressure: -123_456.79,
+            sea_level_pressure: Some(-123_456.79),
+            gas_resistance: Some(-123_456_790.0),
+            derived: scd41.derived,
+        };
+        let line: String<MAX_LINE> = bme680_line(&bme680, &device, Some(u64::MAX))
+            .unwrap()
+            .unwrap();
+        assert!(line.len() <= MAX_LINE);
+    }
+
+    #[test]
+    fn batches_until_full_or_due() {
+        let mut batch: String<BATCH_SIZE> = String::new();
+        let short = Duration::from_secs(1);
+        let point = "m x=1i\n";
+
+        // Nothing to write yet
+        assert!(!flush_due(&batch, Some(point), FLUSH_INTERVAL));
+        assert!(!flush_due(&batch, None, FLUSH_INTERVAL));
+
+        batch.push_str(point).unwrap();
+        assert!(!flush_due(&batch, Some(point), short));
+        assert!(!flush_due(&batch, None, short));
+        assert!(flush_due(&batch, None, FLUSH_INTERVAL));
+        assert!(flush_due(&batch, Some(point), FLUSH_INTERVAL));
+
+        // Fill the batch to the last byte, the next line is written early
+        while batch.len() + point.len() <= BATCH_SIZE {
+            assert!(!flush_due(&batch, Some(point), short));
+            batch.push_str(point).unwrap();
+        }
+        assert!(flush_due(&batch, Some(point), short));
+        assert!(!flush_due(&batch, None, short));
+        // A line of the maximum length always fits an empty batch
+        batch.clear();
+        let longest = "x".repeat(MAX_LINE);
+        assert!(!flush_due(&batch, Some(&longest), FLUSH_INTERVAL));
+        batch.push_str(&longest).unwrap();
+    }
+
+    #[test]
+    fn parses_status_lines() {
+        assert_eq!(parse_status(b"HTTP/1.1 204 No Content\r\n"), Some(204));
+        assert_eq!(
+            parse_status(b"HTTP/1.0 401 Unauthorized\r\nX: y"),
+            Some(401)
+        );
+        assert_eq!(parse_status(b"HTTP/1.1 204"), Some(204));
+        assert_eq!(parse_status(b"SSH-2.0-OpenSSH\r\n"), None);
+        assert_eq!(parse_status(b"HTTP/1.1 abc\r\n"), None);
+        assert_eq!(parse_status(b""), None);
+    }
+
+    #[test]
+    fn encodes_query_values() {
+        let mut out: String<32> = String::new();
+        encode_query(&mut out, "my org/a&b=c ~_.-").unwrap();
+        assert_eq!(out, "my%20org%2Fa%26b%3Dc%20~_.-");
+    }
+
+    #[test]
+    fn writes_batches_to_the_server() {
+        let batch = "scd41,device=air-1 co2=812i\nbme680,device=air-1 temperature=23\n";
+        let mut server = FakeServer::new(b"HTTP/1.1 204 No Content\r\nDate: x\r\n\r\n", 5);
+
+        assert_eq!(block_on(request(&mut server, batch)), Ok(204));
+
+        let (head, body) = server.request().split_once("\r\n\r\n").unwrap();
+        assert_eq!(body, batch);
+        let mut lines = head.split("\r\n");
+        let mut query: String<128> = String::new();
+        encode_query(&mut query, BUCKET).unwrap();
+        let request_line = lines.next().unwrap();
+        assert!(request_line.starts_with("POST /api/v2/write?org="));
+        assert!(request_line.ends_with(&format!("&bucket={query}&precision=ns HTTP/1.1")));
+        let headers: std::vec::Vec<_> = lines.collect();
+        assert!(headers.contains(&format!("Host: {HOST}").as_str()));
+        assert!(headers.contains(&format!("Authorization: Token {TOKEN}").as_str()));
+        assert!(headers.contains(&"Content-Type: text/plain; charset=utf-8"));
+        assert!(headers.contains(&"Connection: close"));
+        let length = format!("Content-Length: {}", batch.len());
+        assert!(headers.contains(&length.as_str()));
+    }
+
+    #[test]
+    fn reports_server_errors() {
+        let mut server = FakeServer::new(b"HTTP/1.1 400 Bad Request\r\n\r\n", 64);
+        assert_eq!(block_on(request(&mut server, "m x=1i\n")), Ok(400));
+
+        // Connection closed before a status line arrived
+        let mut server = FakeServer::new(b"HTTP/1.1", 3);
+        assert_eq!(block_on(request(&mut server, "m x=1i\n")), Err(()));
+
+        let mut server = FakeServer::new(b"", 1);
+        assert_eq!(block_on(request(&mut server, "m x=1i\n")), Err(()));
+    }
+}
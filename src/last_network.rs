@@ -0,0 +1,326 @@
+//! WiFi network last connected to, kept in flash so that it's still preferred
+//! after a restart
+//!
+//! Records go one after another into a single sector, which is only erased
+//! once full, so a board moving between networks wears it slowly. The newest
+//! intact record counts, and one torn by a power loss fails its CRC and is
+//! passed over.
+
+use defmt::Format;
+use embedded_storage::nor_flash::NorFlash;
+use heapless::String;
+
+use crate::history::{self, crc8, SECTOR_SIZE};
+use crate::wifi_networks::{Network, MAX_SSID};
+
+/// The record takes the data partition with this label
+pub const PARTITION_LABEL: &[u8] = b"wifi";
+
+const MAGIC: u8 = 0x57;
+/// Magic, network index, SSID length, SSID and CRC
+const RECORD_SIZE: usize = 3 + MAX_SSID + 1;
+const SLOTS: u32 = (SECTOR_SIZE / RECORD_SIZE) as u32;
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// There's no partition for the record, or it isn't a whole sector
+    Partition,
+    Flash,
+    SsidTooLong,
+}
+
+/// A network as recorded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LastNetwork {
+    /// Position among the configured networks
+    pub index: u8,
+    pub ssid: String<MAX_SSID>,
+}
+
+impl LastNetwork {
+    /// Index of the network among `networks`: the recorded one while it still
+    /// has the SSID, or else the first network that does, as the
+    /// configuration may have changed since
+    pub fn find(&self, networks: &[Network]) -> Option<usize> {
+        let index = usize::from(self.index);
+        match networks.get(index) {
+            Some(network) if network.ssid == self.ssid => Some(index),
+            _ => networks
+                .iter()
+                .position(|network| network.ssid == self.ssid),
+        }
+    }
+
+    fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut record = [0; RECORD_SIZE];
+        record[0] = MAGIC;
+        record[1] = self.index;
+        record[2] = self.ssid.len() as u8;
+        record[3..3 + self.ssid.len()].copy_from_slice(self.ssid.as_bytes());
+        record[RECORD_SIZE - 1] = crc8(&record[..RECORD_SIZE - 1]);
+        record
+    }
+
+    fn decode(record: &[u8; RECORD_SIZE]) -> Option<Self> {
+        if record[0] != MAGIC || crc8(&record[..RECORD_SIZE - 1]) != record[RECORD_SIZE - 1] {
+            return None;
+        }
+        let ssid = record[3..].get(..usize::from(record[2]))?;
+        Some(LastNetwork {
+            index: record[1],
+            ssid: String::try_from(core::str::from_utf8(ssid).ok()?).ok()?,
+        })
+    }
+}
+
+/// The record of the last network in a sector of NOR flash
+pub struct Store<F> {
+    flash: F,
+    offset: u32,
+    /// Slot the next record goes into
+    next: u32,
+    last: Option<LastNetwork>,
+}
+
+impl<F: NorFlash> Store<F> {
+    /// Open the record in the sector at `offset` in a partition of `size`
+    /// bytes, reading back the newest network in it
+    pub fn open(flash: F, offset: u32, size: u32) -> Result<Self, Error> {
+        let sector_size = SECTOR_SIZE as u32;
+        if offset % sector_size != 0
+            || size < sector_size
+            || SECTOR_SIZE % F::ERASE_SIZE != 0
+            || RECORD_SIZE % F::WRITE_SIZE != 0
+        {
+            return Err(Error::Partition);
+        }
+        let mut store = Store {
+            flash,
+            offset,
+            next: 0,
+            last: None,
+        };
+        while store.next < SLOTS {
+            let mut record = [0; RECORD_SIZE];
+            store
+                .flash
+                .read(store.slot_address(store.next), &mut record)
+                .map_err(|_| Error::Flash)?;
+            if record.iter().all(|&byte| byte == 0xff) {
+                break;
+            }
+            if let Some(last) = LastNetwork::decode(&record) {
+                store.last = Some(last);
+            }
+            store.next += 1;
+        }
+        Ok(store)
+    }
+
+    fn slot_address(&self, slot: u32) -> u32 {
+        self.offset + slot * RECORD_SIZE as u32
+    }
+
+    /// The network last connected to, if any was recorded
+    pub fn last(&self) -> Option<&LastNetwork> {
+        self.last.as_ref()
+    }
+
+    /// Record a connection to the network at `index` with `ssid`. Writes
+    /// nothing if it's the one already recorded.
+    pub fn remember(&mut self, index: usize, ssid: &str) -> Result<(), Error> {
+        let last = LastNetwork {
+            // An index that doesn't fit is looked up by the SSID instead
+            index: u8::try_from(index).unwrap_or(u8::MAX),
+            ssid: String::try_from(ssid).map_err(|()| Error::SsidTooLong)?,
+        };
+        if self.last.as_ref() == Some(&last) {
+            return Ok(());
+        }
+        if self.next == SLOTS {
+            self.flash
+                .erase(self.offset, self.offset + SECTOR_SIZE as u32)
+                .map_err(|_| Error::Flash)?;
+            self.next = 0;
+        }
+        let address = self.slot_address(self.next);
+        // A torn record still takes its slot
+        self.next += 1;
+        self.flash
+            .write(address, &last.encode())
+            .map_err(|_| Error::Flash)?;
+        self.last = Some(last);
+        Ok(())
+    }
+}
+
+/// Find the partition in `flash` and open the record there
+pub fn open<F: NorFlash>(mut flash: F) -> Result<Store<F>, Error> {
+    let (offset, size) =
+        history::find_partition(&mut flash, PARTITION_LABEL).map_err(|e| match e {
+            history::Error::Partition => Error::Partition,
+            history::Error::Flash => Error::Flash,
+        })?;
+    Store::open(flash, offset, size)
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_storage::nor_flash::{ErrorType, NorFlashErrorKind, ReadNorFlash};
+
+    use super::*;
+
+    const OFFSET: u32 = 0xe000;
+    const SIZE: u32 = SECTOR_SIZE as u32;
+
+    /// A sector of NOR flash in RAM, where writing can only clear bits and
+    /// stops part way once `write_budget` bytes are used up, like a write cut
+    /// short by a power loss
+    struct SimFlash {
+        data: std::vec::Vec<u8>,
+        write_budget: Option<usize>,
+        erases: usize,
+    }
+
+    impl SimFlash {
+        fn new() -> Self {
+            SimFlash {
+                data: vec![0xff; (OFFSET + SIZE) as usize],
+                write_budget: None,
+                erases: 0,
+            }
+        }
+    }
+
+    impl ErrorType for SimFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for SimFlash {
+        const READ_SIZE: usize = 4;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), NorFlashErrorKind> {
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+    }
+
+    impl NorFlash for SimFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = SECTOR_SIZE;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), NorFlashErrorKind> {
+            self.data[from as usize..to as usize].fill(0xff);
+            self.erases += 1;
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), NorFlashErrorKind> {
+            if offset as usize % Self::WRITE_SIZE != 0 || bytes.len() % Self::WRITE_SIZE != 0 {
+                return Err(NorFlashErrorKind::NotAligned);
+            }
+            let allowed = self
+                .write_budget
+                .map_or(bytes.len(), |budget| budget.min(bytes.len()));
+            for (target, &byte) in self.data[offset as usize..]
+                .iter_mut()
+                .zip(&bytes[..allowed])
+            {
+                *target &= byte;
+            }
+            match allowed < bytes.len() {
+                true => Err(NorFlashErrorKind::Other),
+                false => Ok(()),
+            }
+        }
+    }
+
+    fn open(flash: SimFlash) -> Store<SimFlash> {
+        Store::open(flash, OFFSET, SIZE).unwrap()
+    }
+
+    fn network(ssid: &'static str) -> Network<'static> {
+        Network {
+            ssid,
+            psk: "",
+            priority: 0,
+            enterprise: None,
+        }
+    }
+
+    #[test]
+    fn remembers_across_restarts() {
+        let store = open(SimFlash::new());
+        assert_eq!(store.last(), None);
+
+        let mut store = open(store.flash);
+        store.remember(1, "office").unwrap();
+        store.remember(0, "home").unwrap();
+        let store = open(store.flash);
+        let last = store.last().unwrap();
+        assert_eq!((last.index, last.ssid.as_str()), (0, "home"));
+    }
+
+    #[test]
+    fn the_same_network_isnt_written_again() {
+        let mut store = open(SimFlash::new());
+        store.remember(0, "home").unwrap();
+        store.remember(0, "home").unwrap();
+        assert_eq!(store.next, 1);
+    }
+
+    #[test]
+    fn erases_once_the_sector_is_full() {
+        let mut store = open(SimFlash::new());
+        for round in 0..SLOTS {
+            let ssid = if round % 2 == 0 { "home" } else { "office" };
+            store.remember(round as usize % 2, ssid).unwrap();
+        }
+        assert_eq!(store.flash.erases, 0);
+        store.remember(2, "lab").unwrap();
+        assert_eq!(store.flash.erases, 1);
+        let store = open(store.flash);
+        assert_eq!(store.next, 1);
+        assert_eq!(store.last().unwrap().ssid.as_str(), "lab");
+    }
+
+    #[test]
+    fn torn_record_is_passed_over() {
+        let mut store = open(SimFlash::new());
+        store.remember(0, "home").unwrap();
+        store.flash.write_budget = Some(8);
+        assert_eq!(store.remember(1, "office"), Err(Error::Flash));
+
+        let mut store = open(store.flash);
+        assert_eq!(store.last().unwrap().ssid.as_str(), "home");
+        // The next record goes after the torn one
+        store.flash.write_budget = None;
+        store.remember(1, "office").unwrap();
+        assert_eq!(store.next, 3);
+        let store = open(store.flash);
+        assert_eq!(store.last().unwrap().ssid.as_str(), "office");
+    }
+
+    #[test]
+    fn finds_the_network_after_a_configuration_change() {
+        let last = LastNetwork {
+            index: 1,
+            ssid: String::try_from("office").unwrap(),
+        };
+        assert_eq!(last.find(&[network("home"), network("office")]), Some(1));
+        // Moved or gone
+        assert_eq!(last.find(&[network("office"), network("home")]), Some(0));
+        assert_eq!(last.find(&[network("home")]), None);
+    }
+
+    #[test]
+    fn rejects_a_partial_sector() {
+        assert!(Store::open(SimFlash::new(), OFFSET + 4, SIZE).is_err());
+        assert!(Store::open(SimFlash::new(), OFFSET, SIZE / 2).is_err());
+    }
+}
@@ -0,0 +1,68 @@
+//! Sensor drivers, analysis, encodings and network services of the air
+//! quality monitor, written against embedded-hal, embedded-storage and
+//! embassy-net rather than the ESP32-C6 HAL. The firmware binary binds them
+//! to the board, and everything here builds and is tested on the host with
+//! `cargo test-host`.
+
+#![cfg_attr(not(test), no_std)]
+#![feature(impl_trait_in_assoc_type)]
+#![feature(never_type)]
+
+pub mod alerts;
+pub mod barometric;
+pub mod battery;
+pub mod bme680;
+pub mod broker;
+pub mod clock;
+pub mod config;
+pub mod deadband;
+pub mod device;
+pub mod display;
+pub mod epaper;
+pub mod exception;
+pub mod export;
+pub mod field_topics;
+pub mod fusion;
+pub mod history;
+pub mod http;
+pub mod i2c_scan;
+pub mod influx;
+pub mod last_network;
+pub mod mdns;
+pub mod mqtt;
+pub mod network;
+pub mod network_settings;
+pub mod payload;
+pub mod psychrometrics;
+pub mod push;
+pub mod resolve;
+pub mod scd41;
+pub mod self_heating;
+pub mod serial;
+pub mod sink;
+pub mod slaac;
+pub mod stats;
+pub mod traffic;
+pub mod ventilation;
+pub mod wifi_networks;
+
+/// What the firmware links in for defmt, so code that logs runs in host tests
+#[cfg(test)]
+mod defmt_host {
+    #[defmt::global_logger]
+    struct Discard;
+
+    unsafe impl defmt::Logger for Discard {
+        fn acquire() {}
+        unsafe fn flush() {}
+        unsafe fn release() {}
+        unsafe fn write(_bytes: &[u8]) {}
+    }
+
+    defmt::timestamp!("");
+
+    #[defmt::panic_handler]
+    fn panic() -> ! {
+        panic!("defmt panic")
+    }
+}
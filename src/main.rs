@@ -2,20 +2,30 @@
 #![no_main]
 #![feature(impl_trait_in_assoc_type)]
 #![feature(never_type)]
-
 // Recommended by esp_hal docs, as some esp_hal types rely on Drop
 // implementations to not leave hardware in undefined states
-#[deny(clippy::mem_forget)]
+#![deny(clippy::mem_forget)]
+
+use core::sync::atomic::Ordering;
+
+use air::{
+    alerts, clock, config, device, fusion, history, i2c_scan, influx, mdns, mqtt, push, serial,
+    sink, stats, ventilation,
+};
 use defmt::info;
 use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
 use embassy_executor::Spawner;
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 use embassy_sync::mutex::Mutex;
-use embassy_time::Timer;
+use embassy_time::{Delay, Timer};
+use embedded_hal_bus::spi::ExclusiveDevice;
 use esp_hal::clock::CpuClock;
+use esp_hal::efuse::Efuse;
+use esp_hal::gpio::{Input, InputConfig, Level, Output, OutputConfig};
 use esp_hal::i2c::master::I2c;
 use esp_hal::rmt::{Rmt, TxChannel, TxChannelCreator};
 use esp_hal::rng::Rng;
+use esp_hal::spi::{self, master::Spi};
 use esp_hal::time::Rate;
 use esp_hal::timer::systimer::SystemTimer;
 use esp_hal::timer::timg::TimerGroup;
@@ -30,11 +40,12 @@ use wifi::wifi_init;
 
 extern crate alloc;
 
-mod bme680;
-mod mqtt;
-mod scd41;
+mod board;
 mod wifi;
 
+/// Battery units carry an SPI e-paper panel and a battery voltage divider
+const EPAPER_ENABLED: bool = config::flag(option_env!("DISPLAY_EPAPER"), false);
+
 #[esp_hal_embassy::main]
 async fn main(spawner: Spawner) -> ! {
     // Initialize RTT as the defmt channel
@@ -55,10 +66,7 @@ async fn main(spawner: Spawner) -> ! {
 
     static ESP_WIFI_CONTROLLER: StaticCell<EspWifiController<'static>> = StaticCell::new();
     let esp_wifi_controller = ESP_WIFI_CONTROLLER
-        .init_with(|| esp_wifi::init(timer1.timer0, rng.clone(), peripherals.RADIO_CLK).unwrap());
-
-    let stack = wifi_init(esp_wifi_controller, peripherals.WIFI, spawner, network_seed).await;
-    spawner.must_spawn(mqtt::client(stack));
+        .init_with(|| esp_wifi::init(timer1.timer0, *rng, peripherals.RADIO_CLK).unwrap());
 
     static I2C_BUS: StaticCell<Mutex<NoopRawMutex, I2c<'static, Async>>> = StaticCell::new();
     let i2c = I2c::new(peripherals.I2C0, Default::default())
@@ -68,8 +76,71 @@ async fn main(spawner: Spawner) -> ! {
         .into_async();
     let i2c_bus = I2C_BUS.init(Mutex::new(i2c));
 
-    spawner.must_spawn(scd41::supervisor(I2cDevice::new(i2c_bus)));
-    spawner.must_spawn(bme680::bme680_sensor_task(I2cDevice::new(i2c_bus)));
+    device::init(Efuse::mac_address());
+
+    let inventory = i2c_scan::scan(&mut I2cDevice::new(i2c_bus)).await;
+    info!("I2C scan: inventory: {:?}", inventory);
+
+    let stack = wifi_init(esp_wifi_controller, peripherals.WIFI, spawner, network_seed).await;
+    spawner.must_spawn(mqtt::client(stack, inventory));
+    spawner.must_spawn(clock::sntp(stack));
+    spawner.must_spawn(board::http_server(stack));
+    if mdns::ENABLED {
+        spawner.must_spawn(mdns::responder(stack, inventory));
+    }
+    if influx::ENABLED {
+        spawner.must_spawn(influx::writer(stack));
+    }
+    if push::ENABLED {
+        spawner.must_spawn(push::pusher(stack));
+    }
+    if serial::ENABLED {
+        spawner.must_spawn(serial::logger());
+    }
+
+    if inventory.scd41.is_some() {
+        spawner.must_spawn(board::scd41_supervisor(
+            I2cDevice::new(i2c_bus),
+            inventory.bme680.is_some(),
+        ));
+        spawner.must_spawn(ventilation::advisor());
+    }
+    if let Some(address) = inventory.bme680 {
+        spawner.must_spawn(board::bme680_sensor_task(I2cDevice::new(i2c_bus), address));
+    }
+    spawner.must_spawn(sink::dispatcher());
+    spawner.must_spawn(stats::aggregator());
+    spawner.must_spawn(alerts::engine());
+    spawner.must_spawn(fusion::engine());
+    if history::ENABLED {
+        spawner.must_spawn(board::history_recorder());
+    }
+    if let Some(address) = inventory.ssd1306 {
+        spawner.must_spawn(board::display_task(
+            I2cDevice::new(i2c_bus),
+            address,
+            stack,
+        ));
+    }
+
+    if EPAPER_ENABLED {
+        let spi = Spi::new(
+            peripherals.SPI2,
+            spi::master::Config::default().with_frequency(Rate::from_mhz(4)),
+        )
+        .expect("spi config should be valid")
+        .with_sck(peripherals.GPIO6)
+        .with_mosi(peripherals.GPIO7)
+        .into_async();
+        let cs = Output::new(peripherals.GPIO18, Level::High, OutputConfig::default());
+        let spi = ExclusiveDevice::new(spi, cs, Delay).expect("CS pin should be settable");
+        let dc = Output::new(peripherals.GPIO19, Level::Low, OutputConfig::default());
+        let rst = Output::new(peripherals.GPIO20, Level::High, OutputConfig::default());
+        let busy = Input::new(peripherals.GPIO21, InputConfig::default());
+
+        spawner.must_spawn(board::battery_task(peripherals.ADC1, peripherals.GPIO0));
+        spawner.must_spawn(board::epaper_task(spi, busy, dc, rst));
+    }
 
     let rmt = Rmt::new(peripherals.RMT, Rate::from_mhz(80)).expect("RMT0 should initialize");
     led_rainbow_loop(peripherals.GPIO8, rmt.channel0).await;
@@ -90,7 +161,14 @@ async fn led_rainbow_loop<T: TxChannel, P: esp_hal::gpio::OutputPin + 'static>(
 
     loop {
         for hue in 0..=255 {
-            color.hue = hue;
+            // Blink red while any alert is raised
+            if alerts::ACTIVE.load(Ordering::Relaxed) {
+                color.hue = 0;
+                color.val = if hue % 50 < 25 { 255 } else { 0 };
+            } else {
+                color.hue = hue;
+                color.val = 255;
+            }
 
             // Convert from HSV to RGB color space
             let rgb_data = [hsv2rgb(color)];
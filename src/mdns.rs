@@ -0,0 +1,916 @@
+//! Multicast DNS responder (RFC 6762) answering for `<hostname>.local` and
+//! advertising the HTTP server through DNS-SD (RFC 6763), so sensors can be
+//! found without looking up DHCP leases. Names aren't probed for conflicts
+//! (RFC 6762 section 8.1), so the hostname must contain the device ID to be
+//! unique on the link.
+
+use core::net::{Ipv4Addr, Ipv6Addr};
+
+use defmt::{debug, error, info, warn, Format};
+use embassy_futures::select::{select, Either};
+use embassy_net::{
+    udp::{PacketMetadata, UdpSocket},
+    IpAddress, IpEndpoint, Stack,
+};
+use embassy_time::{Duration, Timer};
+use heapless::{String, Vec};
+
+use crate::config;
+use crate::device;
+use crate::i2c_scan::Inventory;
+
+pub const ENABLED: bool = config::flag(option_env!("MDNS_ENABLED"), true);
+/// Hostname without `.local`, which must have a `{device_id}` placeholder
+const HOSTNAME: &str = match option_env!("MDNS_HOSTNAME") {
+    Some(hostname) => hostname,
+    None => "air-{device_id}",
+};
+/// Also advertise the HTTP server as a Prometheus target
+const PROMETHEUS: bool = config::flag(option_env!("MDNS_PROMETHEUS"), false);
+
+const PORT: u16 = 5353;
+const GROUP_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+/// Port of the HTTP server advertised
+const HTTP_PORT: u16 = 80;
+
+/// How often the addresses are checked for changes to announce
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Longest hostname label, and longest name in a question
+pub const MAX_HOSTNAME: usize = 63;
+const MAX_NAME: usize = 128;
+/// Questions beyond these are ignored
+const MAX_QUESTIONS: usize = 8;
+const MAX_RECORDS: usize = 16;
+/// Labels remembered for name compression
+const MAX_LABELS: usize = 64;
+/// Longest message sent or received, well within the minimum IPv6 MTU
+const MAX_MESSAGE: usize = 512;
+
+/// TTL of records naming the host, and of the others (RFC 6762 section 10)
+const HOST_TTL: u32 = 120;
+const OTHER_TTL: u32 = 4500;
+const LEGACY_TTL: u32 = 10;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Unique records are flagged in the class so caches replace older ones, and
+/// questions to ask for a unicast response
+const CACHE_FLUSH: u16 = 0x8000;
+const UNICAST_RESPONSE: u16 = 0x8000;
+/// Authoritative response
+const FLAGS_RESPONSE: u16 = 0x8400;
+const HEADER_LENGTH: usize = 12;
+
+const SERVICES: &str = "_services._dns-sd._udp";
+const LOCAL: &str = "local";
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The hostname has no `{device_id}` placeholder, so it may already be
+    /// taken by another device
+    NotUnique,
+    /// The expanded hostname doesn't fit a label
+    TooLong,
+}
+
+/// Hostname from its template, which must contain the device ID
+pub fn hostname(template: &str, device_id: &str) -> Result<String<MAX_HOSTNAME>, Error> {
+    if !template.contains("{device_id}") {
+        return Err(Error::NotUnique);
+    }
+    config::expand(template, |name| (name == "device_id").then_some(device_id))
+        .ok_or(Error::TooLong)
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub struct Service<'a> {
+    /// Service type such as `_http._tcp`
+    pub kind: &'a str,
+    pub port: u16,
+    /// TXT record strings such as `version=1.0`
+    pub txt: &'a [&'a str],
+}
+
+/// What is answered for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Host<'a> {
+    /// Hostname without `.local`
+    pub name: &'a str,
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+    pub services: &'a [Service<'a>],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+    pub name: String<MAX_NAME>,
+    pub kind: u16,
+    /// Whether a unicast response was asked for
+    pub unicast: bool,
+}
+
+/// Questions of a query, None if the message isn't a standard query
+pub fn parse_query(message: &[u8]) -> Option<(u16, Vec<Question, MAX_QUESTIONS>)> {
+    if message.len() < HEADER_LENGTH {
+        return None;
+    }
+    let id = u16_at(message, 0)?;
+    let flags = u16_at(message, 2)?;
+    // Responses and opcodes other than a standard query are ignored
+    if flags & 0xf800 != 0 {
+        return None;
+    }
+    let mut questions = Vec::new();
+    let mut offset = HEADER_LENGTH;
+    for _ in 0..u16_at(message, 4)? {
+        let (name, end) = read_name(message, offset)?;
+        let kind = u16_at(message, end)?;
+        let class = u16_at(message, end + 2)?;
+        offset = end + 4;
+        if class & !UNICAST_RESPONSE == CLASS_IN {
+            let _ = questions.push(Question {
+                name,
+                kind,
+                unicast: class & UNICAST_RESPONSE != 0,
+            });
+        }
+    }
+    Some((id, questions))
+}
+
+fn u16_at(message: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *message.get(offset)?,
+        *message.get(offset + 1)?,
+    ]))
+}
+
+/// Read a possibly compressed name at `offset` as dotted labels, returning it
+/// and the offset after it
+fn read_name(message: &[u8], mut offset: usize) -> Option<(String<MAX_NAME>, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    // Bounds pointer loops
+    let mut jumps = 0;
+    loop {
+        let length = usize::from(*message.get(offset)?);
+        match length {
+            0 => return Some((name, end.unwrap_or(offset + 1))),
+            _ if length & 0xc0 == 0xc0 => {
+                jumps += 1;
+                if jumps > 16 {
+                    return None;
+                }
+                end.get_or_insert(offset + 2);
+                offset = usize::from(u16_at(message, offset)? & 0x3fff);
+            }
+            _ if length < 64 => {
+                let label = message.get(offset + 1..offset + 1 + length)?;
+                if !name.is_empty() {
+                    name.push('.').ok()?;
+                }
+                name.push_str(core::str::from_utf8(label).ok()?).ok()?;
+                offset += 1 + length;
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Whether a dotted name equals `parts` joined with dots, ignoring ASCII case
+fn name_eq(name: &str, parts: &[&str]) -> bool {
+    let mut rest = name.as_bytes();
+    for (index, part) in parts.iter().enumerate() {
+        let Some((head, tail)) = rest.split_at_checked(part.len()) else {
+            return false;
+        };
+        if !head.eq_ignore_ascii_case(part.as_bytes()) {
+            return false;
+        }
+        rest = match (index + 1 < parts.len(), tail.split_first()) {
+            (true, Some((b'.', tail))) => tail,
+            (true, _) => return false,
+            (false, _) => tail,
+        };
+    }
+    rest.is_empty()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Record<'a> {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    /// Service type enumeration, pointing to the type
+    Types(&'a Service<'a>),
+    /// Pointer from the service type to the instance
+    Ptr(&'a Service<'a>),
+    Srv(&'a Service<'a>),
+    Txt(&'a Service<'a>),
+}
+
+impl Host<'_> {
+    fn addresses(&self) -> impl Iterator<Item = Record<'_>> {
+        self.ipv4
+            .map(Record::A)
+            .into_iter()
+            .chain(self.ipv6.map(Record::Aaaa))
+    }
+
+    /// Records answering a question and the additional records worth
+    /// sending along (RFC 6763 section 12)
+    fn answer<'a>(
+        &'a self,
+        question: &Question,
+        answers: &mut Vec<Record<'a>, MAX_RECORDS>,
+        additional: &mut Vec<Record<'a>, MAX_RECORDS>,
+    ) {
+        let kind = question.kind;
+        let wants = |record_kind| kind == record_kind || kind == TYPE_ANY;
+        let add = |records: &mut Vec<Record<'a>, MAX_RECORDS>, record| {
+            if !records.contains(&record) {
+                let _ = records.push(record);
+            }
+        };
+        if name_eq(&question.name, &[self.name, LOCAL]) {
+            for record in self.addresses() {
+                let record_kind = match record {
+                    Record::A(_) => TYPE_A,
+                    _ => TYPE_AAAA,
+                };
+                if wants(record_kind) {
+                    add(answers, record);
+                }
+            }
+        }
+        if name_eq(&question.name, &[SERVICES, LOCAL]) && wants(TYPE_PTR) {
+            for service in self.services {
+                add(answers, Record::Types(service));
+            }
+        }
+        for service in self.services {
+            if name_eq(&question.name, &[service.kind, LOCAL]) && wants(TYPE_PTR) {
+                add(answers, Record::Ptr(service));
+                add(additional, Record::Srv(service));
+                add(additional, Record::Txt(service));
+                self.addresses().for_each(|record| add(additional, record));
+            }
+            if name_eq(&question.name, &[self.name, service.kind, LOCAL]) {
+                if wants(TYPE_SRV) {
+                    add(answers, Record::Srv(service));
+                    self.addresses().for_each(|record| add(additional, record));
+                }
+                if wants(TYPE_TXT) {
+                    add(answers, Record::Txt(service));
+                }
+            }
+        }
+    }
+
+    fn write_record(
+        &self,
+        writer: &mut Writer,
+        record: &Record,
+        legacy: bool,
+    ) -> Option<()> {
+        let (kind, ttl, unique) = match record {
+            Record::A(_) => (TYPE_A, HOST_TTL, true),
+            Record::Aaaa(_) => (TYPE_AAAA, HOST_TTL, true),
+            Record::Types(_) | Record::Ptr(_) => (TYPE_PTR, OTHER_TTL, false),
+            Record::Srv(_) => (TYPE_SRV, HOST_TTL, true),
+            Record::Txt(_) => (TYPE_TXT, OTHER_TTL, true),
+        };
+        match record {
+            Record::A(_) | Record::Aaaa(_) => writer.name(&[self.name, LOCAL])?,
+            Record::Types(_) => writer.name(&[SERVICES, LOCAL])?,
+            Record::Ptr(service) => writer.name(&[service.kind, LOCAL])?,
+            Record::Srv(service) | Record::Txt(service) => {
+                writer.name(&[self.name, service.kind, LOCAL])?
+            }
+        }
+        let class = match unique && !legacy {
+            true => CLASS_IN | CACHE_FLUSH,
+            false => CLASS_IN,
+        };
+        let ttl = match legacy {
+            true => ttl.min(LEGACY_TTL),
+            false => ttl,
+        };
+        writer.u16(kind)?;
+        writer.u16(class)?;
+        writer.push(&ttl.to_be_bytes())?;
+        // Length of the data, filled in once it's written
+        let length_at = writer.length;
+        writer.u16(0)?;
+        match record {
+            Record::A(address) => writer.push(&address.octets())?,
+            Record::Aaaa(address) => writer.push(&address.octets())?,
+            Record::Types(service) => writer.name(&[service.kind, LOCAL])?,
+            Record::Ptr(service) => writer.name(&[self.name, service.kind, LOCAL])?,
+            Record::Srv(service) => {
+                // Priority and weight
+                writer.u16(0)?;
+                writer.u16(0)?;
+                writer.u16(service.port)?;
+                writer.name(&[self.name, LOCAL])?;
+            }
+            Record::Txt(service) => {
+                if service.txt.is_empty() {
+                    writer.push(&[0])?;
+                }
+                for entry in service.txt {
+                    writer.push(&[u8::try_from(entry.len()).ok()?])?;
+                    writer.push(entry.as_bytes())?;
+                }
+            }
+        }
+        let length = (writer.length - length_at - 2) as u16;
+        writer.out[length_at..length_at + 2].copy_from_slice(&length.to_be_bytes());
+        Some(())
+    }
+
+    /// Response message with the answers and additional records
+    fn write_response(
+        &self,
+        id: u16,
+        questions: &[Question],
+        answers: &[Record],
+        additional: &[Record],
+        out: &mut [u8],
+    ) -> Option<usize> {
+        // Legacy unicast responses repeat the questions, leave out the cache
+        // flush bit and have short TTLs (RFC 6762 section 6.7)
+        let legacy = !questions.is_empty();
+        let mut writer = Writer::new(out);
+        writer.u16(id)?;
+        writer.u16(FLAGS_RESPONSE)?;
+        writer.u16(questions.len() as u16)?;
+        writer.u16(answers.len() as u16)?;
+        writer.u16(0)?;
+        writer.u16(additional.len() as u16)?;
+        for question in questions {
+            writer.name(&[&question.name])?;
+            writer.u16(question.kind)?;
+            writer.u16(CLASS_IN)?;
+        }
+        for record in answers.iter().chain(additional) {
+            self.write_record(&mut writer, record, legacy)?;
+        }
+        Some(writer.length)
+    }
+
+    /// Response to a query, None if nothing was asked about this host.
+    /// `legacy` is for queries not from port 5353, which get the query ID and
+    /// questions back.
+    pub fn respond(
+        &self,
+        id: u16,
+        questions: &[Question],
+        legacy: bool,
+        out: &mut [u8],
+    ) -> Option<usize> {
+        let mut answers = Vec::new();
+        let mut additional = Vec::new();
+        for question in questions {
+            self.answer(question, &mut answers, &mut additional);
+        }
+        if answers.is_empty() {
+            return None;
+        }
+        additional.retain(|record| !answers.contains(record));
+        match legacy {
+            true => self.write_response(id, questions, &answers, &additional, out),
+            false => self.write_response(0, &[], &answers, &additional, out),
+        }
+    }
+
+    /// Unsolicited response with all records (RFC 6762 section 8.3)
+    pub fn announcement(&self, out: &mut [u8]) -> Option<usize> {
+        let mut records: Vec<Record, MAX_RECORDS> = self.addresses().collect();
+        for service in self.services {
+            for record in [
+                Record::Types(service),
+                Record::Ptr(service),
+                Record::Srv(service),
+                Record::Txt(service),
+            ] {
+                records.push(record).ok()?;
+            }
+        }
+        self.write_response(0, &[], &records, &[], out)
+    }
+}
+
+/// Writes into a byte slice, failing once it's full
+struct Writer<'a> {
+    out: &'a mut [u8],
+    length: usize,
+    /// Offsets of the labels written, to point to for compression
+    labels: Vec<u16, MAX_LABELS>,
+}
+
+impl<'a> Writer<'a> {
+    fn new(out: &'a mut [u8]) -> Self {
+        Writer {
+            out,
+            length: 0,
+            labels: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) -> Option<()> {
+        let end = self.length + bytes.len();
+        self.out.get_mut(self.length..end)?.copy_from_slice(bytes);
+        self.length = end;
+        Some(())
+    }
+
+    fn u16(&mut self, value: u16) -> Option<()> {
+        self.push(&value.to_be_bytes())
+    }
+
+    /// Offset of a name written before that equals `labels`
+    fn find(&self, labels: &[&str]) -> Option<u16> {
+        self.labels.iter().copied().find(|&offset| {
+            read_name(&self.out[..self.length], offset.into())
+                .is_some_and(|(name, _)| name_eq(&name, labels))
+        })
+    }
+
+    /// Name from parts that are dotted labels themselves, compressed by
+    /// pointing to the longest suffix written before (RFC 1035 section 4.1.4)
+    fn name(&mut self, parts: &[&str]) -> Option<()> {
+        let mut labels: Vec<&str, MAX_LABELS> = Vec::new();
+        for label in parts.iter().flat_map(|part| part.split('.')) {
+            if label.is_empty() || label.len() >= 64 {
+                return None;
+            }
+            labels.push(label).ok()?;
+        }
+        for start in 0..labels.len() {
+            if let Some(offset) = self.find(&labels[start..]) {
+                return self.u16(0xc000 | offset);
+            }
+            if let Ok(offset) = u16::try_from(self.length) {
+                if offset < 0x4000 {
+                    let _ = self.labels.push(offset);
+                }
+            }
+            self.push(&[labels[start].len() as u8])?;
+            self.push(labels[start].as_bytes())?;
+        }
+        self.push(&[0])
+    }
+}
+
+/// `sensors=` TXT entry listing the sensors found at boot
+fn sensors_txt(inventory: &Inventory) -> String<32> {
+    let mut txt = String::new();
+    let _ = txt.push_str("sensors=");
+    let sensors = [
+        ("scd41", inventory.scd41.is_some()),
+        ("bme680", inventory.bme680.is_some()),
+    ];
+    for (index, (sensor, _)) in sensors.iter().filter(|(_, found)| *found).enumerate() {
+        if index > 0 {
+            let _ = txt.push(',');
+        }
+        let _ = txt.push_str(sensor);
+    }
+    txt
+}
+
+fn addresses(stack: Stack<'_>) -> (Option<Ipv4Addr>, Option<Ipv6Addr>) {
+    (
+        stack.config_v4().map(|config| config.address.address()),
+        stack.config_v6().map(|config| config.address.address()),
+    )
+}
+
+#[embassy_executor::task]
+pub async fn responder(stack: Stack<'static>, inventory: Inventory) -> ! {
+    let hostname = match hostname(HOSTNAME, device::id()) {
+        Ok(hostname) => hostname,
+        Err(err) => {
+            error!("mDNS: invalid hostname {}: {:?}", HOSTNAME, err);
+            loop {
+                Timer::after_secs(3600).await;
+            }
+        }
+    };
+    let sensors = sensors_txt(&inventory);
+    let txt = [concat!("version=", env!("CARGO_PKG_VERSION")), sensors.as_str()];
+    let http = Service {
+        kind: "_http._tcp",
+        port: HTTP_PORT,
+        txt: &txt,
+    };
+    let prometheus = Service {
+        kind: "_prometheus-http._tcp",
+        ..http
+    };
+    let both = [http, prometheus];
+    let services = match PROMETHEUS {
+        true => &both[..],
+        false => &both[..1],
+    };
+
+    for group in [IpAddress::Ipv4(GROUP_V4), IpAddress::Ipv6(GROUP_V6)] {
+        if let Err(err) = stack.join_multicast_group(group) {
+            warn!("mDNS: failed to join {}: {:?}", group, err);
+        }
+    }
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; MAX_MESSAGE * 2];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0; MAX_MESSAGE * 2];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(err) = socket.bind(PORT) {
+        error!("mDNS: failed to bind port {}: {:?}", PORT, err);
+    }
+    // Multicast responses are only accepted from the link itself
+    socket.set_hop_limit(Some(255));
+    info!("mDNS: responding for {}.local", hostname.as_str());
+
+    let mut announced = (None, None);
+    let mut message = [0; MAX_MESSAGE];
+    let mut response = [0; MAX_MESSAGE];
+    loop {
+        let (ipv4, ipv6) = addresses(stack);
+        let host = Host {
+            name: &hostname,
+            ipv4,
+            ipv6,
+            services,
+        };
+        if (ipv4, ipv6) != announced && (ipv4.is_some() || ipv6.is_some()) {
+            if let Some(length) = host.announcement(&mut response) {
+                // Sent twice a second apart (RFC 6762 section 8.3)
+                for _ in 0..2 {
+                    for group in [IpAddress::Ipv4(GROUP_V4), IpAddress::Ipv6(GROUP_V6)] {
+                        let _ = socket.send_to(&response[..length], (group, PORT)).await;
+                    }
+                    Timer::after_secs(1).await;
+                }
+                info!("mDNS: announced {} {}", ipv4, ipv6);
+            }
+            announced = (ipv4, ipv6);
+        }
+
+        let (length, meta) = match select(
+            socket.recv_from(&mut message),
+            Timer::after(CHECK_INTERVAL),
+        )
+        .await
+        {
+            Either::First(Ok(received)) => received,
+            Either::First(Err(_)) => {
+                warn!("mDNS: query too long");
+                continue;
+            }
+            Either::Second(()) => continue,
+        };
+        let Some((id, questions)) = parse_query(&message[..length]) else {
+            continue;
+        };
+        let legacy = meta.endpoint.port != PORT;
+        let Some(length) = host.respond(id, &questions, legacy, &mut response) else {
+            continue;
+        };
+        let unicast = legacy || questions.iter().all(|question| question.unicast);
+        let destination: IpEndpoint = match (unicast, meta.endpoint.addr) {
+            (true, _) => meta.endpoint,
+            (false, IpAddress::Ipv4(_)) => (IpAddress::Ipv4(GROUP_V4), PORT).into(),
+            (false, IpAddress::Ipv6(_)) => (IpAddress::Ipv6(GROUP_V6), PORT).into(),
+        };
+        debug!("mDNS: answering {} with {} bytes", meta.endpoint, length);
+        if let Err(err) = socket.send_to(&response[..length], destination).await {
+            warn!("mDNS: failed to send response: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smoltcp::wire::DnsPacket;
+
+    use super::*;
+
+    const TXT: [&str; 2] = ["version=0.1.0", "sensors=scd41,bme680"];
+    const HTTP: Service = Service {
+        kind: "_http._tcp",
+        port: 80,
+        txt: &TXT,
+    };
+    const SERVICES_: [Service; 1] = [HTTP];
+    const HOST: Host = Host {
+        name: "air-40a3cc01f2e4",
+        ipv4: Some(Ipv4Addr::new(192, 168, 1, 5)),
+        ipv6: Some(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 5)),
+        services: &SERVICES_,
+    };
+
+    /// Record decoded independently, names through smoltcp
+    #[derive(Debug, PartialEq)]
+    struct Decoded {
+        name: std::string::String,
+        kind: u16,
+        class: u16,
+        ttl: u32,
+        data: std::vec::Vec<u8>,
+        /// Name the data is or ends with, for PTR and SRV records
+        target: Option<std::string::String>,
+    }
+
+    fn name_at(message: &[u8], offset: usize) -> std::string::String {
+        let packet = DnsPacket::new_unchecked(message);
+        let labels: std::vec::Vec<_> = packet
+            .parse_name(&message[offset..])
+            .map(|label| std::str::from_utf8(label.unwrap()).unwrap())
+            .collect();
+        labels.join(".")
+    }
+
+    fn skip_name(message: &[u8], mut offset: usize) -> usize {
+        loop {
+            match message[offset] {
+                0 => return offset + 1,
+                length if length & 0xc0 == 0xc0 => return offset + 2,
+                length => offset += 1 + usize::from(length),
+            }
+        }
+    }
+
+    /// Header counts and the records of a message, after its questions
+    fn decode(message: &[u8]) -> ([u16; 4], std::vec::Vec<Decoded>) {
+        let count = |index: usize| u16_at(message, 4 + 2 * index).unwrap();
+        let counts = [count(0), count(1), count(2), count(3)];
+        let mut offset = HEADER_LENGTH;
+        for _ in 0..counts[0] {
+            offset = skip_name(message, offset) + 4;
+        }
+        let mut records = vec![];
+        for _ in 0..counts[1] + counts[2] + counts[3] {
+            let name = name_at(message, offset);
+            offset = skip_name(message, offset);
+            let kind = u16_at(message, offset).unwrap();
+            let length = usize::from(u16_at(message, offset + 8).unwrap());
+            let data_at = offset + 10;
+            let target = match kind {
+                TYPE_PTR => Some(name_at(message, data_at)),
+                TYPE_SRV => Some(name_at(message, data_at + 6)),
+                _ => None,
+            };
+            records.push(Decoded {
+                name,
+                kind,
+                class: u16_at(message, offset + 2).unwrap(),
+                ttl: u32::from_be_bytes(message[offset + 4..offset + 8].try_into().unwrap()),
+                data: message[data_at..data_at + length].to_vec(),
+                target,
+            });
+            offset = data_at + length;
+        }
+        assert_eq!(offset, message.len());
+        (counts, records)
+    }
+
+    /// Query with one question per `(name, kind)`, names uncompressed
+    fn query(id: u16, questions: &[(&str, u16)]) -> std::vec::Vec<u8> {
+        let mut message = vec![0; HEADER_LENGTH];
+        message[0..2].copy_from_slice(&id.to_be_bytes());
+        message[4..6].copy_from_slice(&(questions.len() as u16).to_be_bytes());
+        for (name, kind) in questions {
+            for label in name.split('.') {
+                message.push(label.len() as u8);
+                message.extend(label.as_bytes());
+            }
+            message.push(0);
+            message.extend(kind.to_be_bytes());
+            message.extend(CLASS_IN.to_be_bytes());
+        }
+        message
+    }
+
+    fn respond(questions: &[(&str, u16)], legacy: bool) -> Option<std::vec::Vec<u8>> {
+        let (id, questions) = parse_query(&query(0x1234, questions)).unwrap();
+        let mut out = [0; MAX_MESSAGE];
+        let length = HOST.respond(id, &questions, legacy, &mut out)?;
+        Some(out[..length].to_vec())
+    }
+
+    #[test]
+    fn hostname_must_be_unique() {
+        assert_eq!(
+            hostname("air-{device_id}", "40a3cc01f2e4")
+                .unwrap()
+                .as_str(),
+            "air-40a3cc01f2e4"
+        );
+        assert_eq!(hostname("air", "40a3cc01f2e4"), Err(Error::NotUnique));
+        assert_eq!(hostname("{device}", "40a3cc01f2e4"), Err(Error::NotUnique));
+        let long = "a".repeat(MAX_HOSTNAME) + "{device_id}";
+        assert_eq!(hostname(&long, "40a3cc01f2e4"), Err(Error::TooLong));
+    }
+
+    #[test]
+    fn parses_compressed_questions() {
+        let mut message = query(0x1234, &[("_http._tcp.local", TYPE_PTR)]);
+        message[5] = 2;
+        // Second question pointing to `local` in the first, asking for a
+        // unicast response
+        message.push(16);
+        message.extend(b"AIR-40a3cc01f2e4");
+        message.extend([0xc0, 23]);
+        message.extend(TYPE_A.to_be_bytes());
+        message.extend((CLASS_IN | UNICAST_RESPONSE).to_be_bytes());
+
+        let (id, questions) = parse_query(&message).unwrap();
+        assert_eq!(id, 0x1234);
+        assert_eq!(questions.len(), 2);
+        assert_eq!(questions[0].name.as_str(), "_http._tcp.local");
+        assert_eq!(questions[0].kind, TYPE_PTR);
+        assert!(!questions[0].unicast);
+        assert_eq!(questions[1].name.as_str(), "AIR-40a3cc01f2e4.local");
+        assert!(questions[1].unicast);
+    }
+
+    #[test]
+    fn rejects_invalid_queries() {
+        let message = query(0, &[("air.local", TYPE_A)]);
+        let mut response = message.clone();
+        response[2] = 0x84;
+        assert_eq!(parse_query(&response), None);
+        assert_eq!(parse_query(&message[..message.len() - 2]), None);
+        assert_eq!(parse_query(&message[..HEADER_LENGTH - 1]), None);
+
+        // A pointer to itself
+        let mut looped = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0xc0, 12];
+        looped.extend([0, 1, 0, 1]);
+        assert_eq!(parse_query(&looped), None);
+
+        // Questions of other classes are skipped
+        let mut chaos = message.clone();
+        let class_at = chaos.len() - 1;
+        chaos[class_at] = 3;
+        assert_eq!(parse_query(&chaos).unwrap().1.len(), 0);
+    }
+
+    #[test]
+    fn names_compare_ignoring_case() {
+        assert!(name_eq("AIR-1.Local", &["air-1", "local"]));
+        assert!(name_eq("a.b._tcp.local", &["a.b", "_tcp", "local"]));
+        assert!(!name_eq("air-1.local", &["air-1"]));
+        assert!(!name_eq("air-1local", &["air-1", "local"]));
+        assert!(!name_eq("air-1.local.x", &["air-1", "local"]));
+    }
+
+    #[test]
+    fn compresses_names() {
+        let mut out = [0; 64];
+        let mut writer = Writer::new(&mut out);
+        writer.name(&["a.b", LOCAL]).unwrap();
+        writer.name(&["c", "b", LOCAL]).unwrap();
+        writer.name(&["a.b.local"]).unwrap();
+        let length = writer.length;
+        assert_eq!(
+            &out[..length],
+            b"\x01a\x01b\x05local\x00\x01c\xc0\x02\xc0\x00"
+        );
+        assert_eq!(name_at(&out[..length], 11), "c.b.local");
+        assert_eq!(name_at(&out[..length], 15), "a.b.local");
+
+        let mut writer = Writer::new(&mut out);
+        assert_eq!(writer.name(&["a..b"]), None);
+        assert_eq!(writer.name(&[&"a".repeat(64)]), None);
+    }
+
+    #[test]
+    fn answers_for_the_host() {
+        let message = respond(&[("AIR-40A3CC01F2E4.local", TYPE_A)], false).unwrap();
+        assert_eq!(u16_at(&message, 0), Some(0));
+        assert_eq!(u16_at(&message, 2), Some(FLAGS_RESPONSE));
+        let (counts, records) = decode(&message);
+        assert_eq!(counts, [0, 1, 0, 0]);
+        assert_eq!(
+            records,
+            [Decoded {
+                name: "air-40a3cc01f2e4.local".into(),
+                kind: TYPE_A,
+                class: CLASS_IN | CACHE_FLUSH,
+                ttl: HOST_TTL,
+                data: vec![192, 168, 1, 5],
+                target: None,
+            }]
+        );
+
+        let message = respond(&[("air-40a3cc01f2e4.local", TYPE_ANY)], false).unwrap();
+        let (_, records) = decode(&message);
+        assert_eq!(records[1].kind, TYPE_AAAA);
+        assert_eq!(records[1].data, HOST.ipv6.unwrap().octets());
+
+        assert_eq!(respond(&[("other.local", TYPE_A)], false), None);
+        assert_eq!(
+            respond(&[("air-40a3cc01f2e4.local", TYPE_TXT)], false),
+            None
+        );
+    }
+
+    #[test]
+    fn browsing_finds_the_service() {
+        let message = respond(&[("_http._tcp.local", TYPE_PTR)], false).unwrap();
+        let (counts, records) = decode(&message);
+        assert_eq!(counts, [0, 1, 0, 4]);
+        let instance = "air-40a3cc01f2e4._http._tcp.local";
+
+        let ptr = &records[0];
+        assert_eq!(ptr.name, "_http._tcp.local");
+        assert_eq!(
+            (ptr.kind, ptr.class, ptr.ttl),
+            (TYPE_PTR, CLASS_IN, OTHER_TTL)
+        );
+        assert_eq!(ptr.target.as_deref(), Some(instance));
+
+        let srv = &records[1];
+        assert_eq!(srv.name, instance);
+        assert_eq!(srv.kind, TYPE_SRV);
+        assert_eq!(&srv.data[..6], &[0, 0, 0, 0, 0, 80]);
+        assert_eq!(srv.target.as_deref(), Some("air-40a3cc01f2e4.local"));
+
+        let txt = &records[2];
+        assert_eq!(txt.kind, TYPE_TXT);
+        assert_eq!(
+            txt.data,
+            b"\x0dversion=0.1.0\x14sensors=scd41,bme680".to_vec()
+        );
+        assert_eq!(records[3].kind, TYPE_A);
+        assert_eq!(records[4].kind, TYPE_AAAA);
+
+        let message = respond(&[("_services._dns-sd._udp.local", TYPE_PTR)], false).unwrap();
+        let (_, records) = decode(&message);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].target.as_deref(), Some("_http._tcp.local"));
+    }
+
+    #[test]
+    fn legacy_queries_get_unicast_answers() {
+        let message = respond(&[("air-40a3cc01f2e4.local", TYPE_A)], true).unwrap();
+        assert_eq!(u16_at(&message, 0), Some(0x1234));
+        assert_eq!(name_at(&message, HEADER_LENGTH), "air-40a3cc01f2e4.local");
+        let (counts, records) = decode(&message);
+        assert_eq!(counts, [1, 1, 0, 0]);
+        assert_eq!(records[0].class, CLASS_IN);
+        assert_eq!(records[0].ttl, LEGACY_TTL);
+    }
+
+    #[test]
+    fn announces_every_record() {
+        let mut out = [0; MAX_MESSAGE];
+        let length = HOST.announcement(&mut out).unwrap();
+        let (counts, records) = decode(&out[..length]);
+        assert_eq!(counts, [0, 6, 0, 0]);
+        let kinds: std::vec::Vec<_> = records.iter().map(|record| record.kind).collect();
+        assert_eq!(
+            kinds,
+            [TYPE_A, TYPE_AAAA, TYPE_PTR, TYPE_PTR, TYPE_SRV, TYPE_TXT]
+        );
+
+        let empty = Service { txt: &[], ..HTTP };
+        let services = [empty];
+        let host = Host {
+            ipv6: None,
+            services: &services,
+            ..HOST
+        };
+        let length = host.announcement(&mut out).unwrap();
+        let (_, records) = decode(&out[..length]);
+        assert_eq!(records.last().unwrap().data, [0]);
+        assert_eq!(host.announcement(&mut out[..100]), None);
+    }
+
+    #[test]
+    fn lists_the_sensors_found() {
+        let mut inventory = Inventory {
+            scd41: Some(1),
+            bme680: Some(0x76),
+            ssd1306: None,
+        };
+        assert_eq!(sensors_txt(&inventory).as_str(), "sensors=scd41,bme680");
+        inventory.scd41 = None;
+        assert_eq!(sensors_txt(&inventory).as_str(), "sensors=bme680");
+        inventory.bme680 = None;
+        assert_eq!(sensors_txt(&inventory).as_str(), "sensors=");
+    }
+}
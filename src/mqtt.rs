@@ -1,17 +1,37 @@
-use defmt::{debug, error, expect, info, warn};
-use embassy_futures::select::{select, Either};
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::{debug, error, expect, info, warn, Format};
+use embassy_futures::select::{select, select4, Either, Either4};
 use embassy_net::{tcp::TcpSocket, Stack};
-use embassy_time::{Duration, Timer, WithTimeout};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant, Timer, WithTimeout};
+use heapless::String;
 use rust_mqtt::{
-    client::{client::MqttClient, client_config::ClientConfig},
+    client::{
+        client_config::ClientConfig,
+        raw_client::{Event, RawMqttClient},
+    },
     packet::v5::{publish_packet::QualityOfService, reason_codes::ReasonCode},
     utils::rng_generator::CountingRng,
 };
-use serde_json_core::ser::Error::BufferFull;
-use smoltcp::wire::DnsQueryType;
+use serde::Serialize;
 
-use crate::bme680;
-use crate::scd41;
+use crate::alerts;
+use crate::broker::{self, Endpoint, Failover};
+use crate::clock;
+use crate::config;
+use crate::deadband::{Deadband, Deadbands};
+use crate::device;
+use crate::exception;
+use crate::field_topics;
+use crate::fusion;
+use crate::i2c_scan::Inventory;
+use crate::payload::{self, Encoding};
+use crate::resolve;
+use crate::sink::{self, Reading};
+use crate::stats;
+use crate::ventilation;
 
 const MQTT_HOST: &str = env!("MQTT_HOST");
 const MQTT_PORT: u16 = const_parse_u16(env!("MQTT_PORT"));
@@ -19,6 +39,67 @@ const MQTT_USERNAME: &str = env!("MQTT_USERNAME");
 const MQTT_PASSWORD: &str = env!("MQTT_PASSWORD");
 const MQTT_TOPIC_SCD41: &str = env!("MQTT_TOPIC_SCD41");
 const MQTT_TOPIC_BME680: &str = env!("MQTT_TOPIC_BME680");
+const MQTT_TOPIC_SCD41_STATS: &str = concat!(env!("MQTT_TOPIC_SCD41"), "/stats");
+const MQTT_TOPIC_BME680_STATS: &str = concat!(env!("MQTT_TOPIC_BME680"), "/stats");
+/// Base of the device's own topics, alerts are published on `<base>/alert`
+const MQTT_BASE: &str = match option_env!("MQTT_BASE") {
+    Some(base) => base,
+    None => "air-quality/{device_id}",
+};
+const MQTT_TOPIC_VENTILATION: &str = match option_env!("MQTT_TOPIC_VENTILATION") {
+    Some(topic) => topic,
+    None => "air-quality/ventilation",
+};
+const MQTT_TOPIC_FUSION: &str = match option_env!("MQTT_TOPIC_FUSION") {
+    Some(topic) => topic,
+    None => "air-quality/fused",
+};
+/// Encoding of sensor readings, `json`, `senml` or `cbor`. Everything else is
+/// published as JSON.
+const MQTT_FORMAT: &str = match option_env!("MQTT_FORMAT") {
+    Some(format) => format,
+    None => "json",
+};
+const MQTT_TOPIC_HEALTH: &str = match option_env!("MQTT_TOPIC_HEALTH") {
+    Some(topic) => topic,
+    None => "air-quality/health",
+};
+/// Keep-alive interval in s agreed with the broker, a PINGREQ is sent every
+/// half interval
+const MQTT_KEEP_ALIVE: &str = match option_env!("MQTT_KEEP_ALIVE") {
+    Some(keep_alive) => keep_alive,
+    None => "60",
+};
+/// Seconds without a sensor reading before it's reported as a health issue
+const MQTT_SENSOR_TIMEOUT: &str = match option_env!("MQTT_SENSOR_TIMEOUT") {
+    Some(timeout) => timeout,
+    None => "60",
+};
+/// Longest wait for a reply from the broker
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+const MQTT_TOPIC_INVENTORY: &str = match option_env!("MQTT_TOPIC_INVENTORY") {
+    Some(topic) => topic,
+    None => "air-quality/inventory",
+};
+
+/// Messages from the analysis tasks waiting to be published. Producers
+/// should `try_send` so they keep running while the broker is unreachable.
+/// Sized for every alert rule changing state at once, with room for the
+/// ventilation advice and fusion estimate. Stats are queued in
+/// `stats::PENDING` instead.
+pub static OUTBOX: Channel<CriticalSectionRawMutex, Outgoing, { alerts::MAX_RULES + 2 }> =
+    Channel::new();
+
+#[derive(Debug, Format, Clone)]
+pub enum Outgoing {
+    Stats(stats::Report),
+    Alert(alerts::Event),
+    Ventilation(ventilation::Advice),
+    Fusion(fusion::Fused),
+}
+
+/// Whether the client currently holds a broker connection
+pub static CONNECTED: AtomicBool = AtomicBool::new(false);
 
 const fn const_parse_u16(s: &str) -> u16 {
     let bytes = s.as_bytes();
@@ -31,16 +112,250 @@ const fn const_parse_u16(s: &str) -> u16 {
     result
 }
 
+/// TCP socket shared by the MQTT client, which reads and writes it, and the
+/// connection loop, which waits for it to become readable. `poll` isn't
+/// cancel-safe as it reads a packet in pieces, so it's only called once a
+/// packet is arriving and never raced against anything else.
+struct Connection<'a, 's> {
+    socket: &'a RefCell<TcpSocket<'s>>,
+}
+
+impl embedded_io_async::ErrorType for Connection<'_, '_> {
+    type Error = embassy_net::tcp::Error;
+}
+
+// The socket is only borrowed by one of the client or the connection loop at
+// a time, so holding the borrow across awaits can't panic
+#[allow(clippy::await_holding_refcell_ref)]
+impl embedded_io_async::Read for Connection<'_, '_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.socket.borrow_mut().read(buf).await
+    }
+}
+
+#[allow(clippy::await_holding_refcell_ref)]
+impl embedded_io_async::Write for Connection<'_, '_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.socket.borrow_mut().write(buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.socket.borrow_mut().flush().await
+    }
+}
+
+#[allow(clippy::await_holding_refcell_ref)]
+async fn readable(socket: &RefCell<TcpSocket<'_>>) {
+    socket.borrow().wait_read_ready().await
+}
+
+/// Next message from the outbox or the pending stats reports
+async fn outgoing() -> Outgoing {
+    match select(OUTBOX.receive(), stats::PENDING.receive()).await {
+        Either::First(outgoing) => outgoing,
+        Either::Second(report) => Outgoing::Stats(report),
+    }
+}
+
+type Topic = String<{ field_topics::MAX_TOPIC }>;
+
+/// Configured topics with `{device_id}` expanded
+struct Topics {
+    scd41: Topic,
+    bme680: Topic,
+    scd41_stats: Topic,
+    bme680_stats: Topic,
+    alert: Topic,
+    ventilation: Topic,
+    fusion: Topic,
+    health: Topic,
+    inventory: Topic,
+}
+
+impl Topics {
+    fn new() -> Self {
+        let expand = |template| {
+            expect!(
+                device::expand(template),
+                "MQTT topic {} should fit {} bytes",
+                template,
+                field_topics::MAX_TOPIC
+            )
+        };
+        Topics {
+            scd41: expand(MQTT_TOPIC_SCD41),
+            bme680: expand(MQTT_TOPIC_BME680),
+            scd41_stats: expand(MQTT_TOPIC_SCD41_STATS),
+            bme680_stats: expand(MQTT_TOPIC_BME680_STATS),
+            alert: {
+                let mut topic: Topic = expand(MQTT_BASE);
+                expect!(
+                    topic.push_str("/alert").ok(),
+                    "MQTT alert topic should fit {} bytes",
+                    field_topics::MAX_TOPIC
+                );
+                topic
+            },
+            ventilation: expand(MQTT_TOPIC_VENTILATION),
+            fusion: expand(MQTT_TOPIC_FUSION),
+            health: expand(MQTT_TOPIC_HEALTH),
+            inventory: expand(MQTT_TOPIC_INVENTORY),
+        }
+    }
+}
+
+type Client<'a, 's> = RawMqttClient<'a, Connection<'a, 's>, 10, CountingRng>;
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq, Serialize)]
+struct Health<'a> {
+    /// Whether no sensor reading arrived within the sensor timeout
+    sensors_silent: bool,
+    /// Seconds since the last sensor reading, None if there hasn't been one
+    last_reading: Option<u32>,
+    /// Host of the broker connected to
+    broker: &'a str,
+}
+
+async fn publish<T: embedded_io_async::Read + embedded_io_async::Write>(
+    client: &mut RawMqttClient<'_, T, 10, CountingRng>,
+    topic: &str,
+    message: &[u8],
+    retain: bool,
+) -> Result<(), ReasonCode> {
+    client
+        .send_message(topic, message, QualityOfService::QoS1, retain)
+        .await
+        .map(|_packet_identifier| ())
+}
+
+/// Publish the fields of a reading that changed beyond their deadband on
+/// their own topics
+async fn publish_fields(
+    client: &mut Client<'_, '_>,
+    reading: &Reading,
+    deadbands: &mut Deadbands<'_>,
+) -> Result<(), ReasonCode> {
+    let (sensor, records) = payload::records(reading);
+    for record in records {
+        if !deadbands.changed(sensor, record.name, record.value) {
+            continue;
+        }
+        deadbands.publish(sensor, record.name, record.value);
+        let decimals = config::lookup(field_topics::PRECISION, record.name).unwrap_or(1.0);
+        let (Some(topic), Some(message)) = (
+            field_topics::topic::<{ field_topics::MAX_TOPIC }>(
+                field_topics::TEMPLATE,
+                field_topics::BASE,
+                device::id(),
+                sensor,
+                record.name,
+            ),
+            field_topics::format_value::<{ field_topics::MAX_PAYLOAD }>(
+                record.value,
+                decimals as usize,
+            ),
+        ) else {
+            error!("MQTT: topic or value of {} too long", record.name);
+            continue;
+        };
+        publish(client, &topic, message.as_bytes(), field_topics::RETAIN).await?;
+    }
+    Ok(())
+}
+
+/// Keep-alive of a connection, a PINGREQ being due every half keep-alive
+/// interval. A broker that hasn't answered one by the time the next is due
+/// counts as gone, well before it would drop the connection itself after 1.5
+/// intervals of silence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct KeepAlive {
+    interval: Duration,
+    last_ping: Instant,
+    outstanding: bool,
+}
+
+impl KeepAlive {
+    /// Keep-alive of `keep_alive` s for a connection made at `now`
+    fn new(keep_alive: u16, now: Instant) -> Self {
+        KeepAlive {
+            interval: Duration::from_secs(u64::from(keep_alive) / 2),
+            last_ping: now,
+            outstanding: false,
+        }
+    }
+
+    /// When the next PINGREQ is due
+    fn due(&self) -> Instant {
+        self.last_ping + self.interval
+    }
+
+    /// Record a PINGREQ sent at `now`, failing if the last one is still
+    /// unanswered
+    fn ping(&mut self, now: Instant) -> Result<(), ()> {
+        if self.outstanding {
+            return Err(());
+        }
+        self.last_ping = now;
+        self.outstanding = true;
+        Ok(())
+    }
+
+    /// Record a PINGRESP
+    fn pong(&mut self) {
+        self.outstanding = false;
+    }
+}
+
+/// Handle a packet from the broker, failing if the connection should be
+/// re-established
+fn handle(event: Result<Event<'_>, ReasonCode>, keep_alive: &mut KeepAlive) -> Result<(), ()> {
+    match event {
+        Ok(Event::Pingresp) => {
+            debug!("MQTT: got PINGRESP");
+            keep_alive.pong();
+        }
+        Ok(Event::Puback(packet_identifier)) => {
+            debug!("MQTT: message {} acknowledged", packet_identifier)
+        }
+        Ok(Event::Message(topic, message)) => {
+            info!("MQTT: got {} bytes on {}", message.len(), topic)
+        }
+        Ok(Event::Disconnect(reason)) => {
+            error!("MQTT: disconnected by broker: {:?}", reason);
+            return Err(());
+        }
+        Ok(Event::Connack | Event::Suback(_) | Event::Unsuback(_)) => {}
+        // A PUBACK with this reason code isn't our fault
+        Err(ReasonCode::NoMatchingSubscribers) => debug!("MQTT: no matching subscribers"),
+        Err(err) => {
+            error!("MQTT: error receiving packet: {:?}", err);
+            return Err(());
+        }
+    }
+    Ok(())
+}
+
 #[embassy_executor::task]
-pub async fn client(stack: Stack<'static>) {
-    let mut scd41_receiver = expect!(
-        scd41::WATCH.receiver(),
-        "SCD41 Watch should have capacity for MQTT Receiver"
-    );
-    let mut bme680_receiver = expect!(
-        bme680::WATCH.receiver(),
-        "BME680 Watch should have capacity for MQTT Receiver"
+pub async fn client(stack: Stack<'static>, inventory: Inventory) {
+    let encoding = Encoding::parse(MQTT_FORMAT).unwrap_or_else(|| {
+        warn!("MQTT: unknown format {}, using JSON", MQTT_FORMAT);
+        Encoding::Json
+    });
+    let topics = Topics::new();
+    info!("MQTT: device ID {}", device::id());
+    let keep_alive: u16 = MQTT_KEEP_ALIVE.parse().unwrap_or(60).max(2);
+    let sensor_timeout = Duration::from_secs(MQTT_SENSOR_TIMEOUT.parse().unwrap_or(60));
+    let mut last_reading: Option<Instant> = None;
+    let boot = Instant::now();
+    let brokers = broker::endpoints(
+        broker::BROKERS,
+        MQTT_PORT,
+        Endpoint {
+            host: MQTT_HOST,
+            port: MQTT_PORT,
+        },
     );
+    let mut failover = Failover::new(brokers.len(), broker::Settings::configured());
 
     loop {
         let mut rx_buffer = [0; 4096];
@@ -51,27 +366,24 @@ pub async fn client(stack: Stack<'static>) {
 
         socket.set_timeout(Some(embassy_time::Duration::from_secs(10)));
 
-        let address = match stack
-            .dns_query(MQTT_HOST, DnsQueryType::A)
-            .await
-            .map(|a| a[0])
-        {
-            Ok(address) => address,
+        let endpoint = brokers[failover.active()];
+        let addresses = match resolve::addresses(stack, endpoint.host).await {
+            Ok(addresses) => addresses,
             Err(e) => {
                 error!("DNS lookup error: {:?}", e);
+                failover.failed(Instant::now().as_secs());
                 continue;
             }
         };
-        info!("resolved {} to: {}", MQTT_HOST, address);
+        info!("resolved {} to: {}", endpoint.host, addresses.as_slice());
 
-        let remote_endpoint = (address, MQTT_PORT);
-        info!("connecting...");
-        let connection = socket.connect(remote_endpoint).await;
-        if let Err(e) = connection {
+        if let Err(e) = resolve::connect(stack, &mut socket, &addresses, endpoint.port).await {
             error!("connect error: {:?}", e);
+            failover.failed(Instant::now().as_secs());
             continue;
         }
         info!("connected!");
+        let socket = RefCell::new(socket);
 
         let mut config = ClientConfig::new(
             rust_mqtt::client::client_config::MqttVersion::MQTTv5,
@@ -81,11 +393,13 @@ pub async fn client(stack: Stack<'static>) {
         config.add_username(MQTT_USERNAME);
         config.add_password(MQTT_PASSWORD);
         config.max_packet_size = 1024;
+        config.keep_alive = keep_alive;
+        config.add_client_id(device::id());
         let mut recv_buffer = [0; 2048];
         let mut write_buffer = [0; 2048];
 
-        let mut client = MqttClient::<_, 10, _>::new(
-            socket,
+        let mut client = RawMqttClient::new(
+            Connection { socket: &socket },
             &mut write_buffer,
             2048,
             &mut recv_buffer,
@@ -93,71 +407,471 @@ pub async fn client(stack: Stack<'static>) {
             config,
         );
 
-        match client.connect_to_broker().await {
-            Ok(()) => {
-                info!("Connected to broker!")
+        let connack = async {
+            client.connect_to_broker().await?;
+            match client.poll::<1>().await? {
+                Event::Connack => Ok(()),
+                Event::Disconnect(reason) => Err(reason),
+                _ => Err(ReasonCode::ImplementationSpecificError),
             }
-            Err(mqtt_error) => match mqtt_error {
-                ReasonCode::NetworkError => {
-                    error!("MQTT Network Error");
-                    continue;
+        };
+        match connack.with_timeout(RESPONSE_TIMEOUT).await {
+            Ok(Ok(())) => {
+                info!("Connected to broker {}:{}!", endpoint.host, endpoint.port);
+                CONNECTED.store(true, Ordering::Relaxed);
+                failover.connected();
+            }
+            Ok(Err(mqtt_error)) => {
+                match mqtt_error {
+                    ReasonCode::NetworkError => error!("MQTT Network Error"),
+                    _ => error!("Other MQTT Error: {:?}", mqtt_error),
                 }
-                _ => {
-                    error!("Other MQTT Error: {:?}", mqtt_error);
-                    continue;
+                failover.failed(Instant::now().as_secs());
+                continue;
+            }
+            Err(_) => {
+                error!("MQTT: timed out waiting for CONNACK");
+                failover.failed(Instant::now().as_secs());
+                continue;
+            }
+        }
+
+        // Report which sensors were found at boot, retained so that consumers
+        // connecting later still see it
+        let mut buf = [0u8; 128];
+        match serde_json_core::to_slice(&inventory, &mut buf) {
+            Ok(size) => {
+                match publish(&mut client, &topics.inventory, &buf[..size], true).await {
+                    Ok(()) => info!("MQTT: published sensor inventory"),
+                    Err(err) => {
+                        error!("MQTT: error publishing sensor inventory: {:?}", err);
+                        CONNECTED.store(false, Ordering::Relaxed);
+                        continue; // Re-connect to broker
+                    }
                 }
-            },
+            }
+            Err(_) => error!("MQTT: failed to serialize sensor inventory"),
         }
 
-        // TODO: this loop needs work
-        while let Ok(val) = select(scd41_receiver.changed(), bme680_receiver.changed())
-            .with_timeout(Duration::from_secs(5))
-            .await
-            .map_err(|_timeout_error| error!("MQTT: timed out waiting for sensor value"))
-        {
-            debug!("MQTT: receiver got val: {:?}", val);
+        // Readings and fields are all published again after reconnecting
+        let mut filter = exception::Filter::new(exception::Settings::configured());
+        let mut deadbands = Deadbands::new(Deadband::configured());
+        let mut pings = KeepAlive::new(keep_alive, Instant::now());
+        // Health is published on connecting and whenever it changes
+        let mut reported_health = None;
 
-            // Serialize the message to JSON
-            let mut buf = [0u8; 512];
-            let (topic, serialization_result) = match val {
-                Either::First(scd41_measurement) => (
-                    MQTT_TOPIC_SCD41,
-                    serde_json_core::to_slice(&scd41_measurement, &mut buf),
-                ),
-                Either::Second(bme680_measurement) => (
-                    MQTT_TOPIC_BME680,
-                    serde_json_core::to_slice(&bme680_measurement, &mut buf),
-                ),
+        // Runs until the connection fails
+        loop {
+            let silent_since = last_reading.unwrap_or(boot);
+            let silent = silent_since.elapsed() >= sensor_timeout;
+            let health = Health {
+                sensors_silent: silent,
+                last_reading: last_reading.map(|time| time.elapsed().as_secs() as u32),
+                broker: endpoint.host,
             };
-            let message = match serialization_result
-                .map(|size| &buf[..size])
-                .map_err(|err| match err {
-                    BufferFull => error!("MQTT: serialized value exceeded 512 bytes"),
-                    error => error!("MQTT: serialization error: {:?}", error),
-                }) {
-                Ok(message) => message,
-                Err(()) => continue, // Can't send this value, try again with the next
-            };
-            debug!("MQTT: created payload of size: {} bytes", message.len());
+            if reported_health.is_none_or(|reported: Health| reported.sensors_silent != silent) {
+                if silent {
+                    warn!(
+                        "MQTT: no sensor readings for {} s",
+                        silent_since.elapsed().as_secs()
+                    );
+                }
+                let mut buf = [0u8; 128];
+                if let Ok(size) = serde_json_core::to_slice(&health, &mut buf) {
+                    if let Err(err) = publish(&mut client, &topics.health, &buf[..size], true).await
+                    {
+                        error!("MQTT: error publishing health: {:?}", err);
+                        break; // Re-connect to broker
+                    }
+                }
+                reported_health = Some(health);
+            }
 
-            // Send the message
-            match client
-                .send_message(topic, message, QualityOfService::QoS1, false)
-                .await
+            let silence = if silent {
+                Instant::MAX
+            } else {
+                silent_since + sensor_timeout
+            };
+            let probe = failover
+                .probe_at()
+                .map_or(Instant::MAX, Instant::from_secs);
+            let deferred = filter.due().map_or(Instant::MAX, Instant::from_secs);
+            // Whether the value is a reading held back by the exception
+            // filter, due now
+            let (val, was_deferred) = match select4(
+                select(sink::MQTT.receive(), outgoing()),
+                Timer::at(pings.due()),
+                readable(&socket),
+                Timer::at(silence.min(probe).min(deferred)),
+            )
+            .await
             {
-                Ok(()) => info!("MQTT: message sent successfully!"),
-                Err(ReasonCode::NoMatchingSubscribers) => {
-                    error!("MQTT: no matching subscribers");
-                    continue; // Not our fault, so we'll try again with the next value
+                Either4::First(val) => (val, false),
+                Either4::Second(()) => {
+                    if pings.ping(Instant::now()).is_err() {
+                        error!("MQTT: no PINGRESP within {} s", pings.interval.as_secs());
+                        break; // Re-connect to broker
+                    }
+                    if let Err(err) = client.send_ping().await {
+                        error!("MQTT: error sending PINGREQ: {:?}", err);
+                        break; // Re-connect to broker
+                    }
+                    continue;
+                }
+                Either4::Third(()) => {
+                    // The packet is already arriving, so it should complete quickly
+                    let event = client.poll::<1>().with_timeout(RESPONSE_TIMEOUT).await;
+                    let handled = match event {
+                        Ok(event) => handle(event, &mut pings),
+                        Err(_) => {
+                            error!("MQTT: timed out receiving packet");
+                            Err(())
+                        }
+                    };
+                    if handled.is_err() {
+                        break; // Re-connect to broker
+                    }
+                    continue;
+                }
+                Either4::Fourth(()) => {
+                    if let Some(reading) = filter.take_due(Instant::now().as_secs()) {
+                        debug!("MQTT: publishing held back reading");
+                        (Either::First(reading), true)
+                    } else if failover.probe(Instant::now().as_secs()) {
+                        info!("MQTT: trying the primary broker again");
+                        let _ = client.disconnect().await;
+                        break; // Re-connect to broker
+                    } else {
+                        // Loops round to report the silence
+                        continue;
+                    }
+                }
+            };
+            debug!("MQTT: receiver got val: {:?}", val);
+
+            let mut suppressed = false;
+            if let (Either::First(reading), false) = (&val, was_deferred) {
+                last_reading = Some(Instant::now());
+                if exception::ENABLED && !filter.publish(reading, Instant::now().as_secs()) {
+                    debug!(
+                        "MQTT: {} reading held back or within deadbands",
+                        reading.sensor()
+                    );
+                    suppressed = true;
                 }
-                Err(err) => {
-                    error!("MQTT: error sending message: {:?}", err);
+            }
+
+            if !suppressed {
+                // Serialize the message to JSON
+                let mut buf = [0u8; 768];
+                let (topic, serialization_result) = match &val {
+                    Either::First(reading) => (
+                        match reading {
+                            Reading::Scd41(_) => &topics.scd41,
+                            Reading::Bme680(_) => &topics.bme680,
+                        },
+                        payload::encode(encoding, reading, device::id(), clock::now(), &mut buf),
+                    ),
+                    Either::Second(Outgoing::Stats(stats::Report::Scd41(scd41_stats))) => (
+                        &topics.scd41_stats,
+                        serde_json_core::to_slice(scd41_stats, &mut buf)
+                            .map_err(payload::Error::from),
+                    ),
+                    Either::Second(Outgoing::Stats(stats::Report::Bme680(bme680_stats))) => (
+                        &topics.bme680_stats,
+                        serde_json_core::to_slice(bme680_stats, &mut buf)
+                            .map_err(payload::Error::from),
+                    ),
+                    Either::Second(Outgoing::Alert(alert)) => (
+                        &topics.alert,
+                        serde_json_core::to_slice(alert, &mut buf).map_err(payload::Error::from),
+                    ),
+                    Either::Second(Outgoing::Ventilation(advice)) => (
+                        &topics.ventilation,
+                        serde_json_core::to_slice(advice, &mut buf).map_err(payload::Error::from),
+                    ),
+                    Either::Second(Outgoing::Fusion(fused)) => (
+                        &topics.fusion,
+                        serde_json_core::to_slice(fused, &mut buf).map_err(payload::Error::from),
+                    ),
+                };
+
+                // Send the message, the PUBACK is handled with the other
+                // incoming packets. A message that can't be serialized is
+                // skipped, trying again with the next.
+                match serialization_result {
+                    Ok(size) => {
+                        debug!("MQTT: created payload of size: {} bytes", size);
+                        match publish(&mut client, topic, &buf[..size], false).await {
+                            Ok(()) => info!("MQTT: message sent successfully!"),
+                            Err(err) => {
+                                error!("MQTT: error sending message: {:?}", err);
+                                break; // Re-connect to broker
+                            }
+                        }
+                    }
+                    Err(payload::Error::BufferFull) => {
+                        error!("MQTT: serialized value exceeded {} bytes", buf.len())
+                    }
+                    Err(error) => error!("MQTT: serialization error: {:?}", error),
+                }
+            }
+
+            // Fields have deadbands of their own, so they're published even
+            // if the reading as a whole isn't
+            if let (true, Either::First(reading)) = (field_topics::ENABLED, &val) {
+                if let Err(err) = publish_fields(&mut client, reading, &mut deadbands).await {
+                    error!("MQTT: error sending field: {:?}", err);
                     break; // Re-connect to broker
                 }
             }
         }
 
-        // The inner loop runs until an error is encountered sending the message
+        CONNECTED.store(false, Ordering::Relaxed);
         warn!("MQTT: re-connecting to broker due to error");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+
+    use embassy_futures::block_on;
+    use rust_mqtt::client::client_config::MqttVersion;
+
+    use super::*;
+
+    /// Stand-in for the broker, answering with the packets in `replies`, one
+    /// per read as the client reads them whole, and recording what the client
+    /// sends
+    struct FakeBroker<'a> {
+        sent: &'a RefCell<std::vec::Vec<u8>>,
+        replies: &'a [&'a [u8]],
+    }
+
+    impl embedded_io_async::ErrorType for FakeBroker<'_> {
+        type Error = Infallible;
+    }
+
+    impl embedded_io_async::Read for FakeBroker<'_> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+            let Some((packet, rest)) = self.replies.split_first() else {
+                return Ok(0);
+            };
+            buf[..packet.len()].copy_from_slice(packet);
+            self.replies = rest;
+            Ok(packet.len())
+        }
+    }
+
+    impl embedded_io_async::Write for FakeBroker<'_> {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+            self.sent.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    const CONNACK: [u8; 5] = [0x20, 0x03, 0x00, 0x00, 0x00];
+    const PINGREQ: [u8; 2] = [0xc0, 0x00];
+    const PINGRESP: [u8; 2] = [0xd0, 0x00];
+
+    #[test]
+    fn pings_every_half_interval() {
+        let start = Instant::from_secs(100);
+        let mut pings = KeepAlive::new(60, start);
+        assert_eq!(pings.due(), Instant::from_secs(130));
+
+        assert_eq!(pings.ping(Instant::from_secs(130)), Ok(()));
+        assert_eq!(pings.due(), Instant::from_secs(160));
+        pings.pong();
+        assert_eq!(pings.ping(Instant::from_secs(160)), Ok(()));
+        assert_eq!(pings.due(), Instant::from_secs(190));
+    }
+
+    #[test]
+    fn unanswered_ping_drops_the_connection() {
+        let mut pings = KeepAlive::new(60, Instant::from_secs(0));
+        assert_eq!(pings.ping(Instant::from_secs(30)), Ok(()));
+        assert_eq!(pings.ping(Instant::from_secs(60)), Err(()));
+        // The failed check doesn't move the schedule on
+        assert_eq!(pings.due(), Instant::from_secs(60));
+    }
+
+    #[test]
+    fn broker_packets_are_handled() {
+        let mut pings = KeepAlive::new(60, Instant::from_secs(0));
+        pings.ping(Instant::from_secs(30)).unwrap();
+        assert_eq!(handle(Ok(Event::Pingresp), &mut pings), Ok(()));
+        assert!(!pings.outstanding);
+
+        assert_eq!(handle(Ok(Event::Puback(1)), &mut pings), Ok(()));
+        assert_eq!(
+            handle(Err(ReasonCode::NoMatchingSubscribers), &mut pings),
+            Ok(())
+        );
+        assert_eq!(
+            handle(
+                Ok(Event::Disconnect(ReasonCode::ServerShuttingDown)),
+                &mut pings
+            ),
+            Err(())
+        );
+        assert_eq!(handle(Err(ReasonCode::NetworkError), &mut pings), Err(()));
+    }
+
+    #[test]
+    fn keeps_the_connection_alive_with_a_broker() {
+        let replies = [CONNACK.as_slice(), &PINGRESP];
+        let sent = RefCell::new(vec![]);
+        let mut config = ClientConfig::new(MqttVersion::MQTTv5, CountingRng(20000));
+        config.keep_alive = 60;
+        config.add_client_id("test");
+        let mut recv_buffer = [0; 256];
+        let mut write_buffer = [0; 256];
+        let mut client = RawMqttClient::<_, 10, _>::new(
+            FakeBroker {
+                sent: &sent,
+                replies: &replies,
+            },
+            &mut write_buffer,
+            256,
+            &mut recv_buffer,
+            256,
+            config,
+        );
+
+        block_on(client.connect_to_broker()).unwrap();
+        assert!(matches!(block_on(client.poll::<1>()), Ok(Event::Connack)));
+
+        let mut pings = KeepAlive::new(60, Instant::from_secs(0));
+        pings.ping(Instant::from_secs(30)).unwrap();
+        block_on(client.send_ping()).unwrap();
+        let event = block_on(client.poll::<1>());
+        assert_eq!(handle(event, &mut pings), Ok(()));
+        assert_eq!(pings.ping(Instant::from_secs(60)), Ok(()));
+
+        // The broker is told the keep-alive the pings are scheduled by
+        let sent = sent.borrow();
+        let protocol = sent.windows(4).position(|name| name == b"MQTT").unwrap();
+        assert_eq!(sent[protocol + 4], 5);
+        assert_eq!(&sent[protocol + 6..protocol + 8], &60u16.to_be_bytes());
+        assert!(sent.ends_with(&PINGREQ));
+    }
+
+    /// Blocking TCP stream to a real broker, fine under `block_on`
+    struct StdConnection(std::net::TcpStream);
+
+    impl embedded_io_async::ErrorType for StdConnection {
+        type Error = embedded_io_async::ErrorKind;
+    }
+
+    impl embedded_io_async::Read for StdConnection {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            use std::io::Read;
+            self.0
+                .read(buf)
+                .map_err(|_| embedded_io_async::ErrorKind::Other)
+        }
+    }
+
+    impl embedded_io_async::Write for StdConnection {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            use std::io::Write;
+            self.0
+                .write(buf)
+                .map_err(|_| embedded_io_async::ErrorKind::Other)
+        }
+    }
+
+    /// Connect to the broker in `MQTT_TEST_BROKER`, a local one by default,
+    /// and wait for the CONNACK
+    fn connect_to_test_broker<'a>(
+        client_id: &'a str,
+        keep_alive: u16,
+        buffers: &'a mut [[u8; 1024]; 2],
+    ) -> RawMqttClient<'a, StdConnection, 10, CountingRng> {
+        let broker = std::env::var("MQTT_TEST_BROKER");
+        let broker = broker.as_deref().unwrap_or("127.0.0.1:1883");
+        let stream = std::net::TcpStream::connect(broker).expect("broker should be running");
+        stream
+            .set_read_timeout(Some(std::time::Duration::from_secs(10)))
+            .unwrap();
+        let mut config = ClientConfig::new(MqttVersion::MQTTv5, CountingRng(20000));
+        config.add_max_subscribe_qos(QualityOfService::QoS1);
+        config.max_packet_size = 1024;
+        config.keep_alive = keep_alive;
+        config.add_client_id(client_id);
+        let [recv_buffer, write_buffer] = buffers;
+        let mut client = RawMqttClient::new(
+            StdConnection(stream),
+            write_buffer,
+            1024,
+            recv_buffer,
+            1024,
+            config,
+        );
+        block_on(client.connect_to_broker()).unwrap();
+        assert!(matches!(block_on(client.poll::<1>()), Ok(Event::Connack)));
+        client
+    }
+
+    /// Run with `cargo test-host -- --ignored` and a broker listening, e.g.
+    /// `mosquitto -p 1883`
+    #[test]
+    #[ignore = "needs an MQTT broker at MQTT_TEST_BROKER, 127.0.0.1:1883 by default"]
+    fn keeps_the_connection_alive_with_a_real_broker() {
+        const KEEP_ALIVE: u16 = 2;
+        let topic = std::format!("air-test/{}/health", std::process::id());
+        let mut buffers = [[0; 1024]; 2];
+        let mut client = connect_to_test_broker("air-test-device", KEEP_ALIVE, &mut buffers);
+
+        // Ping for twice the keep-alive, well past the 1.5 times after which
+        // the broker drops a silent client
+        let mut pings = KeepAlive::new(KEEP_ALIVE, Instant::now());
+        let until = Instant::now() + Duration::from_secs(2 * u64::from(KEEP_ALIVE));
+        while Instant::now() < until {
+            block_on(Timer::at(pings.due()));
+            pings.ping(Instant::now()).unwrap();
+            block_on(client.send_ping()).unwrap();
+            let event = block_on(client.poll::<1>());
+            assert!(matches!(event, Ok(Event::Pingresp)));
+            assert_eq!(handle(event, &mut pings), Ok(()));
+        }
+
+        // Health is retained, so a consumer subscribing later gets it
+        let health = Health {
+            sensors_silent: true,
+            last_reading: Some(75),
+            broker: "127.0.0.1",
+        };
+        let mut payload = [0; 128];
+        let length = serde_json_core::to_slice(&health, &mut payload).unwrap();
+        let payload = &payload[..length];
+        block_on(publish(&mut client, &topic, payload, true)).unwrap();
+        assert!(matches!(block_on(client.poll::<1>()), Ok(Event::Puback(_))));
+
+        let mut buffers = [[0; 1024]; 2];
+        let mut consumer = connect_to_test_broker("air-test-consumer", 60, &mut buffers);
+        let topics: heapless::Vec<&str, 1> = heapless::Vec::from_slice(&[topic.as_str()]).unwrap();
+        block_on(consumer.subscribe_to_topics(&topics)).unwrap();
+        let mut retained = None;
+        for _ in 0..2 {
+            match block_on(consumer.poll::<1>()) {
+                Ok(Event::Suback(_)) => {}
+                Ok(Event::Message(received, message)) => {
+                    assert_eq!(received, topic);
+                    retained = Some(message.to_vec());
+                }
+                Ok(_) => panic!("unexpected packet"),
+                Err(reason) => panic!("subscribing failed: {reason:?}"),
+            }
+        }
+        assert_eq!(retained.as_deref(), Some(payload));
+
+        // Clear the retained message again
+        block_on(publish(&mut client, &topic, &[], true)).unwrap();
+        assert!(matches!(block_on(client.poll::<1>()), Ok(Event::Puback(_))));
+        block_on(client.disconnect()).unwrap();
+        block_on(consumer.disconnect()).unwrap();
+    }
+}
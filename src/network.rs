@@ -0,0 +1,76 @@
+//! IPv4 configuration of the network stack: DHCP with an optional hostname,
+//! or a static address, gateway and DNS servers for networks without DHCP
+
+use defmt::{error, info, Format, Formatter};
+use embassy_net::{Config, DhcpConfig, Ipv4Cidr, StaticConfigV4};
+
+use crate::device;
+use crate::network_settings::{parse_hostname, parse_static, Error, MAX_HOSTNAME};
+
+/// Static address with prefix length, e.g. `192.168.10.20/24`. DHCP is used
+/// when unset.
+const ADDRESS: &str = match option_env!("NETWORK_ADDRESS") {
+    Some(address) => address,
+    None => "",
+};
+const GATEWAY: &str = match option_env!("NETWORK_GATEWAY") {
+    Some(gateway) => gateway,
+    None => "",
+};
+/// DNS servers as `address,...` for a static address
+const DNS: &str = match option_env!("NETWORK_DNS") {
+    Some(dns) => dns,
+    None => "",
+};
+/// Hostname sent to the DHCP server, with `{device_id}` placeholders. None
+/// is sent when unset.
+const HOSTNAME: &str = match option_env!("NETWORK_HOSTNAME") {
+    Some(hostname) => hostname,
+    None => "",
+};
+
+// Implemented here as the build script compiles `network_settings` without
+// defmt
+impl Format for Error {
+    fn format(&self, f: Formatter) {
+        let name = match self {
+            Error::InvalidAddress => "InvalidAddress",
+            Error::UnusableAddress => "UnusableAddress",
+            Error::InvalidGateway => "InvalidGateway",
+            Error::UnreachableGateway => "UnreachableGateway",
+            Error::InvalidDnsServer => "InvalidDnsServer",
+            Error::TooManyDnsServers => "TooManyDnsServers",
+            Error::InvalidHostname => "InvalidHostname",
+        };
+        defmt::write!(f, "{}", name);
+    }
+}
+
+/// Network stack configuration from the settings. The build rejects an
+/// invalid static address, but one set through the environment falls back to
+/// DHCP, and an invalid hostname to none, so the board stays reachable.
+pub fn configured() -> Config {
+    match parse_static(ADDRESS, GATEWAY, DNS) {
+        Ok(Some(config)) => {
+            info!(
+                "Network: static address {}/{}",
+                config.address, config.prefix_len
+            );
+            return Config::ipv4_static(StaticConfigV4 {
+                address: Ipv4Cidr::new(config.address, config.prefix_len),
+                gateway: config.gateway,
+                dns_servers: config.dns_servers,
+            });
+        }
+        Ok(None) => {}
+        Err(err) => error!("Network: invalid static address, using DHCP: {:?}", err),
+    }
+
+    let mut dhcp = DhcpConfig::default();
+    let hostname = device::expand::<MAX_HOSTNAME>(HOSTNAME).ok_or(Error::InvalidHostname);
+    match hostname.and_then(|hostname| parse_hostname(&hostname)) {
+        Ok(hostname) => dhcp.hostname = hostname,
+        Err(err) => error!("Network: invalid hostname {}: {:?}", HOSTNAME, err),
+    }
+    Config::dhcpv4(dhcp)
+}
@@ -0,0 +1,285 @@
+//! Parsing and validation of the network settings. Needs nothing but `core`
+//! and heapless, so that the build script includes it as well and rejects an
+//! invalid static configuration instead of the board falling back to DHCP.
+
+use core::net::Ipv4Addr;
+
+use heapless::{String, Vec};
+
+/// Most DNS servers, as many as embassy-net keeps
+pub const MAX_DNS_SERVERS: usize = 3;
+/// Longest hostname embassy-net sends to the DHCP server
+pub const MAX_HOSTNAME: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The address isn't `a.b.c.d/prefix`
+    InvalidAddress,
+    /// The address is the network or broadcast address of its subnet, or not
+    /// a unicast address
+    UnusableAddress,
+    InvalidGateway,
+    /// The gateway isn't in the subnet of the address
+    UnreachableGateway,
+    InvalidDnsServer,
+    TooManyDnsServers,
+    /// The hostname is empty, too long or has characters other than letters,
+    /// digits and inner hyphens
+    InvalidHostname,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Static {
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Option<Ipv4Addr>,
+    pub dns_servers: Vec<Ipv4Addr, MAX_DNS_SERVERS>,
+}
+
+fn netmask(prefix_len: u8) -> u32 {
+    u32::MAX
+        .checked_shl(32 - u32::from(prefix_len))
+        .unwrap_or(0)
+}
+
+fn in_subnet(address: Ipv4Addr, network: Ipv4Addr, prefix_len: u8) -> bool {
+    let mask = netmask(prefix_len);
+    u32::from(address) & mask == u32::from(network) & mask
+}
+
+/// Parse and validate a static configuration. None if `address` is empty,
+/// meaning DHCP.
+pub fn parse_static(address: &str, gateway: &str, dns: &str) -> Result<Option<Static>, Error> {
+    let address = address.trim();
+    if address.is_empty() {
+        return Ok(None);
+    }
+    let (address, prefix_len) = address.split_once('/').ok_or(Error::InvalidAddress)?;
+    let address: Ipv4Addr = address.parse().map_err(|_| Error::InvalidAddress)?;
+    let prefix_len: u8 = prefix_len
+        .parse()
+        .ok()
+        .filter(|prefix_len| (1..=32).contains(prefix_len))
+        .ok_or(Error::InvalidAddress)?;
+    let host = u32::from(address) & !netmask(prefix_len);
+    // /31 and /32 have no network and broadcast addresses (RFC 3021)
+    let reserved = prefix_len < 31 && (host == 0 || host == !netmask(prefix_len));
+    if reserved
+        || address.is_unspecified()
+        || address.is_broadcast()
+        || address.is_multicast()
+        || address.is_loopback()
+    {
+        return Err(Error::UnusableAddress);
+    }
+
+    let gateway = match gateway.trim() {
+        "" => None,
+        gateway => {
+            let gateway: Ipv4Addr = gateway.parse().map_err(|_| Error::InvalidGateway)?;
+            if gateway == address || !in_subnet(gateway, address, prefix_len) {
+                return Err(Error::UnreachableGateway);
+            }
+            Some(gateway)
+        }
+    };
+
+    let mut dns_servers = Vec::new();
+    for server in dns
+        .split(',')
+        .map(str::trim)
+        .filter(|server| !server.is_empty())
+    {
+        let server = server.parse().map_err(|_| Error::InvalidDnsServer)?;
+        dns_servers
+            .push(server)
+            .map_err(|_| Error::TooManyDnsServers)?;
+    }
+
+    Ok(Some(Static {
+        address,
+        prefix_len,
+        gateway,
+        dns_servers,
+    }))
+}
+
+/// Validate a hostname as a single DNS label (RFC 1123). None if `hostname`
+/// is empty.
+pub fn parse_hostname(hostname: &str) -> Result<Option<String<MAX_HOSTNAME>>, Error> {
+    if hostname.is_empty() {
+        return Ok(None);
+    }
+    let valid = !hostname.starts_with('-')
+        && !hostname.ends_with('-')
+        && hostname
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-');
+    match valid {
+        true => String::try_from(hostname)
+            .map(Some)
+            .map_err(|()| Error::InvalidHostname),
+        false => Err(Error::InvalidHostname),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(address: &str) -> Ipv4Addr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_static_configuration() {
+        let config = parse_static(
+            " 192.168.10.20/24 ",
+            "192.168.10.1",
+            "192.168.10.1, 9.9.9.9",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(config.address, address("192.168.10.20"));
+        assert_eq!(config.prefix_len, 24);
+        assert_eq!(config.gateway, Some(address("192.168.10.1")));
+        assert_eq!(
+            config.dns_servers.as_slice(),
+            &[address("192.168.10.1"), address("9.9.9.9")]
+        );
+
+        // Gateway and DNS servers are optional
+        let config = parse_static("10.0.0.5/8", "", "").unwrap().unwrap();
+        assert_eq!(config.gateway, None);
+        assert!(config.dns_servers.is_empty());
+    }
+
+    #[test]
+    fn no_address_means_dhcp() {
+        // The other settings don't matter then
+        assert_eq!(parse_static("", "gateway", "x"), Ok(None));
+        assert_eq!(parse_static("  ", "", ""), Ok(None));
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        for invalid in [
+            "192.168.10.20",
+            "192.168.10.20/",
+            "192.168.10.20/0",
+            "192.168.10.20/33",
+            "192.168.10/24",
+            "air/24",
+        ] {
+            assert_eq!(
+                parse_static(invalid, "", ""),
+                Err(Error::InvalidAddress),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_unusable_addresses() {
+        for unusable in [
+            "192.168.10.0/24",
+            "192.168.10.255/24",
+            "0.0.0.0/8",
+            "255.255.255.255/32",
+            "224.0.0.251/24",
+            "127.0.0.1/8",
+        ] {
+            assert_eq!(
+                parse_static(unusable, "", ""),
+                Err(Error::UnusableAddress),
+                "{unusable}"
+            );
+        }
+        // Point-to-point links and single hosts have no network or broadcast
+        // address
+        assert!(parse_static("10.0.0.0/31", "10.0.0.1", "")
+            .unwrap()
+            .is_some());
+        assert!(parse_static("10.0.0.1/31", "10.0.0.0", "")
+            .unwrap()
+            .is_some());
+        assert!(parse_static("10.0.0.1/32", "", "").unwrap().is_some());
+    }
+
+    #[test]
+    fn checks_the_gateway() {
+        assert_eq!(
+            parse_static("192.168.10.20/24", "gateway", ""),
+            Err(Error::InvalidGateway)
+        );
+        assert_eq!(
+            parse_static("192.168.10.20/24", "192.168.11.1", ""),
+            Err(Error::UnreachableGateway)
+        );
+        assert_eq!(
+            parse_static("192.168.10.20/24", "192.168.10.20", ""),
+            Err(Error::UnreachableGateway)
+        );
+        // Reachable in a wider subnet
+        assert!(parse_static("192.168.10.20/16", "192.168.11.1", "")
+            .unwrap()
+            .is_some());
+        // A /32 reaches nothing
+        assert_eq!(
+            parse_static("10.0.0.1/32", "10.0.0.2", ""),
+            Err(Error::UnreachableGateway)
+        );
+    }
+
+    #[test]
+    fn checks_dns_servers() {
+        assert_eq!(
+            parse_static("192.168.10.20/24", "", "1.1.1.1,dns"),
+            Err(Error::InvalidDnsServer)
+        );
+        assert_eq!(
+            parse_static("192.168.10.20/24", "", "1.1.1.1,1.0.0.1,8.8.8.8,9.9.9.9"),
+            Err(Error::TooManyDnsServers)
+        );
+        // Empty entries are skipped
+        let config = parse_static("192.168.10.20/24", "", "1.1.1.1,,")
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.dns_servers.as_slice(), &[address("1.1.1.1")]);
+    }
+
+    #[test]
+    fn parses_hostnames() {
+        assert_eq!(parse_hostname(""), Ok(None));
+        assert_eq!(
+            parse_hostname("air-40a3cc01f2e4")
+                .unwrap()
+                .unwrap()
+                .as_str(),
+            "air-40a3cc01f2e4"
+        );
+        assert_eq!(parse_hostname("A1").unwrap().unwrap().as_str(), "A1");
+        let longest = "a".repeat(MAX_HOSTNAME);
+        assert_eq!(parse_hostname(&longest).unwrap().unwrap().as_str(), longest);
+    }
+
+    #[test]
+    fn rejects_invalid_hostnames() {
+        let too_long = "a".repeat(MAX_HOSTNAME + 1);
+        for invalid in [
+            "-air",
+            "air-",
+            "air_1",
+            "air.local",
+            "air 1",
+            "lüft",
+            &too_long,
+        ] {
+            assert_eq!(
+                parse_hostname(invalid),
+                Err(Error::InvalidHostname),
+                "{invalid}"
+            );
+        }
+    }
+}
@@ -0,0 +1,622 @@
+//! Payload encodings for sensor readings: the measurement structs as plain
+//! JSON, SenML (RFC 8428) JSON with units and a base name, or SenML CBOR for
+//! constrained links
+
+use core::fmt::Write;
+
+use defmt::Format;
+use heapless::{String, Vec};
+
+use crate::psychrometrics::Psychrometrics;
+use crate::sink::Reading;
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// The measurement structs as they are
+    Json,
+    /// SenML JSON
+    Senml,
+    /// SenML CBOR
+    Cbor,
+}
+
+impl Encoding {
+    pub fn parse(encoding: &str) -> Option<Encoding> {
+        Some(match encoding {
+            "json" => Encoding::Json,
+            "senml" => Encoding::Senml,
+            "cbor" => Encoding::Cbor,
+            _ => return None,
+        })
+    }
+
+    /// Media type of the payload, as registered for SenML in RFC 8428
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Json => "application/json",
+            Encoding::Senml => "application/senml+json",
+            Encoding::Cbor => "application/senml+cbor",
+        }
+    }
+}
+
+#[derive(Debug, Format)]
+pub enum Error {
+    /// The payload didn't fit the output buffer
+    BufferFull,
+    Json(serde_json_core::ser::Error),
+}
+
+impl From<serde_json_core::ser::Error> for Error {
+    fn from(error: serde_json_core::ser::Error) -> Self {
+        match error {
+            serde_json_core::ser::Error::BufferFull => Error::BufferFull,
+            error => Error::Json(error),
+        }
+    }
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Ppm,
+    Celsius,
+    RelativeHumidity,
+    Hectopascal,
+    Ohm,
+    GramsPerCubicMetre,
+    GramsPerKilogram,
+}
+
+impl Unit {
+    /// SenML unit symbol and the factor converting to it. SenML only
+    /// registers SI units, so hPa, g/m³ and g/kg are scaled.
+    fn senml(self) -> (&'static str, f32) {
+        match self {
+            Unit::Ppm => ("ppm", 1.0),
+            Unit::Celsius => ("Cel", 1.0),
+            Unit::RelativeHumidity => ("%RH", 1.0),
+            Unit::Hectopascal => ("Pa", 100.0),
+            Unit::Ohm => ("Ohm", 1.0),
+            Unit::GramsPerCubicMetre => ("kg/m3", 0.001),
+            Unit::GramsPerKilogram => ("/", 0.001),
+        }
+    }
+}
+
+/// One named value of a reading
+#[derive(Debug, Format, Clone, Copy, PartialEq)]
+pub struct Record {
+    pub name: &'static str,
+    /// None for dimensionless values
+    pub unit: Option<Unit>,
+    pub value: f32,
+}
+
+impl Record {
+    const fn new(name: &'static str, unit: Option<Unit>, value: f32) -> Self {
+        Record { name, unit, value }
+    }
+
+    /// The record in SenML units
+    pub fn senml(&self) -> Senml<'static> {
+        let (unit, value) = match self.unit.map(Unit::senml) {
+            Some((symbol, factor)) => (Some(symbol), self.value * factor),
+            None => (None, self.value),
+        };
+        Senml {
+            name: self.name,
+            unit,
+            value,
+        }
+    }
+}
+
+/// One SenML record, relative to the base name of its pack
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Senml<'a> {
+    pub name: &'a str,
+    /// SenML unit symbol
+    pub unit: Option<&'a str>,
+    pub value: f32,
+}
+
+/// Most records in a pack, all BME680 values
+pub const MAX_RECORDS: usize = 12;
+
+fn derived(records: &mut Vec<Record, MAX_RECORDS>, derived: &Psychrometrics) {
+    for record in [
+        Record::new("dew_point", Some(Unit::Celsius), derived.dew_point),
+        Record::new(
+            "absolute_humidity",
+            Some(Unit::GramsPerCubicMetre),
+            derived.absolute_humidity,
+        ),
+        Record::new(
+            "humidity_ratio",
+            Some(Unit::GramsPerKilogram),
+            derived.humidity_ratio,
+        ),
+        Record::new("heat_index", Some(Unit::Celsius), derived.heat_index),
+        Record::new("humidex", None, derived.humidex),
+    ] {
+        let _ = records.push(record);
+    }
+}
+
+/// Sensor name and records of a reading, leaving out values that aren't
+/// finite
+pub fn records(reading: &Reading) -> (&'static str, Vec<Record, MAX_RECORDS>) {
+    let mut records = Vec::new();
+    let sensor = match reading {
+        Reading::Scd41(measurement) => {
+            for record in [
+                Record::new("co2", Some(Unit::Ppm), measurement.co2.into()),
+                Record::new("temperature", Some(Unit::Celsius), measurement.temperature),
+                Record::new(
+                    "humidity",
+                    Some(Unit::RelativeHumidity),
+                    measurement.humidity,
+                ),
+            ] {
+                let _ = records.push(record);
+            }
+            derived(&mut records, &measurement.derived);
+            "scd41"
+        }
+        Reading::Bme680(measurement) => {
+            for record in [
+                Record::new("temperature", Some(Unit::Celsius), measurement.temperature),
+                Record::new(
+                    "self_heating",
+                    Some(Unit::Celsius),
+                    measurement.self_heating,
+                ),
+                Record::new(
+                    "humidity",
+                    Some(Unit::RelativeHumidity),
+                    measurement.humidity,
+                ),
+                Record::new("pressure", Some(Unit::Hectopascal), measurement.pressure),
+            ] {
+                let _ = records.push(record);
+            }
+            if let Some(pressure) = measurement.sea_level_pressure {
+                let _ = records.push(Record::new(
+                    "sea_level_pressure",
+                    Some(Unit::Hectopascal),
+                    pressure,
+                ));
+            }
+            if let Some(resistance) = measurement.gas_resistance {
+                let _ = records.push(Record::new("gas_resistance", Some(Unit::Ohm), resistance));
+            }
+            derived(&mut records, &measurement.derived);
+            "bme680"
+        }
+    };
+    records.retain(|record| record.value.is_finite());
+    (sensor, records)
+}
+
+/// Writes into a byte slice, failing once it's full
+struct Cursor<'a> {
+    out: &'a mut [u8],
+    length: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(out: &'a mut [u8]) -> Self {
+        Cursor { out, length: 0 }
+    }
+
+    fn push(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let end = self.length + bytes.len();
+        self.out
+            .get_mut(self.length..end)
+            .ok_or(Error::BufferFull)?
+            .copy_from_slice(bytes);
+        self.length = end;
+        Ok(())
+    }
+}
+
+impl Write for Cursor<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.push(s.as_bytes()).map_err(|_| core::fmt::Error)
+    }
+}
+
+/// SenML JSON pack with the base name and time on the first record
+pub fn senml_json(
+    base_name: &str,
+    base_time: Option<u32>,
+    records: &[Senml],
+    out: &mut [u8],
+) -> Result<usize, Error> {
+    let mut cursor = Cursor::new(out);
+    let overflow = |_| Error::BufferFull;
+    cursor.push(b"[")?;
+    for (index, record) in records.iter().enumerate() {
+        if index == 0 {
+            write!(cursor, "{{\"bn\":\"{base_name}\",").map_err(overflow)?;
+            if let Some(base_time) = base_time {
+                write!(cursor, "\"bt\":{base_time},").map_err(overflow)?;
+            }
+        } else {
+            cursor.push(b",{")?;
+        }
+        write!(cursor, "\"n\":\"{}\",", record.name).map_err(overflow)?;
+        if let Some(unit) = record.unit {
+            write!(cursor, "\"u\":\"{unit}\",").map_err(overflow)?;
+        }
+        write!(cursor, "\"v\":{}}}", record.value).map_err(overflow)?;
+    }
+    cursor.push(b"]")?;
+    Ok(cursor.length)
+}
+
+/// CBOR labels for SenML fields (RFC 8428 section 6)
+mod label {
+    pub const BASE_NAME: i32 = -2;
+    pub const BASE_TIME: i32 = -3;
+    pub const NAME: i32 = 0;
+    pub const UNIT: i32 = 1;
+    pub const VALUE: i32 = 2;
+}
+
+/// Major types of CBOR data items
+mod major {
+    pub const UNSIGNED: u8 = 0;
+    pub const NEGATIVE: u8 = 1;
+    pub const TEXT: u8 = 3;
+    pub const ARRAY: u8 = 4;
+    pub const MAP: u8 = 5;
+}
+
+/// Largest integer an f32 represents exactly
+const F32_EXACT: f32 = 16_777_216.0;
+
+impl Cursor<'_> {
+    /// Head of a data item with its argument in the shortest form
+    fn cbor_head(&mut self, major: u8, argument: u32) -> Result<(), Error> {
+        let major = major << 5;
+        match argument {
+            0..=23 => self.push(&[major | argument as u8]),
+            24..=0xff => self.push(&[major | 24, argument as u8]),
+            0x100..=0xffff => {
+                self.push(&[major | 25])?;
+                self.push(&(argument as u16).to_be_bytes())
+            }
+            _ => {
+                self.push(&[major | 26])?;
+                self.push(&argument.to_be_bytes())
+            }
+        }
+    }
+
+    fn cbor_int(&mut self, value: i32) -> Result<(), Error> {
+        match u32::try_from(value) {
+            Ok(value) => self.cbor_head(major::UNSIGNED, value),
+            Err(_) => self.cbor_head(major::NEGATIVE, (-1 - value) as u32),
+        }
+    }
+
+    fn cbor_text(&mut self, text: &str) -> Result<(), Error> {
+        self.cbor_head(major::TEXT, text.len() as u32)?;
+        self.push(text.as_bytes())
+    }
+
+    /// A number as an integer where that's exact, otherwise as a float
+    fn cbor_number(&mut self, value: f32) -> Result<(), Error> {
+        if value == libm::truncf(value) && value.abs() < F32_EXACT {
+            self.cbor_int(value as i32)
+        } else {
+            self.push(&[0xfa])?;
+            self.push(&value.to_bits().to_be_bytes())
+        }
+    }
+}
+
+/// SenML CBOR pack with the base name and time on the first record
+pub fn senml_cbor(
+    base_name: &str,
+    base_time: Option<u32>,
+    records: &[Senml],
+    out: &mut [u8],
+) -> Result<usize, Error> {
+    let mut cursor = Cursor::new(out);
+    cursor.cbor_head(major::ARRAY, records.len() as u32)?;
+    for (index, record) in records.iter().enumerate() {
+        let base_time = base_time.filter(|_| index == 0);
+        let entries = 2
+            + u32::from(index == 0)
+            + u32::from(base_time.is_some())
+            + u32::from(record.unit.is_some());
+        cursor.cbor_head(major::MAP, entries)?;
+        if index == 0 {
+            cursor.cbor_int(label::BASE_NAME)?;
+            cursor.cbor_text(base_name)?;
+        }
+        if let Some(base_time) = base_time {
+            cursor.cbor_int(label::BASE_TIME)?;
+            cursor.cbor_head(major::UNSIGNED, base_time)?;
+        }
+        cursor.cbor_int(label::NAME)?;
+        cursor.cbor_text(record.name)?;
+        if let Some(unit) = record.unit {
+            cursor.cbor_int(label::UNIT)?;
+            cursor.cbor_text(unit)?;
+        }
+        cursor.cbor_int(label::VALUE)?;
+        cursor.cbor_number(record.value)?;
+    }
+    Ok(cursor.length)
+}
+
+/// SenML base name of a sensor's readings, a URN built from the device ID
+/// (the MAC address) as described in RFC 9039
+pub fn base_name<const N: usize>(device_id: &str, sensor: &str) -> Result<String<N>, Error> {
+    let mut base_name = String::new();
+    write!(base_name, "urn:dev:mac:{device_id}:{sensor}:").map_err(|_| Error::BufferFull)?;
+    Ok(base_name)
+}
+
+/// Encode a reading from the device `device_id` into `out`, returning the
+/// payload length. `time` is the Unix time of the reading if known, which
+/// only SenML carries.
+pub fn encode(
+    encoding: Encoding,
+    reading: &Reading,
+    device_id: &str,
+    time: Option<u32>,
+    out: &mut [u8],
+) -> Result<usize, Error> {
+    match encoding {
+        Encoding::Json => Ok(match reading {
+            Reading::Scd41(measurement) => serde_json_core::to_slice(measurement, out)?,
+            Reading::Bme680(measurement) => serde_json_core::to_slice(measurement, out)?,
+        }),
+        Encoding::Senml | Encoding::Cbor => {
+            let (sensor, records) = records(reading);
+            let base_name: String<48> = base_name(device_id, sensor)?;
+            let records: Vec<Senml, MAX_RECORDS> = records.iter().map(Record::senml).collect();
+            match encoding {
+                Encoding::Senml => senml_json(&base_name, time, &records, out),
+                _ => senml_cbor(&base_name, time, &records, out),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bme680::Bme680Measurement;
+    use crate::scd41::Scd41Measurement;
+
+    use super::*;
+
+    /// The example pack of RFC 8428 section 5.1.2
+    const RFC_BASE_NAME: &str = "urn:dev:ow:10e2073a01080063:";
+    const RFC_RECORDS: [Senml; 2] = [
+        Senml {
+            name: "voltage",
+            unit: Some("V"),
+            value: 120.1,
+        },
+        Senml {
+            name: "current",
+            unit: Some("A"),
+            value: 1.2,
+        },
+    ];
+
+    fn cbor_number(value: f32) -> std::vec::Vec<u8> {
+        let mut out = [0; 8];
+        let mut cursor = Cursor::new(&mut out);
+        cursor.cbor_number(value).unwrap();
+        let length = cursor.length;
+        out[..length].to_vec()
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    #[test]
+    fn rfc_8428_json_pack() {
+        let mut out = [0; 256];
+        let length = senml_json(RFC_BASE_NAME, None, &RFC_RECORDS, &mut out).unwrap();
+        assert_eq!(
+            core::str::from_utf8(&out[..length]).unwrap(),
+            r#"[{"bn":"urn:dev:ow:10e2073a01080063:","n":"voltage","u":"V","v":120.1},"#.to_owned()
+                + r#"{"n":"current","u":"A","v":1.2}]"#
+        );
+    }
+
+    #[test]
+    fn rfc_8428_cbor_pack() {
+        // The example of RFC 8428 section 6, except that the values are
+        // single precision floats as readings are f32
+        let mut expected = vec![0x82, 0xa4, 0x21, 0x78, 0x1c];
+        expected.extend(RFC_BASE_NAME.as_bytes());
+        expected.extend([0x00, 0x67]);
+        expected.extend(b"voltage");
+        expected.extend([0x01, 0x61, b'V', 0x02, 0xfa, 0x42, 0xf0, 0x33, 0x33]);
+        expected.extend([0xa3, 0x00, 0x67]);
+        expected.extend(b"current");
+        expected.extend([0x01, 0x61, b'A', 0x02, 0xfa, 0x3f, 0x99, 0x99, 0x9a]);
+
+        let mut out = [0; 256];
+        let length = senml_cbor(RFC_BASE_NAME, None, &RFC_RECORDS, &mut out).unwrap();
+        assert_eq!(out[..length], expected);
+    }
+
+    #[test]
+    fn base_time_goes_on_the_first_record() {
+        let mut out = [0; 256];
+        let length = senml_json("d:", Some(1_320_067_464), &RFC_RECORDS, &mut out).unwrap();
+        assert_eq!(
+            core::str::from_utf8(&out[..length]).unwrap(),
+            r#"[{"bn":"d:","bt":1320067464,"n":"voltage","u":"V","v":120.1},"#.to_owned()
+                + r#"{"n":"current","u":"A","v":1.2}]"#
+        );
+
+        let length = senml_cbor("d:", Some(1_320_067_464), &RFC_RECORDS, &mut out).unwrap();
+        // Base name, base time, name, unit and value
+        assert_eq!(out[..2], [0x82, 0xa5]);
+        assert!(contains(
+            &out[..length],
+            &[0x22, 0x1a, 0x4e, 0xae, 0xa1, 0x88, 0x00, 0x67]
+        ));
+        assert_eq!(
+            out[..length].iter().filter(|&&byte| byte == 0x22).count(),
+            1
+        );
+    }
+
+    #[test]
+    fn records_without_units() {
+        let records = [Senml {
+            name: "humidex",
+            unit: None,
+            value: 30.0,
+        }];
+        let mut out = [0; 64];
+        let length = senml_json("b:", None, &records, &mut out).unwrap();
+        assert_eq!(&out[..length], br#"[{"bn":"b:","n":"humidex","v":30}]"#);
+
+        let length = senml_cbor("b:", None, &records, &mut out).unwrap();
+        let mut expected = vec![0x81, 0xa3, 0x21, 0x62, b'b', b':', 0x00, 0x67];
+        expected.extend(b"humidex");
+        expected.extend([0x02, 0x18, 0x1e]);
+        assert_eq!(out[..length], expected);
+    }
+
+    #[test]
+    fn cbor_numbers_are_integers_where_exact() {
+        assert_eq!(cbor_number(0.0), [0x00]);
+        assert_eq!(cbor_number(-0.0), [0x00]);
+        assert_eq!(cbor_number(23.0), [0x17]);
+        assert_eq!(cbor_number(24.0), [0x18, 0x18]);
+        assert_eq!(cbor_number(255.0), [0x18, 0xff]);
+        assert_eq!(cbor_number(256.0), [0x19, 0x01, 0x00]);
+        assert_eq!(cbor_number(1000.0), [0x19, 0x03, 0xe8]);
+        assert_eq!(cbor_number(101_325.0), [0x1a, 0x00, 0x01, 0x8b, 0xcd]);
+        assert_eq!(cbor_number(16_777_215.0), [0x1a, 0x00, 0xff, 0xff, 0xff]);
+        assert_eq!(cbor_number(-1.0), [0x20]);
+        assert_eq!(cbor_number(-24.0), [0x37]);
+        assert_eq!(cbor_number(-25.0), [0x38, 0x18]);
+        assert_eq!(cbor_number(-500.0), [0x39, 0x01, 0xf3]);
+    }
+
+    #[test]
+    fn cbor_numbers_are_floats_otherwise() {
+        assert_eq!(cbor_number(0.5), [0xfa, 0x3f, 0x00, 0x00, 0x00]);
+        assert_eq!(cbor_number(-0.25), [0xfa, 0xbe, 0x80, 0x00, 0x00]);
+        assert_eq!(cbor_number(21.5), [0xfa, 0x41, 0xac, 0x00, 0x00]);
+        // Integers from 2^24 on may have been rounded, so stay floats
+        assert_eq!(cbor_number(16_777_216.0), [0xfa, 0x4b, 0x80, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn base_name_identifies_the_device() {
+        let name: String<48> = base_name("0123456789ab", "scd41").unwrap();
+        assert_eq!(name, "urn:dev:mac:0123456789ab:scd41:");
+        assert!(base_name::<16>("0123456789ab", "scd41").is_err());
+    }
+
+    #[test]
+    fn encodes_readings_as_senml() {
+        let mut measurement = Scd41Measurement::default();
+        measurement.co2 = 812;
+        measurement.temperature = 21.5;
+        measurement.humidity = 40.0;
+        let reading = Reading::Scd41(measurement);
+
+        let mut out = [0; 768];
+        let length = encode(
+            Encoding::Senml,
+            &reading,
+            "0123456789ab",
+            Some(1_700_000_000),
+            &mut out,
+        )
+        .unwrap();
+        let expected = [
+            r#"[{"bn":"urn:dev:mac:0123456789ab:scd41:","bt":1700000000,"#,
+            r#""n":"co2","u":"ppm","v":812},"#,
+            r#"{"n":"temperature","u":"Cel","v":21.5},"#,
+            r#"{"n":"humidity","u":"%RH","v":40},"#,
+            r#"{"n":"dew_point","u":"Cel","v":0},"#,
+            r#"{"n":"absolute_humidity","u":"kg/m3","v":0},"#,
+            r#"{"n":"humidity_ratio","u":"/","v":0},"#,
+            r#"{"n":"heat_index","u":"Cel","v":0},"#,
+            r#"{"n":"humidex","v":0}]"#,
+        ]
+        .concat();
+        assert_eq!(core::str::from_utf8(&out[..length]).unwrap(), expected);
+    }
+
+    #[test]
+    fn encodes_readings_as_cbor_in_si_units() {
+        let reading = Reading::Bme680(Bme680Measurement {
+            pressure: 1013.25,
+            ..Default::default()
+        });
+
+        let mut out = [0; 768];
+        let length = encode(Encoding::Cbor, &reading, "0123456789ab", None, &mut out).unwrap();
+        let payload = &out[..length];
+
+        let mut base_name = vec![0x21, 0x78, 0x20];
+        base_name.extend(b"urn:dev:mac:0123456789ab:bme680:");
+        assert!(contains(payload, &base_name));
+        // hPa are sent as Pa
+        let mut pressure = vec![0xa3, 0x00, 0x68];
+        pressure.extend(b"pressure");
+        pressure.extend([0x01, 0x62, b'P', b'a', 0x02, 0x1a, 0x00, 0x01, 0x8b, 0xcd]);
+        assert!(contains(payload, &pressure));
+    }
+
+    #[test]
+    fn json_is_the_measurement_as_it_is() {
+        let mut measurement = Scd41Measurement::default();
+        measurement.co2 = 812;
+        let mut out = [0; 768];
+        let length = encode(
+            Encoding::Json,
+            &Reading::Scd41(measurement),
+            "0123456789ab",
+            Some(1),
+            &mut out,
+        )
+        .unwrap();
+        let json = core::str::from_utf8(&out[..length]).unwrap();
+        assert!(json.starts_with(r#"{"timestamp":null,"co2":812,"#));
+        assert!(!json.contains("urn:"));
+    }
+
+    #[test]
+    fn full_buffers_are_an_error() {
+        let mut out = [0; 16];
+        assert!(matches!(
+            senml_json(RFC_BASE_NAME, None, &RFC_RECORDS, &mut out),
+            Err(Error::BufferFull)
+        ));
+        assert!(matches!(
+            senml_cbor(RFC_BASE_NAME, None, &RFC_RECORDS, &mut out),
+            Err(Error::BufferFull)
+        ));
+    }
+
+    #[test]
+    fn parses_encodings() {
+        assert_eq!(Encoding::parse("json"), Some(Encoding::Json));
+        assert_eq!(Encoding::parse("senml"), Some(Encoding::Senml));
+        assert_eq!(Encoding::parse("cbor"), Some(Encoding::Cbor));
+        assert_eq!(Encoding::parse("xml"), None);
+        assert_eq!(Encoding::Cbor.content_type(), "application/senml+cbor");
+    }
+}
@@ -0,0 +1,263 @@
+//! Values derived from temperature, relative humidity and pressure
+
+use defmt::Format;
+use libm::{expf, logf};
+use serde::Serialize;
+
+/// Magnus coefficients over water (Sonntag 1990), valid from -45 °C to 60 °C
+const MAGNUS_A: f32 = 6.112;
+const MAGNUS_B: f32 = 17.62;
+const MAGNUS_C: f32 = 243.12;
+
+/// Ratio of the molar masses of water vapour and dry air
+const EPSILON: f32 = 0.621_98;
+
+/// Specific gas constant of water vapour in J/(kg K)
+const R_VAPOUR: f32 = 461.5;
+
+const KELVIN: f32 = 273.15;
+
+#[derive(Debug, Format, Clone, Copy, Default, Serialize)]
+pub struct Psychrometrics {
+    /// Dew point in °C
+    pub dew_point: f32,
+    /// Absolute humidity in g/m³
+    pub absolute_humidity: f32,
+    /// Humidity ratio (mixing ratio) in g of water per kg of dry air
+    pub humidity_ratio: f32,
+    /// NWS heat index in °C
+    pub heat_index: f32,
+    /// Canadian humidex
+    pub humidex: f32,
+}
+
+impl Psychrometrics {
+    /// Derive all values from temperature in °C, relative humidity in % and
+    /// pressure in hPa
+    pub fn new(temperature: f32, humidity: f32, pressure: f32) -> Self {
+        let humidity = humidity.clamp(0.1, 100.0);
+        let dew_point = dew_point(temperature, humidity);
+        Psychrometrics {
+            dew_point,
+            absolute_humidity: absolute_humidity(temperature, humidity),
+            humidity_ratio: humidity_ratio(temperature, humidity, pressure),
+            heat_index: heat_index(temperature, humidity),
+            humidex: humidex(temperature, dew_point),
+        }
+    }
+}
+
+/// Saturation vapour pressure over water in hPa
+pub fn saturation_vapour_pressure(temperature: f32) -> f32 {
+    MAGNUS_A * expf(MAGNUS_B * temperature / (MAGNUS_C + temperature))
+}
+
+/// Partial pressure of water vapour in hPa
+pub fn vapour_pressure(temperature: f32, humidity: f32) -> f32 {
+    humidity / 100.0 * saturation_vapour_pressure(temperature)
+}
+
+/// Relative humidity in % at `temperature` for air measured at
+/// `measured_temperature` and `humidity`, keeping the vapour pressure constant
+pub fn humidity_at(measured_temperature: f32, humidity: f32, temperature: f32) -> f32 {
+    let humidity = humidity * saturation_vapour_pressure(measured_temperature)
+        / saturation_vapour_pressure(temperature);
+    humidity.clamp(0.0, 100.0)
+}
+
+/// Dew point in °C by inverting the Magnus formula
+pub fn dew_point(temperature: f32, humidity: f32) -> f32 {
+    let gamma = logf(humidity / 100.0) + MAGNUS_B * temperature / (MAGNUS_C + temperature);
+    MAGNUS_C * gamma / (MAGNUS_B - gamma)
+}
+
+/// Absolute humidity in g/m³ from the ideal gas law for water vapour
+pub fn absolute_humidity(temperature: f32, humidity: f32) -> f32 {
+    // x100 for hPa to Pa and x1000 for kg to g
+    vapour_pressure(temperature, humidity) * 1e5 / (R_VAPOUR * (temperature + KELVIN))
+}
+
+/// Humidity ratio in g/kg of dry air
+pub fn humidity_ratio(temperature: f32, humidity: f32, pressure: f32) -> f32 {
+    let vapour_pressure = vapour_pressure(temperature, humidity);
+    1000.0 * EPSILON * vapour_pressure / (pressure - vapour_pressure)
+}
+
+/// Heat index in °C using the NWS algorithm: Steadman's simple formula below
+/// 80 °F, otherwise the Rothfusz regression with its low and high humidity
+/// adjustments
+pub fn heat_index(temperature: f32, humidity: f32) -> f32 {
+    let t = temperature * 9.0 / 5.0 + 32.0;
+    let rh = humidity;
+
+    let simple = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    let fahrenheit = if (simple + t) / 2.0 < 80.0 {
+        simple
+    } else {
+        let mut hi = -42.379 + 2.049_015_2 * t + 10.143_331 * rh
+            - 0.224_755_4 * t * rh
+            - 6.837_83e-3 * t * t
+            - 5.481_717e-2 * rh * rh
+            + 1.228_74e-3 * t * t * rh
+            + 8.528_2e-4 * t * rh * rh
+            - 1.99e-6 * t * t * rh * rh;
+        if rh < 13.0 && (80.0..=112.0).contains(&t) {
+            hi -= (13.0 - rh) / 4.0 * libm::sqrtf((17.0 - (t - 95.0).abs()) / 17.0);
+        } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+            hi += (rh - 85.0) / 10.0 * (87.0 - t) / 5.0;
+        }
+        hi
+    };
+
+    (fahrenheit - 32.0) * 5.0 / 9.0
+}
+
+/// Humidex from temperature and dew point in °C (Masterton & Richardson 1979)
+pub fn humidex(temperature: f32, dew_point: f32) -> f32 {
+    let vapour_pressure = 6.11 * expf(5417.753 * (1.0 / 273.16 - 1.0 / (dew_point + KELVIN)));
+    temperature + 0.5555 * (vapour_pressure - 10.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{actual} should be within {tolerance} of {expected}"
+        );
+    }
+
+    fn celsius(fahrenheit: f32) -> f32 {
+        (fahrenheit - 32.0) * 5.0 / 9.0
+    }
+
+    #[test]
+    fn saturation_vapour_pressure_matches_tables() {
+        // WMO reference values over water in hPa
+        for (temperature, expected) in [
+            (-20.0, 1.254),
+            (-10.0, 2.863),
+            (0.0, 6.112),
+            (10.0, 12.28),
+            (20.0, 23.39),
+            (30.0, 42.46),
+            (40.0, 73.85),
+        ] {
+            assert_close(
+                saturation_vapour_pressure(temperature),
+                expected,
+                0.005 * expected,
+            );
+        }
+    }
+
+    #[test]
+    fn dew_point_matches_tables() {
+        for (temperature, humidity, expected) in [
+            (10.0, 90.0, 8.4),
+            (20.0, 50.0, 9.3),
+            (25.0, 60.0, 16.7),
+            (30.0, 80.0, 26.2),
+            (0.0, 70.0, -4.8),
+        ] {
+            assert_close(dew_point(temperature, humidity), expected, 0.15);
+        }
+        // Saturated air is at its dew point
+        assert_close(dew_point(17.3, 100.0), 17.3, 1e-4);
+    }
+
+    #[test]
+    fn absolute_humidity_matches_tables() {
+        // Water vapour in saturated air in g/m³
+        for (temperature, expected) in [(0.0, 4.85), (10.0, 9.40), (20.0, 17.3), (30.0, 30.4)] {
+            assert_close(
+                absolute_humidity(temperature, 100.0),
+                expected,
+                0.01 * expected,
+            );
+        }
+        assert_close(
+            absolute_humidity(25.0, 50.0),
+            absolute_humidity(25.0, 100.0) / 2.0,
+            1e-4,
+        );
+    }
+
+    #[test]
+    fn humidity_ratio_matches_ashrae_tables() {
+        // Saturated humidity ratio at standard pressure in g/kg
+        for (temperature, expected) in [(0.0, 3.789), (10.0, 7.661), (20.0, 14.76), (25.0, 20.17)] {
+            assert_close(
+                humidity_ratio(temperature, 100.0, 1013.25),
+                expected,
+                0.01 * expected,
+            );
+        }
+        // Thinner air holds more water per kg at the same vapour pressure
+        assert!(humidity_ratio(20.0, 50.0, 850.0) > humidity_ratio(20.0, 50.0, 1013.25));
+    }
+
+    #[test]
+    fn heat_index_matches_nws_chart() {
+        // The chart is rounded to whole °F
+        let tolerance = 0.6 * 5.0 / 9.0;
+        for (fahrenheit, humidity, expected) in [
+            (80.0, 40.0, 80.0),
+            (80.0, 60.0, 82.0),
+            (90.0, 40.0, 91.0),
+            (90.0, 50.0, 95.0),
+            (90.0, 70.0, 106.0),
+            (100.0, 40.0, 109.0),
+            (100.0, 50.0, 118.0),
+            // With the high humidity adjustment
+            (86.0, 90.0, 105.0),
+        ] {
+            assert_close(
+                heat_index(celsius(fahrenheit), humidity),
+                celsius(expected),
+                tolerance,
+            );
+        }
+        // Below 80 °F Steadman's formula stays close to the air temperature
+        assert_close(heat_index(21.1, 50.0), 20.6, 0.1);
+        // With the low humidity adjustment, hot dry air feels cooler
+        assert_close(heat_index(40.0, 10.0), celsius(98.1), 0.1);
+    }
+
+    #[test]
+    fn humidex_matches_environment_canada_table() {
+        for (temperature, dew_point, expected) in [
+            (30.0, 15.0, 34.0),
+            (30.0, 20.0, 38.0),
+            (30.0, 25.0, 42.0),
+            (35.0, 25.0, 47.0),
+            (40.0, 30.0, 59.0),
+        ] {
+            assert_close(humidex(temperature, dew_point), expected, 0.5);
+        }
+    }
+
+    #[test]
+    fn humidity_at_keeps_vapour_pressure() {
+        // Air at 20 °C and 50 % warmed to 25 °C
+        let humidity = humidity_at(20.0, 50.0, 25.0);
+        assert_close(humidity, 36.9, 0.2);
+        assert_close(
+            vapour_pressure(25.0, humidity),
+            vapour_pressure(20.0, 50.0),
+            1e-3,
+        );
+        // Cooled below its dew point it is saturated
+        assert_eq!(humidity_at(20.0, 80.0, 10.0), 100.0);
+    }
+
+    #[test]
+    fn clamps_humidity() {
+        let dry = Psychrometrics::new(20.0, 0.0, 1013.25);
+        assert!(dry.dew_point.is_finite());
+        let wet = Psychrometrics::new(20.0, 104.0, 1013.25);
+        assert_close(wet.dew_point, 20.0, 1e-4);
+    }
+}
@@ -0,0 +1,276 @@
+//! HTTP push of every reading as a `POST` to a configured endpoint, such as a
+//! webhook or the REST API of a home automation server
+
+use core::fmt::Write as _;
+
+use defmt::{debug, error, info, warn, Format};
+use embassy_net::{tcp::TcpSocket, IpAddress, Stack};
+use embassy_time::Duration;
+use embedded_io_async::{Read, Write};
+use heapless::{String, Vec};
+
+use crate::clock;
+use crate::config;
+use crate::device;
+use crate::influx;
+use crate::payload::{self, Encoding};
+use crate::resolve::{self, MAX_ADDRESSES};
+use crate::sink;
+
+pub const ENABLED: bool = config::flag(option_env!("PUSH_ENABLED"), false);
+const HOST: &str = match option_env!("PUSH_HOST") {
+    Some(host) => host,
+    None => "homeassistant.local",
+};
+const PORT: &str = match option_env!("PUSH_PORT") {
+    Some(port) => port,
+    None => "80",
+};
+/// Request path, `{device_id}` expanding to the device ID and `{sensor}` to
+/// the name of the sensor the reading is from
+const PATH: &str = match option_env!("PUSH_PATH") {
+    Some(path) => path,
+    None => "/air-quality/{device_id}/{sensor}",
+};
+/// Encoding of the readings, `json`, `senml` or `cbor`
+const FORMAT: &str = match option_env!("PUSH_FORMAT") {
+    Some(format) => format,
+    None => "json",
+};
+/// Sent as a bearer token if not empty
+const TOKEN: &str = match option_env!("PUSH_TOKEN") {
+    Some(token) => token,
+    None => "",
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The request didn't fit its buffer
+    BufferFull,
+}
+
+/// Head of the request for a body of `length` bytes, authorised with
+/// `token` unless it's empty
+pub fn request_head<const N: usize>(
+    path: &str,
+    content_type: &str,
+    token: &str,
+    length: usize,
+) -> Result<String<N>, Error> {
+    let mut head = String::new();
+    write!(
+        head,
+        "POST {path} HTTP/1.1\r\nHost: {HOST}\r\nContent-Type: {content_type}\r\n\
+         Content-Length: {length}\r\nConnection: close\r\n"
+    )
+    .map_err(|_| Error::BufferFull)?;
+    if !token.is_empty() {
+        write!(head, "Authorization: Bearer {token}\r\n").map_err(|_| Error::BufferFull)?;
+    }
+    head.push_str("\r\n").map_err(|_| Error::BufferFull)?;
+    Ok(head)
+}
+
+/// Send a request over a connected socket and read the status line of the
+/// response
+async fn request<S>(socket: &mut S, head: &str, body: &[u8]) -> Result<u16, ()>
+where
+    S: Read + Write,
+    S::Error: Format,
+{
+    socket
+        .write_all(head.as_bytes())
+        .await
+        .map_err(|e| warn!("Push: write error: {:?}", e))?;
+    socket
+        .write_all(body)
+        .await
+        .map_err(|e| warn!("Push: write error: {:?}", e))?;
+    socket
+        .flush()
+        .await
+        .map_err(|e| warn!("Push: write error: {:?}", e))?;
+
+    // Only the status line is of interest
+    let mut response = [0; 64];
+    let mut length = 0;
+    while length < response.len() && !response[..length].contains(&b'\r') {
+        match socket.read(&mut response[length..]).await {
+            Ok(0) => break,
+            Ok(read) => length += read,
+            Err(e) => {
+                warn!("Push: read error: {:?}", e);
+                return Err(());
+            }
+        }
+    }
+    influx::parse_status(&response[..length]).ok_or_else(|| warn!("Push: invalid response"))
+}
+
+async fn post(
+    stack: Stack<'static>,
+    addresses: &[IpAddress],
+    port: u16,
+    head: &str,
+    body: &[u8],
+) -> Result<u16, ()> {
+    let mut rx_buffer = [0; 256];
+    let mut tx_buffer = [0; 1024];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    socket.set_timeout(Some(TIMEOUT));
+    resolve::connect(stack, &mut socket, addresses, port)
+        .await
+        .map_err(|e| warn!("Push: connect error: {:?}", e))?;
+    let status = request(&mut socket, head, body).await;
+    socket.close();
+    let _ = socket.flush().await;
+    status
+}
+
+#[embassy_executor::task]
+pub async fn pusher(stack: Stack<'static>) -> ! {
+    let port: u16 = PORT.parse().unwrap_or(80);
+    let encoding = Encoding::parse(FORMAT).unwrap_or_else(|| {
+        warn!("Push: unknown format {}, using JSON", FORMAT);
+        Encoding::Json
+    });
+    info!("Push: posting readings to {}:{}{}", HOST, port, PATH);
+
+    let mut addresses: Vec<IpAddress, MAX_ADDRESSES> = Vec::new();
+    let mut body = [0; 512];
+
+    loop {
+        let reading = sink::PUSH.receive().await;
+
+        let path: Option<String<128>> = config::expand(PATH, |name| match name {
+            "device_id" => Some(device::id()),
+            "sensor" => Some(reading.sensor()),
+            _ => None,
+        });
+        let Some(path) = path else {
+            error!("Push: path {} too long", PATH);
+            continue;
+        };
+        let length =
+            match payload::encode(encoding, &reading, device::id(), clock::now(), &mut body) {
+                Ok(length) => length,
+                Err(e) => {
+                    error!("Push: failed to encode reading: {:?}", e);
+                    continue;
+                }
+            };
+        let Ok(head) = request_head::<512>(&path, encoding.content_type(), TOKEN, length) else {
+            error!("Push: request head too long");
+            continue;
+        };
+
+        if addresses.is_empty() {
+            addresses = resolve::addresses(stack, HOST).await.unwrap_or_else(|e| {
+                warn!("Push: DNS lookup of {} failed: {:?}", HOST, e);
+                Vec::new()
+            });
+        }
+        // Readings that couldn't be posted are dropped rather than retried,
+        // the next one follows within seconds
+        if addresses.is_empty() {
+            continue;
+        }
+        match post(stack, &addresses, port, &head, &body[..length]).await {
+            Ok(200..=299) => debug!("Push: posted {} bytes to {}", length, path),
+            Ok(status) => warn!("Push: post failed with status {}", status),
+            // Look the host up again in case it moved
+            Err(()) => addresses.clear(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+
+    use embassy_futures::block_on;
+
+    use super::*;
+
+    /// Stand-in for the receiving server, recording the request and
+    /// answering with `response`
+    struct FakeServer {
+        request: std::vec::Vec<u8>,
+        response: &'static [u8],
+    }
+
+    impl embedded_io_async::ErrorType for FakeServer {
+        type Error = Infallible;
+    }
+
+    impl Read for FakeServer {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+            let length = buf.len().min(self.response.len()).min(7);
+            buf[..length].copy_from_slice(&self.response[..length]);
+            self.response = &self.response[length..];
+            Ok(length)
+        }
+    }
+
+    impl Write for FakeServer {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+            self.request.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    #[test]
+    fn posts_the_reading() {
+        let body = br#"{"co2":812}"#;
+        let head: String<256> =
+            request_head("/air/scd41", "application/json", "", body.len()).unwrap();
+        let mut server = FakeServer {
+            request: vec![],
+            response: b"HTTP/1.1 201 Created\r\n\r\n",
+        };
+
+        assert_eq!(block_on(request(&mut server, &head, body)), Ok(201));
+
+        let request = std::string::String::from_utf8(server.request).unwrap();
+        let (head, sent) = request.split_once("\r\n\r\n").unwrap();
+        assert_eq!(sent.as_bytes(), body);
+        let mut lines = head.split("\r\n");
+        assert_eq!(lines.next(), Some("POST /air/scd41 HTTP/1.1"));
+        let headers: std::vec::Vec<_> = lines.collect();
+        assert!(headers.contains(&format!("Host: {HOST}").as_str()));
+        assert!(headers.contains(&"Content-Type: application/json"));
+        assert!(headers.contains(&"Content-Length: 11"));
+        assert!(headers.contains(&"Connection: close"));
+        assert!(!head.contains("Authorization"));
+    }
+
+    #[test]
+    fn sends_the_token() {
+        let head: String<256> = request_head("/", "application/senml+json", "abc", 2).unwrap();
+        assert!(head.contains("\r\nContent-Type: application/senml+json\r\n"));
+        assert!(head.ends_with("\r\nAuthorization: Bearer abc\r\n\r\n"));
+    }
+
+    #[test]
+    fn reports_missing_status() {
+        let mut server = FakeServer {
+            request: vec![],
+            response: b"",
+        };
+        assert_eq!(
+            block_on(request(&mut server, "POST / HTTP/1.1\r\n\r\n", b"{}")),
+            Err(())
+        );
+    }
+
+    #[test]
+    fn long_heads_are_an_error() {
+        let path = "/x".repeat(100);
+        assert_eq!(
+            request_head::<128>(&path, "application/json", "", 10),
+            Err(Error::BufferFull)
+        );
+    }
+}
@@ -0,0 +1,368 @@
+//! Name resolution and connection for outgoing TCP connections, racing IPv6
+//! and IPv4 addresses against each other in the style of Happy Eyeballs (RFC
+//! 8305)
+
+use core::cell::Cell;
+use core::pin::pin;
+
+use defmt::{info, warn};
+use embassy_futures::{
+    join::join,
+    select::{select, Either},
+};
+use embassy_net::{
+    dns::{self, DnsQueryType},
+    tcp::{ConnectError, TcpSocket},
+    IpAddress, Stack,
+};
+use embassy_time::{Duration, Timer};
+use heapless::Vec;
+
+use crate::slaac;
+
+/// Most addresses tried for a name, as many as DNS returns for both families
+pub const MAX_ADDRESSES: usize = 8;
+
+/// Time given to a connection attempt before racing the next address against
+/// it, the Connection Attempt Delay of RFC 8305 section 5
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Alternate between addresses of the preferred and the other family,
+/// starting with the preferred one
+pub fn interleave<T: Copy, const N: usize>(preferred: &[T], other: &[T]) -> Vec<T, N> {
+    let mut addresses = Vec::new();
+    for index in 0..preferred.len().max(other.len()) {
+        for address in [preferred.get(index), other.get(index)]
+            .into_iter()
+            .flatten()
+        {
+            let _ = addresses.push(*address);
+        }
+    }
+    addresses
+}
+
+/// Whether the stack has an IPv6 address that reaches beyond the link
+pub fn has_ipv6(stack: Stack<'_>) -> bool {
+    stack
+        .config_v6()
+        .is_some_and(|config| !slaac::is_link_local(config.address.address()))
+}
+
+/// Addresses of `host` in the order to try them, IPv6 first when the stack
+/// has a global IPv6 address. Fails only if no address was found at all.
+pub async fn addresses(
+    stack: Stack<'_>,
+    host: &str,
+) -> Result<Vec<IpAddress, MAX_ADDRESSES>, dns::Error> {
+    let query = |enabled: bool, query_type| async move {
+        match enabled {
+            true => stack.dns_query(host, query_type).await,
+            false => Ok(Vec::new()),
+        }
+    };
+    let (ipv6, ipv4) = join(
+        query(has_ipv6(stack), DnsQueryType::Aaaa),
+        query(stack.config_v4().is_some(), DnsQueryType::A),
+    )
+    .await;
+    let addresses = interleave(
+        ipv6.as_deref().unwrap_or_default(),
+        ipv4.as_deref().unwrap_or_default(),
+    );
+    match (addresses.is_empty(), ipv6, ipv4) {
+        (false, _, _) => Ok(addresses),
+        (true, Err(error), _) | (true, _, Err(error)) => Err(error),
+        (true, Ok(_), Ok(_)) => Err(dns::Error::Failed),
+    }
+}
+
+/// Socket a connection attempt is made on, so that the race between
+/// attempts also runs on the host
+trait Attempt {
+    async fn connect(&mut self, address: IpAddress, port: u16) -> Result<(), ConnectError>;
+
+    /// Abandon a connection or an attempt at one
+    async fn reset(&mut self);
+}
+
+impl Attempt for TcpSocket<'_> {
+    async fn connect(&mut self, address: IpAddress, port: u16) -> Result<(), ConnectError> {
+        TcpSocket::connect(self, (address, port)).await
+    }
+
+    async fn reset(&mut self) {
+        self.abort();
+        self.flush().await.ok();
+    }
+}
+
+/// Try the addresses in turn on one socket, taking each from `next` so that
+/// sockets racing each other try different ones
+async fn attempts<S: Attempt>(
+    socket: &mut S,
+    addresses: &[IpAddress],
+    port: u16,
+    next: &Cell<usize>,
+) -> Result<IpAddress, ConnectError> {
+    let mut result = Err(ConnectError::NoRoute);
+    while let Some(&address) = addresses.get(next.get()) {
+        next.set(next.get() + 1);
+        info!("connecting to {}...", address);
+        result = socket.connect(address, port).await.map(|()| address);
+        match result {
+            Ok(address) => return Ok(address),
+            Err(error) => {
+                warn!("connecting to {} failed: {:?}", address, error);
+                socket.reset().await;
+            }
+        }
+    }
+    result
+}
+
+/// Race attempts on two sockets, the second joining in after `delay`. A
+/// socket whose attempt fails moves straight on to the next address.
+async fn race<A: Attempt, B: Attempt>(
+    first: &mut A,
+    second: &mut B,
+    addresses: &[IpAddress],
+    port: u16,
+    delay: Duration,
+) -> Result<(usize, IpAddress), ConnectError> {
+    let next = Cell::new(0);
+    let result = {
+        let mut first_attempts = pin!(attempts(first, addresses, port, &next));
+        let mut second_attempts = pin!(async {
+            Timer::after(delay).await;
+            attempts(second, addresses, port, &next).await
+        });
+        match select(first_attempts.as_mut(), second_attempts.as_mut()).await {
+            Either::First(Ok(address)) => Ok((0, address)),
+            Either::Second(Ok(address)) => Ok((1, address)),
+            // The other socket may still get through
+            Either::First(Err(error)) => second_attempts
+                .await
+                .map(|address| (1, address))
+                .map_err(|_| error),
+            Either::Second(Err(error)) => first_attempts
+                .await
+                .map(|address| (0, address))
+                .map_err(|_| error),
+        }
+    };
+    match result {
+        Ok((0, _)) => second.reset().await,
+        Ok(_) => first.reset().await,
+        Err(_) => {}
+    }
+    result
+}
+
+/// Size of each buffer of the socket the second attempt runs on. That socket
+/// only ever sees the handshake, so it needs none of the room the connection
+/// itself does.
+const PROBE_BUFFER: usize = 64;
+
+/// Timeout of the attempts on the probe socket
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Race attempts on `socket` and `probe`, moving the connection over to
+/// `socket` if the probe won, so that only `socket` needs full-size buffers
+async fn connect_on<S: Attempt, P: Attempt>(
+    socket: &mut S,
+    probe: &mut P,
+    addresses: &[IpAddress],
+    port: u16,
+    delay: Duration,
+) -> Result<IpAddress, ConnectError> {
+    let (connected, address) = race(socket, probe, addresses, port, delay).await?;
+    if connected == 1 {
+        probe.reset().await;
+        info!("connecting to {} again...", address);
+        socket.connect(address, port).await?;
+    }
+    Ok(address)
+}
+
+/// Connect `socket` to one of `addresses`: attempts start in order,
+/// [`ATTEMPT_DELAY`] apart or as soon as one fails, with up to two under way
+/// at once, the second on a probe socket with small buffers, and the first to
+/// connect wins. With [`interleave`]d addresses that's an attempt over each
+/// address family. An address the probe reached first is connected to again
+/// on `socket`, costing a round trip but sparing a second pair of full-size
+/// buffers. Returns the address connected to.
+pub async fn connect(
+    stack: Stack<'_>,
+    socket: &mut TcpSocket<'_>,
+    addresses: &[IpAddress],
+    port: u16,
+) -> Result<IpAddress, ConnectError> {
+    let mut rx_buffer = [0; PROBE_BUFFER];
+    let mut tx_buffer = [0; PROBE_BUFFER];
+    let mut probe = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    probe.set_timeout(Some(PROBE_TIMEOUT));
+    connect_on(socket, &mut probe, addresses, port, ATTEMPT_DELAY).await
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::RefCell;
+    use core::net::{Ipv4Addr, Ipv6Addr};
+
+    use embassy_futures::block_on;
+    use embassy_time::Instant;
+
+    use super::*;
+
+    const V6: IpAddress = IpAddress::Ipv6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+    const V4: IpAddress = IpAddress::Ipv4(Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER_V4: IpAddress = IpAddress::Ipv4(Ipv4Addr::new(192, 0, 2, 2));
+
+    const DELAY: Duration = Duration::from_millis(100);
+
+    /// Outcome of connecting to an address, after how long
+    type Outcome = (IpAddress, u64, Result<(), ConnectError>);
+
+    /// Socket connecting as `outcomes` say, logging the attempts made on
+    /// any socket
+    struct FakeSocket<'a> {
+        outcomes: &'a [Outcome],
+        log: &'a RefCell<std::vec::Vec<(IpAddress, Instant)>>,
+        resets: usize,
+    }
+
+    impl Attempt for FakeSocket<'_> {
+        async fn connect(&mut self, address: IpAddress, _port: u16) -> Result<(), ConnectError> {
+            self.log.borrow_mut().push((address, Instant::now()));
+            let &(_, after, result) = self
+                .outcomes
+                .iter()
+                .find(|(outcome, _, _)| *outcome == address)
+                .unwrap();
+            Timer::after_millis(after).await;
+            result
+        }
+
+        async fn reset(&mut self) {
+            self.resets += 1;
+        }
+    }
+
+    type Connected = Result<(usize, IpAddress), ConnectError>;
+
+    /// Race the sockets, returning the result, the attempts made and the
+    /// resets of each socket
+    fn run(
+        addresses: &[IpAddress],
+        outcomes: &[Outcome],
+    ) -> (Connected, std::vec::Vec<(IpAddress, Duration)>, [usize; 2]) {
+        let log = RefCell::new(vec![]);
+        let mut first = FakeSocket {
+            outcomes,
+            log: &log,
+            resets: 0,
+        };
+        let mut second = FakeSocket {
+            outcomes,
+            log: &log,
+            resets: 0,
+        };
+        let start = Instant::now();
+        let result = block_on(race(&mut first, &mut second, addresses, 1883, DELAY));
+        let resets = [first.resets, second.resets];
+        let attempts = log
+            .take()
+            .into_iter()
+            .map(|(address, time)| (address, time - start))
+            .collect();
+        (result, attempts, resets)
+    }
+
+    #[test]
+    fn interleaves_families() {
+        let addresses: Vec<u8, 8> = interleave(&[1, 2, 3], &[10]);
+        assert_eq!(addresses.as_slice(), &[1, 10, 2, 3]);
+        let addresses: Vec<u8, 8> = interleave(&[], &[10, 11]);
+        assert_eq!(addresses.as_slice(), &[10, 11]);
+        let addresses: Vec<u8, 3> = interleave(&[1, 2], &[10, 11]);
+        assert_eq!(addresses.as_slice(), &[1, 10, 2]);
+    }
+
+    #[test]
+    fn fast_first_address_wins_alone() {
+        let (result, attempts, resets) = run(&[V6, V4], &[(V6, 0, Ok(())), (V4, 0, Ok(()))]);
+        assert_eq!(result, Ok((0, V6)));
+        assert_eq!(attempts.len(), 1);
+        assert_eq!(resets, [0, 1]);
+    }
+
+    #[test]
+    fn slow_first_address_is_raced() {
+        let (result, attempts, resets) = run(&[V6, V4], &[(V6, 2000, Ok(())), (V4, 10, Ok(()))]);
+        assert_eq!(result, Ok((1, V4)));
+        assert_eq!(attempts[0].0, V6);
+        assert_eq!(attempts[1].0, V4);
+        // The second attempt joined in after the delay rather than waiting
+        // for the first to finish
+        assert!(attempts[1].1 >= DELAY);
+        assert!(attempts[1].1 < Duration::from_millis(1000));
+        // The abandoned attempt is reset
+        assert_eq!(resets, [1, 0]);
+    }
+
+    #[test]
+    fn failure_moves_straight_on() {
+        let (result, attempts, _) = run(
+            &[V6, V4],
+            &[(V6, 0, Err(ConnectError::ConnectionReset)), (V4, 0, Ok(()))],
+        );
+        assert_eq!(result, Ok((0, V4)));
+        assert_eq!(attempts.len(), 2);
+        assert!(attempts[1].1 < DELAY);
+    }
+
+    #[test]
+    fn every_address_is_tried_once() {
+        let failed = Err(ConnectError::TimedOut);
+        let (result, attempts, _) = run(
+            &[V6, V4, OTHER_V4],
+            &[(V6, 300, failed), (V4, 50, failed), (OTHER_V4, 50, failed)],
+        );
+        assert_eq!(result, Err(ConnectError::TimedOut));
+        let mut tried: std::vec::Vec<_> = attempts.iter().map(|(address, _)| *address).collect();
+        tried.sort();
+        let mut expected = vec![V6, V4, OTHER_V4];
+        expected.sort();
+        assert_eq!(tried, expected);
+    }
+
+    #[test]
+    fn connection_moves_off_the_probe() {
+        let log = RefCell::new(vec![]);
+        let outcomes = [(V6, 2000, Ok(())), (V4, 10, Ok(()))];
+        let mut socket = FakeSocket {
+            outcomes: &outcomes,
+            log: &log,
+            resets: 0,
+        };
+        let mut probe = FakeSocket {
+            outcomes: &outcomes,
+            log: &log,
+            resets: 0,
+        };
+        let result = block_on(connect_on(&mut socket, &mut probe, &[V6, V4], 1883, DELAY));
+        assert_eq!(result, Ok(V4));
+        // The probe got through to V4 first, then the socket connected there
+        let tried: std::vec::Vec<_> = log.take().into_iter().map(|(address, _)| address).collect();
+        assert_eq!(tried, [V6, V4, V4]);
+        assert_eq!([socket.resets, probe.resets], [1, 1]);
+    }
+
+    #[test]
+    fn no_addresses_is_an_error() {
+        let (result, attempts, _) = run(&[], &[]);
+        assert_eq!(result, Err(ConnectError::NoRoute));
+        assert!(attempts.is_empty());
+    }
+}
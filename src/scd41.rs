@@ -1,47 +1,84 @@
 use defmt::{debug, error, expect, info, warn, Format};
-use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
-use embassy_sync::{
-    blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex},
-    watch::Watch,
-};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, watch::Watch};
 use embassy_time::{Delay, Duration, Timer};
-use esp_hal::{i2c::master::I2c, Async};
+use embedded_hal_async::i2c::I2c;
 use scd4x::Scd4xAsync;
 use serde::Serialize;
 
+use crate::barometric;
 use crate::bme680;
+use crate::fusion;
+use crate::psychrometrics::Psychrometrics;
 
+/// Newest measurement. Only the sink dispatcher reads it, everything else
+/// consuming readings does so through a sink.
 pub static WATCH: Watch<CriticalSectionRawMutex, Scd41Measurement, 2> = Watch::new();
 
 const PRESSURE_MIN_HPA: f32 = 700.0;
 const PRESSURE_MAX_HPA: f32 = 1200.0;
 const PRESSURE_FALLBACK_HPA: u16 = 1015;
+/// Highest altitude the SCD41 accepts for compensation, in m
+const ALTITUDE_MAX_M: f32 = 3000.0;
+/// Temperature offset range the SCD41 supports, in °C
+const TEMPERATURE_OFFSET_MAX: f32 = 20.0;
 
-#[derive(Debug, Format, Clone, Serialize)]
+#[derive(Debug, Format, Clone, Default, Serialize)]
 pub struct Scd41Measurement {
     // TODO
     timestamp: Option<()>,
     pub co2: u16,
-    temperature: f32,
-    humidity: f32,
+    pub temperature: f32,
+    pub humidity: f32,
+    /// Values derived from temperature and humidity, using the pressure the
+    /// sensor was compensated with
+    pub derived: Psychrometrics,
+}
+
+/// How the SCD41 corrects CO2 for air pressure
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Compensation {
+    /// Ambient pressure set before every measurement, from the BME680 or
+    /// `fallback` in hPa without a valid BME680 reading
+    AmbientPressure { fallback: u16 },
+    /// Altitude in m set once, `pressure` being the average pressure there in
+    /// hPa for the derived values
+    Altitude { altitude: u16, pressure: u16 },
+}
+
+/// Without a BME680 to measure pressure, let the sensor compensate for the
+/// average pressure at the site altitude instead of a fixed fallback.
+/// Altitudes beyond the range the sensor accepts are clamped to it.
+pub fn compensation(bme680_present: bool, site_altitude: Option<f32>) -> Compensation {
+    match (bme680_present, site_altitude) {
+        (false, Some(altitude)) => Compensation::Altitude {
+            altitude: altitude.clamp(0.0, ALTITUDE_MAX_M) as u16,
+            pressure: barometric::standard_pressure(altitude) as u16,
+        },
+        (true, Some(altitude)) => Compensation::AmbientPressure {
+            fallback: barometric::standard_pressure(altitude) as u16,
+        },
+        (_, None) => Compensation::AmbientPressure {
+            fallback: PRESSURE_FALLBACK_HPA,
+        },
+    }
 }
 
 /// Supervisor task that inititalizes the SCD41 sensor task and restarts
 /// it if it fails.
-#[embassy_executor::task]
-pub async fn supervisor(i2c_device: I2cDevice<'static, NoopRawMutex, I2c<'static, Async>>) -> ! {
+pub async fn supervisor<I: I2c>(i2c_device: I, bme680_present: bool) -> ! {
     let mut sensor = Scd4xAsync::new(i2c_device, Delay);
 
     loop {
         info!("SCD41: starting sensor task...");
-        let _ = scd41_sensor_task(&mut sensor).await;
+        let _ = scd41_sensor_task(&mut sensor, bme680_present).await;
         error!("SCD41: sensor failed. restarting...");
         Timer::after_secs(1).await;
     }
 }
 
-async fn scd41_sensor_task(
-    sensor: &mut Scd4xAsync<I2cDevice<'static, NoopRawMutex, I2c<'static, Async>>, Delay>,
+async fn scd41_sensor_task<I: I2c>(
+    sensor: &mut Scd4xAsync<I, Delay>,
+    bme680_present: bool,
 ) -> Result<!, ()> {
     debug!("SCD41: sending wake-up...");
     sensor.wake_up().await; // Sensor does not acknowledge wake-up
@@ -57,8 +94,11 @@ async fn scd41_sensor_task(
         Err(_) => Err(error!("SCD41: failed to get SCD41 serial number"))?,
     };
 
-    match sensor.temperature_offset().await {
-        Ok(temp_offset) => info!("SCD41: temperature offset: {}", temp_offset),
+    let mut temperature_offset = match sensor.temperature_offset().await {
+        Ok(temp_offset) => {
+            info!("SCD41: temperature offset: {}", temp_offset);
+            temp_offset
+        }
         Err(_) => Err(error!("SCD41: failed to get temperature offset"))?,
     };
 
@@ -72,6 +112,18 @@ async fn scd41_sensor_task(
         Err(_) => Err(error!("SCD41: failed to enable automatic self calibration"))?,
     };
 
+    let compensation = compensation(bme680_present, barometric::site_altitude());
+    let (altitude_compensated, fallback_pressure_hpa) = match compensation {
+        Compensation::Altitude { altitude, pressure } => {
+            match sensor.set_altitude(altitude).await {
+                Ok(()) => info!("SCD41: set altitude compensation to {} m", altitude),
+                Err(_) => Err(error!("SCD41: failed to set altitude"))?,
+            };
+            (true, pressure)
+        }
+        Compensation::AmbientPressure { fallback } => (false, fallback),
+    };
+
     match sensor.start_periodic_measurement().await {
         Ok(()) => info!("SCD41: started periodic measurement"),
         Err(_) => Err(error!("SCD41: failed to start periodic measurement"))?,
@@ -83,8 +135,29 @@ async fn scd41_sensor_task(
     loop {
         Timer::after(Duration::from_secs(5)).await;
 
+        // The offset can only be changed while the sensor is idle
+        if let Some(correction) = fusion::SCD41_OFFSET_CORRECTION.try_take() {
+            temperature_offset =
+                (temperature_offset + correction).clamp(0.0, TEMPERATURE_OFFSET_MAX);
+            sensor
+                .stop_periodic_measurement()
+                .await
+                .map_err(|_| error!("SCD41: failed to stop periodic measurement"))?;
+            match sensor.set_temperature_offset(temperature_offset).await {
+                Ok(()) => info!("SCD41: set temperature offset to {}", temperature_offset),
+                Err(_) => Err(error!("SCD41: failed to set temperature offset"))?,
+            }
+            sensor
+                .start_periodic_measurement()
+                .await
+                .map_err(|_| error!("SCD41: failed to restart periodic measurement"))?;
+            continue; // First measurement takes another 5 s
+        }
+
         // Get latest pressure from BME680, validate, and clamp if needed
-        let pressure_hpa = if let Some(pressure) = bme680_receiver.try_get().map(|m| m.pressure) {
+        let pressure_hpa = if altitude_compensated {
+            fallback_pressure_hpa
+        } else if let Some(pressure) = bme680_receiver.try_get().map(|m| m.pressure) {
             if (PRESSURE_MIN_HPA..=PRESSURE_MAX_HPA).contains(&pressure) {
                 debug!("SCD41: got pressure measurement: {} hPa", pressure);
                 pressure as u16
@@ -98,21 +171,24 @@ async fn scd41_sensor_task(
             } else {
                 warn!(
                     "SCD41: invalid pressure, using fallback: {} hPa",
-                    PRESSURE_FALLBACK_HPA
+                    fallback_pressure_hpa
                 );
-                PRESSURE_FALLBACK_HPA
+                fallback_pressure_hpa
             }
         } else {
             error!(
                 "SCD41: no BME680 pressure data, using fallback: {} hPa",
-                PRESSURE_FALLBACK_HPA
+                fallback_pressure_hpa
             );
-            PRESSURE_FALLBACK_HPA
+            fallback_pressure_hpa
         };
 
-        match sensor.set_ambient_pressure(pressure_hpa).await {
-            Ok(()) => debug!("SCD41: set ambient pressure to {} hPa", pressure_hpa),
-            Err(_) => Err(error!("SCD41: failed to set ambient pressure"))?,
+        // Setting ambient pressure would override altitude compensation
+        if !altitude_compensated {
+            match sensor.set_ambient_pressure(pressure_hpa).await {
+                Ok(()) => debug!("SCD41: set ambient pressure to {} hPa", pressure_hpa),
+                Err(_) => Err(error!("SCD41: failed to set ambient pressure"))?,
+            }
         }
 
         let measurement = sensor
@@ -123,6 +199,7 @@ async fn scd41_sensor_task(
                 co2: data.co2,
                 temperature: data.temperature,
                 humidity: data.humidity,
+                derived: Psychrometrics::new(data.temperature, data.humidity, pressure_hpa as f32),
             })
             .map_err(|_| error!("SCD41: failed to read measurement"))?;
 
@@ -133,3 +210,55 @@ async fn scd41_sensor_task(
         debug!("SCD41: sent measurement to Watch")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bme680_pressure_is_preferred() {
+        assert_eq!(
+            compensation(true, Some(1609.0)),
+            Compensation::AmbientPressure { fallback: 834 }
+        );
+    }
+
+    #[test]
+    fn altitude_without_bme680() {
+        assert_eq!(
+            compensation(false, Some(1609.0)),
+            Compensation::Altitude {
+                altitude: 1609,
+                pressure: 834,
+            }
+        );
+    }
+
+    #[test]
+    fn fixed_fallback_without_altitude() {
+        let fallback = Compensation::AmbientPressure {
+            fallback: PRESSURE_FALLBACK_HPA,
+        };
+        assert_eq!(compensation(false, None), fallback);
+        assert_eq!(compensation(true, None), fallback);
+    }
+
+    #[test]
+    fn altitude_is_clamped_to_sensor_range() {
+        // The derived values still use the pressure at the real altitude
+        assert_eq!(
+            compensation(false, Some(4000.0)),
+            Compensation::Altitude {
+                altitude: 3000,
+                pressure: 616,
+            }
+        );
+        assert_eq!(
+            compensation(false, Some(-400.0)),
+            Compensation::Altitude {
+                altitude: 0,
+                pressure: 1062,
+            }
+        );
+    }
+}
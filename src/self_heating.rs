@@ -0,0 +1,189 @@
+//! Compensation for the board heating the BME680 as the ESP32-C6 and its
+//! radio warm up
+
+use libm::expf;
+
+/// Temperature rise in °C once the board has warmed up
+const WARMUP: &str = match option_env!("SELF_HEATING_WARMUP") {
+    Some(warmup) => warmup,
+    None => "1.0",
+};
+/// Time constant of the warm-up in s, the board is ~95% warm after three
+const WARMUP_TIME: &str = match option_env!("SELF_HEATING_WARMUP_TIME") {
+    Some(time) => time,
+    None => "600",
+};
+/// Additional rise in °C per kB/s of Wi-Fi traffic sent
+const TX: &str = match option_env!("SELF_HEATING_TX") {
+    Some(tx) => tx,
+    None => "0.5",
+};
+/// Time constant in s over which the rise follows changes in traffic
+const TX_TIME: &str = match option_env!("SELF_HEATING_TX_TIME") {
+    Some(time) => time,
+    None => "120",
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Model {
+    /// °C
+    pub warmup: f32,
+    /// s
+    pub warmup_time: f32,
+    /// °C per kB/s
+    pub tx: f32,
+    /// s
+    pub tx_time: f32,
+}
+
+impl Model {
+    /// Model from the configured parameters
+    pub fn configured() -> Self {
+        Model {
+            warmup: WARMUP.parse().unwrap_or(1.0),
+            warmup_time: WARMUP_TIME.parse().unwrap_or(600.0),
+            tx: TX.parse().unwrap_or(0.5),
+            tx_time: TX_TIME.parse().unwrap_or(120.0),
+        }
+    }
+
+    /// Rise from the board warming up, `uptime` seconds after boot
+    pub fn warmup_rise(&self, uptime: f32) -> f32 {
+        if self.warmup_time <= 0.0 {
+            return self.warmup;
+        }
+        self.warmup * (1.0 - expf(-uptime / self.warmup_time))
+    }
+}
+
+/// Tracks the temperature rise of the board, to be subtracted from the
+/// BME680 temperature
+#[derive(Debug, Clone, Copy)]
+pub struct Compensator {
+    model: Model,
+    /// Current rise from Wi-Fi traffic in °C
+    tx_rise: f32,
+    /// Uptime in s and total bytes sent at the previous update
+    last: Option<(f32, u32)>,
+}
+
+impl Compensator {
+    pub const fn new(model: Model) -> Self {
+        Compensator {
+            model,
+            tx_rise: 0.0,
+            last: None,
+        }
+    }
+
+    /// Temperature rise in °C at `uptime` seconds after boot, given the total
+    /// number of bytes sent over Wi-Fi so far. The traffic term follows the
+    /// send rate since the previous update with a first-order lag.
+    pub fn update(&mut self, uptime: f32, tx_bytes: u32) -> f32 {
+        if let Some((last_uptime, last_bytes)) = self.last {
+            let elapsed = uptime - last_uptime;
+            if elapsed > 0.0 {
+                let rate = tx_bytes.wrapping_sub(last_bytes) as f32 / 1000.0 / elapsed;
+                let target = self.model.tx * rate;
+                let alpha = match self.model.tx_time > 0.0 {
+                    true => 1.0 - expf(-elapsed / self.model.tx_time),
+                    false => 1.0,
+                };
+                self.tx_rise += alpha * (target - self.tx_rise);
+            }
+        }
+        self.last = Some((uptime, tx_bytes));
+        self.model.warmup_rise(uptime) + self.tx_rise
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODEL: Model = Model {
+        warmup: 1.0,
+        warmup_time: 600.0,
+        tx: 0.5,
+        tx_time: 120.0,
+    };
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{actual} should be within {tolerance} of {expected}"
+        );
+    }
+
+    /// Send at a constant `rate` in B/s for `seconds` from `start`, updating
+    /// every 10 s, returning the last rise and bytes sent
+    fn send(
+        compensator: &mut Compensator,
+        start: u32,
+        seconds: u32,
+        rate: u32,
+        bytes: u32,
+    ) -> (f32, u32) {
+        let mut rise = 0.0;
+        let mut bytes = bytes;
+        for t in (start..=start + seconds).step_by(10) {
+            rise = compensator.update(t as f32, bytes);
+            bytes = bytes.wrapping_add(rate * 10);
+        }
+        (rise, bytes)
+    }
+
+    #[test]
+    fn warms_up_exponentially() {
+        assert_eq!(MODEL.warmup_rise(0.0), 0.0);
+        assert_close(MODEL.warmup_rise(600.0), 1.0 - expf(-1.0), 1e-5);
+        assert_close(MODEL.warmup_rise(1800.0), 0.95, 0.01);
+        assert_close(MODEL.warmup_rise(36_000.0), 1.0, 1e-5);
+
+        let instant = Model {
+            warmup_time: 0.0,
+            ..MODEL
+        };
+        assert_eq!(instant.warmup_rise(0.0), 1.0);
+    }
+
+    #[test]
+    fn idle_radio_adds_nothing() {
+        let mut compensator = Compensator::new(MODEL);
+        let (rise, _) = send(&mut compensator, 0, 3600, 0, 0);
+        assert_close(rise, MODEL.warmup_rise(3600.0), 1e-5);
+    }
+
+    #[test]
+    fn traffic_rise_follows_rate_with_lag() {
+        let mut compensator = Compensator::new(MODEL);
+        // Warm board, then 2 kB/s for one time constant
+        let (before, bytes) = send(&mut compensator, 0, 36_000, 0, 0);
+        let (rise, bytes) = send(&mut compensator, 36_010, 120, 2000, bytes);
+        assert_close(rise - before, 1.0 * (1.0 - expf(-1.0)), 0.07);
+
+        // Settles at 0.5 °C per kB/s
+        let (rise, bytes) = send(&mut compensator, 36_140, 1200, 2000, bytes);
+        assert_close(rise - before, 1.0, 1e-3);
+
+        // And decays once the traffic stops
+        let (rise, _) = send(&mut compensator, 37_350, 1200, 0, bytes);
+        assert_close(rise, before, 1e-3);
+    }
+
+    #[test]
+    fn survives_counter_wrap() {
+        let mut compensator = Compensator::new(MODEL);
+        let (before, _) = send(&mut compensator, 0, 36_000, 0, u32::MAX - 50_000);
+        let (rise, bytes) = send(&mut compensator, 36_010, 1200, 1000, u32::MAX - 50_000);
+        assert!(bytes < u32::MAX - 50_000, "should have wrapped");
+        assert_close(rise - before, 0.5, 1e-3);
+    }
+
+    #[test]
+    fn repeated_uptime_changes_nothing() {
+        let mut compensator = Compensator::new(MODEL);
+        let rise = compensator.update(100.0, 0);
+        assert_eq!(compensator.update(100.0, 1_000_000), rise);
+    }
+}
@@ -0,0 +1,44 @@
+//! Serial logging sink, writing every reading to the log for a board on a
+//! debug probe or a quick look at a unit without setting up a server
+
+use defmt::{error, info, warn};
+
+use crate::clock;
+use crate::config;
+use crate::device;
+use crate::payload::{self, Encoding};
+use crate::sink;
+
+pub const ENABLED: bool = config::flag(option_env!("SERIAL_ENABLED"), false);
+/// Encoding of the logged readings, `json`, `senml` or `cbor`
+const FORMAT: &str = match option_env!("SERIAL_FORMAT") {
+    Some(format) => format,
+    None => "json",
+};
+
+#[embassy_executor::task]
+pub async fn logger() -> ! {
+    let encoding = Encoding::parse(FORMAT).unwrap_or_else(|| {
+        warn!("Serial: unknown format {}, using JSON", FORMAT);
+        Encoding::Json
+    });
+    info!("Serial: logging readings as {:?}", encoding);
+
+    let mut buf = [0; 768];
+    loop {
+        let reading = sink::SERIAL.receive().await;
+        let length = match payload::encode(encoding, &reading, device::id(), clock::now(), &mut buf)
+        {
+            Ok(length) => length,
+            Err(e) => {
+                error!("Serial: failed to encode reading: {:?}", e);
+                continue;
+            }
+        };
+        let payload = &buf[..length];
+        match (encoding, core::str::from_utf8(payload)) {
+            (Encoding::Json | Encoding::Senml, Ok(text)) => info!("Serial: {=str}", text),
+            _ => info!("Serial: {=[u8]:02x}", payload),
+        }
+    }
+}
@@ -0,0 +1,375 @@
+//! Fan-out of sensor readings to the output sinks and to every other task
+//! that consumes them. Each sink drains its own queue, so one that is slow or
+//! reconnecting doesn't hold up the others.
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::{expect, info, warn, Format};
+use embassy_futures::select::{select, Either};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    channel::Channel,
+};
+
+use crate::bme680::{self, Bme680Measurement};
+use crate::scd41::{self, Scd41Measurement};
+
+#[derive(Debug, Format, Clone)]
+pub enum Reading {
+    Scd41(Scd41Measurement),
+    Bme680(Bme680Measurement),
+}
+
+impl Reading {
+    /// Name of the sensor the reading is from
+    pub fn sensor(&self) -> &'static str {
+        match self {
+            Reading::Scd41(_) => "scd41",
+            Reading::Bme680(_) => "bme680",
+        }
+    }
+}
+
+pub trait Sink {
+    fn name(&self) -> &'static str;
+
+    /// Whether anything is consuming readings, inactive sinks are skipped
+    fn active(&self) -> bool;
+
+    /// Queue a reading without waiting, returning false if an older one had
+    /// to be dropped to make room
+    fn offer(&self, reading: &Reading) -> bool;
+}
+
+/// Bounded queue of readings for one sink, which keeps the newest readings
+/// when its consumer falls behind
+pub struct Queue<const N: usize> {
+    name: &'static str,
+    active: AtomicBool,
+    channel: Channel<CriticalSectionRawMutex, Reading, N>,
+}
+
+impl<const N: usize> Queue<N> {
+    pub const fn new(name: &'static str) -> Self {
+        Queue {
+            name,
+            active: AtomicBool::new(false),
+            channel: Channel::new(),
+        }
+    }
+
+    /// Wait for the next reading. The queue only starts filling once this
+    /// has first been called.
+    pub async fn receive(&self) -> Reading {
+        self.active.store(true, Ordering::Relaxed);
+        self.channel.receive().await
+    }
+}
+
+impl<const N: usize> Sink for Queue<N> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    fn offer(&self, reading: &Reading) -> bool {
+        if self.channel.try_send(reading.clone()).is_ok() {
+            return true;
+        }
+        let _ = self.channel.try_receive();
+        let _ = self.channel.try_send(reading.clone());
+        false
+    }
+}
+
+/// Newest reading of each sensor, for consumers that show the current
+/// values rather than act on every reading
+pub struct Latest {
+    scd41: Mutex<CriticalSectionRawMutex, RefCell<Option<Scd41Measurement>>>,
+    bme680: Mutex<CriticalSectionRawMutex, RefCell<Option<Bme680Measurement>>>,
+}
+
+impl Latest {
+    pub const fn new() -> Self {
+        Latest {
+            scd41: Mutex::new(RefCell::new(None)),
+            bme680: Mutex::new(RefCell::new(None)),
+        }
+    }
+
+    pub fn scd41(&self) -> Option<Scd41Measurement> {
+        self.scd41.lock(|scd41| scd41.borrow().clone())
+    }
+
+    pub fn bme680(&self) -> Option<Bme680Measurement> {
+        self.bme680.lock(|bme680| bme680.borrow().clone())
+    }
+}
+
+impl Default for Latest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sink for Latest {
+    fn name(&self) -> &'static str {
+        "Latest"
+    }
+
+    fn active(&self) -> bool {
+        true
+    }
+
+    fn offer(&self, reading: &Reading) -> bool {
+        match reading {
+            Reading::Scd41(measurement) => self
+                .scd41
+                .lock(|scd41| *scd41.borrow_mut() = Some(measurement.clone())),
+            Reading::Bme680(measurement) => self
+                .bme680
+                .lock(|bme680| *bme680.borrow_mut() = Some(measurement.clone())),
+        }
+        true
+    }
+}
+
+pub static LATEST: Latest = Latest::new();
+
+// Outputs
+pub static MQTT: Queue<4> = Queue::new("MQTT");
+pub static INFLUX: Queue<8> = Queue::new("Influx");
+pub static PUSH: Queue<4> = Queue::new("HTTP push");
+pub static SERIAL: Queue<4> = Queue::new("Serial");
+
+// Analysis and displays
+pub static ALERTS: Queue<4> = Queue::new("Alerts");
+pub static STATS: Queue<4> = Queue::new("Stats");
+pub static FUSION: Queue<4> = Queue::new("Fusion");
+pub static VENTILATION: Queue<4> = Queue::new("Ventilation");
+pub static DISPLAY: Queue<2> = Queue::new("Display");
+pub static EPAPER: Queue<2> = Queue::new("E-paper");
+
+/// The latest values are updated first, so a consumer woken by its queue
+/// sees them already
+static SINKS: [&(dyn Sink + Sync); 11] = [
+    &LATEST,
+    &MQTT,
+    &INFLUX,
+    &PUSH,
+    &SERIAL,
+    &ALERTS,
+    &STATS,
+    &FUSION,
+    &VENTILATION,
+    &DISPLAY,
+    &EPAPER,
+];
+
+/// Passes each reading to every active sink
+pub struct Dispatcher<'a> {
+    sinks: &'a [&'a (dyn Sink + Sync)],
+}
+
+impl<'a> Dispatcher<'a> {
+    pub fn new(sinks: &'a [&'a (dyn Sink + Sync)]) -> Self {
+        Dispatcher { sinks }
+    }
+
+    /// Offer a reading to the active sinks, calling `dropped` for each one
+    /// that had to drop an older reading. Returns the number of sinks offered
+    /// the reading.
+    pub fn dispatch(&self, reading: &Reading, mut dropped: impl FnMut(&dyn Sink)) -> usize {
+        let mut offered = 0;
+        for sink in self.sinks.iter().filter(|sink| sink.active()) {
+            if !sink.offer(reading) {
+                dropped(*sink);
+            }
+            offered += 1;
+        }
+        offered
+    }
+}
+
+#[embassy_executor::task]
+pub async fn dispatcher() -> ! {
+    let mut scd41_receiver = expect!(
+        scd41::WATCH.receiver(),
+        "SCD41 Watch should have capacity for sink receiver"
+    );
+    let mut bme680_receiver = expect!(
+        bme680::WATCH.receiver(),
+        "BME680 Watch should have capacity for sink receiver"
+    );
+    let dispatcher = Dispatcher::new(&SINKS);
+    info!("Sink: dispatching to {} sinks", SINKS.len());
+
+    loop {
+        let reading = match select(scd41_receiver.changed(), bme680_receiver.changed()).await {
+            Either::First(measurement) => Reading::Scd41(measurement),
+            Either::Second(measurement) => Reading::Bme680(measurement),
+        };
+        dispatcher.dispatch(&reading, |sink| {
+            warn!("Sink: {} queue full, dropped oldest reading", sink.name())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::future::ready;
+    use std::sync::Mutex;
+
+    use embassy_futures::block_on;
+
+    use super::*;
+
+    /// Sink with room for `capacity` readings that never drains on its own,
+    /// like one whose connection is down
+    struct FakeSink {
+        name: &'static str,
+        active: bool,
+        capacity: usize,
+        received: Mutex<std::vec::Vec<u16>>,
+    }
+
+    impl FakeSink {
+        fn new(name: &'static str, capacity: usize) -> Self {
+            FakeSink {
+                name,
+                active: true,
+                capacity,
+                received: Mutex::new(vec![]),
+            }
+        }
+
+        fn received(&self) -> std::vec::Vec<u16> {
+            self.received.lock().unwrap().clone()
+        }
+    }
+
+    impl Sink for FakeSink {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn active(&self) -> bool {
+            self.active
+        }
+
+        fn offer(&self, reading: &Reading) -> bool {
+            let Reading::Scd41(measurement) = reading else {
+                return true;
+            };
+            let mut received = self.received.lock().unwrap();
+            let room = received.len() < self.capacity;
+            if !room {
+                received.remove(0);
+            }
+            received.push(measurement.co2);
+            room
+        }
+    }
+
+    fn co2(co2: u16) -> Reading {
+        let mut measurement = Scd41Measurement::default();
+        measurement.co2 = co2;
+        Reading::Scd41(measurement)
+    }
+
+    fn received_co2<const N: usize>(queue: &Queue<N>) -> u16 {
+        match block_on(queue.receive()) {
+            Reading::Scd41(measurement) => measurement.co2,
+            Reading::Bme680(_) => panic!("expected an SCD41 reading"),
+        }
+    }
+
+    /// Start waiting on the queue once, as its consumer would
+    fn activate<const N: usize>(queue: &Queue<N>) {
+        block_on(select(queue.receive(), ready(())));
+    }
+
+    #[test]
+    fn slow_sink_drops_only_its_own_oldest_readings() {
+        let slow = FakeSink::new("slow", 2);
+        let fast = FakeSink::new("fast", 100);
+        let sinks: [&(dyn Sink + Sync); 2] = [&slow, &fast];
+        let dispatcher = Dispatcher::new(&sinks);
+
+        let mut dropped = std::vec::Vec::new();
+        for value in 400..405 {
+            let offered = dispatcher.dispatch(&co2(value), |sink| dropped.push(sink.name()));
+            assert_eq!(offered, 2);
+        }
+
+        assert_eq!(slow.received(), [403, 404]);
+        assert_eq!(fast.received(), [400, 401, 402, 403, 404]);
+        assert_eq!(dropped, ["slow", "slow", "slow"]);
+    }
+
+    #[test]
+    fn inactive_sinks_are_skipped() {
+        let mut idle = FakeSink::new("idle", 4);
+        idle.active = false;
+        let active = FakeSink::new("active", 4);
+        let sinks: [&(dyn Sink + Sync); 2] = [&idle, &active];
+
+        let offered = Dispatcher::new(&sinks).dispatch(&co2(400), |_| panic!("nothing dropped"));
+
+        assert_eq!(offered, 1);
+        assert!(idle.received().is_empty());
+        assert_eq!(active.received(), [400]);
+    }
+
+    #[test]
+    fn queue_fills_once_received_from() {
+        let queue: Queue<2> = Queue::new("queue");
+        let sinks: [&(dyn Sink + Sync); 1] = [&queue];
+        let dispatcher = Dispatcher::new(&sinks);
+
+        // Nothing waits on the queue yet, so nothing is held for it
+        assert_eq!(dispatcher.dispatch(&co2(400), |_| {}), 0);
+
+        activate(&queue);
+        assert_eq!(dispatcher.dispatch(&co2(401), |_| {}), 1);
+        assert_eq!(received_co2(&queue), 401);
+    }
+
+    #[test]
+    fn full_queue_keeps_the_newest_readings() {
+        let queue: Queue<2> = Queue::new("queue");
+        activate(&queue);
+
+        assert!(queue.offer(&co2(400)));
+        assert!(queue.offer(&co2(401)));
+        // Dispatching never waits on a full queue
+        assert!(!queue.offer(&co2(402)));
+        assert!(!queue.offer(&co2(403)));
+
+        assert_eq!(received_co2(&queue), 402);
+        assert_eq!(received_co2(&queue), 403);
+        assert!(queue.offer(&co2(404)));
+        assert_eq!(received_co2(&queue), 404);
+    }
+
+    #[test]
+    fn latest_keeps_one_reading_per_sensor() {
+        let latest = Latest::new();
+        assert!(latest.scd41().is_none() && latest.bme680().is_none());
+
+        let bme680 = Bme680Measurement {
+            temperature: 21.5,
+            ..Default::default()
+        };
+        assert!(latest.offer(&co2(400)));
+        assert!(latest.offer(&Reading::Bme680(bme680)));
+        assert!(latest.offer(&co2(420)));
+
+        assert_eq!(latest.scd41().map(|m| m.co2), Some(420));
+        assert_eq!(latest.bme680().map(|m| m.temperature), Some(21.5));
+    }
+}
@@ -0,0 +1,668 @@
+//! IPv6 stateless address autoconfiguration (RFC 4862): a link-local address
+//! from the MAC address, then a global address from the /64 prefix in router
+//! advertisements, along with the router as gateway and RDNSS (RFC 8106) DNS
+//! servers. Each address is checked for duplicates (RFC 4862 section 5.4)
+//! before it's used, as another node may already have taken it by hand or
+//! with a cloned MAC address.
+//!
+//! embassy-net holds a single IPv6 address, so the global address replaces
+//! the link-local one rather than joining it. Nothing needs the link-local
+//! address once there's a global one: routers and other neighbors are
+//! reached from the global address, which neighbor discovery resolves like
+//! any other. Router solicitations must come from a link-local address, and
+//! are only sent while it's configured, before the first advertisement and
+//! after the prefix expires. A router checking its neighbor cache entry for
+//! the link-local address stops getting answers, which only costs it the
+//! entry.
+
+use core::net::Ipv6Addr;
+
+use defmt::{debug, error, info, warn};
+use embassy_futures::select::{select, Either};
+use embassy_net::{
+    driver::Driver,
+    raw::{IpProtocol, IpVersion, PacketMetadata, RawSocket},
+    ConfigV6, Ipv6Cidr, Stack, StaticConfigV6,
+};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+
+use crate::config;
+use crate::device;
+
+pub const ENABLED: bool = config::flag(option_env!("NETWORK_IPV6"), true);
+
+/// Router solicitations sent before waiting for unsolicited advertisements
+/// (RFC 4861 section 10)
+const MAX_SOLICITATIONS: u32 = 3;
+const SOLICITATION_INTERVAL: Duration = Duration::from_secs(4);
+
+const ALL_ROUTERS: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 2);
+
+const HEADER_LENGTH: usize = 40;
+const NEXT_HEADER_ICMPV6: u8 = 58;
+/// Neighbor discovery packets are only accepted from the link itself
+const HOP_LIMIT: u8 = 255;
+
+const ROUTER_SOLICITATION: u8 = 133;
+const ROUTER_ADVERTISEMENT: u8 = 134;
+const NEIGHBOR_SOLICITATION: u8 = 135;
+const NEIGHBOR_ADVERTISEMENT: u8 = 136;
+
+const OPTION_SOURCE_LINK_LAYER_ADDRESS: u8 = 1;
+const OPTION_PREFIX_INFORMATION: u8 = 3;
+const OPTION_RDNSS: u8 = 25;
+/// Autonomous address-configuration flag of a prefix
+const FLAG_AUTONOMOUS: u8 = 0x40;
+
+/// Most DNS servers taken from an advertisement, as many as embassy-net keeps
+pub const MAX_DNS_SERVERS: usize = 3;
+
+/// Length of a router solicitation with the source link-layer address
+pub const SOLICITATION_LENGTH: usize = HEADER_LENGTH + 16;
+/// Length of a neighbor solicitation without options, as sent for duplicate
+/// address detection
+pub const NEIGHBOR_SOLICITATION_LENGTH: usize = HEADER_LENGTH + 24;
+
+/// Wait for another node to defend a tentative address, RetransTimer of RFC
+/// 4861 section 10
+const DUPLICATE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Modified EUI-64 interface identifier of a MAC address (RFC 4291 appendix A)
+pub fn interface_id(mac: [u8; 6]) -> [u8; 8] {
+    [
+        mac[0] ^ 0x02,
+        mac[1],
+        mac[2],
+        0xff,
+        0xfe,
+        mac[3],
+        mac[4],
+        mac[5],
+    ]
+}
+
+/// Address in a /64 prefix with the interface identifier of a MAC address
+pub fn address(prefix: Ipv6Addr, mac: [u8; 6]) -> Ipv6Addr {
+    let mut octets = prefix.octets();
+    octets[8..].copy_from_slice(&interface_id(mac));
+    Ipv6Addr::from(octets)
+}
+
+pub fn link_local(mac: [u8; 6]) -> Ipv6Addr {
+    address(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0), mac)
+}
+
+/// Whether an address is in fe80::/10
+pub fn is_link_local(address: Ipv6Addr) -> bool {
+    address.segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// Solicited-node multicast address of an address (RFC 4291 section 2.7.1)
+pub fn solicited_node(address: Ipv6Addr) -> Ipv6Addr {
+    let octets = address.octets();
+    Ipv6Addr::new(
+        0xff02,
+        0,
+        0,
+        0,
+        0,
+        1,
+        0xff00 | u16::from(octets[13]),
+        u16::from_be_bytes([octets[14], octets[15]]),
+    )
+}
+
+/// ICMPv6 checksum over the pseudo-header and `message`, whose checksum
+/// field is included as it is
+pub fn checksum(source: Ipv6Addr, destination: Ipv6Addr, message: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    let mut add = |bytes: &[u8]| {
+        for chunk in bytes.chunks(2) {
+            sum += u32::from(u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]));
+        }
+    };
+    add(&source.octets());
+    add(&destination.octets());
+    add(&(message.len() as u32).to_be_bytes());
+    add(&[0, 0, 0, NEXT_HEADER_ICMPV6]);
+    add(message);
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// IPv6 packet of `N` bytes carrying an ICMPv6 message, filled in by `fill`
+/// apart from the checksum
+fn icmpv6_packet<const N: usize>(
+    source: Ipv6Addr,
+    destination: Ipv6Addr,
+    fill: impl FnOnce(&mut [u8]),
+) -> [u8; N] {
+    let mut packet = [0; N];
+    let (header, message) = packet.split_at_mut(HEADER_LENGTH);
+    header[0] = 0x60;
+    header[4..6].copy_from_slice(&(message.len() as u16).to_be_bytes());
+    header[6] = NEXT_HEADER_ICMPV6;
+    header[7] = HOP_LIMIT;
+    header[8..24].copy_from_slice(&source.octets());
+    header[24..40].copy_from_slice(&destination.octets());
+    fill(message);
+    let checksum = checksum(source, destination, message);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+    packet
+}
+
+/// IPv6 packet carrying a router solicitation to all routers
+pub fn router_solicitation(source: Ipv6Addr, mac: [u8; 6]) -> [u8; SOLICITATION_LENGTH] {
+    icmpv6_packet(source, ALL_ROUTERS, |message| {
+        message[0] = ROUTER_SOLICITATION;
+        message[8] = OPTION_SOURCE_LINK_LAYER_ADDRESS;
+        message[9] = 1;
+        message[10..16].copy_from_slice(&mac);
+    })
+}
+
+/// IPv6 packet carrying a neighbor solicitation for a tentative address, from
+/// the unspecified address as duplicate address detection requires
+pub fn neighbor_solicitation(target: Ipv6Addr) -> [u8; NEIGHBOR_SOLICITATION_LENGTH] {
+    icmpv6_packet(Ipv6Addr::UNSPECIFIED, solicited_node(target), |message| {
+        message[0] = NEIGHBOR_SOLICITATION;
+        message[8..24].copy_from_slice(&target.octets());
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Prefix {
+    pub prefix: Ipv6Addr,
+    /// In s, u32::MAX meaning forever
+    pub valid_lifetime: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouterAdvertisement {
+    pub router: Ipv6Addr,
+    /// In s, 0 if the router isn't a default router
+    pub router_lifetime: u16,
+    /// First /64 prefix for autonomous address configuration
+    pub prefix: Option<Prefix>,
+    pub dns_servers: Vec<Ipv6Addr, MAX_DNS_SERVERS>,
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+fn address_at(bytes: &[u8], offset: usize) -> Ipv6Addr {
+    let mut octets = [0; 16];
+    octets.copy_from_slice(&bytes[offset..offset + 16]);
+    Ipv6Addr::from(octets)
+}
+
+/// Source address and message of an IPv6 packet carrying a neighbor
+/// discovery message, None if it isn't one or its checksum is wrong
+fn neighbor_discovery(packet: &[u8]) -> Option<(Ipv6Addr, &[u8])> {
+    if packet.len() < HEADER_LENGTH
+        || packet[0] >> 4 != 6
+        || packet[6] != NEXT_HEADER_ICMPV6
+        || packet[7] != HOP_LIMIT
+    {
+        return None;
+    }
+    let source = address_at(packet, 8);
+    let destination = address_at(packet, 24);
+    let message = packet.get(HEADER_LENGTH..HEADER_LENGTH + usize::from(u16_at(packet, 4)))?;
+    (message.len() >= 4 && message[1] == 0 && checksum(source, destination, message) == 0)
+        .then_some((source, message))
+}
+
+/// Whether a packet shows another node using or claiming `address`: a
+/// neighbor advertisement for it, or a neighbor solicitation for it from a
+/// node checking it for duplicates itself
+pub fn claims(packet: &[u8], address: Ipv6Addr) -> bool {
+    let Some((source, message)) = neighbor_discovery(packet) else {
+        return false;
+    };
+    if message.len() < 24 || address_at(message, 8) != address {
+        return false;
+    }
+    match message[0] {
+        NEIGHBOR_ADVERTISEMENT => true,
+        NEIGHBOR_SOLICITATION => source.is_unspecified(),
+        _ => false,
+    }
+}
+
+/// Parse an IPv6 packet as a router advertisement, None if it isn't a valid
+/// one
+pub fn parse_router_advertisement(packet: &[u8]) -> Option<RouterAdvertisement> {
+    let (router, message) = neighbor_discovery(packet)?;
+    if message.len() < 16 || message[0] != ROUTER_ADVERTISEMENT || !is_link_local(router) {
+        return None;
+    }
+
+    let mut advertisement = RouterAdvertisement {
+        router,
+        router_lifetime: u16_at(message, 6),
+        prefix: None,
+        dns_servers: Vec::new(),
+    };
+    let mut options = &message[16..];
+    while options.len() >= 2 {
+        let length = usize::from(options[1]) * 8;
+        if length == 0 || length > options.len() {
+            return None;
+        }
+        let option = &options[..length];
+        match option[0] {
+            OPTION_PREFIX_INFORMATION if length == 32 => {
+                let prefix = address_at(option, 16);
+                let valid_lifetime = u32_at(option, 4);
+                if advertisement.prefix.is_none()
+                    && option[2] == 64
+                    && option[3] & FLAG_AUTONOMOUS != 0
+                    && valid_lifetime > 0
+                    && !is_link_local(prefix)
+                    && !prefix.is_multicast()
+                {
+                    advertisement.prefix = Some(Prefix {
+                        prefix,
+                        valid_lifetime,
+                    });
+                }
+            }
+            OPTION_RDNSS if length >= 24 && u32_at(option, 4) > 0 => {
+                for server in option[8..].chunks_exact(16) {
+                    let _ = advertisement.dns_servers.push(address_at(server, 0));
+                }
+            }
+            _ => {}
+        }
+        options = &options[length..];
+    }
+    Some(advertisement)
+}
+
+fn link_local_config(address: Ipv6Addr) -> ConfigV6 {
+    ConfigV6::Static(StaticConfigV6 {
+        address: Ipv6Cidr::new(address, 64),
+        gateway: None,
+        dns_servers: Vec::new(),
+    })
+}
+
+/// Duplicate address detection for a tentative address: solicit its owner
+/// and wait for another node to defend it, returning whether one did
+async fn is_duplicate(
+    stack: Stack<'_>,
+    socket: &RawSocket<'_>,
+    address: Ipv6Addr,
+    packet: &mut [u8],
+) -> bool {
+    // Receive other nodes' solicitations for the address too
+    let group = solicited_node(address);
+    if let Err(err) = stack.join_multicast_group(group) {
+        warn!("SLAAC: failed to join {}: {:?}", group, err);
+    }
+    socket.send(&neighbor_solicitation(address)).await;
+    let deadline = Instant::now() + DUPLICATE_TIMEOUT;
+    let duplicate = loop {
+        match select(socket.recv(packet), Timer::at(deadline)).await {
+            Either::First(Ok(length)) if claims(&packet[..length], address) => break true,
+            Either::First(_) => {}
+            Either::Second(()) => break false,
+        }
+    };
+    let _ = stack.leave_multicast_group(group);
+    duplicate
+}
+
+/// Configure IPv6 from router advertisements, starting with a link-local
+/// address until one arrives and falling back to it when the prefix expires.
+/// `D` is the driver of the stack.
+pub async fn slaac<D: Driver>(stack: Stack<'static>) -> ! {
+    let mac = device::mac();
+    let link_local = link_local(mac);
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; NEIGHBOR_SOLICITATION_LENGTH];
+    let socket = RawSocket::new::<D>(
+        stack,
+        IpVersion::Ipv6,
+        IpProtocol::Icmpv6,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+
+    let mut packet = [0; 1024];
+
+    // An interface ID derived from the MAC address that's taken on the link
+    // would clash with every prefix, so IPv6 stays off (RFC 4862 section
+    // 5.4.5)
+    if is_duplicate(stack, &socket, link_local, &mut packet).await {
+        error!(
+            "SLAAC: link-local address {} in use, IPv6 disabled",
+            link_local
+        );
+        core::future::pending().await
+    }
+    stack.set_config_v6(link_local_config(link_local));
+    info!("SLAAC: link-local address {}", link_local);
+
+    let solicitation = router_solicitation(link_local, mac);
+    let mut solicitations = 0;
+    let mut next_solicitation = Instant::now();
+    // The configured address and when it expires
+    let mut configured: Option<(StaticConfigV6, Instant)> = None;
+    // Address found to be in use, not tried again
+    let mut duplicate = None;
+
+    loop {
+        let expires = configured
+            .as_ref()
+            .map_or(Instant::MAX, |(_, expires)| *expires);
+        let solicit = if configured.is_none() && solicitations < MAX_SOLICITATIONS {
+            next_solicitation
+        } else {
+            Instant::MAX
+        };
+        match select(socket.recv(&mut packet), Timer::at(expires.min(solicit))).await {
+            Either::First(Ok(length)) => {
+                let Some(advertisement) = parse_router_advertisement(&packet[..length]) else {
+                    continue;
+                };
+                debug!("SLAAC: router advertisement from {}", advertisement.router);
+                let Some(prefix) = advertisement.prefix else {
+                    continue;
+                };
+                let global = address(prefix.prefix, mac);
+                let new = configured
+                    .as_ref()
+                    .is_none_or(|(config, _)| config.address.address() != global);
+                if duplicate == Some(global) {
+                    continue;
+                }
+                if new && is_duplicate(stack, &socket, global, &mut packet).await {
+                    warn!("SLAAC: address {} in use, not configuring it", global);
+                    duplicate = Some(global);
+                    continue;
+                }
+                let config = StaticConfigV6 {
+                    address: Ipv6Cidr::new(global, 64),
+                    gateway: (advertisement.router_lifetime > 0).then_some(advertisement.router),
+                    dns_servers: advertisement.dns_servers.iter().copied().collect(),
+                };
+                let expires = match prefix.valid_lifetime {
+                    u32::MAX => Instant::MAX,
+                    lifetime => Instant::now() + Duration::from_secs(lifetime.into()),
+                };
+                if configured.as_ref().map(|(config, _)| config) != Some(&config) {
+                    info!(
+                        "SLAAC: address {}, gateway {}",
+                        config.address, config.gateway
+                    );
+                    stack.set_config_v6(ConfigV6::Static(config.clone()));
+                }
+                configured = Some((config, expires));
+            }
+            Either::First(Err(_)) => warn!("SLAAC: packet too long"),
+            Either::Second(()) if Instant::now() >= expires => {
+                warn!("SLAAC: prefix expired, soliciting routers");
+                stack.set_config_v6(link_local_config(link_local));
+                configured = None;
+                solicitations = 0;
+                next_solicitation = Instant::now();
+            }
+            Either::Second(()) => {
+                debug!("SLAAC: sending router solicitation");
+                socket.send(&solicitation).await;
+                solicitations += 1;
+                next_solicitation = Instant::now() + SOLICITATION_INTERVAL;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smoltcp::wire::{Icmpv6Packet, Ipv6Packet, NdiscRepr};
+
+    use super::*;
+
+    const MAC: [u8; 6] = [0x40, 0xa3, 0xcc, 0x01, 0xf2, 0xe4];
+    const ROUTER: Ipv6Addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+    const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+    const PREFIX: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 1, 2, 0, 0, 0, 0);
+    const DNS: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x53);
+
+    /// Check a packet with smoltcp, returning its ICMPv6 message
+    fn verified(packet: &[u8]) -> Icmpv6Packet<&[u8]> {
+        let ip = Ipv6Packet::new_checked(packet).unwrap();
+        let icmp = Icmpv6Packet::new_checked(ip.payload()).unwrap();
+        assert!(icmp.verify_checksum(&ip.src_addr(), &ip.dst_addr()));
+        icmp
+    }
+
+    fn prefix_option(flags: u8, valid_lifetime: u32) -> std::vec::Vec<u8> {
+        let mut option = vec![OPTION_PREFIX_INFORMATION, 4, 64, flags];
+        option.extend(valid_lifetime.to_be_bytes());
+        option.extend(1800u32.to_be_bytes());
+        option.extend([0; 4]);
+        option.extend(PREFIX.octets());
+        option
+    }
+
+    fn rdnss_option(servers: &[Ipv6Addr]) -> std::vec::Vec<u8> {
+        let mut option = vec![OPTION_RDNSS, 1 + 2 * servers.len() as u8, 0, 0];
+        option.extend(600u32.to_be_bytes());
+        for server in servers {
+            option.extend(server.octets());
+        }
+        option
+    }
+
+    /// Router advertisement from `source` with `options`
+    fn advertisement<const N: usize>(source: Ipv6Addr, options: &[u8]) -> [u8; N] {
+        icmpv6_packet(source, ALL_NODES, |message| {
+            message[0] = ROUTER_ADVERTISEMENT;
+            message[4] = 64;
+            message[6..8].copy_from_slice(&1800u16.to_be_bytes());
+            message[16..].copy_from_slice(options);
+        })
+    }
+
+    #[test]
+    fn addresses_from_the_mac_address() {
+        assert_eq!(
+            interface_id(MAC),
+            [0x42, 0xa3, 0xcc, 0xff, 0xfe, 0x01, 0xf2, 0xe4]
+        );
+        assert_eq!(
+            link_local(MAC),
+            "fe80::42a3:ccff:fe01:f2e4".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(
+            address(PREFIX, MAC),
+            "2001:db8:1:2:42a3:ccff:fe01:f2e4"
+                .parse::<Ipv6Addr>()
+                .unwrap()
+        );
+        assert!(is_link_local(link_local(MAC)));
+        assert!(is_link_local("febf::1".parse().unwrap()));
+        assert!(!is_link_local(address(PREFIX, MAC)));
+        assert_eq!(
+            solicited_node(address(PREFIX, MAC)),
+            "ff02::1:ff01:f2e4".parse::<Ipv6Addr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn checksums_match_smoltcp() {
+        let solicitation = router_solicitation(link_local(MAC), MAC);
+        let icmp = verified(&solicitation);
+        assert_eq!(u8::from(icmp.msg_type()), ROUTER_SOLICITATION);
+        assert_eq!(u16_at(&solicitation, 4), 16);
+        assert_eq!(
+            checksum(link_local(MAC), ALL_ROUTERS, &solicitation[40..]),
+            0
+        );
+
+        // An odd length is padded with a zero byte
+        let message = [ROUTER_SOLICITATION, 0, 0, 0, 0, 0, 0, 0, 0xab];
+        let sum = checksum(ROUTER, ALL_ROUTERS, &message);
+        let mut checked = message;
+        checked[2..4].copy_from_slice(&sum.to_be_bytes());
+        assert_eq!(checksum(ROUTER, ALL_ROUTERS, &checked), 0);
+    }
+
+    #[test]
+    fn solicits_tentative_addresses() {
+        let target = address(PREFIX, MAC);
+        let packet = neighbor_solicitation(target);
+        let ip = Ipv6Packet::new_checked(&packet[..]).unwrap();
+        assert_eq!(ip.src_addr(), Ipv6Addr::UNSPECIFIED);
+        assert_eq!(ip.dst_addr(), solicited_node(target));
+        assert_eq!(ip.hop_limit(), HOP_LIMIT);
+        let icmp = verified(&packet);
+        match NdiscRepr::parse(&icmp).unwrap() {
+            NdiscRepr::NeighborSolicit {
+                target_addr,
+                lladdr,
+            } => {
+                assert_eq!(target_addr, target);
+                assert_eq!(lladdr, None);
+            }
+            _ => panic!("not a neighbor solicitation"),
+        }
+    }
+
+    #[test]
+    fn detects_duplicates() {
+        let target = address(PREFIX, MAC);
+        let other = address(PREFIX, [0x40, 0xa3, 0xcc, 0x01, 0xf2, 0xe5]);
+        let advertisement: [u8; NEIGHBOR_SOLICITATION_LENGTH] =
+            icmpv6_packet(other, ALL_NODES, |message| {
+                message[0] = NEIGHBOR_ADVERTISEMENT;
+                message[8..24].copy_from_slice(&target.octets());
+            });
+        assert!(claims(&advertisement, target));
+        assert!(!claims(&advertisement, other));
+
+        // Another node checking the same address
+        assert!(claims(&neighbor_solicitation(target), target));
+        // An ordinary solicitation only looks the address up
+        let lookup: [u8; NEIGHBOR_SOLICITATION_LENGTH] =
+            icmpv6_packet(other, solicited_node(target), |message| {
+                message[0] = NEIGHBOR_SOLICITATION;
+                message[8..24].copy_from_slice(&target.octets());
+            });
+        assert!(!claims(&lookup, target));
+
+        let mut corrupted = advertisement;
+        corrupted[50] ^= 1;
+        assert!(!claims(&corrupted, target));
+        assert!(!claims(&router_solicitation(link_local(MAC), MAC), target));
+    }
+
+    #[test]
+    fn parses_router_advertisements() {
+        let mut options = vec![OPTION_SOURCE_LINK_LAYER_ADDRESS, 1];
+        options.extend(MAC);
+        options.extend(prefix_option(0xc0, 3600));
+        options.extend(rdnss_option(&[DNS]));
+        let packet: [u8; HEADER_LENGTH + 16 + 64] = advertisement(ROUTER, &options);
+        verified(&packet);
+
+        let parsed = parse_router_advertisement(&packet).unwrap();
+        assert_eq!(parsed.router, ROUTER);
+        assert_eq!(parsed.router_lifetime, 1800);
+        assert_eq!(
+            parsed.prefix,
+            Some(Prefix {
+                prefix: PREFIX,
+                valid_lifetime: 3600
+            })
+        );
+        assert_eq!(parsed.dns_servers.as_slice(), &[DNS]);
+    }
+
+    #[test]
+    fn skips_unusable_prefixes() {
+        // Not for autonomous configuration
+        let packet: [u8; HEADER_LENGTH + 16 + 32] =
+            advertisement(ROUTER, &prefix_option(0x80, 3600));
+        assert_eq!(parse_router_advertisement(&packet).unwrap().prefix, None);
+        // Expired
+        let packet: [u8; HEADER_LENGTH + 16 + 32] = advertisement(ROUTER, &prefix_option(0xc0, 0));
+        assert_eq!(parse_router_advertisement(&packet).unwrap().prefix, None);
+        // Not a /64
+        let mut option = prefix_option(0xc0, 3600);
+        option[2] = 48;
+        let packet: [u8; HEADER_LENGTH + 16 + 32] = advertisement(ROUTER, &option);
+        assert_eq!(parse_router_advertisement(&packet).unwrap().prefix, None);
+    }
+
+    #[test]
+    fn keeps_the_first_dns_servers() {
+        let servers = [DNS, ROUTER, ALL_ROUTERS, PREFIX];
+        let packet: [u8; HEADER_LENGTH + 16 + 72] = advertisement(ROUTER, &rdnss_option(&servers));
+        assert_eq!(
+            parse_router_advertisement(&packet)
+                .unwrap()
+                .dns_servers
+                .as_slice(),
+            &servers[..MAX_DNS_SERVERS]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_advertisements() {
+        let options = prefix_option(0xc0, 3600);
+        let valid: [u8; HEADER_LENGTH + 16 + 32] = advertisement(ROUTER, &options);
+        assert!(parse_router_advertisement(&valid).is_some());
+
+        let mut corrupted = valid;
+        corrupted[HEADER_LENGTH + 20] ^= 1;
+        assert_eq!(parse_router_advertisement(&corrupted), None);
+
+        // Forwarded by a router
+        let mut forwarded = valid;
+        forwarded[7] = 64;
+        assert_eq!(parse_router_advertisement(&forwarded), None);
+
+        // Only routers on the link advertise from a link-local address
+        let global: [u8; HEADER_LENGTH + 16 + 32] = advertisement(DNS, &options);
+        assert_eq!(parse_router_advertisement(&global), None);
+
+        // Options must not be empty or run past the end
+        let empty: [u8; HEADER_LENGTH + 16 + 8] = advertisement(ROUTER, &[1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(parse_router_advertisement(&empty), None);
+        let overlong: [u8; HEADER_LENGTH + 16 + 8] =
+            advertisement(ROUTER, &[1, 2, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(parse_router_advertisement(&overlong), None);
+
+        assert_eq!(
+            parse_router_advertisement(&valid[..HEADER_LENGTH + 20]),
+            None
+        );
+        assert_eq!(parse_router_advertisement(&[0; 10]), None);
+        assert_eq!(
+            parse_router_advertisement(&router_solicitation(ROUTER, MAC)),
+            None
+        );
+    }
+}
@@ -0,0 +1,550 @@
+use core::cell::RefCell;
+
+use defmt::{debug, info, warn, Format};
+use embassy_futures::select::{select, Either};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+use serde::Serialize;
+
+use crate::bme680::Bme680Measurement;
+use crate::scd41::Scd41Measurement;
+use crate::sink::{self, Reading};
+
+pub const MAX_WINDOWS: usize = 4;
+
+/// Window lengths in seconds, comma separated
+const STATS_WINDOWS: &str = match option_env!("STATS_WINDOWS") {
+    Some(windows) => windows,
+    None => "60,900,3600",
+};
+
+/// Running min/max/mean/variance of a single field, using Welford's online
+/// algorithm so the variance doesn't suffer from catastrophic cancellation
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Accumulator {
+    count: u32,
+    mean: f32,
+    m2: f32,
+    min: f32,
+    max: f32,
+}
+
+impl Accumulator {
+    pub fn push(&mut self, value: f32) {
+        if !value.is_finite() {
+            return;
+        }
+        if self.count == 0 {
+            self.min = value;
+            self.max = value;
+        } else {
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+        }
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f32;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    /// Summary of the samples so far, None if there weren't any
+    pub fn summary(&self) -> Option<Summary> {
+        if self.count == 0 {
+            return None;
+        }
+        // Population standard deviation, the window is the whole population
+        let variance = self.m2 / self.count as f32;
+        Some(Summary {
+            min: self.min,
+            max: self.max,
+            mean: self.mean,
+            stddev: libm::sqrtf(variance),
+            count: self.count,
+        })
+    }
+}
+
+#[derive(Debug, Format, Clone, Copy, Serialize)]
+pub struct Summary {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    pub stddev: f32,
+    pub count: u32,
+}
+
+/// A sensor whose measurements can be aggregated field by field
+pub trait Fields {
+    /// Field values in a fixed order, None for fields that are missing
+    fn values(&self) -> [Option<f32>; 4];
+}
+
+impl Fields for Scd41Measurement {
+    fn values(&self) -> [Option<f32>; 4] {
+        [
+            Some(self.co2 as f32),
+            Some(self.temperature),
+            Some(self.humidity),
+            None,
+        ]
+    }
+}
+
+impl Fields for Bme680Measurement {
+    fn values(&self) -> [Option<f32>; 4] {
+        [
+            Some(self.temperature),
+            Some(self.humidity),
+            Some(self.pressure),
+            self.gas_resistance,
+        ]
+    }
+}
+
+/// Tumbling window of fixed length over one sensor's fields
+#[derive(Debug, Clone, Copy)]
+pub struct Window {
+    length: Duration,
+    start: Option<Instant>,
+    fields: [Accumulator; 4],
+}
+
+impl Window {
+    pub const fn new(length: Duration) -> Self {
+        Window {
+            length,
+            start: None,
+            fields: [Accumulator {
+                count: 0,
+                mean: 0.0,
+                m2: 0.0,
+                min: 0.0,
+                max: 0.0,
+            }; 4],
+        }
+    }
+
+    /// When the current window ends, None until a measurement starts one
+    pub fn end(&self) -> Option<Instant> {
+        self.start.map(|start| start + self.length)
+    }
+
+    /// Close the current window if it has ended by `now` and return its
+    /// summaries. The next measurement starts a new window.
+    pub fn close_if_ended(&mut self, now: Instant) -> Option<[Option<Summary>; 4]> {
+        if self.end()? > now {
+            return None;
+        }
+        let summaries = self.fields.map(|field| field.summary());
+        self.fields = [Accumulator::default(); 4];
+        self.start = None;
+        Some(summaries)
+    }
+
+    /// Add a measurement taken at `now`. If it falls after the end of the
+    /// current window, that window is closed and its summaries returned, and
+    /// the measurement starts the next one.
+    pub fn push<T: Fields>(
+        &mut self,
+        measurement: &T,
+        now: Instant,
+    ) -> Option<[Option<Summary>; 4]> {
+        let closed = self.close_if_ended(now);
+        self.start.get_or_insert(now);
+
+        for (field, value) in self.fields.iter_mut().zip(measurement.values()) {
+            if let Some(value) = value {
+                field.push(value);
+            }
+        }
+
+        closed
+    }
+
+    pub fn length(&self) -> Duration {
+        self.length
+    }
+}
+
+#[derive(Debug, Format, Clone, Serialize)]
+pub struct Scd41Stats {
+    /// Window length in seconds
+    pub window: u32,
+    pub co2: Option<Summary>,
+    pub temperature: Option<Summary>,
+    pub humidity: Option<Summary>,
+}
+
+#[derive(Debug, Format, Clone, Serialize)]
+pub struct Bme680Stats {
+    /// Window length in seconds
+    pub window: u32,
+    pub temperature: Option<Summary>,
+    pub humidity: Option<Summary>,
+    pub pressure: Option<Summary>,
+    pub gas_resistance: Option<Summary>,
+}
+
+#[derive(Debug, Format, Clone)]
+pub enum Report {
+    Scd41(Scd41Stats),
+    Bme680(Bme680Stats),
+}
+
+impl Report {
+    /// Sensor and window length the report is for
+    fn slot(&self) -> (&'static str, u32) {
+        match self {
+            Report::Scd41(stats) => ("scd41", stats.window),
+            Report::Bme680(stats) => ("bme680", stats.window),
+        }
+    }
+}
+
+/// Reports waiting to be published over MQTT
+pub static PENDING: Pending = Pending::new();
+
+/// Newest report of each sensor and window waiting to be published, oldest
+/// first. The windows of both sensors close together, so a queue shared with
+/// other messages would overflow at every window boundary and while the
+/// broker is unreachable. Here a report only ever replaces the older one of
+/// its own window.
+pub struct Pending {
+    reports: Mutex<CriticalSectionRawMutex, RefCell<Vec<Report, { 2 * MAX_WINDOWS }>>>,
+    added: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl Pending {
+    pub const fn new() -> Self {
+        Pending {
+            reports: Mutex::new(RefCell::new(Vec::new())),
+            added: Signal::new(),
+        }
+    }
+
+    /// Queue a report, replacing one of the same window not yet published
+    pub fn put(&self, report: Report) {
+        self.reports.lock(|reports| {
+            let mut reports = reports.borrow_mut();
+            let slot = report.slot();
+            match reports.iter_mut().find(|queued| queued.slot() == slot) {
+                Some(queued) => *queued = report,
+                // Each sensor has at most MAX_WINDOWS windows
+                None => {
+                    let _ = reports.push(report);
+                }
+            }
+        });
+        self.added.signal(());
+    }
+
+    /// Take the oldest report without waiting
+    pub fn take(&self) -> Option<Report> {
+        self.reports.lock(|reports| {
+            let mut reports = reports.borrow_mut();
+            (!reports.is_empty()).then(|| reports.remove(0))
+        })
+    }
+
+    /// Wait for a report and take it
+    pub async fn receive(&self) -> Report {
+        loop {
+            if let Some(report) = self.take() {
+                return report;
+            }
+            self.added.wait().await;
+        }
+    }
+}
+
+impl Default for Pending {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Parse a comma separated list of window lengths in seconds, skipping
+/// anything that isn't a positive integer
+pub fn parse_windows(windows: &str) -> Vec<Duration, MAX_WINDOWS> {
+    windows
+        .split(',')
+        .filter_map(|window| window.trim().parse::<u32>().ok())
+        .filter(|&seconds| seconds > 0)
+        .map(|seconds| Duration::from_secs(seconds as u64))
+        .take(MAX_WINDOWS)
+        .collect()
+}
+
+fn publish_scd41(window: &Window, [co2, temperature, humidity, _]: [Option<Summary>; 4]) {
+    let stats = Scd41Stats {
+        window: window.length().as_secs() as u32,
+        co2,
+        temperature,
+        humidity,
+    };
+    debug!("Stats: closed SCD41 window: {:?}", stats);
+    PENDING.put(Report::Scd41(stats));
+}
+
+fn publish_bme680(
+    window: &Window,
+    [temperature, humidity, pressure, gas_resistance]: [Option<Summary>; 4],
+) {
+    let stats = Bme680Stats {
+        window: window.length().as_secs() as u32,
+        temperature,
+        humidity,
+        pressure,
+        gas_resistance,
+    };
+    debug!("Stats: closed BME680 window: {:?}", stats);
+    PENDING.put(Report::Bme680(stats));
+}
+
+#[embassy_executor::task]
+pub async fn aggregator() -> ! {
+    let lengths = parse_windows(STATS_WINDOWS);
+    if lengths.is_empty() {
+        warn!("Stats: no valid windows configured in {}", STATS_WINDOWS);
+    }
+    let mut scd41_windows: Vec<Window, MAX_WINDOWS> =
+        lengths.iter().map(|&length| Window::new(length)).collect();
+    let mut bme680_windows = scd41_windows.clone();
+    info!("Stats: aggregating over {} windows", lengths.len());
+
+    loop {
+        // Windows are closed on time rather than by the next measurement, so
+        // the last window before the sensors stop is reported as well
+        let next_end = scd41_windows
+            .iter()
+            .chain(bme680_windows.iter())
+            .filter_map(Window::end)
+            .min()
+            .unwrap_or(Instant::MAX);
+
+        match select(sink::STATS.receive(), Timer::at(next_end)).await {
+            Either::First(Reading::Scd41(measurement)) => {
+                let now = Instant::now();
+                for window in scd41_windows.iter_mut() {
+                    if let Some(summaries) = window.push(&measurement, now) {
+                        publish_scd41(window, summaries);
+                    }
+                }
+            }
+            Either::First(Reading::Bme680(measurement)) => {
+                let now = Instant::now();
+                for window in bme680_windows.iter_mut() {
+                    if let Some(summaries) = window.push(&measurement, now) {
+                        publish_bme680(window, summaries);
+                    }
+                }
+            }
+            Either::Second(()) => {
+                let now = Instant::now();
+                for window in scd41_windows.iter_mut() {
+                    if let Some(summaries) = window.close_if_ended(now) {
+                        publish_scd41(window, summaries);
+                    }
+                }
+                for window in bme680_windows.iter_mut() {
+                    if let Some(summaries) = window.close_if_ended(now) {
+                        publish_bme680(window, summaries);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const T0: Instant = Instant::from_secs(100);
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn accumulate(values: &[f32]) -> Accumulator {
+        let mut accumulator = Accumulator::default();
+        for &value in values {
+            accumulator.push(value);
+        }
+        accumulator
+    }
+
+    fn sample(value: f32) -> Bme680Measurement {
+        // Any sensor will do, its first field carries the test value
+        Bme680Measurement {
+            temperature: value,
+            ..Default::default()
+        }
+    }
+
+    fn first_field(summaries: [Option<Summary>; 4]) -> Summary {
+        summaries[0].expect("field should have samples")
+    }
+
+    #[test]
+    fn summary_of_known_series() {
+        let summary = accumulate(&[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0])
+            .summary()
+            .unwrap();
+        assert_eq!(summary.count, 8);
+        assert_eq!(summary.min, 2.0);
+        assert_eq!(summary.max, 9.0);
+        assert_eq!(summary.mean, 5.0);
+        assert_eq!(summary.stddev, 2.0);
+    }
+
+    #[test]
+    fn variance_survives_large_offset() {
+        // The naive sum of squares loses everything to cancellation in f32
+        let values: std::vec::Vec<f32> = (0..1000)
+            .map(|i| 100_000.0 + if i % 2 == 0 { 1.0 } else { -1.0 })
+            .collect();
+        let summary = accumulate(&values).summary().unwrap();
+        assert!((summary.mean - 100_000.0).abs() < 0.01);
+        assert!((summary.stddev - 1.0).abs() < 0.01, "{}", summary.stddev);
+    }
+
+    #[test]
+    fn skips_non_finite_values() {
+        let summary = accumulate(&[f32::NAN, 3.0, f32::INFINITY])
+            .summary()
+            .unwrap();
+        assert_eq!(summary.count, 1);
+        assert_eq!(summary.stddev, 0.0);
+        assert!(Accumulator::default().summary().is_none());
+    }
+
+    #[test]
+    fn window_closes_on_next_measurement() {
+        let mut window = Window::new(MINUTE);
+        assert_eq!(window.end(), None);
+        assert!(window.push(&sample(400.0), T0).is_none());
+        assert_eq!(window.end(), Some(T0 + MINUTE));
+        assert!(window
+            .push(&sample(600.0), T0 + MINUTE - Duration::from_millis(1))
+            .is_none());
+
+        let closed = window.push(&sample(900.0), T0 + MINUTE).unwrap();
+        let summary = first_field(closed);
+        assert_eq!((summary.count, summary.mean), (2, 500.0));
+        // The measurement that closed it starts the next window
+        assert_eq!(window.end(), Some(T0 + 2 * MINUTE));
+        assert_eq!(
+            window
+                .close_if_ended(T0 + 2 * MINUTE)
+                .map(first_field)
+                .unwrap()
+                .mean,
+            900.0
+        );
+    }
+
+    #[test]
+    fn last_window_closes_on_time() {
+        let mut window = Window::new(MINUTE);
+        window.push(&sample(400.0), T0);
+        window.push(&sample(500.0), T0 + Duration::from_secs(30));
+        assert!(window
+            .close_if_ended(T0 + Duration::from_secs(59))
+            .is_none());
+
+        let summary = first_field(window.close_if_ended(T0 + MINUTE).unwrap());
+        assert_eq!((summary.count, summary.min, summary.max), (2, 400.0, 500.0));
+        // Nothing more until another measurement arrives
+        assert_eq!(window.end(), None);
+        assert!(window.close_if_ended(T0 + 10 * MINUTE).is_none());
+    }
+
+    #[test]
+    fn missing_fields_have_no_summary() {
+        let mut window = Window::new(MINUTE);
+        window.push(&sample(400.0), T0);
+        let [_, _, _, gas_resistance] = window.close_if_ended(T0 + MINUTE).unwrap();
+        assert!(gas_resistance.is_none());
+    }
+
+    #[test]
+    fn parses_windows() {
+        let windows = parse_windows("60, 900,x,0,-5,3600,7200,86400");
+        let seconds: std::vec::Vec<u64> = windows.iter().map(|window| window.as_secs()).collect();
+        assert_eq!(seconds, [60, 900, 3600, 7200]);
+        assert!(parse_windows("").is_empty());
+    }
+
+    fn report(sensor: &str, window: u32, count: u32) -> Report {
+        let summary = Summary {
+            count,
+            min: 0.0,
+            max: 0.0,
+            mean: 0.0,
+            stddev: 0.0,
+        };
+        match sensor {
+            "scd41" => Report::Scd41(Scd41Stats {
+                window,
+                co2: Some(summary),
+                temperature: None,
+                humidity: None,
+            }),
+            _ => Report::Bme680(Bme680Stats {
+                window,
+                temperature: Some(summary),
+                humidity: None,
+                pressure: None,
+                gas_resistance: None,
+            }),
+        }
+    }
+
+    fn count(report: Report) -> u32 {
+        match report {
+            Report::Scd41(stats) => stats.co2.unwrap().count,
+            Report::Bme680(stats) => stats.temperature.unwrap().count,
+        }
+    }
+
+    #[test]
+    fn pending_keeps_every_window_of_both_sensors() {
+        let pending = Pending::new();
+        for window in [60, 900, 3600, 86_400] {
+            pending.put(report("scd41", window, 1));
+            pending.put(report("bme680", window, 2));
+        }
+        let mut slots = std::vec::Vec::new();
+        while let Some(report) = pending.take() {
+            slots.push(report.slot());
+        }
+        assert_eq!(slots.len(), 2 * MAX_WINDOWS);
+        assert_eq!(slots[..3], [("scd41", 60), ("bme680", 60), ("scd41", 900)]);
+    }
+
+    #[test]
+    fn pending_replaces_unpublished_reports() {
+        // Windows keep closing while the broker is unreachable
+        let pending = Pending::new();
+        pending.put(report("scd41", 60, 1));
+        pending.put(report("scd41", 900, 2));
+        pending.put(report("scd41", 60, 3));
+        assert_eq!(count(pending.take().unwrap()), 3);
+        assert_eq!(count(pending.take().unwrap()), 2);
+        assert!(pending.take().is_none());
+    }
+
+    #[test]
+    fn pending_receive_waits_for_a_report() {
+        let pending = Pending::new();
+        pending.put(report("bme680", 60, 4));
+        assert_eq!(count(embassy_futures::block_on(pending.receive())), 4);
+
+        let mut receive = core::pin::pin!(pending.receive());
+        assert!(embassy_futures::poll_once(receive.as_mut()).is_pending());
+        pending.put(report("bme680", 60, 5));
+        assert_eq!(count(embassy_futures::block_on(receive)), 5);
+    }
+}
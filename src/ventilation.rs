@@ -0,0 +1,321 @@
+use defmt::{debug, info, warn, Format};
+use embassy_time::{Duration, Instant};
+use serde::Serialize;
+
+use crate::mqtt::{self, Outgoing};
+use crate::sink::{self, Reading};
+
+/// Room volume in m³
+const ROOM_VOLUME: &str = match option_env!("VENTILATION_ROOM_VOLUME") {
+    Some(volume) => volume,
+    None => "50",
+};
+/// Outdoor CO2 concentration in ppm
+const OUTDOOR_CO2: &str = match option_env!("VENTILATION_OUTDOOR_CO2") {
+    Some(co2) => co2,
+    None => "420",
+};
+/// Air changes per hour assumed until one has been measured from a decay
+const DEFAULT_AIR_CHANGES: &str = match option_env!("VENTILATION_AIR_CHANGES") {
+    Some(air_changes) => air_changes,
+    None => "0.5",
+};
+
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// CO2 exhaled by a seated adult in m³/h (about 5 ml/s)
+const CO2_PER_PERSON: f32 = 0.018;
+
+/// Smoothing of the CO2 level and its slope, see [`SlopeFilter`]
+const LEVEL_SMOOTHING: f32 = 0.3;
+const TREND_SMOOTHING: f32 = 0.1;
+
+/// Recommend ventilating above this regardless of trend
+const CO2_VENTILATE: f32 = 1400.0;
+/// Recommend ventilating above this if CO2 is still rising
+const CO2_RISING_VENTILATE: f32 = 1000.0;
+/// Slopes in ppm/min beyond which CO2 counts as rising or falling
+const SLOPE_RISING: f32 = 2.0;
+const SLOPE_FALLING: f32 = -2.0;
+/// Decay estimates are unreliable this close to outdoor levels, in ppm
+const MIN_EXCESS_CO2: f32 = 100.0;
+
+/// Holt's double exponential smoothing of the CO2 level and trend, allowing
+/// for irregular sample intervals
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SlopeFilter {
+    state: Option<FilterState>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FilterState {
+    level: f32,
+    /// ppm/min
+    slope: f32,
+    at: Instant,
+}
+
+impl SlopeFilter {
+    pub const fn new() -> Self {
+        SlopeFilter { state: None }
+    }
+
+    /// Add a sample taken at `at`, returning the smoothed level in ppm and
+    /// slope in ppm/min
+    pub fn update(&mut self, co2: f32, at: Instant) -> (f32, f32) {
+        let state = match self.state {
+            None => FilterState {
+                level: co2,
+                slope: 0.0,
+                at,
+            },
+            Some(previous) => {
+                let minutes =
+                    at.saturating_duration_since(previous.at).as_millis() as f32 / 60_000.0;
+                if minutes <= 0.0 {
+                    return (previous.level, previous.slope);
+                }
+                let predicted = previous.level + previous.slope * minutes;
+                let level = LEVEL_SMOOTHING * co2 + (1.0 - LEVEL_SMOOTHING) * predicted;
+                let slope = TREND_SMOOTHING * (level - previous.level) / minutes
+                    + (1.0 - TREND_SMOOTHING) * previous.slope;
+                FilterState { level, slope, at }
+            }
+        };
+        self.state = Some(state);
+        (state.level, state.slope)
+    }
+}
+
+/// Single-zone mass balance `V dC/dt = G - Q (C - C_out)`
+#[derive(Debug, Clone, Copy)]
+pub struct RoomModel {
+    /// m³
+    pub volume: f32,
+    /// ppm
+    pub outdoor_co2: f32,
+}
+
+impl RoomModel {
+    /// Air change rate in 1/h from a decay with nobody in the room, i.e.
+    /// `G = 0` so `λ = -(dC/dt) / (C - C_out)`. None unless CO2 is clearly
+    /// falling, since a slight dip says as much about the occupants as about
+    /// the ventilation, or if it is too close to outdoor levels to tell.
+    pub fn air_changes(&self, co2: f32, slope: f32) -> Option<f32> {
+        let excess = co2 - self.outdoor_co2;
+        if slope >= SLOPE_FALLING || excess < MIN_EXCESS_CO2 {
+            return None;
+        }
+        Some(-slope * 60.0 / excess)
+    }
+
+    /// Approximate number of occupants given the air change rate in 1/h, from
+    /// `G = V (dC/dt + λ (C - C_out))`
+    pub fn occupancy(&self, co2: f32, slope: f32, air_changes: f32) -> f32 {
+        let excess = (co2 - self.outdoor_co2).max(0.0);
+        let ppm_per_hour = slope * 60.0 + air_changes * excess;
+        let generation = self.volume * ppm_per_hour * 1e-6;
+        (generation / CO2_PER_PERSON).max(0.0)
+    }
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Recommendation {
+    VentilateNow,
+    Ok,
+    Improving,
+}
+
+pub fn recommend(co2: f32, slope: f32) -> Recommendation {
+    if co2 >= CO2_VENTILATE || (co2 >= CO2_RISING_VENTILATE && slope > SLOPE_RISING) {
+        Recommendation::VentilateNow
+    } else if slope < SLOPE_FALLING {
+        Recommendation::Improving
+    } else {
+        Recommendation::Ok
+    }
+}
+
+#[derive(Debug, Format, Clone, Serialize)]
+pub struct Advice {
+    /// Smoothed CO2 in ppm
+    pub co2: f32,
+    /// CO2 rate of change in ppm/min
+    pub slope: f32,
+    /// Most recent air change rate measured from a decay, in 1/h
+    pub air_changes: Option<f32>,
+    /// Estimated number of occupants
+    pub occupancy: f32,
+    pub recommendation: Recommendation,
+}
+
+#[embassy_executor::task]
+pub async fn advisor() -> ! {
+    let model = RoomModel {
+        volume: ROOM_VOLUME.parse().unwrap_or(50.0),
+        outdoor_co2: OUTDOOR_CO2.parse().unwrap_or(420.0),
+    };
+    let default_air_changes: f32 = DEFAULT_AIR_CHANGES.parse().unwrap_or(0.5);
+    info!(
+        "Ventilation: room of {} m3, outdoor CO2 {} ppm",
+        model.volume, model.outdoor_co2
+    );
+
+    let mut filter = SlopeFilter::new();
+    let mut measured_air_changes = None;
+    let mut last_published: Option<Instant> = None;
+
+    loop {
+        let Reading::Scd41(measurement) = sink::VENTILATION.receive().await else {
+            continue;
+        };
+        let now = Instant::now();
+        let (co2, slope) = filter.update(measurement.co2 as f32, now);
+
+        if let Some(air_changes) = model.air_changes(co2, slope) {
+            measured_air_changes = Some(air_changes);
+        }
+        let occupancy = model.occupancy(
+            co2,
+            slope,
+            measured_air_changes.unwrap_or(default_air_changes),
+        );
+
+        let advice = Advice {
+            co2,
+            slope,
+            air_changes: measured_air_changes,
+            occupancy,
+            recommendation: recommend(co2, slope),
+        };
+        debug!("Ventilation: {:?}", advice);
+
+        if last_published.is_some_and(|at| now.saturating_duration_since(at) < PUBLISH_INTERVAL) {
+            continue;
+        }
+        last_published = Some(now);
+        if mqtt::OUTBOX
+            .try_send(Outgoing::Ventilation(advice))
+            .is_err()
+        {
+            warn!("Ventilation: MQTT outbox full, dropping advice");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODEL: RoomModel = RoomModel {
+        volume: 50.0,
+        outdoor_co2: 420.0,
+    };
+    /// The SCD41 measures every 5 s
+    const SAMPLE_INTERVAL: u64 = 5;
+
+    /// CO2 after `hours` of the mass balance with `occupants` people, starting
+    /// from `start` ppm
+    fn co2_at(start: f32, occupants: f32, air_changes: f32, hours: f32) -> f32 {
+        let steady =
+            MODEL.outdoor_co2 + occupants * CO2_PER_PERSON / (MODEL.volume * air_changes) * 1e6;
+        steady + (start - steady) * libm::expf(-air_changes * hours)
+    }
+
+    /// Run the filter over `minutes` of the model, returning the smoothed
+    /// level and slope at every sample
+    fn simulate(
+        start: f32,
+        occupants: f32,
+        air_changes: f32,
+        minutes: u64,
+    ) -> std::vec::Vec<(f32, f32)> {
+        let mut filter = SlopeFilter::new();
+        (0..=minutes * 60 / SAMPLE_INTERVAL)
+            .map(|i| {
+                let seconds = i * SAMPLE_INTERVAL;
+                let co2 = co2_at(start, occupants, air_changes, seconds as f32 / 3600.0);
+                filter.update(co2, Instant::from_secs(seconds))
+            })
+            .collect()
+    }
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{actual} should be within {tolerance} of {expected}"
+        );
+    }
+
+    #[test]
+    fn filter_follows_linear_ramp() {
+        let mut filter = SlopeFilter::new();
+        let mut slope = 0.0;
+        for i in 0..=360 {
+            let minutes = i as f32 * SAMPLE_INTERVAL as f32 / 60.0;
+            (_, slope) = filter.update(
+                600.0 + 10.0 * minutes,
+                Instant::from_secs(i * SAMPLE_INTERVAL),
+            );
+        }
+        assert_close(slope, 10.0, 0.1);
+    }
+
+    #[test]
+    fn filter_ignores_repeated_timestamps() {
+        let mut filter = SlopeFilter::new();
+        filter.update(600.0, Instant::from_secs(0));
+        let (level, slope) = filter.update(650.0, Instant::from_secs(60));
+        assert_eq!(
+            filter.update(2000.0, Instant::from_secs(60)),
+            (level, slope)
+        );
+    }
+
+    #[test]
+    fn air_changes_from_empty_room_decay() {
+        for air_changes in [1.0, 2.0, 4.0] {
+            let samples = simulate(1800.0, 0.0, air_changes, 30);
+            // Once the filter has settled, 10 minutes into the decay
+            for &(co2, slope) in &samples[120..] {
+                let measured = MODEL.air_changes(co2, slope).unwrap();
+                assert_close(measured, air_changes, 0.15 * air_changes);
+            }
+        }
+    }
+
+    #[test]
+    fn no_air_changes_from_slow_or_low_decay() {
+        // A slope of -1 ppm/min at 1000 ppm would be 0.1 air changes an hour
+        assert_eq!(MODEL.air_changes(1000.0, -1.0), None);
+        assert_eq!(MODEL.air_changes(1000.0, 0.0), None);
+        assert_eq!(MODEL.air_changes(1000.0, 5.0), None);
+        // Falling fast, but within noise of outdoor levels
+        assert_eq!(MODEL.air_changes(500.0, -10.0), None);
+        assert_close(MODEL.air_changes(1000.0, -5.8).unwrap(), 0.6, 1e-3);
+    }
+
+    #[test]
+    fn occupancy_at_steady_state() {
+        let co2 = co2_at(420.0, 3.0, 1.0, 100.0);
+        assert_close(MODEL.occupancy(co2, 0.0, 1.0), 3.0, 0.01);
+        assert_eq!(MODEL.occupancy(400.0, -3.0, 1.0), 0.0);
+    }
+
+    #[test]
+    fn occupancy_while_filling() {
+        let (co2, slope) = *simulate(420.0, 4.0, 0.5, 60).last().unwrap();
+        assert!(slope > SLOPE_RISING);
+        assert_close(MODEL.occupancy(co2, slope, 0.5), 4.0, 0.4);
+    }
+
+    #[test]
+    fn recommendations() {
+        assert_eq!(recommend(1500.0, -20.0), Recommendation::VentilateNow);
+        assert_eq!(recommend(1100.0, 3.0), Recommendation::VentilateNow);
+        assert_eq!(recommend(1100.0, 1.0), Recommendation::Ok);
+        assert_eq!(recommend(900.0, 10.0), Recommendation::Ok);
+        assert_eq!(recommend(1100.0, -3.0), Recommendation::Improving);
+    }
+}
@@ -1,17 +1,24 @@
-use defmt::{debug, error, info};
+use air::last_network::{self, Store};
+use air::wifi_networks::{self, Method, Network, Phase2, Seen, Trust, CA_CERT};
+use air::{network, resolve, slaac, traffic};
+use defmt::{debug, error, info, warn};
 use embassy_executor::Spawner;
 use embassy_net::{Runner, Stack, StackResources};
 use embassy_time::{Duration, Timer};
 use esp_hal::peripherals::WIFI;
+use esp_storage::FlashStorage;
 use esp_wifi::{
-    wifi::{ClientConfiguration, Configuration, WifiController, WifiDevice, WifiEvent, WifiState},
+    wifi::{
+        AuthMethod, ClientConfiguration, Configuration, EapClientConfiguration, TtlsPhase2Method,
+        WifiController, WifiDevice, WifiEvent, WifiState,
+    },
     EspWifiController,
 };
 
 use static_cell::StaticCell;
 
-const SSID: &str = env!("SSID");
-const PASSWORD: &str = env!("PSK");
+/// Most access points kept from a scan
+const MAX_SCAN: usize = 20;
 
 pub async fn wifi_init(
     esp_wifi_controller: &'static mut EspWifiController<'static>,
@@ -27,30 +34,79 @@ pub async fn wifi_init(
 
     let wifi_interface = interfaces.sta;
 
-    let config = embassy_net::Config::dhcpv4(Default::default());
+    let config = network::configured();
 
-    // Init network stack
-    static RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
-    let resources = RESOURCES.init_with(|| StackResources::<3>::new());
-    let (stack, runner) = embassy_net::new(wifi_interface, config, resources, random_seed);
+    // Init network stack. Every outgoing TCP connection takes two sockets
+    // while its attempts race each other.
+    static RESOURCES: StaticCell<StackResources<16>> = StaticCell::new();
+    let resources = RESOURCES.init_with(StackResources::<16>::new);
+    let (stack, runner) = embassy_net::new(
+        traffic::Counting(wifi_interface),
+        config,
+        resources,
+        random_seed,
+    );
 
     spawner.must_spawn(connection(controller));
     spawner.must_spawn(net_task(runner));
+    if slaac::ENABLED {
+        spawner.must_spawn(slaac_task(stack));
+    }
 
     while !stack.is_link_up() {
         Timer::after(Duration::from_millis(500)).await;
     }
 
+    // Either family will do, so the board also comes up on networks that
+    // only hand out IPv6 addresses through SLAAC
     debug!("waiting to get IP address...");
     loop {
         if let Some(config) = stack.config_v4() {
             info!("got IP: {}", config.address);
             break;
         }
+        if let (true, Some(config)) = (resolve::has_ipv6(stack), stack.config_v6()) {
+            info!("got IPv6 address: {}", config.address);
+            break;
+        }
         Timer::after(Duration::from_millis(500)).await;
     }
 
-    return stack;
+    stack
+}
+
+fn client_configuration(network: &Network) -> Configuration {
+    let Some(enterprise) = network.enterprise else {
+        return Configuration::Client(ClientConfiguration {
+            ssid: network.ssid.into(),
+            password: network.psk.into(),
+            ..Default::default()
+        });
+    };
+    let ttls_phase2_method = match enterprise.method {
+        Method::Peap => None,
+        Method::Ttls(phase2) => Some(match phase2 {
+            Phase2::Mschapv2 => TtlsPhase2Method::Mschapv2,
+            Phase2::Mschap => TtlsPhase2Method::Mschap,
+            Phase2::Pap => TtlsPhase2Method::Pap,
+            Phase2::Chap => TtlsPhase2Method::Chap,
+        }),
+    };
+    // WPA2-Enterprise is the weakest mode accepted, so WPA3-Enterprise
+    // networks are joined as well
+    Configuration::EapClient(EapClientConfiguration {
+        ssid: network.ssid.into(),
+        auth_method: AuthMethod::WPA2Enterprise,
+        identity: Some(enterprise.identity.into()),
+        username: Some(enterprise.username.into()),
+        password: Some(network.psk.into()),
+        ca_cert: (!CA_CERT.is_empty()).then_some(CA_CERT),
+        ttls_phase2_method,
+        // The clock is only set over the network, so certificate validity
+        // periods can't be checked yet
+        time_check: false,
+        ..Default::default()
+    })
 }
 
 #[embassy_executor::task]
@@ -59,41 +115,88 @@ async fn connection(mut controller: WifiController<'static>) -> ! {
     for capability in controller.capabilities().unwrap() {
         info!("WiFi controller reports capability: {:?}", capability);
     }
+    let networks = wifi_networks::configured();
+    if networks.is_empty() {
+        error!("WiFi: no networks configured");
+    }
+    let enterprise = networks.iter().any(|network| network.enterprise.is_some());
+    if enterprise && Trust::configured() == Trust::Any {
+        warn!("WiFi: no CA certificate configured, enterprise servers aren't verified");
+    }
+    // Network last connected to, preferred while it's about as strong as the
+    // others. Kept in flash, so it's still preferred after a restart.
+    let mut store = last_network::open(FlashStorage::new())
+        .inspect_err(|e| warn!("WiFi: can't remember the last network: {:?}", e))
+        .ok();
+    let mut last = store
+        .as_ref()
+        .and_then(Store::last)
+        .and_then(|last| last.find(&networks));
     loop {
-        match esp_wifi::wifi::wifi_state() {
-            WifiState::StaConnected => {
-                // wait until we're no longer connected
-                controller.wait_for_event(WifiEvent::StaDisconnected).await;
-                Timer::after(Duration::from_millis(5000)).await
-            }
-            _ => {}
+        if esp_wifi::wifi::wifi_state() == WifiState::StaConnected {
+            // wait until we're no longer connected
+            controller.wait_for_event(WifiEvent::StaDisconnected).await;
+            Timer::after(Duration::from_millis(5000)).await
         }
 
         if !matches!(controller.is_started(), Ok(true)) {
-            let client_config = Configuration::Client(ClientConfiguration {
-                ssid: SSID.try_into().unwrap(),
-                password: PASSWORD.try_into().unwrap(),
-                ..Default::default()
-            });
+            let client_config = Configuration::Client(Default::default());
             controller.set_configuration(&client_config).unwrap();
             info!("starting WiFi...");
             controller.start_async().await.unwrap();
             info!("WiFi started!");
         }
 
-        debug!("WiFi: about to connect...");
-
-        match controller.connect_async().await {
-            Ok(_) => info!("WiFi connected!"),
+        let scan = match controller.scan_n_async(MAX_SCAN).await {
+            Ok(scan) => scan,
             Err(e) => {
-                error!("failed to connect to WiFi: {:?}", e);
-                Timer::after_secs(5).await;
+                warn!("WiFi: scan failed: {:?}", e);
+                Default::default()
+            }
+        };
+        let seen: heapless::Vec<Seen, MAX_SCAN> = scan
+            .iter()
+            .map(|access_point| Seen {
+                ssid: &access_point.ssid,
+                rssi: access_point.signal_strength,
+            })
+            .collect();
+        debug!("WiFi: scan found {:?}", seen.as_slice());
+
+        for index in wifi_networks::rank(&networks, &seen, last) {
+            let network = &networks[index];
+            debug!("WiFi: about to connect to {}...", network.ssid);
+            if let Err(e) = controller.set_configuration(&client_configuration(network)) {
+                error!("WiFi: invalid configuration for {}: {:?}", network.ssid, e);
+                continue;
+            }
+            match controller.connect_async().await {
+                Ok(_) => {
+                    info!("WiFi connected to {}!", network.ssid);
+                    last = Some(index);
+                    let remembered = store
+                        .as_mut()
+                        .map(|store| store.remember(index, network.ssid));
+                    if let Some(Err(e)) = remembered {
+                        warn!("WiFi: failed to remember {}: {:?}", network.ssid, e);
+                    }
+                    break;
+                }
+                Err(e) => error!("failed to connect to {}: {:?}", network.ssid, e),
             }
         }
+        if esp_wifi::wifi::wifi_state() != WifiState::StaConnected {
+            Timer::after_secs(5).await;
+        }
     }
 }
 
 #[embassy_executor::task]
-async fn net_task(mut runner: Runner<'static, WifiDevice<'static>>) -> ! {
+async fn net_task(mut runner: Runner<'static, traffic::Counting<WifiDevice<'static>>>) -> ! {
     runner.run().await
 }
+
+#[embassy_executor::task]
+async fn slaac_task(stack: Stack<'static>) -> ! {
+    slaac::slaac::<traffic::Counting<WifiDevice<'static>>>(stack).await
+}
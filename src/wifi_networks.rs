@@ -0,0 +1,493 @@
+//! Known Wi-Fi networks, personal or enterprise (802.1X with EAP-PEAP or
+//! EAP-TTLS), and the choice between them after a scan, by priority and then
+//! signal strength
+
+use core::cmp::Reverse;
+
+use defmt::{error, Format};
+use heapless::Vec;
+
+use crate::config;
+
+/// Networks as `priority\x1fssid\x1fpsk\x1feap\x1fidentity\x1fusername\x1fphase2`
+/// records separated by `\x1e`, control characters being the only ones a
+/// passphrase can't contain. The priority defaults to 0, and the fields from
+/// `eap` on are only set for enterprise networks, `psk` then being the EAP
+/// password.
+const NETWORKS: &str = match option_env!("WIFI_NETWORKS") {
+    Some(networks) => networks,
+    None => "",
+};
+/// The single network configured before lists of them were supported
+const SSID: &str = match option_env!("SSID") {
+    Some(ssid) => ssid,
+    None => "",
+};
+const PSK: &str = match option_env!("PSK") {
+    Some(psk) => psk,
+    None => "",
+};
+/// CA certificate the enterprise authentication server is checked against,
+/// PEM with a terminating NUL or DER. Empty if none is configured.
+pub static CA_CERT: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/wifi_ca_cert"));
+/// Join enterprise networks without a CA certificate, accepting any server
+const INSECURE: bool = config::flag(option_env!("WIFI_INSECURE"), false);
+
+pub const MAX_NETWORKS: usize = 8;
+
+/// Longest SSID, and longest EAP identity, username and password esp-wifi
+/// accepts
+pub const MAX_SSID: usize = 32;
+const MAX_IDENTITY: usize = 128;
+const MAX_USERNAME: usize = 128;
+const MAX_PASSWORD: usize = 64;
+
+/// How much stronger in dB another network of the same priority must be to
+/// be preferred over the one last connected to, so the board doesn't hop
+/// between networks of similar strength
+const STICKINESS: i8 = 10;
+
+/// Inner authentication of EAP-TTLS
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Phase2 {
+    Mschapv2,
+    Mschap,
+    Pap,
+    Chap,
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    /// EAP-PEAP with MSCHAPv2 inside
+    Peap,
+    Ttls(Phase2),
+}
+
+/// How the authentication server of enterprise networks is trusted
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Trust {
+    /// Verified against `CA_CERT`
+    CaCert,
+    /// Any server is accepted, as `wifi.insecure` is set
+    Any,
+    /// No CA certificate is configured, nor `wifi.insecure` set, so
+    /// enterprise networks can't be joined
+    Missing,
+}
+
+impl Trust {
+    pub fn configured() -> Self {
+        match (CA_CERT.is_empty(), INSECURE) {
+            (false, _) => Trust::CaCert,
+            (true, true) => Trust::Any,
+            (true, false) => Trust::Missing,
+        }
+    }
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub struct Enterprise<'a> {
+    pub method: Method,
+    /// Outer identity sent in the clear, often `anonymous@realm`
+    pub identity: &'a str,
+    pub username: &'a str,
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    InvalidPriority,
+    MissingSsid,
+    SsidTooLong,
+    /// The EAP method isn't `peap` or `ttls`
+    UnknownMethod,
+    /// The TTLS phase 2 isn't `mschapv2`, `mschap`, `pap` or `chap`
+    UnknownPhase2,
+    /// A phase 2 method was given for PEAP, which only does MSCHAPv2
+    Phase2WithoutTtls,
+    MissingUsername,
+    MissingPassword,
+    IdentityTooLong,
+    UsernameTooLong,
+    PasswordTooLong,
+    /// No CA certificate is configured and `wifi.insecure` isn't set
+    MissingCaCert,
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub struct Network<'a> {
+    pub ssid: &'a str,
+    /// Passphrase, or the EAP password of an enterprise network
+    pub psk: &'a str,
+    /// Higher is preferred
+    pub priority: i32,
+    /// None for personal networks
+    pub enterprise: Option<Enterprise<'a>>,
+}
+
+/// An access point found by a scan
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub struct Seen<'a> {
+    pub ssid: &'a str,
+    /// In dBm
+    pub rssi: i8,
+}
+
+/// Parse and validate the enterprise settings of a network. None if `method`
+/// is empty, meaning a personal network.
+pub fn parse_enterprise<'a>(
+    method: &str,
+    identity: &'a str,
+    username: &'a str,
+    password: &str,
+    phase2: &str,
+    trust: Trust,
+) -> Result<Option<Enterprise<'a>>, Error> {
+    let phase2 = match phase2.trim() {
+        "" => None,
+        "mschapv2" => Some(Phase2::Mschapv2),
+        "mschap" => Some(Phase2::Mschap),
+        "pap" => Some(Phase2::Pap),
+        "chap" => Some(Phase2::Chap),
+        _ => return Err(Error::UnknownPhase2),
+    };
+    let method = match (method.trim(), phase2) {
+        ("", _) => return Ok(None),
+        ("peap", None) => Method::Peap,
+        ("peap", Some(_)) => return Err(Error::Phase2WithoutTtls),
+        ("ttls", phase2) => Method::Ttls(phase2.unwrap_or(Phase2::Mschapv2)),
+        _ => return Err(Error::UnknownMethod),
+    };
+    // The username doubles as the outer identity if none is given
+    let identity = match identity {
+        "" => username,
+        identity => identity,
+    };
+    match () {
+        _ if username.is_empty() => Err(Error::MissingUsername),
+        _ if password.is_empty() => Err(Error::MissingPassword),
+        _ if identity.len() > MAX_IDENTITY => Err(Error::IdentityTooLong),
+        _ if username.len() > MAX_USERNAME => Err(Error::UsernameTooLong),
+        _ if password.len() > MAX_PASSWORD => Err(Error::PasswordTooLong),
+        _ if trust == Trust::Missing => Err(Error::MissingCaCert),
+        _ => Ok(Some(Enterprise {
+            method,
+            identity,
+            username,
+        })),
+    }
+}
+
+/// Parse a network record in the form of `NETWORKS`
+pub fn parse_network(network: &str, trust: Trust) -> Result<Network<'_>, Error> {
+    let mut fields = network.split('\x1f');
+    let mut field = || fields.next().unwrap_or("");
+    let priority = match field().trim() {
+        "" => 0,
+        priority => priority.parse().map_err(|_| Error::InvalidPriority)?,
+    };
+    let ssid = field();
+    let psk = field();
+    let (method, identity, username, phase2) = (field(), field(), field(), field());
+    match ssid.len() {
+        0 => return Err(Error::MissingSsid),
+        length if length > MAX_SSID => return Err(Error::SsidTooLong),
+        _ => {}
+    }
+    Ok(Network {
+        ssid,
+        psk,
+        priority,
+        enterprise: parse_enterprise(method, identity, username, psk, phase2, trust)?,
+    })
+}
+
+/// Parse networks in the form of `NETWORKS`, passing invalid ones to
+/// `invalid` and leaving them out
+pub fn parse(
+    networks: &str,
+    trust: Trust,
+    mut invalid: impl FnMut(&str, Error),
+) -> Vec<Network<'_>, MAX_NETWORKS> {
+    networks
+        .split('\x1e')
+        .filter(|network| !network.is_empty())
+        .filter_map(|network| match parse_network(network, trust) {
+            Ok(network) => Some(network),
+            Err(error) => {
+                let ssid = network.split('\x1f').nth(1).unwrap_or("");
+                invalid(ssid, error);
+                None
+            }
+        })
+        .take(MAX_NETWORKS)
+        .collect()
+}
+
+/// The configured networks, or the single `wifi.ssid` one if no list is
+/// configured
+pub fn configured() -> Vec<Network<'static>, MAX_NETWORKS> {
+    let mut networks = parse(NETWORKS, Trust::configured(), |ssid, error| {
+        error!("WiFi: ignoring network {}: {:?}", ssid, error)
+    });
+    let single = Some(SSID).filter(|ssid| !ssid.is_empty());
+    if let (true, Some(ssid)) = (networks.is_empty(), single) {
+        let _ = networks.push(Network {
+            ssid,
+            psk: PSK,
+            priority: 0,
+            enterprise: None,
+        });
+    }
+    networks
+}
+
+/// Indices of the networks to try in order. Networks seen by the scan come
+/// by priority and then signal strength, `last` getting the benefit of
+/// `STICKINESS`. If none were seen, all are tried by priority in case they
+/// are hidden.
+pub fn rank(networks: &[Network], seen: &[Seen], last: Option<usize>) -> Vec<usize, MAX_NETWORKS> {
+    let rssi = |index: usize| {
+        seen.iter()
+            .filter(|seen| seen.ssid == networks[index].ssid)
+            .map(|seen| seen.rssi)
+            .max()
+    };
+    let mut ranked: Vec<(usize, Option<i8>), MAX_NETWORKS> = (0..networks.len())
+        .take(MAX_NETWORKS)
+        .map(|index| (index, rssi(index)))
+        .collect();
+    if ranked.iter().any(|(_, rssi)| rssi.is_some()) {
+        ranked.retain(|(_, rssi)| rssi.is_some());
+    }
+    ranked.sort_unstable_by_key(|&(index, rssi)| {
+        let is_last = Some(index) == last;
+        let sticky = if is_last { STICKINESS } else { 0 };
+        let rssi = i16::from(rssi.unwrap_or(i8::MIN)) + i16::from(sticky);
+        // A network exactly STICKINESS dB stronger wins the tie
+        (
+            Reverse(networks[index].priority),
+            Reverse(rssi),
+            is_last,
+            index,
+        )
+    });
+    ranked.into_iter().map(|(index, _)| index).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NETWORKS: [Network; 3] = [
+        Network {
+            ssid: "a",
+            psk: "",
+            priority: 0,
+            enterprise: None,
+        },
+        Network {
+            ssid: "b",
+            psk: "",
+            priority: 0,
+            enterprise: None,
+        },
+        Network {
+            ssid: "c",
+            psk: "",
+            priority: 1,
+            enterprise: None,
+        },
+    ];
+
+    fn seen(ssid: &str, rssi: i8) -> Seen<'_> {
+        Seen { ssid, rssi }
+    }
+
+    fn rank(seen: &[Seen], last: Option<usize>) -> std::vec::Vec<usize> {
+        super::rank(&NETWORKS, seen, last).to_vec()
+    }
+
+    #[test]
+    fn priority_beats_signal_strength() {
+        assert_eq!(rank(&[seen("a", -40), seen("c", -80)], None), [2, 0]);
+    }
+
+    #[test]
+    fn strongest_access_point_counts() {
+        let scan = [seen("a", -70), seen("b", -60), seen("a", -50)];
+        assert_eq!(rank(&scan, None), [0, 1]);
+        assert_eq!(rank(&[seen("a", -60), seen("b", -60)], None), [0, 1]);
+    }
+
+    #[test]
+    fn last_network_is_sticky() {
+        // Kept while another is less than STICKINESS dB stronger
+        let scan = [seen("a", -65), seen("b", -56)];
+        assert_eq!(rank(&scan, Some(0)), [0, 1]);
+        assert_eq!(rank(&scan, None), [1, 0]);
+        let scan = [seen("a", -70), seen("b", -60)];
+        assert_eq!(rank(&scan, Some(0)), [1, 0]);
+        // Even a strong signal doesn't beat priority
+        assert_eq!(rank(&[seen("a", -30), seen("c", -90)], Some(0)), [2, 0]);
+        // Nor overflows at the extremes
+        assert_eq!(
+            rank(&[seen("a", i8::MAX), seen("b", i8::MIN)], Some(1)),
+            [0, 1]
+        );
+    }
+
+    #[test]
+    fn tries_all_when_none_seen() {
+        assert_eq!(rank(&[], None), [2, 0, 1]);
+        assert_eq!(rank(&[seen("x", -30)], Some(1)), [2, 1, 0]);
+        assert!(super::rank(&[], &[seen("a", -30)], None).is_empty());
+    }
+
+    #[test]
+    fn parses_networks() {
+        let mut invalid = vec![];
+        let networks = parse(
+            "2\x1flab, 1\x1fa;b,c\x1e\x1foffice\x1fpw\x1ex\x1fbad\x1fp\x1e3\x1f\x1fp\x1e\x1e1\x1fopen",
+            Trust::Missing,
+            |ssid, error| invalid.push((ssid.to_owned(), error)),
+        );
+        assert_eq!(
+            networks.as_slice(),
+            [
+                Network {
+                    ssid: "lab, 1",
+                    psk: "a;b,c",
+                    priority: 2,
+                    enterprise: None,
+                },
+                Network {
+                    ssid: "office",
+                    psk: "pw",
+                    priority: 0,
+                    enterprise: None,
+                },
+                Network {
+                    ssid: "open",
+                    psk: "",
+                    priority: 1,
+                    enterprise: None,
+                },
+            ]
+        );
+        assert_eq!(
+            invalid,
+            [
+                ("bad".to_owned(), Error::InvalidPriority),
+                ("".to_owned(), Error::MissingSsid),
+            ]
+        );
+        let long = "\x1f".to_owned() + &"s".repeat(MAX_SSID + 1);
+        assert_eq!(
+            parse_network(&long, Trust::Missing),
+            Err(Error::SsidTooLong)
+        );
+    }
+
+    #[test]
+    fn keeps_at_most_max_networks() {
+        let networks = "\x1fn\x1e".repeat(MAX_NETWORKS + 2);
+        assert_eq!(
+            parse(&networks, Trust::Missing, |_, _| panic!()).len(),
+            MAX_NETWORKS
+        );
+    }
+
+    #[test]
+    fn parses_enterprise_settings() {
+        let peap = parse_enterprise("peap", "anonymous@corp", "user", "pw", "", Trust::CaCert);
+        assert_eq!(
+            peap,
+            Ok(Some(Enterprise {
+                method: Method::Peap,
+                identity: "anonymous@corp",
+                username: "user",
+            }))
+        );
+        // The username is the outer identity unless one is given
+        let ttls = parse_enterprise(" ttls ", "", "user", "pw", "", Trust::CaCert).unwrap();
+        assert_eq!(
+            ttls,
+            Some(Enterprise {
+                method: Method::Ttls(Phase2::Mschapv2),
+                identity: "user",
+                username: "user",
+            })
+        );
+        for (phase2, expected) in [
+            ("mschapv2", Phase2::Mschapv2),
+            ("mschap", Phase2::Mschap),
+            ("pap", Phase2::Pap),
+            ("chap", Phase2::Chap),
+        ] {
+            let ttls = parse_enterprise("ttls", "", "user", "pw", phase2, Trust::Any);
+            assert_eq!(ttls.unwrap().unwrap().method, Method::Ttls(expected));
+        }
+        assert_eq!(
+            parse_enterprise("", "", "", "", "", Trust::Missing),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_enterprise_settings() {
+        let enterprise = |method, identity: &'static str, username, password: &str, phase2| {
+            parse_enterprise(method, identity, username, password, phase2, Trust::CaCert)
+        };
+        assert_eq!(
+            enterprise("tls", "", "u", "p", ""),
+            Err(Error::UnknownMethod)
+        );
+        assert_eq!(
+            enterprise("ttls", "", "u", "p", "eap"),
+            Err(Error::UnknownPhase2)
+        );
+        assert_eq!(
+            enterprise("peap", "", "u", "p", "pap"),
+            Err(Error::Phase2WithoutTtls)
+        );
+        assert_eq!(enterprise("", "", "u", "p", "pap"), Ok(None));
+        assert_eq!(
+            enterprise("peap", "", "", "p", ""),
+            Err(Error::MissingUsername)
+        );
+        assert_eq!(
+            enterprise("peap", "", "u", "", ""),
+            Err(Error::MissingPassword)
+        );
+
+        let long = "x".repeat(MAX_IDENTITY + 1);
+        let identity = parse_enterprise("peap", &long, "u", "p", "", Trust::CaCert);
+        assert_eq!(identity, Err(Error::IdentityTooLong));
+        let username = parse_enterprise("peap", "anonymous", &long, "p", "", Trust::CaCert);
+        assert_eq!(username, Err(Error::UsernameTooLong));
+        let password = "p".repeat(MAX_PASSWORD + 1);
+        assert_eq!(
+            enterprise("peap", "", "u", &password, ""),
+            Err(Error::PasswordTooLong)
+        );
+    }
+
+    #[test]
+    fn enterprise_networks_need_a_ca_certificate() {
+        let record = "\x1fcorp\x1fpw\x1fpeap\x1f\x1fuser";
+        assert_eq!(
+            parse_network(record, Trust::Missing),
+            Err(Error::MissingCaCert)
+        );
+        assert!(parse_network(record, Trust::CaCert)
+            .unwrap()
+            .enterprise
+            .is_some());
+        assert!(parse_network(record, Trust::Any)
+            .unwrap()
+            .enterprise
+            .is_some());
+        // Personal networks don't
+        assert!(parse_network("\x1fhome\x1fpw", Trust::Missing).is_ok());
+    }
+}